@@ -0,0 +1,25 @@
+//! Lists the versions of the migrations in `migrations/`, so the server can
+//! tell which are pending without running them.
+use std::{env, fs, path::Path};
+
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    let mut versions: Vec<String> = fs::read_dir("migrations")
+        .expect("failed to read migrations")
+        .map(|entry| entry.expect("failed to read migration").path())
+        .filter(|path| path.is_dir())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?.to_string();
+            // Like Diesel: `2020-12-19-000000_create_rate_limits` is version
+            // `20201219000000`.
+            Some(name.split('_').next()?.replace('-', ""))
+        })
+        .collect();
+    versions.sort();
+    let out = Path::new(&env::var("OUT_DIR").expect("no OUT_DIR")).join("migrations.rs");
+    fs::write(
+        out,
+        format!("pub const MIGRATIONS: &[&str] = &{:?};\n", versions),
+    )
+    .expect("failed to write migration versions");
+}
@@ -0,0 +1,8 @@
+CREATE TABLE integrations (
+  id INTEGER PRIMARY KEY NOT NULL,
+  owner_id INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  secret TEXT NOT NULL UNIQUE,
+  created DATETIME NOT NULL DEFAULT (datetime('now')),
+  FOREIGN KEY(owner_id) REFERENCES users(id)
+);
@@ -0,0 +1 @@
+CREATE INDEX readings_sensor_id_timestamp ON readings (sensor_id, timestamp);
@@ -0,0 +1,17 @@
+ALTER TABLE sensors ADD COLUMN model TEXT;
+
+CREATE TABLE model_channels (
+  model TEXT NOT NULL,
+  name TEXT NOT NULL,
+  unit TEXT NOT NULL,
+  type TEXT NOT NULL,
+  PRIMARY KEY (model, name)
+);
+
+CREATE TABLE reading_values (
+  reading_id INTEGER NOT NULL,
+  channel TEXT NOT NULL,
+  value FLOAT NOT NULL,
+  PRIMARY KEY (reading_id, channel),
+  FOREIGN KEY(reading_id) REFERENCES readings(id)
+);
@@ -0,0 +1 @@
+DROP VIEW reading_channel_values;
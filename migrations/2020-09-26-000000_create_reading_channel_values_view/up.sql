@@ -0,0 +1,13 @@
+CREATE VIEW reading_channel_values AS
+SELECT sensor_id, timestamp, 'peak_power_mW' AS channel, peak_power_mW AS value FROM readings
+UNION ALL
+SELECT sensor_id, timestamp, 'peak_current_mA' AS channel, peak_current_mA AS value FROM readings
+UNION ALL
+SELECT sensor_id, timestamp, 'peak_voltage_V' AS channel, peak_voltage_V AS value FROM readings
+UNION ALL
+SELECT sensor_id, timestamp, 'temp_celsius' AS channel, temp_celsius AS value FROM readings
+UNION ALL
+SELECT sensor_id, timestamp, 'batt_V' AS channel, batt_V AS value FROM readings
+UNION ALL
+SELECT r.sensor_id, r.timestamp, v.channel, v.value
+FROM reading_values v JOIN readings r ON r.id = v.reading_id;
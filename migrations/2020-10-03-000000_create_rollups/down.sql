@@ -0,0 +1,2 @@
+DROP TABLE daily_rollups;
+DROP TABLE hourly_rollups;
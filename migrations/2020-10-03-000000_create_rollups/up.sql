@@ -0,0 +1,75 @@
+CREATE TABLE hourly_rollups (
+  sensor_id INTEGER NOT NULL,
+  bucket DATETIME NOT NULL,
+  channel TEXT NOT NULL,
+  count INTEGER NOT NULL,
+  min FLOAT NOT NULL,
+  max FLOAT NOT NULL,
+  sum FLOAT NOT NULL,
+  last FLOAT NOT NULL,
+  last_timestamp DATETIME NOT NULL,
+  integral FLOAT NOT NULL,
+  PRIMARY KEY (sensor_id, bucket, channel),
+  FOREIGN KEY(sensor_id) REFERENCES sensors(id)
+);
+
+CREATE TABLE daily_rollups (
+  sensor_id INTEGER NOT NULL,
+  bucket DATETIME NOT NULL,
+  channel TEXT NOT NULL,
+  count INTEGER NOT NULL,
+  min FLOAT NOT NULL,
+  max FLOAT NOT NULL,
+  sum FLOAT NOT NULL,
+  last FLOAT NOT NULL,
+  last_timestamp DATETIME NOT NULL,
+  integral FLOAT NOT NULL,
+  PRIMARY KEY (sensor_id, bucket, channel),
+  FOREIGN KEY(sensor_id) REFERENCES sensors(id)
+);
+
+-- Backfill from existing readings. New readings are rolled up as they are
+-- inserted.
+WITH
+ wins AS (
+  SELECT sensor_id, timestamp, channel, value,
+   lead(value) OVER win AS next_value,
+   lead(timestamp) OVER win AS next_timestamp,
+   row_number() OVER (PARTITION BY sensor_id, strftime('%Y-%m-%d %H:00:00', timestamp), channel ORDER BY timestamp DESC) AS rn
+  FROM reading_channel_values
+  WINDOW win AS (PARTITION BY sensor_id, channel ORDER BY timestamp ASC)
+ )
+INSERT INTO hourly_rollups (sensor_id, bucket, channel, count, min, max, sum, last, last_timestamp, integral)
+SELECT
+ sensor_id,
+ strftime('%Y-%m-%d %H:00:00', timestamp) AS hour,
+ channel,
+ count(*),
+ min(value),
+ max(value),
+ sum(value),
+ max(CASE WHEN rn = 1 THEN value END),
+ max(timestamp),
+ coalesce(sum((min(value, next_value) + abs(value - next_value) * 0.5) * (julianday(next_timestamp) - julianday(timestamp)) * 24), 0)
+FROM wins
+GROUP BY sensor_id, hour, channel;
+
+WITH
+ ranked AS (
+  SELECT *, row_number() OVER (PARTITION BY sensor_id, date(bucket), channel ORDER BY bucket DESC) AS rn
+  FROM hourly_rollups
+ )
+INSERT INTO daily_rollups (sensor_id, bucket, channel, count, min, max, sum, last, last_timestamp, integral)
+SELECT
+ sensor_id,
+ date(bucket) || ' 00:00:00' AS day,
+ channel,
+ sum(count),
+ min(min),
+ max(max),
+ sum(sum),
+ max(CASE WHEN rn = 1 THEN last END),
+ max(last_timestamp),
+ sum(integral)
+FROM ranked
+GROUP BY sensor_id, day, channel;
@@ -0,0 +1,6 @@
+CREATE TABLE retention_cutoffs (
+  sensor_id INTEGER PRIMARY KEY NOT NULL,
+  raw_before DATETIME NOT NULL,
+  hourly_before DATETIME,
+  FOREIGN KEY(sensor_id) REFERENCES sensors(id)
+);
@@ -0,0 +1,2 @@
+DROP TABLE tariff_rates;
+DROP TABLE energy_settings;
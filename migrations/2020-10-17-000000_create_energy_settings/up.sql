@@ -0,0 +1,17 @@
+CREATE TABLE energy_settings (
+  sensor_id INTEGER PRIMARY KEY NOT NULL,
+  system_size_kW FLOAT,
+  panel_rated_mW FLOAT,
+  tariff_per_kWh FLOAT,
+  emissions_per_kWh FLOAT,
+  FOREIGN KEY(sensor_id) REFERENCES sensors(id)
+);
+
+CREATE TABLE tariff_rates (
+  sensor_id INTEGER NOT NULL,
+  start_hour INTEGER NOT NULL,
+  end_hour INTEGER NOT NULL,
+  rate_per_kWh FLOAT NOT NULL,
+  PRIMARY KEY (sensor_id, start_hour),
+  FOREIGN KEY(sensor_id) REFERENCES sensors(id)
+);
@@ -0,0 +1 @@
+ALTER TABLE sensors ADD COLUMN timezone TEXT;
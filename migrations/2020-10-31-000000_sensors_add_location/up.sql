@@ -0,0 +1,5 @@
+ALTER TABLE sensors ADD COLUMN latitude FLOAT;
+ALTER TABLE sensors ADD COLUMN longitude FLOAT;
+ALTER TABLE sensors ADD COLUMN elevation_m FLOAT;
+ALTER TABLE sensors ADD COLUMN tilt_deg FLOAT;
+ALTER TABLE sensors ADD COLUMN azimuth_deg FLOAT;
@@ -0,0 +1 @@
+DROP INDEX readings_sensor_id_created;
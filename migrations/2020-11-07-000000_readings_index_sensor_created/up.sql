@@ -0,0 +1 @@
+CREATE INDEX readings_sensor_id_created ON readings (sensor_id, created);
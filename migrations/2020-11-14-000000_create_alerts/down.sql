@@ -0,0 +1,2 @@
+DROP TABLE alert_incidents;
+DROP TABLE alert_rules;
@@ -0,0 +1,26 @@
+CREATE TABLE alert_rules (
+  id INTEGER PRIMARY KEY NOT NULL,
+  sensor_id INTEGER NOT NULL,
+  kind TEXT NOT NULL,
+  channel TEXT,
+  threshold FLOAT NOT NULL,
+  muted_until DATETIME,
+  created DATETIME NOT NULL DEFAULT (datetime('now')),
+  FOREIGN KEY(sensor_id) REFERENCES sensors(id)
+);
+
+CREATE INDEX alert_rules_sensor_id ON alert_rules (sensor_id);
+
+CREATE TABLE alert_incidents (
+  id INTEGER PRIMARY KEY NOT NULL,
+  rule_id INTEGER NOT NULL,
+  opened DATETIME NOT NULL,
+  resolved DATETIME,
+  acknowledged DATETIME,
+  message TEXT NOT NULL,
+  FOREIGN KEY(rule_id) REFERENCES alert_rules(id)
+);
+
+-- A rule has at most one open incident, so a condition that keeps holding
+-- is only reported once.
+CREATE UNIQUE INDEX alert_incidents_open_rule_id ON alert_incidents (rule_id) WHERE resolved IS NULL;
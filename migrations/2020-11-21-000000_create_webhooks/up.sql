@@ -0,0 +1,32 @@
+CREATE TABLE webhooks (
+  id INTEGER PRIMARY KEY NOT NULL,
+  owner_id INTEGER NOT NULL,
+  url TEXT NOT NULL,
+  secret TEXT NOT NULL,
+  events TEXT NOT NULL,
+  active BOOLEAN NOT NULL DEFAULT 1,
+  created DATETIME NOT NULL DEFAULT (datetime('now')),
+  FOREIGN KEY(owner_id) REFERENCES users(id)
+);
+
+CREATE INDEX webhooks_owner_id ON webhooks (owner_id);
+
+-- Deliveries are both the queue of events still to be sent and the log of
+-- what was sent.
+CREATE TABLE webhook_deliveries (
+  id INTEGER PRIMARY KEY NOT NULL,
+  webhook_id INTEGER NOT NULL,
+  event TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  status TEXT NOT NULL DEFAULT 'pending',
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt DATETIME NOT NULL,
+  last_attempt DATETIME,
+  response_status INTEGER,
+  error TEXT,
+  created DATETIME NOT NULL DEFAULT (datetime('now')),
+  FOREIGN KEY(webhook_id) REFERENCES webhooks(id)
+);
+
+CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
+CREATE INDEX webhook_deliveries_pending ON webhook_deliveries (next_attempt) WHERE status = 'pending';
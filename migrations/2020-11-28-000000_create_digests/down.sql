@@ -0,0 +1,2 @@
+DROP TABLE unsubscribe_tokens;
+DROP TABLE digest_subscriptions;
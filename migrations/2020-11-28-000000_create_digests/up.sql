@@ -0,0 +1,16 @@
+CREATE TABLE digest_subscriptions (
+  user_id INTEGER PRIMARY KEY NOT NULL,
+  frequency TEXT NOT NULL,
+  last_sent DATETIME,
+  created DATETIME NOT NULL DEFAULT (datetime('now')),
+  FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+-- Each digest has its own unsubscribe link, which works once.
+CREATE TABLE unsubscribe_tokens (
+  nonce TEXT PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL,
+  created DATETIME NOT NULL DEFAULT (datetime('now')),
+  used DATETIME,
+  FOREIGN KEY(user_id) REFERENCES users(id)
+);
@@ -0,0 +1,24 @@
+CREATE TABLE jobs (
+  name TEXT PRIMARY KEY NOT NULL,
+  -- Cron expression, in UTC.
+  schedule TEXT NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT 1,
+  next_run DATETIME NOT NULL,
+  -- The server running the job, until the lock expires.
+  locked_by TEXT,
+  locked_until DATETIME,
+  last_run DATETIME
+);
+
+CREATE TABLE job_runs (
+  id INTEGER PRIMARY KEY NOT NULL,
+  job_name TEXT NOT NULL,
+  runner TEXT NOT NULL,
+  started DATETIME NOT NULL,
+  finished DATETIME,
+  status TEXT NOT NULL DEFAULT 'running',
+  output TEXT,
+  FOREIGN KEY(job_name) REFERENCES jobs(name)
+);
+
+CREATE INDEX job_runs_job_name ON job_runs (job_name, id);
@@ -0,0 +1,16 @@
+-- Security-relevant actions, kept for admins to look back through.
+CREATE TABLE audit_log (
+  id INTEGER PRIMARY KEY NOT NULL,
+  -- The user who acted, or whose account was acted on for failed logins.
+  user_id INTEGER,
+  action TEXT NOT NULL,
+  -- What was acted on, like "sensor 3".
+  target TEXT,
+  detail TEXT,
+  ip TEXT,
+  request_id TEXT,
+  created DATETIME NOT NULL DEFAULT (datetime('now')),
+  FOREIGN KEY(user_id) REFERENCES users(id)
+);
+
+CREATE INDEX audit_log_user_id ON audit_log (user_id, id);
@@ -0,0 +1,18 @@
+-- Limits an admin raised for one sensor or user, as a multiple of the usual
+-- limits. Principals are like "sensor:3" or "user:7".
+CREATE TABLE rate_limit_overrides (
+  principal TEXT PRIMARY KEY NOT NULL,
+  multiplier INTEGER NOT NULL,
+  created DATETIME NOT NULL DEFAULT (datetime('now'))
+);
+
+-- Token buckets, when they're kept in the database so that several servers
+-- share them.
+CREATE TABLE rate_limit_buckets (
+  key TEXT PRIMARY KEY NOT NULL,
+  -- Tokens taken and not yet refilled, so a raised limit takes effect at once.
+  used DOUBLE NOT NULL,
+  updated DATETIME NOT NULL
+);
+
+CREATE INDEX rate_limit_buckets_updated ON rate_limit_buckets (updated);
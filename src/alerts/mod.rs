@@ -0,0 +1,143 @@
+//! Evaluates every alert rule from the `alerts` job, opening an incident when
+//! a rule's condition starts holding and resolving it when it stops. Owners
+//! are emailed once when an incident opens and once when it resolves, unless
+//! the rule is muted. Owners' webhooks are told about every change, muted or not.
+use crate::{
+    logging,
+    models::{
+        alert::{self, AlertRule},
+        webhook, Sensor, SensorQuery, User,
+    },
+    result::Result,
+    util::email::{Notifier, SITE_URL},
+};
+use chrono::NaiveDateTime;
+use diesel::SqliteConnection;
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub opened: usize,
+    pub resolved: usize,
+    pub notified: usize,
+}
+
+fn sensor_name(sensor: &SensorQuery) -> String {
+    sensor
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("Sensor {}", sensor.id))
+}
+
+fn notify(
+    rule: &AlertRule,
+    sensor: &SensorQuery,
+    subject: &str,
+    message: &str,
+    conn: &SqliteConnection,
+    notifier: &dyn Notifier,
+) -> Result<()> {
+    let owner = User::by_id(sensor.owner_id, conn)?;
+    let html = format!(
+        "<html><body><p>{}</p><p>{}</p><p><a href=\"{}/sensor/{}/alerts\">View alerts</a></p></body></html>",
+        rule.describe(),
+        message,
+        SITE_URL,
+        sensor.id
+    );
+    notifier.notify(
+        &owner.email,
+        &format!("{}: {}", sensor_name(sensor), subject),
+        &html,
+        None,
+    )
+}
+
+fn emit(
+    event: &str,
+    rule: &AlertRule,
+    sensor: &SensorQuery,
+    message: &str,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    webhook::emit(
+        sensor.owner_id,
+        event,
+        json!({
+            "sensor_id": sensor.id,
+            "rule": rule.clone().described(),
+            "message": message,
+            "timestamp": now.timestamp(),
+        }),
+        conn,
+    )
+}
+
+/// Evaluates one rule, opens or resolves its incident, and emails the owner
+/// about the change.
+fn run_rule(
+    rule: &AlertRule,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+    notifier: &dyn Notifier,
+    report: &mut Report,
+) -> Result<()> {
+    let sensor = Sensor::find(rule.sensor_id, conn)?;
+    if !sensor.active {
+        return Ok(());
+    }
+    let firing = alert::evaluate(rule, &sensor, now, conn)?;
+    let open = alert::open_incident(rule.id, conn)?;
+    let subject = match (firing, open) {
+        (Some(message), None) => {
+            alert::open(rule.id, &message, now, conn)?;
+            report.opened += 1;
+            emit(webhook::ALERT_OPENED, rule, &sensor, &message, now, conn)?;
+            Some((format!("alert for {}", rule.describe()), message))
+        }
+        (None, Some(incident)) => {
+            alert::resolve(incident.id, now, conn)?;
+            report.resolved += 1;
+            emit(
+                webhook::ALERT_RESOLVED,
+                rule,
+                &sensor,
+                &incident.message,
+                now,
+                conn,
+            )?;
+            Some((
+                format!("resolved alert for {}", rule.describe()),
+                incident.message,
+            ))
+        }
+        _ => None,
+    };
+    if let Some((subject, message)) = subject {
+        if !rule.is_muted(now) {
+            // The incident is already recorded, so a failed email isn't
+            // retried and doesn't stop other rules from being evaluated.
+            match notify(rule, &sensor, &subject, &message, conn, notifier) {
+                Ok(()) => report.notified += 1,
+                Err(e) => {
+                    logging::error("alerts", format!("failed to email rule {}: {}", rule.id, e))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates every rule at `now`.
+pub fn run(now: NaiveDateTime, conn: &SqliteConnection, notifier: &dyn Notifier) -> Result<Report> {
+    let mut report = Report::default();
+    for rule in alert::all_rules(conn)? {
+        if let Err(e) = run_rule(&rule, now, conn, notifier, &mut report) {
+            logging::error(
+                "alerts",
+                format!("failed to evaluate rule {}: {}", rule.id, e),
+            );
+        }
+    }
+    Ok(report)
+}
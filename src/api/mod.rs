@@ -1,16 +1,42 @@
 use crate::{
     auth,
-    db::SolDbConn,
+    db::{DbPool, SolDbConn, SolDbConnPool},
+    export::{Export, ExportOptions, Format},
+    import::{self, ImportOptions, ImportReport},
+    live::{self, EventStream, LiveReading, Topic},
+    logging::RequestInfo,
+    lorawan,
     models::{
-        Energy, Reading, ReadingInsert, ReadingQueryUnix, Sensor, SensorInsert, Token, User,
-        UserQuery,
+        self,
+        aggregate::{self, Interval},
+        alert::{self, AlertRule, AlertRuleInsert, DescribedRule, Incident},
+        audit::{self, Entry},
+        channel::{self, Channel, ChannelValues},
+        digest::{self, Frequency},
+        energy::{self, EnergySettings, EnergySummary, Period, TariffRate},
+        health::{self, Health},
+        integration,
+        performance::{self, Performance},
+        rate_limit::{self, Override},
+        retention,
+        webhook::{self, Delivery, Webhook},
+        Cursor, Energy, Order, Reading, ReadingInsert, ReadingQueryUnix, Sensor, SensorInsert,
+        SensorLocation, SensorQuery, Token, User, UserQuery,
     },
+    ratelimit::Principal,
     result::{Error, Result},
 };
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use diesel::{Connection, SqliteConnection};
 use git_version::git_version;
-use rocket::{get, http::RawStr, post, request::FromFormValue};
-use rocket_contrib::json::Json;
+use rocket::{
+    delete, get,
+    http::RawStr,
+    post, put,
+    request::{Form, FromFormValue},
+    Data as RequestData, State,
+};
+use rocket_contrib::json::{Json, JsonValue};
 
 mod res;
 use self::res::Data;
@@ -76,35 +102,681 @@ pub struct GetEnergyStatsResponse {
     pub stats: Vec<Energy>,
 }
 
-#[get("/sensor/<id>/energy_stats")]
-pub fn get_energy_stats(id: i32, conn: SolDbConn) -> ApiResult<Json<GetEnergyStatsResponse>> {
-    let res = Sensor::energy_stats(id, &conn)
+/// A `YYYY-MM-DD` date in a query string.
+pub struct IsoDate(NaiveDate);
+
+impl<'v> FromFormValue<'v> for IsoDate {
+    type Error = &'v RawStr;
+    fn from_form_value(form_value: &'v RawStr) -> std::result::Result<Self, Self::Error> {
+        NaiveDate::parse_from_str(form_value.as_str(), "%Y-%m-%d")
+            .map(IsoDate)
+            .map_err(|_| form_value)
+    }
+}
+
+/// How many days of energy stats are returned without a `start`.
+pub const DEFAULT_ENERGY_STATS_DAYS: i64 = 10;
+
+/// The most days energy stats and performance are computed for at once.
+pub const MAX_ENERGY_DAYS: i64 = 3 * 366;
+
+/// The local days from `start` to `end`, inclusive. `end` defaults to today
+/// in the sensor's timezone and `start` to the 10 days ending at `end`. At
+/// most `MAX_ENERGY_DAYS` days can be asked for.
+fn date_range(
+    sensor: &SensorQuery,
+    start: Option<IsoDate>,
+    end: Option<IsoDate>,
+) -> Result<(NaiveDate, NaiveDate)> {
+    let end = end.map(|d| d.0).unwrap_or_else(|| sensor.today());
+    let start = start
+        .map(|d| d.0)
+        .unwrap_or_else(|| end - Duration::days(DEFAULT_ENERGY_STATS_DAYS - 1));
+    if start > end {
+        return Err(Error::InvalidDateRange);
+    }
+    if (end - start).num_days() + 1 > MAX_ENERGY_DAYS {
+        return Err(Error::DateRangeTooLong(MAX_ENERGY_DAYS));
+    }
+    Ok((start, end))
+}
+
+/// Daily energy stats for the days from `start` to `end`, inclusive, in the
+/// sensor's timezone. `end` defaults to today and `start` to the 10 days
+/// ending at `end`.
+#[get("/sensor/<id>/energy_stats?<start>&<end>")]
+pub fn get_energy_stats(
+    id: i32,
+    start: Option<IsoDate>,
+    end: Option<IsoDate>,
+    conn: SolDbConn,
+) -> ApiResult<Json<GetEnergyStatsResponse>> {
+    let sensor = Sensor::find(id, &conn)?;
+    let (start, end) = date_range(&sensor, start, end)?;
+    let res = Sensor::energy_stats(&sensor, start, end, &conn)
         .map(|stats| GetEnergyStatsResponse { stats })
         .map(Json)?;
     Ok(res)
 }
 
-#[get("/sensor/<id>/readings?<start>&<end>&<unixtime>")]
+/// Energy totals for the week, month or year containing `date`, with deltas
+/// against the previous period and the same period a year earlier. `period`
+/// defaults to month and `date` to today in the sensor's timezone.
+#[get("/sensor/<id>/energy_summary?<period>&<date>")]
+pub fn get_energy_summary(
+    id: i32,
+    period: Option<String>,
+    date: Option<IsoDate>,
+    conn: SolDbConn,
+) -> ApiResult<Json<EnergySummary>> {
+    let sensor = Sensor::find(id, &conn)?;
+    let period = match period {
+        Some(name) => Period::from_string(&name)?,
+        None => Period::Month,
+    };
+    let today = sensor.today();
+    let date = date.map(|d| d.0).unwrap_or(today);
+    let res = energy::summary(&sensor, period, date, today, &conn)?;
+    Ok(Json(res))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EnergySettingsBody {
+    #[serde(flatten)]
+    pub settings: EnergySettings,
+    #[serde(default)]
+    pub tariff_rates: Vec<TariffRate>,
+}
+
+#[derive(Serialize)]
+pub struct GetEnergySettingsResponse {
+    #[serde(flatten)]
+    pub settings: EnergySettingsBody,
+    /// The settings actually used, with defaults filled in.
+    pub effective: JsonValue,
+}
+
+#[get("/sensor/<id>/energy_settings")]
+pub fn get_energy_settings(id: i32, conn: SolDbConn) -> ApiResult<Json<GetEnergySettingsResponse>> {
+    let sensor = Sensor::find(id, &conn)?;
+    let settings = energy::settings(sensor.id, &conn)?;
+    let tariff_rates = energy::tariff_rates(sensor.id, &conn)?;
+    let effective = json!({
+        "system_size_kW": settings.system_size_kW(),
+        "panel_rated_mW": settings.panel_rated_mW(),
+        "tariff_per_kWh": settings.tariff_per_kWh(),
+        "emissions_per_kWh": settings.emissions_per_kWh(),
+    });
+    Ok(Json(GetEnergySettingsResponse {
+        settings: EnergySettingsBody {
+            settings,
+            tariff_rates,
+        },
+        effective,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct SetEnergySettingsResponse {}
+
+/// Replaces a sensor's energy settings and time-of-use rates. Settings that
+/// are left out or null use the defaults.
+#[put(
+    "/sensor/<id>/energy_settings",
+    format = "application/json",
+    data = "<body>"
+)]
+pub fn set_energy_settings(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    body: Json<EnergySettingsBody>,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> ApiResult<Json<SetEnergySettingsResponse>> {
+    let user = auth?.user();
+    let sensor = Sensor::find(id, &conn)?;
+    if sensor.owner_id != user.id {
+        return Err(Error::NotSensorOwner.into());
+    }
+    let body = body.into_inner();
+    energy::save(sensor.id, body.settings, body.tariff_rates, &conn)?;
+    audit::record(
+        Some(user.id),
+        audit::SENSOR_EDITED,
+        Some(&format!("sensor {}", id)),
+        Some("energy settings"),
+        &info,
+        &conn,
+    )?;
+    Ok(Json(SetEnergySettingsResponse {}))
+}
+
+/// Measured against clear-sky energy of a sensor's panel for each local day
+/// from `start` to `end`, with the same defaults as energy stats.
+#[get("/sensor/<id>/performance?<start>&<end>")]
+pub fn get_performance(
+    id: i32,
+    start: Option<IsoDate>,
+    end: Option<IsoDate>,
+    conn: SolDbConn,
+) -> ApiResult<Json<Performance>> {
+    let sensor = Sensor::find(id, &conn)?;
+    let (start, end) = date_range(&sensor, start, end)?;
+    Ok(Json(performance::performance(&sensor, start, end, &conn)?))
+}
+
+/// Whether a sensor is reporting, its battery trend and temperature extremes,
+/// and an overall status of healthy, degraded or offline.
+#[get("/sensor/<id>/health")]
+pub fn get_sensor_health(id: i32, conn: SolDbConn) -> ApiResult<Json<Health>> {
+    let sensor = Sensor::find(id, &conn)?;
+    Ok(Json(health::health(
+        &sensor,
+        Utc::now().naive_utc(),
+        &conn,
+    )?))
+}
+
+/// The sensor with `id`, if the token's user owns it.
+fn owned_sensor(id: i32, user: &UserQuery, conn: &SqliteConnection) -> Result<SensorQuery> {
+    let sensor = Sensor::find(id, conn)?;
+    if sensor.owner_id != user.id {
+        return Err(Error::NotSensorOwner);
+    }
+    Ok(sensor)
+}
+
+#[derive(Serialize)]
+pub struct GetAlertsResponse {
+    pub rules: Vec<DescribedRule>,
+    pub incidents: Vec<Incident>,
+}
+
+#[get("/sensor/<id>/alerts")]
+pub fn get_alerts(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    conn: SolDbConn,
+) -> ApiResult<Json<GetAlertsResponse>> {
+    let sensor = owned_sensor(id, &auth?.user(), &conn)?;
+    let rules = alert::rules_for_sensor(sensor.id, &conn)?
+        .into_iter()
+        .map(AlertRule::described)
+        .collect();
+    let incidents = alert::incidents_for_sensor(sensor.id, alert::HISTORY_LIMIT, &conn)?;
+    Ok(Json(GetAlertsResponse { rules, incidents }))
+}
+
+#[derive(Serialize)]
+pub struct AlertResponse {}
+
+#[post(
+    "/sensor/<id>/alert_rules",
+    format = "application/json",
+    data = "<body>"
+)]
+pub fn add_alert_rule(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    body: Json<AlertRuleInsert>,
+    conn: SolDbConn,
+) -> ApiResult<Json<AlertResponse>> {
+    let sensor = owned_sensor(id, &auth?.user(), &conn)?;
+    let rule = AlertRuleInsert {
+        sensor_id: sensor.id,
+        ..body.into_inner()
+    };
+    alert::create(&rule, &conn)?;
+    Ok(Json(AlertResponse {}))
+}
+
+#[derive(Deserialize)]
+pub struct MuteAlertRule {
+    /// Hours to mute the rule for, or `None` to unmute it.
+    pub hours: Option<f64>,
+}
+
+#[put("/alert_rule/<id>/mute", format = "application/json", data = "<body>")]
+pub fn mute_alert_rule(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    body: Json<MuteAlertRule>,
+    conn: SolDbConn,
+) -> ApiResult<Json<AlertResponse>> {
+    let sensor = alert::rule_sensor(id, &conn)?;
+    owned_sensor(sensor.id, &auth?.user(), &conn)?;
+    let until = match body.hours {
+        Some(hours) => Some(alert::mute_until(Utc::now().naive_utc(), hours)?),
+        None => None,
+    };
+    alert::mute(id, until, &conn)?;
+    Ok(Json(AlertResponse {}))
+}
+
+#[post("/alert/<id>/acknowledge")]
+pub fn acknowledge_alert(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    conn: SolDbConn,
+) -> ApiResult<Json<AlertResponse>> {
+    let incident = alert::find_incident(id, &conn)?;
+    let sensor = alert::rule_sensor(incident.rule_id, &conn)?;
+    owned_sensor(sensor.id, &auth?.user(), &conn)?;
+    alert::acknowledge(id, Utc::now().naive_utc(), &conn)?;
+    Ok(Json(AlertResponse {}))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DigestSettings {
+    /// `daily` or `weekly`, or `None` for no digest emails.
+    pub frequency: Option<String>,
+}
+
+#[get("/digest")]
+pub fn get_digest(
+    auth: Result<auth::UserToken>,
+    conn: SolDbConn,
+) -> ApiResult<Json<DigestSettings>> {
+    let sub = digest::find(auth?.user().id, &conn)?;
+    Ok(Json(DigestSettings {
+        frequency: sub.map(|s| s.frequency),
+    }))
+}
+
+#[put("/digest", format = "application/json", data = "<body>")]
+pub fn set_digest(
+    auth: Result<auth::UserToken>,
+    body: Json<DigestSettings>,
+    conn: SolDbConn,
+) -> ApiResult<Json<DigestSettings>> {
+    let frequency = match body.frequency {
+        Some(ref f) => Some(Frequency::from_string(f)?),
+        None => None,
+    };
+    digest::subscribe(auth?.user().id, frequency, &conn)?;
+    Ok(Json(body.into_inner()))
+}
+
+#[derive(Deserialize)]
+pub struct TransferSensor {
+    /// Email of the user to give the sensor to.
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct TransferSensorResponse {}
+
+#[post("/sensor/<id>/transfer", format = "application/json", data = "<body>")]
+pub fn transfer_sensor(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    body: Json<TransferSensor>,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> ApiResult<Json<TransferSensorResponse>> {
+    let user = auth?.user();
+    owned_sensor(id, &user, &conn)?;
+    let new_owner = User::by_email(&body.email, &conn)?;
+    Sensor::transfer(id, new_owner.id, &conn)?;
+    audit::record(
+        Some(user.id),
+        audit::SENSOR_TRANSFERRED,
+        Some(&format!("sensor {}", id)),
+        Some(&format!("to {}", new_owner.email)),
+        &info,
+        &conn,
+    )?;
+    Ok(Json(TransferSensorResponse {}))
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhook {
+    pub url: String,
+    /// Generated if it isn't given.
+    pub secret: Option<String>,
+    /// Events to send, or every event if it isn't given.
+    pub events: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct CreateWebhookResponse {
+    pub id: i32,
+    /// Only returned here, so keep it somewhere safe.
+    pub secret: String,
+}
+
+#[post("/webhooks", format = "application/json", data = "<body>")]
+pub fn add_webhook(
+    auth: Result<auth::UserToken>,
+    body: Json<CreateWebhook>,
+    conn: SolDbConn,
+) -> ApiResult<Json<CreateWebhookResponse>> {
+    let body = body.into_inner();
+    let (id, secret) =
+        webhook::create(auth?.user().id, &body.url, body.secret, body.events, &conn)?;
+    Ok(Json(CreateWebhookResponse { id, secret }))
+}
+
+#[get("/webhooks")]
+pub fn get_webhooks(
+    auth: Result<auth::UserToken>,
+    conn: SolDbConn,
+) -> ApiResult<Json<Vec<Webhook>>> {
+    Ok(Json(webhook::for_user(auth?.user().id, &conn)?))
+}
+
+fn owned_webhook(id: i32, user: &UserQuery, conn: &SqliteConnection) -> Result<Webhook> {
+    let hook = webhook::find(id, conn)?;
+    if hook.owner_id != user.id {
+        return Err(Error::NotWebhookOwner);
+    }
+    Ok(hook)
+}
+
+#[derive(Serialize)]
+pub struct DeleteWebhookResponse {}
+
+#[delete("/webhook/<id>")]
+pub fn delete_webhook(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    conn: SolDbConn,
+) -> ApiResult<Json<DeleteWebhookResponse>> {
+    owned_webhook(id, &auth?.user(), &conn)?;
+    webhook::deactivate(id, &conn)?;
+    Ok(Json(DeleteWebhookResponse {}))
+}
+
+/// How many of a webhook's latest deliveries are returned.
+pub const DELIVERY_HISTORY_LIMIT: i64 = 100;
+
+#[get("/webhook/<id>/deliveries")]
+pub fn get_webhook_deliveries(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    conn: SolDbConn,
+) -> ApiResult<Json<Vec<Delivery>>> {
+    owned_webhook(id, &auth?.user(), &conn)?;
+    Ok(Json(webhook::deliveries(
+        id,
+        DELIVERY_HISTORY_LIMIT,
+        &conn,
+    )?))
+}
+
+/// Clear-sky irradiance and expected power of a sensor's panel every five
+/// minutes in a time range.
+#[get("/sensor/<id>/clear_sky?<start>&<end>")]
+pub fn get_clear_sky(
+    id: i32,
+    start: UnixEpochTime,
+    end: UnixEpochTime,
+    conn: SolDbConn,
+) -> ApiResult<Data> {
+    let sensor = Sensor::find(id, &conn)?;
+    let points = performance::clear_sky(&sensor, start.0, end.0, &conn)?;
+    let obj = json!({ "points": points });
+    Ok(Data::new(
+        "computed clear-sky output for sensor in range",
+        obj,
+    ))
+}
+
+#[derive(Serialize)]
+pub struct SetLocationResponse {}
+
+#[put("/sensor/<id>/location", format = "application/json", data = "<body>")]
+pub fn set_location(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    body: Json<SensorLocation>,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> ApiResult<Json<SetLocationResponse>> {
+    let user = auth?.user();
+    let sensor = Sensor::find(id, &conn)?;
+    if sensor.owner_id != user.id {
+        return Err(Error::NotSensorOwner.into());
+    }
+    Sensor::set_location(sensor.id, &body, &conn)?;
+    audit::record(
+        Some(user.id),
+        audit::SENSOR_EDITED,
+        Some(&format!("sensor {}", id)),
+        Some("location"),
+        &info,
+        &conn,
+    )?;
+    Ok(Json(SetLocationResponse {}))
+}
+
+impl<'v> FromFormValue<'v> for Order {
+    type Error = &'v RawStr;
+    fn from_form_value(form_value: &'v RawStr) -> std::result::Result<Self, Self::Error> {
+        match form_value.as_str() {
+            "asc" => Ok(Order::Asc),
+            "desc" => Ok(Order::Desc),
+            _ => Err(form_value),
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for Cursor {
+    type Error = &'v RawStr;
+    fn from_form_value(form_value: &'v RawStr) -> std::result::Result<Self, Self::Error> {
+        Cursor::parse(form_value.as_str()).ok_or(form_value)
+    }
+}
+
+/// Page size for requests with a `cursor` but no `limit`.
+pub const DEFAULT_READINGS_LIMIT: i64 = 1000;
+pub const MAX_READINGS_LIMIT: i64 = 10000;
+
+fn to_value<T: serde::Serialize>(reading: T) -> serde_json::Value {
+    serde_json::to_value(reading).expect("failed to serialize reading")
+}
+
+/// Fields that every reading has, besides its channels.
+const READING_FIELDS: [&str; 4] = ["id", "sensor_id", "timestamp", "created"];
+
+/// Returns a page of readings. When there may be more, the response has a
+/// `next_cursor` to pass back as `cursor` to get the next page. Without a
+/// `limit` or `cursor`, every reading in the range is returned, as before
+/// readings were paged. `fields` is a comma-separated list of the fields to
+/// include in each reading.
+#[get("/sensor/<id>/readings?<start>&<end>&<unixtime>&<limit>&<cursor>&<order>&<fields>")]
+#[allow(clippy::too_many_arguments)]
 pub fn get_readings(
     id: i32,
     start: UnixEpochTime,
     end: UnixEpochTime,
     conn: SolDbConn,
     unixtime: Option<bool>,
+    limit: Option<i64>,
+    cursor: Option<Cursor>,
+    order: Option<Order>,
+    fields: Option<String>,
 ) -> ApiResult<Data> {
-    let res =
-        Reading::find_for_sensor_in_time_range(id, start.0, end.0, &conn).map(|readings| {
-            let obj = match unixtime {
-                Some(true) => {
-                    let rs: Vec<ReadingQueryUnix> =
-                        readings.into_iter().map(ReadingQueryUnix::from).collect();
-                    json!({ "readings": rs })
-                }
-                _ => json!({ "readings": readings }),
-            };
-            Data::new("found all readings for sensor in range", obj)
-        })?;
-    Ok(res)
+    let limit = match (limit, cursor) {
+        (Some(limit), _) => Some(limit.max(1).min(MAX_READINGS_LIMIT)),
+        (None, Some(_)) => Some(DEFAULT_READINGS_LIMIT),
+        (None, None) => None,
+    };
+    let fields: Option<Vec<String>> =
+        fields.map(|f| f.split(',').map(|s| s.trim().to_string()).collect());
+    if let Some(ref fields) = fields {
+        let sensor = Sensor::find(id, &conn)?;
+        let channels = channel::for_sensor(&sensor, &conn)?;
+        for f in fields {
+            let known =
+                READING_FIELDS.contains(&f.as_str()) || channels.iter().any(|c| &c.name == f);
+            if !known {
+                return Err(Error::UnknownField(f.clone()).into());
+            }
+        }
+    }
+
+    let readings = retention::find_readings(
+        id,
+        start.0,
+        end.0,
+        cursor,
+        order.unwrap_or(Order::Asc),
+        limit.unwrap_or(std::i64::MAX),
+        &conn,
+    )?;
+    let next_cursor = match (readings.last(), limit) {
+        (Some(r), Some(limit)) if readings.len() as i64 == limit => {
+            Some(Cursor::after(&r.reading).to_string())
+        }
+        _ => None,
+    };
+    let mut rs: Vec<serde_json::Value> = match unixtime {
+        Some(true) => readings
+            .into_iter()
+            .map(|r| to_value(r.map(ReadingQueryUnix::from)))
+            .collect(),
+        _ => readings.into_iter().map(to_value).collect(),
+    };
+    if let Some(fields) = fields {
+        rs = rs
+            .into_iter()
+            .map(|r| match r {
+                serde_json::Value::Object(obj) => serde_json::Value::Object(
+                    obj.into_iter()
+                        .filter(|(k, _)| fields.contains(k))
+                        .collect(),
+                ),
+                r => r,
+            })
+            .collect();
+    }
+    let obj = json!({ "readings": rs, "next_cursor": next_cursor });
+    Ok(Data::new("found readings for sensor in range", obj))
+}
+
+impl<'v> FromFormValue<'v> for Interval {
+    type Error = &'v RawStr;
+    fn from_form_value(form_value: &'v RawStr) -> std::result::Result<Self, Self::Error> {
+        Interval::from_string(form_value.as_str()).map_err(|_| form_value)
+    }
+}
+
+/// Min, max, mean, last value and count of every channel of a sensor, bucketed
+/// by `interval`. Days, weeks and months start at local midnight in `tz`, or
+/// in the sensor's timezone.
+#[get("/sensor/<id>/aggregate?<start>&<end>&<interval>&<tz>")]
+pub fn get_aggregates(
+    id: i32,
+    start: UnixEpochTime,
+    end: UnixEpochTime,
+    interval: Interval,
+    tz: Option<String>,
+    conn: SolDbConn,
+) -> ApiResult<Data> {
+    let sensor = Sensor::find(id, &conn)?;
+    let tz = match tz {
+        Some(name) => models::parse_timezone(&name)?,
+        None => sensor.tz(),
+    };
+    let buckets = aggregate::aggregate(id, start.0, end.0, interval, tz, &conn)?;
+    let obj = json!({
+        "interval": interval.get_string(),
+        "timezone": tz.name(),
+        "buckets": buckets,
+    });
+    Ok(Data::new("found aggregates for sensor in range", obj))
+}
+
+/// Streams a sensor's readings in a time range as a file. Like the readings
+/// endpoint, anyone can export a sensor's readings.
+#[get("/sensor/<id>/export?<start>&<end>&<format>&<unixtime>")]
+pub fn export_sensor_readings(
+    id: i32,
+    start: UnixEpochTime,
+    end: UnixEpochTime,
+    format: Option<Format>,
+    unixtime: Option<bool>,
+    pool: State<SolDbConnPool>,
+    conn: SolDbConn,
+) -> ApiResult<Export> {
+    let sensor = Sensor::find(id, &conn)?;
+    let opts = ExportOptions {
+        start: start.0,
+        end: end.0,
+        format: format.unwrap_or(Format::Csv),
+        unixtime: unixtime.unwrap_or(false),
+    };
+    Ok(Export::new(DbPool::new(&pool), &conn, &[sensor], opts)?)
+}
+
+/// Streams the readings of every sensor the user owns.
+#[get("/export?<start>&<end>&<format>&<unixtime>")]
+pub fn export_readings(
+    auth: Result<auth::UserToken>,
+    start: UnixEpochTime,
+    end: UnixEpochTime,
+    format: Option<Format>,
+    unixtime: Option<bool>,
+    pool: State<SolDbConnPool>,
+    conn: SolDbConn,
+) -> ApiResult<Export> {
+    let user = auth?.user();
+    let sensors = Sensor::find_for_user(user.id, &conn)?;
+    let opts = ExportOptions {
+        start: start.0,
+        end: end.0,
+        format: format.unwrap_or(Format::Csv),
+        unixtime: unixtime.unwrap_or(false),
+    };
+    Ok(Export::new(DbPool::new(&pool), &conn, &sensors, opts)?)
+}
+
+#[derive(Serialize)]
+pub struct GetChannelsResponse {
+    pub channels: Vec<Channel>,
+}
+
+#[get("/sensor/<id>/channels")]
+pub fn get_sensor_channels(id: i32, conn: SolDbConn) -> ApiResult<Json<GetChannelsResponse>> {
+    let sensor = Sensor::find(id, &conn)?;
+    let channels = channel::for_sensor(&sensor, &conn)?;
+    Ok(Json(GetChannelsResponse { channels }))
+}
+
+#[get("/models/<model>/channels")]
+pub fn get_model_channels(model: String, conn: SolDbConn) -> ApiResult<Json<GetChannelsResponse>> {
+    let mut channels = channel::builtin();
+    channels.extend(channel::for_model(&model, &conn)?);
+    Ok(Json(GetChannelsResponse { channels }))
+}
+
+#[derive(Serialize)]
+pub struct SetModelChannelsResponse {}
+
+#[post(
+    "/models/<model>/channels",
+    format = "application/json",
+    data = "<channels>"
+)]
+pub fn set_model_channels(
+    model: String,
+    admin: Result<auth::AdminToken>,
+    channels: Json<Vec<Channel>>,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> ApiResult<Json<SetModelChannelsResponse>> {
+    let admin = admin?.user();
+    channel::declare(&model, &channels.0, &conn)?;
+    audit::record(
+        Some(admin.id),
+        audit::ADMIN_MODEL_CHANNELS_SET,
+        Some(&format!("model {}", model)),
+        None,
+        &info,
+        &conn,
+    )?;
+    Ok(Json(SetModelChannelsResponse {}))
 }
 
 #[derive(Deserialize)]
@@ -122,13 +794,23 @@ pub fn get_sensor_token(
     auth: auth::UserToken,
     conn: SolDbConn,
     sensor_hw_id: Json<SensorHardwareId>,
+    info: RequestInfo,
 ) -> ApiResult<Json<GetSensorTokenResponse>> {
     let user = auth.user();
     let hardware_id = sensor_hw_id.0.hardware_id;
     let sensor = Sensor::find_by_hardware_id(hardware_id, &conn)?;
     if user.id == sensor.owner_id {
+        let target = format!("sensor {}", sensor.id);
         let token = Token::new_sensor_token(sensor);
         Token::insert(&token, &conn)?;
+        audit::record(
+            Some(user.id),
+            audit::TOKEN_CREATED,
+            Some(&target),
+            None,
+            &info,
+            &conn,
+        )?;
         Ok(Json(GetSensorTokenResponse { token: token.token }))
     } else {
         Err(Error::NotSensorOwner.into())
@@ -144,6 +826,42 @@ pub struct CreateReading {
     peak_voltage_V: f32,
     temp_celsius: f32,
     batt_V: f32,
+    /// Values for channels declared by the sensor's model.
+    #[serde(default)]
+    channels: ChannelValues,
+}
+
+impl CreateReading {
+    pub fn to_insert(&self, sensor_id: i32) -> ReadingInsert {
+        ReadingInsert {
+            sensor_id,
+            timestamp: self.timestamp.0,
+            peak_power_mW: self.peak_power_mW,
+            peak_current_mA: self.peak_current_mA,
+            peak_voltage_V: self.peak_voltage_V,
+            temp_celsius: self.temp_celsius,
+            batt_V: self.batt_V,
+            created: None,
+        }
+    }
+}
+
+/// Stores readings from a sensor along with their extra channel values.
+pub fn store_readings(
+    sensor: &SensorQuery,
+    readings: &[CreateReading],
+    conn: &SqliteConnection,
+) -> Result<usize> {
+    let inserts: Vec<ReadingInsert> = readings.iter().map(|r| r.to_insert(sensor.id)).collect();
+    let values: Vec<(NaiveDateTime, ChannelValues)> = readings
+        .iter()
+        .map(|r| (r.timestamp.0, r.channels.clone()))
+        .collect();
+    conn.transaction(|| {
+        let count = Reading::insert_many(&inserts, conn)?;
+        channel::insert_values(sensor, &values, conn)?;
+        Ok(count)
+    })
 }
 
 #[derive(Serialize)]
@@ -153,18 +871,23 @@ pub struct AddReadingResponse {}
 pub fn add_reading(
     auth: auth::SensorToken,
     conn: SolDbConn,
+    hub: State<live::Hub>,
     reading: Json<CreateReading>,
 ) -> ApiResult<Json<AddReadingResponse>> {
-    let reading = ReadingInsert {
-        sensor_id: auth.sensor().id,
-        timestamp: reading.0.timestamp.0,
-        peak_power_mW: reading.0.peak_power_mW,
-        peak_current_mA: reading.0.peak_current_mA,
-        peak_voltage_V: reading.0.peak_voltage_V,
-        temp_celsius: reading.0.temp_celsius,
-        batt_V: reading.0.batt_V,
-    };
-    Reading::insert(&reading, &conn)?;
+    let sensor = auth.sensor();
+    let insert = reading.to_insert(sensor.id);
+    let values = [(reading.timestamp.0, reading.channels.clone())];
+    let count = conn.transaction(|| {
+        let count = Reading::insert(&insert, &conn)?;
+        channel::insert_values(&sensor, &values, &conn)?;
+        Ok(count)
+    })?;
+    if count > 0 {
+        hub.publish(
+            sensor.owner_id,
+            &[LiveReading::new(&insert, reading.channels.clone())],
+        );
+    }
     Ok(Json(AddReadingResponse {}))
 }
 
@@ -175,43 +898,244 @@ pub struct AddReadingsResponse {}
 pub fn add_readings(
     auth: auth::SensorToken,
     conn: SolDbConn,
+    hub: State<live::Hub>,
     readings: Json<Vec<CreateReading>>,
 ) -> ApiResult<Json<AddReadingsResponse>> {
-    let sensor_id = auth.sensor().id;
-    let readings = readings
-        .0
-        .iter()
-        .map(|r| ReadingInsert {
-            sensor_id,
-            timestamp: r.timestamp.0,
-            peak_power_mW: r.peak_power_mW,
-            peak_current_mA: r.peak_current_mA,
-            peak_voltage_V: r.peak_voltage_V,
-            temp_celsius: r.temp_celsius,
-            batt_V: r.batt_V,
-        })
-        .collect();
-    Reading::insert_many(&readings, &conn)?;
+    let sensor = auth.sensor();
+    if store_readings(&sensor, &readings.0, &conn)? > 0 {
+        let live: Vec<LiveReading> = readings
+            .iter()
+            .map(|r| LiveReading::new(&r.to_insert(sensor.id), r.channels.clone()))
+            .collect();
+        hub.publish(sensor.owner_id, &live);
+    }
     Ok(Json(AddReadingsResponse {}))
 }
 
+/// Streams a sensor's readings as they're added, as Server-Sent Events. Like
+/// the readings themselves, anyone can follow a sensor.
+#[get("/sensor/<id>/stream")]
+pub fn sensor_stream(
+    id: i32,
+    conn: SolDbConn,
+    hub: State<live::Hub>,
+    info: RequestInfo,
+) -> ApiResult<EventStream> {
+    let sensor = Sensor::find(id, &conn)?;
+    let client = format!(
+        "ip:{}",
+        info.ip.as_ref().map_or("unknown", |ip| ip.as_str())
+    );
+    Ok(hub.subscribe(Topic::Sensor(sensor.id), &client)?)
+}
+
+/// Streams the readings of every sensor the user owns as they're added.
+#[get("/user/stream")]
+pub fn user_stream(auth: Result<auth::UserToken>, hub: State<live::Hub>) -> ApiResult<EventStream> {
+    let user = auth?.user();
+    Ok(hub.subscribe(Topic::Owner(user.id), &format!("user:{}", user.id))?)
+}
+
+/// Imports historical readings from a CSV body. The column mapping and other
+/// options are given as query parameters.
+#[post("/sensor/<id>/import?<opts..>", data = "<data>")]
+pub fn import_readings(
+    id: i32,
+    auth: Result<auth::UserToken>,
+    opts: Form<ImportOptions>,
+    data: RequestData,
+    conn: SolDbConn,
+) -> ApiResult<Json<ImportReport>> {
+    let user = auth?.user();
+    let sensor = Sensor::find(id, &conn)?;
+    if sensor.owner_id != user.id {
+        return Err(Error::NotSensorOwner.into());
+    }
+    let input = import::spool(data.open())?;
+    let report = import::import_csv(sensor.id, input, &opts, &conn)?;
+    Ok(Json(report))
+}
+
+#[derive(Deserialize)]
+pub struct CreateIntegration {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct AddIntegrationResponse {
+    pub secret: String,
+}
+
+#[post("/integrations/new", format = "application/json", data = "<data>")]
+pub fn add_integration(
+    auth: Result<auth::UserToken>,
+    data: Json<CreateIntegration>,
+    conn: SolDbConn,
+) -> ApiResult<Json<AddIntegrationResponse>> {
+    let user = auth?.user();
+    let secret = integration::create(user.id, data.0.name, &conn)?;
+    Ok(Json(AddIntegrationResponse { secret }))
+}
+
+#[derive(Serialize)]
+pub struct LorawanUplinkResponse {
+    pub inserted: usize,
+}
+
+/// ChirpStack posts every event type to the same url, with the type in the
+/// `event` query parameter. Only uplinks carry readings.
+#[post(
+    "/lorawan/uplink?<event>",
+    format = "application/json",
+    data = "<body>"
+)]
+pub fn lorawan_uplink(
+    auth: Result<auth::WebhookSecret>,
+    body: Json<serde_json::Value>,
+    event: Option<String>,
+    conn: SolDbConn,
+) -> ApiResult<Json<LorawanUplinkResponse>> {
+    let integration = auth?.integration();
+    if event.map(|e| e != "up").unwrap_or(false) {
+        return Ok(Json(LorawanUplinkResponse { inserted: 0 }));
+    }
+    let inserted = lorawan::handle_uplink(&integration, body.0, &conn)?;
+    Ok(Json(LorawanUplinkResponse { inserted }))
+}
+
 #[derive(Serialize, Deserialize, PartialEq)]
 pub struct GetTokenResponse {
     pub token: String,
 }
 
 #[post("/token")]
-pub fn get_token(auth: Result<auth::Basic>, conn: SolDbConn) -> ApiResult<Json<GetTokenResponse>> {
+pub fn get_token(
+    auth: Result<auth::Basic>,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> ApiResult<Json<GetTokenResponse>> {
     let user = auth?.user();
     let token = Token::new_user_token(&user);
-    let res =
-        Token::insert(&token, &conn).map(|_count| Json(GetTokenResponse { token: token.token }))?;
-    Ok(res)
+    Token::insert(&token, &conn)?;
+    audit::record(
+        Some(user.id),
+        audit::TOKEN_CREATED,
+        Some(&format!("user {}", user.id)),
+        None,
+        &info,
+        &conn,
+    )?;
+    Ok(Json(GetTokenResponse { token: token.token }))
+}
+
+#[derive(Serialize)]
+pub struct GetAuditLogResponse {
+    pub entries: Vec<Entry>,
+}
+
+/// The latest audit log entries, only those of the user with `email` if it's
+/// given.
+#[get("/audit?<email>&<limit>")]
+pub fn get_audit_log(
+    admin: Result<auth::AdminToken>,
+    email: Option<String>,
+    limit: Option<i64>,
+    conn: SolDbConn,
+) -> ApiResult<Json<GetAuditLogResponse>> {
+    admin?;
+    let user_id = match email {
+        Some(email) => Some(User::by_email(&email, &conn)?.id),
+        None => None,
+    };
+    let entries = audit::latest(user_id, limit.unwrap_or(audit::DEFAULT_LIMIT), &conn)?;
+    Ok(Json(GetAuditLogResponse { entries }))
+}
+
+#[derive(Serialize)]
+pub struct GetRateLimitsResponse {
+    pub overrides: Vec<Override>,
+}
+
+/// The sensors and users whose rate limits were raised.
+#[get("/rate_limits")]
+pub fn get_rate_limits(
+    admin: Result<auth::AdminToken>,
+    conn: SolDbConn,
+) -> ApiResult<Json<GetRateLimitsResponse>> {
+    admin?;
+    let overrides = rate_limit::overrides(&conn)?;
+    Ok(Json(GetRateLimitsResponse { overrides }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RateLimitMultiplier {
+    /// How many times the usual limits to allow, or 1 for the usual limits.
+    pub multiplier: i32,
+}
+
+fn set_rate_limit(
+    principal: Principal,
+    admin: auth::AdminToken,
+    multiplier: i32,
+    conn: &SqliteConnection,
+    info: &RequestInfo,
+) -> ApiResult<Json<RateLimitMultiplier>> {
+    let admin = admin.user();
+    if multiplier < 1 {
+        return Err(Error::InvalidRateLimitMultiplier(multiplier).into());
+    }
+    rate_limit::set_multiplier(&principal.key(), multiplier, conn)?;
+    audit::record(
+        Some(admin.id),
+        audit::ADMIN_RATE_LIMIT_SET,
+        Some(&principal.key()),
+        Some(&format!("multiplier {}", multiplier)),
+        info,
+        conn,
+    )?;
+    Ok(Json(RateLimitMultiplier { multiplier }))
+}
+
+#[put(
+    "/rate_limits/sensor/<id>",
+    format = "application/json",
+    data = "<body>"
+)]
+pub fn set_sensor_rate_limit(
+    id: i32,
+    admin: Result<auth::AdminToken>,
+    body: Json<RateLimitMultiplier>,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> ApiResult<Json<RateLimitMultiplier>> {
+    // Checked first, so only admins can tell which sensors exist.
+    let admin = admin?;
+    Sensor::find(id, &conn)?;
+    set_rate_limit(Principal::Sensor(id), admin, body.multiplier, &conn, &info)
+}
+
+#[put("/rate_limits/user/<id>", format = "application/json", data = "<body>")]
+pub fn set_user_rate_limit(
+    id: i32,
+    admin: Result<auth::AdminToken>,
+    body: Json<RateLimitMultiplier>,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> ApiResult<Json<RateLimitMultiplier>> {
+    let admin = admin?;
+    User::by_id(id, &conn)?;
+    set_rate_limit(Principal::User(id), admin, body.multiplier, &conn, &info)
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateSensor {
     hardware_id: i64,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(flatten)]
+    location: SensorLocation,
 }
 
 #[derive(Serialize)]
@@ -227,6 +1151,9 @@ pub fn add_sensor(
     let sensor = SensorInsert {
         owner_id: auth.user().id,
         hardware_id: data.hardware_id,
+        model: data.0.model,
+        timezone: data.0.timezone,
+        location: data.0.location,
     };
     Sensor::insert(&sensor, &conn)?;
     Ok(Json(AddSensorResponse {}))
@@ -1,15 +1,31 @@
 use crate::{
     db::SolDbConn,
-    models::{Sensor, SensorQuery, Token, TokenQuery, TokenType, User, UserQuery},
-    result::Error,
+    logging::{self, Identity, RequestInfo},
+    models::{
+        audit,
+        integration::{self, Integration},
+        Sensor, SensorQuery, Token, TokenQuery, TokenType, User, UserQuery,
+    },
+    result::{Error, Result},
 };
+use diesel::SqliteConnection;
 use rocket::{
-    http::Status,
+    http::{Cookie, SameSite, Status},
     request::{FromRequest, Outcome},
     Request,
 };
 use std::str::from_utf8;
 
+/// The private cookie that keeps a user logged in on the web. Other sites'
+/// posts don't carry it, and their links only do when they're followed.
+pub fn session_cookie(token: String) -> Cookie<'static> {
+    Cookie::build("user_token", token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
 pub struct UserCookie(UserQuery);
 
 impl UserCookie {
@@ -29,12 +45,36 @@ impl<'a, 'r> FromRequest<'a, 'r> for UserCookie {
             .map(|ck| ck.value().to_string())
             .and_then(|tok| User::by_token(&tok, &conn));
         match res {
-            Ok(user) => Outcome::Success(UserCookie(user)),
+            Ok(user) => {
+                logging::identify(req, Identity::User(user.id));
+                Outcome::Success(UserCookie(user))
+            }
             Err(err) => Outcome::Failure((Status::BadRequest, err.into())),
         }
     }
 }
 
+/// Records a failed login in the audit log, against the account it was for.
+/// Logins to accounts that don't exist aren't recorded, since anyone can make
+/// up as many emails as they like.
+pub fn failed_login(email: &str, info: &RequestInfo, conn: &SqliteConnection) {
+    let user = match User::by_email(&email.to_string(), conn) {
+        Ok(user) => user,
+        Err(_) => return,
+    };
+    let res = audit::record(
+        Some(user.id),
+        audit::LOGIN_FAILED,
+        None,
+        Some(email),
+        info,
+        conn,
+    );
+    if let Err(e) = res {
+        logging::error("auth", format!("failed to audit failed login: {}", e));
+    }
+}
+
 pub struct Basic(UserQuery);
 
 impl Basic {
@@ -74,8 +114,14 @@ impl<'a, 'r> FromRequest<'a, 'r> for Basic {
         let conn: SolDbConn = req.guard().expect("req guard failed");
         let res = User::verify_password(&words[0], &words[1], &conn);
         match res {
-            Ok(user) => Outcome::Success(Basic(user)),
-            Err(err) => Outcome::Failure((Status::BadRequest, err)),
+            Ok(user) => {
+                logging::identify(req, Identity::User(user.id));
+                Outcome::Success(Basic(user))
+            }
+            Err(err) => {
+                failed_login(&words[0], &RequestInfo::of(req), &conn);
+                Outcome::Failure((Status::BadRequest, err))
+            }
         }
     }
 }
@@ -106,6 +152,19 @@ impl<'a, 'r> FromRequest<'a, 'r> for AuthToken {
     }
 }
 
+/// Look up the active sensor that a sensor token belongs to. This is used by
+/// ingestion paths that don't go through a request guard.
+pub fn sensor_for_token(token: &String, conn: &SqliteConnection) -> Result<SensorQuery> {
+    let token = Token::find(token, conn).map_err(|_| Error::InvalidToken)?;
+    match TokenType::from_string(token.type_) {
+        TokenType::User => Err(Error::WrongTokenType),
+        TokenType::Sensor => {
+            let sensor_id = token.sensor_id.ok_or(Error::InvalidToken)?;
+            Sensor::find(sensor_id, conn).map_err(|_| Error::InvalidToken)
+        }
+    }
+}
+
 pub struct SensorToken(SensorQuery);
 
 impl SensorToken {
@@ -126,7 +185,10 @@ impl<'a, 'r> FromRequest<'a, 'r> for SensorToken {
                 let conn: SolDbConn = req.guard().expect("request guard failed");
                 let sensor_id = token.sensor_id.expect("token had no sensor id");
                 match Sensor::find(sensor_id, &conn) {
-                    Ok(sensor) => Outcome::Success(SensorToken(sensor)),
+                    Ok(sensor) => {
+                        logging::identify(req, Identity::Sensor(sensor.id));
+                        Outcome::Success(SensorToken(sensor))
+                    }
                     Err(_err) => Outcome::Failure((Status::BadRequest, Error::InvalidToken)),
                 }
             }
@@ -154,7 +216,10 @@ impl<'a, 'r> FromRequest<'a, 'r> for UserToken {
                 let conn: SolDbConn = req.guard().expect("request guard failed");
                 let user_id = token.user_id.expect("token had no user id");
                 match User::by_id(user_id, &conn) {
-                    Ok(user) => Outcome::Success(UserToken(user)),
+                    Ok(user) => {
+                        logging::identify(req, Identity::User(user.id));
+                        Outcome::Success(UserToken(user))
+                    }
                     Err(_err) => Outcome::Failure((Status::BadRequest, Error::InvalidToken)),
                 }
             }
@@ -164,6 +229,12 @@ impl<'a, 'r> FromRequest<'a, 'r> for UserToken {
 
 pub struct AdminToken(UserQuery);
 
+impl AdminToken {
+    pub fn user(self) -> UserQuery {
+        self.0
+    }
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
     type Error = Error;
 
@@ -177,3 +248,49 @@ impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
         }
     }
 }
+
+pub struct AdminCookie(UserQuery);
+
+impl AdminCookie {
+    pub fn user(self) -> UserQuery {
+        self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminCookie {
+    type Error = Error;
+
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let cookie: UserCookie = req.guard()?;
+        let user = cookie.0;
+        if user.superuser {
+            Outcome::Success(AdminCookie(user))
+        } else {
+            Outcome::Failure((Status::BadRequest, Error::NotAdmin))
+        }
+    }
+}
+
+pub struct WebhookSecret(Integration);
+
+impl WebhookSecret {
+    pub fn integration(self) -> Integration {
+        self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for WebhookSecret {
+    type Error = Error;
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let secret = match req.headers().get_one("X-Webhook-Secret") {
+            Some(secret) => secret.to_string(),
+            None => return Outcome::Failure((Status::BadRequest, Error::MissingWebhookSecret)),
+        };
+
+        let conn: SolDbConn = req.guard().expect("request guard failed");
+        match integration::by_secret(&secret, &conn) {
+            Ok(integration) => Outcome::Success(WebhookSecret(integration)),
+            Err(_err) => Outcome::Failure((Status::BadRequest, Error::InvalidWebhookSecret)),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Protects web forms from cross-site request forgery.
+//!
+//! Each browser session gets a random token in a private cookie, which other
+//! sites can neither read nor set. `TemplateCtx` hands the token to templates,
+//! which post it back in a `csrf_token` field, and routes that change anything
+//! check it with `Csrf::verify` before acting. Scripts that post without a form
+//! send it in `X-CSRF-Token` instead.
+//!
+//! Logging in or out drops the token, so a session gets a new one along with
+//! its new user.
+use crate::{
+    result::{Error, Result},
+    util::token::{rand_str, same},
+};
+use rocket::{
+    http::{Cookie, Cookies, SameSite, Status},
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+pub const COOKIE: &str = "csrf_token";
+pub const HEADER: &str = "X-CSRF-Token";
+
+/// A form with nothing in it but the CSRF token, for posts that are only a
+/// button.
+#[derive(FromForm)]
+pub struct TokenForm {
+    pub csrf_token: String,
+}
+
+/// Forgets the session's token.
+pub fn reset(cookies: &mut Cookies) {
+    cookies.remove_private(Cookie::named(COOKIE));
+}
+
+/// The session's token, made when a session first needs one.
+#[derive(Clone)]
+pub struct Csrf(String);
+
+impl Csrf {
+    pub fn token(&self) -> &str {
+        &self.0
+    }
+
+    /// Fails unless `given` is the session's token.
+    pub fn verify(&self, given: &str) -> Result<()> {
+        if same(&self.0, given) {
+            Ok(())
+        } else {
+            Err(Error::CsrfTokenMismatch)
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Csrf {
+    type Error = ();
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        // Cached so that every guard on a request agrees on a new token.
+        let csrf = req.local_cache(|| {
+            let mut cookies = req.cookies();
+            if let Some(cookie) = cookies.get_private(COOKIE) {
+                return Csrf(cookie.value().to_string());
+            }
+            let token = rand_str();
+            cookies.add_private(
+                Cookie::build(COOKIE, token.clone())
+                    .path("/")
+                    .http_only(true)
+                    .same_site(SameSite::Lax)
+                    .finish(),
+            );
+            Csrf(token)
+        });
+        Outcome::Success(csrf.clone())
+    }
+}
+
+/// A request with the session's token in `X-CSRF-Token`.
+pub struct HeaderToken;
+
+impl<'a, 'r> FromRequest<'a, 'r> for HeaderToken {
+    type Error = Error;
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let csrf: Csrf = req.guard().expect("csrf guard failed");
+        let given = req.headers().get_one(HEADER).unwrap_or("");
+        match csrf.verify(given) {
+            Ok(()) => Outcome::Success(HeaderToken),
+            Err(e) => Outcome::Failure((Status::Forbidden, e)),
+        }
+    }
+}
@@ -1,12 +1,78 @@
-use diesel::{Connection, SqliteConnection};
+use crate::result::{Error, Result};
+use diesel::{sql_query, sql_types::Text, Connection, RunQueryDsl, SqliteConnection};
 use diesel_migrations::embed_migrations;
+use rocket_contrib::databases::{r2d2, Poolable};
+use std::collections::HashSet;
 
 embed_migrations!("./migrations");
 
+// `MIGRATIONS`, the versions of the migrations in `migrations/` oldest
+// first, as listed by `build.rs`.
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
 #[database("sqlite_sol")]
 pub struct SolDbConn(SqliteConnection);
 
+/// Connections of the request pool.
+pub struct PoolUsage {
+    /// Open, whether in use or idle.
+    pub connections: u32,
+    pub idle: u32,
+    /// The most the pool will open.
+    pub max: u32,
+}
+
+pub fn pool_usage(pool: &SolDbConnPool) -> PoolUsage {
+    let state = pool.0.state();
+    PoolUsage {
+        connections: state.connections,
+        idle: state.idle_connections,
+        max: pool.0.max_size(),
+    }
+}
+
+/// A handle on the request pool that can outlive a request, for responses
+/// that read from the database while they are being sent. Unlike
+/// `SolDbConn`, it only holds a connection while a query runs.
+#[derive(Clone)]
+pub struct DbPool(r2d2::Pool<<SqliteConnection as Poolable>::Manager>);
+
+impl DbPool {
+    pub fn new(pool: &SolDbConnPool) -> DbPool {
+        DbPool(pool.0.clone())
+    }
+
+    pub fn get(&self) -> Result<SolDbConn> {
+        self.0
+            .get()
+            .map(SolDbConn)
+            .map_err(|_| Error::DbConnectionFailed)
+    }
+}
+
 pub fn run_migrations(uri: &str) {
     let conn = SqliteConnection::establish(uri).expect("error connecting to db");
     embedded_migrations::run(&conn).expect("failed to run migrations");
 }
+
+#[derive(QueryableByName)]
+struct Applied {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+/// Versions of the embedded migrations that haven't been applied, from the
+/// table Diesel keeps of the ones it has run.
+pub fn pending_migrations(conn: &SqliteConnection) -> Result<Vec<String>> {
+    let applied: HashSet<String> = sql_query("select version from __diesel_schema_migrations")
+        .load::<Applied>(conn)
+        .map_err(|e| Error::MigrationCheck(e.to_string()))?
+        .into_iter()
+        .map(|a| a.version)
+        .collect();
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|version| !applied.contains(**version))
+        .map(|version| version.to_string())
+        .collect())
+}
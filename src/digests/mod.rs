@@ -0,0 +1,278 @@
+//! Emails subscribed users a daily or weekly digest of their sensors: energy
+//! produced and what it saved, how much of the time each sensor reported, and
+//! the alerts opened along the way.
+//!
+//! Digests go out at `SOL_DIGEST_HOUR` UTC, on `SOL_DIGEST_WEEKDAY` for weekly
+//! ones. A digest missed while the server was down is sent when it comes back,
+//! but only once.
+use crate::{
+    logging,
+    models::{
+        aggregate,
+        alert::{self, Incident},
+        digest::{self, Frequency, Subscription},
+        Sensor, SensorQuery, User, UserQuery,
+    },
+    result::{Error, Result},
+    schema::hourly_rollups,
+    util::email::{Notifier, SITE_URL},
+};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Weekday};
+use diesel::{dsl::count_star, prelude::*};
+use rocket_contrib::templates::tera::Tera;
+
+/// Incidents of each sensor that are looked through for ones in the period.
+const INCIDENT_LIMIT: i64 = 100;
+
+const TEMPLATES: &str = "templates/email/*.tera";
+
+#[derive(Clone, Copy, Debug)]
+pub struct Schedule {
+    /// Hour of the day, in UTC, that digests are sent.
+    pub hour: u32,
+    /// Day that weekly digests are sent.
+    pub weekday: Weekday,
+}
+
+impl Default for Schedule {
+    fn default() -> Schedule {
+        Schedule {
+            hour: 7,
+            weekday: Weekday::Mon,
+        }
+    }
+}
+
+impl Schedule {
+    /// Reads the schedule from `SOL_DIGEST_HOUR` and `SOL_DIGEST_WEEKDAY`,
+    /// like `mon`, using the default for either one that isn't set.
+    pub fn from_env() -> Schedule {
+        let default = Schedule::default();
+        let hour = std::env::var("SOL_DIGEST_HOUR")
+            .ok()
+            .map_or(default.hour, |h| match h.parse() {
+                Ok(h) if h < 24 => h,
+                _ => panic!("SOL_DIGEST_HOUR must be an hour from 0 to 23"),
+            });
+        let weekday = std::env::var("SOL_DIGEST_WEEKDAY")
+            .ok()
+            .map_or(default.weekday, |d| {
+                d.parse()
+                    .unwrap_or_else(|_| panic!("SOL_DIGEST_WEEKDAY must be a day like mon"))
+            });
+        Schedule { hour, weekday }
+    }
+
+    /// The latest time at or before `now` that digests of `frequency` were
+    /// due.
+    pub fn latest(&self, frequency: Frequency, now: NaiveDateTime) -> NaiveDateTime {
+        let mut due = now.date().and_hms(self.hour, 0, 0);
+        if due > now {
+            due -= Duration::days(1);
+        }
+        if frequency == Frequency::Weekly {
+            while due.weekday() != self.weekday {
+                due -= Duration::days(1);
+            }
+        }
+        due
+    }
+
+    /// Whether a subscriber is due a digest. New subscribers get their first
+    /// one at the next scheduled time.
+    pub fn is_due(&self, sub: &Subscription, now: NaiveDateTime) -> Result<bool> {
+        let latest = self.latest(sub.frequency()?, now);
+        Ok(sub.last_sent.unwrap_or(sub.created) < latest)
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct SensorDigest {
+    pub sensor_id: i32,
+    pub name: String,
+    pub equiv_kWh: f64,
+    pub dollars_saved: f64,
+    pub co2_saved: f64,
+    /// Percent of the period's hours with readings.
+    pub uptime_percent: f64,
+    /// Incidents opened in the period.
+    pub incidents: Vec<Incident>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct Digest {
+    pub email: String,
+    pub frequency: Frequency,
+    /// First and last local day covered.
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub sensors: Vec<SensorDigest>,
+    pub total_kWh: f64,
+    pub total_dollars_saved: f64,
+    pub total_co2_saved: f64,
+    pub site_url: String,
+    pub unsubscribe_url: String,
+    /// Saved once the digest is sent, so unsent digests don't leave working
+    /// links behind.
+    #[serde(skip)]
+    pub unsubscribe_nonce: String,
+}
+
+/// The local days a digest sent at `now` covers for a sensor: the day before,
+/// or the week before.
+fn period(
+    sensor: &SensorQuery,
+    frequency: Frequency,
+    now: NaiveDateTime,
+) -> (NaiveDate, NaiveDate) {
+    let today = sensor.tz().from_utc_datetime(&now).date().naive_local();
+    let end = today.pred();
+    (end - Duration::days(frequency.days() - 1), end)
+}
+
+fn sensor_digest(
+    sensor: &SensorQuery,
+    frequency: Frequency,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<SensorDigest> {
+    let (start, end) = period(sensor, frequency, now);
+    let stats = Sensor::energy_stats(sensor, start, end, conn)?;
+    let days = aggregate::local_days(sensor.tz(), start, end);
+    let from = days.first().map_or(now, |d| d.start);
+    let until = days.last().map_or(now, |d| d.end);
+    let hours_with_readings: i64 = hourly_rollups::table
+        .select(count_star())
+        .filter(hourly_rollups::sensor_id.eq(sensor.id))
+        .filter(hourly_rollups::channel.eq("peak_power_mW"))
+        .filter(hourly_rollups::bucket.ge(from))
+        .filter(hourly_rollups::bucket.lt(until))
+        .first(conn)?;
+    let hours = (until - from).num_hours().max(1);
+    let incidents = alert::incidents_for_sensor(sensor.id, INCIDENT_LIMIT, conn)?
+        .into_iter()
+        .filter(|i| from <= i.opened && i.opened < until)
+        .collect();
+    Ok(SensorDigest {
+        sensor_id: sensor.id,
+        name: sensor
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("Sensor {}", sensor.id)),
+        equiv_kWh: stats.iter().map(|s| f64::from(s.equiv_kWh)).sum(),
+        dollars_saved: stats.iter().map(|s| f64::from(s.dollars_saved)).sum(),
+        co2_saved: stats.iter().map(|s| f64::from(s.co2_saved)).sum(),
+        uptime_percent: 100.0 * hours_with_readings as f64 / hours as f64,
+        incidents,
+    })
+}
+
+/// A user's digest, or `None` if they have no sensors to tell them about.
+pub fn build(
+    user: &UserQuery,
+    frequency: Frequency,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<Option<Digest>> {
+    let sensors = Sensor::find_for_user(user.id, conn)?;
+    let first = match sensors.first() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+    // Sensors in other timezones cover slightly different hours, but the
+    // same dates.
+    let (start, end) = period(first, frequency, now);
+    let sensors = sensors
+        .iter()
+        .map(|sensor| sensor_digest(sensor, frequency, now, conn))
+        .collect::<Result<Vec<_>>>()?;
+    let (token, nonce) = digest::new_unsubscribe_token();
+    Ok(Some(Digest {
+        email: user.email.clone(),
+        frequency,
+        start,
+        end,
+        total_kWh: sensors.iter().map(|s| s.equiv_kWh).sum(),
+        total_dollars_saved: sensors.iter().map(|s| s.dollars_saved).sum(),
+        total_co2_saved: sensors.iter().map(|s| s.co2_saved).sum(),
+        sensors,
+        site_url: SITE_URL.to_string(),
+        unsubscribe_url: format!("{}/digest/unsubscribe/{}", SITE_URL, token),
+        unsubscribe_nonce: nonce,
+    }))
+}
+
+pub fn templates() -> Result<Tera> {
+    let mut tera = Tera::new(TEMPLATES).map_err(|e| Error::RenderTemplate(e.to_string()))?;
+    tera.autoescape_on(vec![".html.tera"]);
+    Ok(tera)
+}
+
+/// The HTML and plain text of a digest email.
+pub fn render(digest: &Digest, tera: &Tera) -> Result<(String, String)> {
+    let render = |name: &str| {
+        tera.render(name, digest)
+            .map_err(|e| Error::RenderTemplate(e.to_string()))
+    };
+    Ok((render("digest.html.tera")?, render("digest.txt.tera")?))
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub sent: usize,
+    pub failed: usize,
+}
+
+fn send(
+    sub: &Subscription,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+    tera: &Tera,
+    notifier: &dyn Notifier,
+) -> Result<bool> {
+    let user = User::by_id(sub.user_id, conn)?;
+    let frequency = sub.frequency()?;
+    let sent = match build(&user, frequency, now, conn)? {
+        Some(digest) => {
+            let (html, text) = render(&digest, tera)?;
+            let subject = format!("Your {} Sol digest", frequency.as_str());
+            notifier.notify(&user.email, &subject, &html, Some(&text))?;
+            digest::save_unsubscribe_token(&digest.unsubscribe_nonce, user.id, conn)?;
+            true
+        }
+        None => false,
+    };
+    digest::mark_sent(user.id, now, conn)?;
+    Ok(sent)
+}
+
+/// Sends the digests that are due at `now`. A digest that fails to send is
+/// tried again on the next run.
+pub fn run(
+    schedule: &Schedule,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+    tera: &Tera,
+    notifier: &dyn Notifier,
+) -> Result<Report> {
+    let mut report = Report::default();
+    for sub in digest::all(conn)? {
+        if !schedule.is_due(&sub, now)? {
+            continue;
+        }
+        match send(&sub, now, conn, tera, notifier) {
+            Ok(true) => report.sent += 1,
+            Ok(false) => {}
+            Err(e) => {
+                report.failed += 1;
+                logging::error(
+                    "digests",
+                    format!("failed to send to user {}: {}", sub.user_id, e),
+                );
+            }
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,294 @@
+//! Export of raw readings as CSV, newline-delimited JSON or Parquet.
+//!
+//! Readings are read from SQLite a page at a time as the response body is
+//! written, so an export never holds more than one page in memory, and a
+//! pool connection is only checked out while a page is being read. Parquet
+//! is sent a row group at a time, with the footer written once every page
+//! has been read.
+use crate::{
+    db::{DbPool, SolDbConn},
+    models::{
+        channel, Cursor, Order, Reading, ReadingQuery, ReadingQueryUnix, ReadingWithChannels,
+        SensorQuery,
+    },
+    result::{Error, Result},
+};
+use chrono::NaiveDateTime;
+use rocket::{
+    http::{ContentType, RawStr, Status},
+    request::{FromFormValue, Request},
+    response::{Responder, Response},
+};
+use std::io::{self, Read};
+
+#[cfg(feature = "parquet")]
+mod parquet_writer;
+
+/// How many readings are loaded from the database at a time.
+pub const PAGE_SIZE: i64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Parquet => "parquet",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            Format::Csv => ContentType::CSV,
+            Format::Ndjson => ContentType::new("application", "x-ndjson"),
+            Format::Parquet => ContentType::Binary,
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for Format {
+    type Error = &'v RawStr;
+    fn from_form_value(form_value: &'v RawStr) -> std::result::Result<Self, Self::Error> {
+        match form_value.as_str() {
+            "csv" => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(form_value),
+        }
+    }
+}
+
+pub struct ExportOptions {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub format: Format,
+    pub unixtime: bool,
+}
+
+/// Pages through the readings of each sensor in turn.
+pub struct Pages {
+    pool: DbPool,
+    sensors: Vec<i32>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    current: usize,
+    cursor: Option<Cursor>,
+}
+
+impl Pages {
+    fn new(pool: DbPool, sensors: &[SensorQuery], opts: &ExportOptions) -> Pages {
+        Pages {
+            pool,
+            sensors: sensors.iter().map(|s| s.id).collect(),
+            start: opts.start,
+            end: opts.end,
+            current: 0,
+            cursor: None,
+        }
+    }
+
+    /// Returns the next non-empty page, or `None` once every sensor is done.
+    pub fn next_page(&mut self) -> Result<Option<Vec<ReadingWithChannels<ReadingQuery>>>> {
+        let conn = self.pool.get()?;
+        while self.current < self.sensors.len() {
+            let page = Reading::find_for_sensor_in_time_range(
+                self.sensors[self.current],
+                self.start,
+                self.end,
+                self.cursor,
+                Order::Asc,
+                PAGE_SIZE,
+                &conn,
+            )?;
+            match page.last() {
+                Some(last) if page.len() as i64 == PAGE_SIZE => {
+                    self.cursor = Some(Cursor::after(last))
+                }
+                _ => {
+                    self.current += 1;
+                    self.cursor = None;
+                }
+            }
+            if !page.is_empty() {
+                return Reading::with_channels(page, &conn).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// All the channels of the exported sensors, built-in channels first.
+fn columns(sensors: &[SensorQuery], conn: &SolDbConn) -> Result<Vec<String>> {
+    let mut builtin: Vec<String> = channel::builtin().into_iter().map(|c| c.name).collect();
+    let mut extra = Vec::new();
+    for sensor in sensors {
+        for c in channel::for_sensor(sensor, conn)? {
+            if !c.builtin && !extra.contains(&c.name) {
+                extra.push(c.name);
+            }
+        }
+    }
+    extra.sort();
+    builtin.extend(extra);
+    Ok(builtin)
+}
+
+fn format_timestamp(ts: NaiveDateTime, unixtime: bool) -> String {
+    if unixtime {
+        ts.timestamp().to_string()
+    } else {
+        ts.format("%Y-%m-%dT%H:%M:%S").to_string()
+    }
+}
+
+fn to_io(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Formats pages of readings as CSV or NDJSON as they are read.
+struct TextReader {
+    pages: Pages,
+    format: Format,
+    unixtime: bool,
+    columns: Vec<String>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl TextReader {
+    fn new(pages: Pages, opts: &ExportOptions, columns: Vec<String>) -> Result<TextReader> {
+        let mut reader = TextReader {
+            pages,
+            format: opts.format,
+            unixtime: opts.unixtime,
+            columns,
+            buf: Vec::new(),
+            pos: 0,
+        };
+        if reader.format == Format::Csv {
+            let mut header = vec!["sensor_id".to_string(), "timestamp".to_string()];
+            header.extend(reader.columns.iter().cloned());
+            reader.write_csv(&[header])?;
+        }
+        Ok(reader)
+    }
+
+    fn write_csv(&mut self, records: &[Vec<String>]) -> Result<()> {
+        let mut wtr = csv::Writer::from_writer(&mut self.buf);
+        for record in records {
+            wtr.write_record(record)
+                .map_err(|e| Error::UnknownError(e.to_string()))?;
+        }
+        wtr.flush()
+            .map_err(|e| Error::UnknownError(e.to_string()))?;
+        Ok(())
+    }
+
+    fn write_page(&mut self, page: Vec<ReadingWithChannels<ReadingQuery>>) -> Result<()> {
+        match self.format {
+            Format::Csv => {
+                let records: Vec<Vec<String>> = page
+                    .iter()
+                    .map(|r| {
+                        let mut record = vec![
+                            r.reading.sensor_id.to_string(),
+                            format_timestamp(r.reading.timestamp, self.unixtime),
+                        ];
+                        record.extend(
+                            self.columns
+                                .iter()
+                                .map(|c| r.value(c).map(|v| v.to_string()).unwrap_or_default()),
+                        );
+                        record
+                    })
+                    .collect();
+                self.write_csv(&records)
+            }
+            _ => {
+                for r in page {
+                    if self.unixtime {
+                        serde_json::to_writer(&mut self.buf, &r.map(ReadingQueryUnix::from))
+                    } else {
+                        serde_json::to_writer(&mut self.buf, &r)
+                    }
+                    .map_err(|e| Error::UnknownError(e.to_string()))?;
+                    self.buf.push(b'\n');
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for TextReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            match self.pages.next_page().map_err(to_io)? {
+                Some(page) => self.write_page(page).map_err(to_io)?,
+                None => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+pub struct Export {
+    filename: String,
+    format: Format,
+    body: Box<dyn Read>,
+}
+
+impl Export {
+    /// Exports the readings of `sensors` in the given time range. Rows are
+    /// read while the response is being sent, so pages take a connection
+    /// from `pool` as they need one rather than keeping the request's.
+    pub fn new(
+        pool: DbPool,
+        conn: &SolDbConn,
+        sensors: &[SensorQuery],
+        opts: ExportOptions,
+    ) -> Result<Export> {
+        let columns = columns(sensors, conn)?;
+        let pages = Pages::new(pool, sensors, &opts);
+        let body: Box<dyn Read> = match opts.format {
+            Format::Csv | Format::Ndjson => Box::new(TextReader::new(pages, &opts, columns)?),
+            #[cfg(feature = "parquet")]
+            Format::Parquet => Box::new(parquet_writer::ParquetReader::new(pages, &opts, columns)?),
+            #[cfg(not(feature = "parquet"))]
+            Format::Parquet => return Err(Error::UnsupportedFormat("parquet".into())),
+        };
+        let name = match sensors {
+            [sensor] => format!("sensor-{}", sensor.id),
+            _ => "readings".to_string(),
+        };
+        Ok(Export {
+            filename: format!("{}.{}", name, opts.format.extension()),
+            format: opts.format,
+            body,
+        })
+    }
+}
+
+impl<'r> Responder<'r> for Export {
+    fn respond_to(self, _: &Request) -> ::std::result::Result<Response<'r>, Status> {
+        Ok(Response::build()
+            .header(self.format.content_type())
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            )
+            .streamed_body(self.body)
+            .finalize())
+    }
+}
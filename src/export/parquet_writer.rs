@@ -0,0 +1,215 @@
+use super::{to_io, ExportOptions, Pages};
+use crate::{
+    models::{ReadingQuery, ReadingWithChannels},
+    result::{Error, Result},
+};
+use parquet::{
+    column::writer::ColumnWriter,
+    file::{
+        properties::WriterProperties,
+        writer::{FileWriter, RowGroupWriter, SerializedFileWriter, TryClone},
+    },
+    schema::parser::parse_message_type,
+};
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    rc::Rc,
+};
+
+/// Pages are collected into row groups of about this many rows.
+const ROW_GROUP_SIZE: usize = 50_000;
+
+fn to_err<E: Display>(e: E) -> Error {
+    Error::UnknownError(format!("failed to write parquet: {}", e))
+}
+
+fn schema(columns: &[String], unixtime: bool) -> String {
+    let mut fields = vec!["REQUIRED INT32 sensor_id;".to_string()];
+    if unixtime {
+        fields.push("REQUIRED INT64 timestamp;".to_string());
+    } else {
+        fields.push("REQUIRED INT64 timestamp (TIMESTAMP_MILLIS);".to_string());
+    }
+    for c in columns {
+        fields.push(format!("OPTIONAL FLOAT {};", c));
+    }
+    format!("message reading {{ {} }}", fields.join(" "))
+}
+
+fn write_row_group(
+    writer: &mut SerializedFileWriter<Sink>,
+    rows: &[ReadingWithChannels<ReadingQuery>],
+    columns: &[String],
+    unixtime: bool,
+) -> Result<()> {
+    let mut row_group = writer.next_row_group().map_err(to_err)?;
+    let mut col = 0;
+    while let Some(mut column) = row_group.next_column().map_err(to_err)? {
+        match column {
+            ColumnWriter::Int32ColumnWriter(ref mut typed) if col == 0 => {
+                let values: Vec<i32> = rows.iter().map(|r| r.reading.sensor_id).collect();
+                typed.write_batch(&values, None, None)
+            }
+            ColumnWriter::Int64ColumnWriter(ref mut typed) if col == 1 => {
+                let values: Vec<i64> = rows
+                    .iter()
+                    .map(|r| {
+                        if unixtime {
+                            r.reading.timestamp.timestamp()
+                        } else {
+                            r.reading.timestamp.timestamp_millis()
+                        }
+                    })
+                    .collect();
+                typed.write_batch(&values, None, None)
+            }
+            ColumnWriter::FloatColumnWriter(ref mut typed) => {
+                let values: Vec<Option<f32>> =
+                    rows.iter().map(|r| r.value(&columns[col - 2])).collect();
+                let def_levels: Vec<i16> = values.iter().map(|v| v.is_some() as i16).collect();
+                let present: Vec<f32> = values.into_iter().filter_map(|v| v).collect();
+                typed.write_batch(&present, Some(&def_levels), None)
+            }
+            _ => unreachable!("column does not match schema"),
+        }
+        .map_err(to_err)?;
+        row_group.close_column(column).map_err(to_err)?;
+        col += 1;
+    }
+    writer.close_row_group(row_group).map_err(to_err)
+}
+
+/// Where the Parquet writer puts its output until it is sent. The writer
+/// keeps a clone for each column, the way it would duplicate a file handle,
+/// so they all append to the same buffer.
+#[derive(Clone, Default)]
+struct Sink(Rc<RefCell<SinkState>>);
+
+#[derive(Default)]
+struct SinkState {
+    buf: Vec<u8>,
+    /// Every byte written so far, which the writer reads back as the file
+    /// offset.
+    written: u64,
+}
+
+impl Sink {
+    /// Takes what has been written since the last call.
+    fn take(&self) -> Vec<u8> {
+        mem::replace(&mut self.0.borrow_mut().buf, Vec::new())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+        state.buf.extend_from_slice(data);
+        state.written += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Sink {
+    /// The writer only ever asks where it is, which is all a stream can
+    /// answer.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.0.borrow().written),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "can't seek in a streamed parquet file",
+            )),
+        }
+    }
+}
+
+impl TryClone for Sink {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+/// Writes a row group at a time as the response body is read, and the
+/// footer once every page has been.
+pub struct ParquetReader {
+    pages: Pages,
+    /// `None` once the footer has been written.
+    writer: Option<SerializedFileWriter<Sink>>,
+    sink: Sink,
+    columns: Vec<String>,
+    unixtime: bool,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ParquetReader {
+    pub fn new(pages: Pages, opts: &ExportOptions, columns: Vec<String>) -> Result<ParquetReader> {
+        let schema = parse_message_type(&schema(&columns, opts.unixtime)).map_err(to_err)?;
+        let props = WriterProperties::builder().build();
+        let sink = Sink::default();
+        let writer = SerializedFileWriter::new(sink.clone(), Rc::new(schema), Rc::new(props))
+            .map_err(to_err)?;
+        Ok(ParquetReader {
+            pages,
+            writer: Some(writer),
+            sink,
+            columns,
+            unixtime: opts.unixtime,
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Writes the next row group, and the footer if the pages ran out.
+    fn write_next(&mut self) -> Result<()> {
+        let mut rows = Vec::with_capacity(ROW_GROUP_SIZE);
+        let mut done = false;
+        while rows.len() < ROW_GROUP_SIZE {
+            match self.pages.next_page()? {
+                Some(page) => rows.extend(page),
+                None => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            if !rows.is_empty() {
+                write_row_group(writer, &rows, &self.columns, self.unixtime)?;
+            }
+            if done {
+                writer.close().map_err(to_err)?;
+            }
+        }
+        if done {
+            self.writer = None;
+        }
+        Ok(())
+    }
+}
+
+impl Read for ParquetReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            self.pos = 0;
+            self.buf = self.sink.take();
+            if self.buf.is_empty() {
+                if self.writer.is_none() {
+                    return Ok(0);
+                }
+                self.write_next().map_err(to_io)?;
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,263 @@
+//! Bulk import of historical readings from a CSV file, such as a log pulled
+//! off a sensor's SD card.
+//!
+//! Rows go through the same validation as live ingestion, and rows whose
+//! timestamp the sensor already has a reading for are skipped, so the same log
+//! can be imported twice. The upload is spooled to a temporary file first, so
+//! one that's too large is turned away before anything is stored, and it's
+//! then read and stored in chunks so large logs never have to fit in memory.
+use crate::{
+    models::{Reading, ReadingInsert},
+    result::{Error, Result},
+    util::token::rand_str,
+};
+use chrono::{DateTime, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use diesel::SqliteConnection;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+};
+
+/// Readings are checked for duplicates and stored this many at a time, which
+/// bounds how much of the file is held in memory and how long each write
+/// holds the database lock.
+pub const CHUNK_SIZE: usize = 500;
+
+/// Imports larger than this are rejected.
+pub const MAX_IMPORT_BYTES: u64 = 64 * 1024 * 1024;
+
+const MAX_ERRORS: usize = 100;
+const MAX_PREVIEW: usize = 20;
+
+/// Which CSV columns hold which reading fields. Any column that isn't given
+/// is expected to have the same name as the field.
+#[allow(non_snake_case)]
+#[derive(FromForm, Default)]
+pub struct ImportOptions {
+    pub dry_run: Option<bool>,
+    /// IANA name of the timezone that non-unix timestamps are in. Defaults to
+    /// UTC.
+    pub timezone: Option<String>,
+    /// strftime-style format for timestamps that aren't unix seconds or
+    /// RFC 3339.
+    pub timestamp_format: Option<String>,
+    pub timestamp_column: Option<String>,
+    pub peak_power_mW_column: Option<String>,
+    pub peak_current_mA_column: Option<String>,
+    pub peak_voltage_V_column: Option<String>,
+    pub temp_celsius_column: Option<String>,
+    pub batt_V_column: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RowError {
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub rows: usize,
+    pub valid: usize,
+    pub duplicates: usize,
+    pub inserted: usize,
+    pub errors: Vec<RowError>,
+    pub preview: Vec<ReadingInsert>,
+}
+
+struct Columns {
+    timestamp: usize,
+    values: [usize; 5],
+}
+
+fn find_column(headers: &csv::StringRecord, name: &str) -> Result<usize> {
+    headers
+        .iter()
+        .position(|h| h.trim() == name)
+        .ok_or_else(|| Error::MalformedCsv(format!("missing column '{}'", name)))
+}
+
+impl Columns {
+    fn new(headers: &csv::StringRecord, opts: &ImportOptions) -> Result<Columns> {
+        let name = |col: &Option<String>, default: &'static str| {
+            col.clone().unwrap_or_else(|| default.to_string())
+        };
+        Ok(Columns {
+            timestamp: find_column(headers, &name(&opts.timestamp_column, "timestamp"))?,
+            values: [
+                find_column(headers, &name(&opts.peak_power_mW_column, "peak_power_mW"))?,
+                find_column(
+                    headers,
+                    &name(&opts.peak_current_mA_column, "peak_current_mA"),
+                )?,
+                find_column(
+                    headers,
+                    &name(&opts.peak_voltage_V_column, "peak_voltage_V"),
+                )?,
+                find_column(headers, &name(&opts.temp_celsius_column, "temp_celsius"))?,
+                find_column(headers, &name(&opts.batt_V_column, "batt_V"))?,
+            ],
+        })
+    }
+}
+
+/// Parses a timestamp as unix seconds, RFC 3339, or a local time in `tz`.
+pub fn parse_timestamp(
+    s: &str,
+    tz: Tz,
+    format: Option<&str>,
+) -> std::result::Result<NaiveDateTime, String> {
+    let s = s.trim();
+    if let Ok(unix) = s.parse::<i64>() {
+        return Ok(NaiveDateTime::from_timestamp(unix, 0));
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.naive_utc());
+    }
+    let local = match format {
+        Some(f) => NaiveDateTime::parse_from_str(s, f).ok(),
+        None => NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S"))
+            .ok(),
+    };
+    let local = local.ok_or_else(|| format!("could not parse timestamp '{}'", s))?;
+    tz.from_local_datetime(&local)
+        .earliest()
+        .map(|dt| dt.naive_utc())
+        .ok_or_else(|| format!("{} does not exist in {}", local, tz.name()))
+}
+
+fn parse_row(
+    record: &csv::StringRecord,
+    cols: &Columns,
+    sensor_id: i32,
+    tz: Tz,
+    format: Option<&str>,
+) -> std::result::Result<ReadingInsert, String> {
+    let field = |idx: usize| record.get(idx).unwrap_or("").trim();
+    let timestamp = parse_timestamp(field(cols.timestamp), tz, format)?;
+    let mut values = [0f32; 5];
+    for (value, &idx) in values.iter_mut().zip(cols.values.iter()) {
+        *value = field(idx)
+            .parse()
+            .map_err(|_| format!("could not parse number '{}'", field(idx)))?;
+    }
+    let reading = ReadingInsert {
+        sensor_id,
+        timestamp,
+        peak_power_mW: values[0],
+        peak_current_mA: values[1],
+        peak_voltage_V: values[2],
+        temp_celsius: values[3],
+        batt_V: values[4],
+        created: Some(timestamp),
+    };
+    Reading::validate(&reading).map_err(|e| e.to_string())?;
+    Ok(reading)
+}
+
+fn flush(
+    chunk: &mut Vec<ReadingInsert>,
+    sensor_id: i32,
+    report: &mut ImportReport,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    if report.dry_run {
+        let timestamps: Vec<NaiveDateTime> = chunk.iter().map(|r| r.timestamp).collect();
+        report.duplicates += Reading::existing_timestamps(sensor_id, &timestamps, conn)?.len();
+    } else {
+        let timestamps: Vec<NaiveDateTime> = chunk.iter().map(|r| r.timestamp).collect();
+        let existing: HashSet<NaiveDateTime> =
+            Reading::existing_timestamps(sensor_id, &timestamps, conn)?
+                .into_iter()
+                .collect();
+        let new: Vec<ReadingInsert> = chunk
+            .drain(..)
+            .filter(|r| !existing.contains(&r.timestamp))
+            .collect();
+        report.duplicates += timestamps.len() - new.len();
+        report.inserted += Reading::insert_many(&new, conn)?;
+    }
+    chunk.clear();
+    Ok(())
+}
+
+/// Copies an upload to a temporary file, and returns it opened for reading
+/// from the start. Uploads over `MAX_IMPORT_BYTES` are rejected. The file is
+/// unlinked right away, so it goes away once it's dropped.
+pub fn spool<R: Read>(input: R) -> Result<File> {
+    let to_err = |e: io::Error| Error::UnknownError(format!("failed to spool import: {}", e));
+    let path = std::env::temp_dir().join(format!("sol-import-{}.csv", rand_str()));
+    let mut file = File::create(&path).map_err(to_err)?;
+    let copied = io::copy(&mut input.take(MAX_IMPORT_BYTES + 1), &mut file);
+    let file = File::open(&path).map_err(to_err);
+    fs::remove_file(&path).map_err(to_err)?;
+    if copied.map_err(to_err)? > MAX_IMPORT_BYTES {
+        return Err(Error::ImportTooLarge(MAX_IMPORT_BYTES));
+    }
+    file
+}
+
+/// Imports readings for a sensor from CSV with a header row. With `dry_run`
+/// set nothing is stored, and the report shows what would have happened.
+pub fn import_csv<R: Read>(
+    sensor_id: i32,
+    input: R,
+    opts: &ImportOptions,
+    conn: &SqliteConnection,
+) -> Result<ImportReport> {
+    let tz: Tz = match opts.timezone {
+        Some(ref name) => name
+            .parse()
+            .map_err(|_| Error::InvalidTimezone(name.clone()))?,
+        None => Tz::UTC,
+    };
+    let format = opts.timestamp_format.as_ref().map(|f| f.as_str());
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(input);
+    let headers = rdr
+        .headers()
+        .map_err(|e| Error::MalformedCsv(e.to_string()))?
+        .clone();
+    let cols = Columns::new(&headers, opts)?;
+
+    let mut report = ImportReport {
+        dry_run: opts.dry_run.unwrap_or(false),
+        ..Default::default()
+    };
+    let mut seen = HashSet::new();
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    for record in rdr.records() {
+        let record = record.map_err(|e| Error::MalformedCsv(e.to_string()))?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        report.rows += 1;
+        match parse_row(&record, &cols, sensor_id, tz, format) {
+            Ok(reading) => {
+                report.valid += 1;
+                if !seen.insert(reading.timestamp) {
+                    report.duplicates += 1;
+                    continue;
+                }
+                if report.preview.len() < MAX_PREVIEW {
+                    report.preview.push(reading.clone());
+                }
+                chunk.push(reading);
+            }
+            Err(message) => {
+                if report.errors.len() < MAX_ERRORS {
+                    report.errors.push(RowError { line, message });
+                }
+            }
+        }
+        if chunk.len() >= CHUNK_SIZE {
+            flush(&mut chunk, sensor_id, &mut report, conn)?;
+        }
+    }
+    flush(&mut chunk, sensor_id, &mut report, conn)?;
+    Ok(report)
+}
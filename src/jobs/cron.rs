@@ -0,0 +1,131 @@
+//! Cron expressions: minute, hour, day of month, month and day of week, in UTC.
+//! Each field is `*`, a number, a range like `1-5`, any of those with a step
+//! like `*/15`, or a comma separated list of them. Days of the week go from 0
+//! for Sunday to 6, and 7 is Sunday too.
+use crate::result::{Error, Result};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+
+/// How far ahead `next_after` looks, so expressions that can never match,
+/// like the 31st of February, don't loop forever.
+const MAX_LOOKAHEAD_DAYS: i64 = 5 * 366;
+
+#[derive(Clone, Debug)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// Like cron, when both days of the month and days of the week are
+    /// restricted, a time matches if either one does.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+fn invalid(expr: &str, why: &str) -> Error {
+    Error::InvalidSchedule(format!("'{}': {}", expr, why))
+}
+
+/// Which values of `min..=max` a field matches, indexed by value.
+fn field(expr: &str, spec: &str, min: u32, max: u32) -> Result<Vec<bool>> {
+    let mut matches = vec![false; max as usize + 1];
+    for part in spec.split(',') {
+        let mut pieces = part.splitn(2, '/');
+        let range = pieces.next().unwrap_or("");
+        let step = match pieces.next() {
+            Some(step) => step
+                .parse::<u32>()
+                .ok()
+                .filter(|&s| s > 0)
+                .ok_or_else(|| invalid(expr, &format!("bad step in '{}'", part)))?,
+            None => 1,
+        };
+        let number = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|&n| min <= n && n <= max)
+                .ok_or_else(|| {
+                    invalid(
+                        expr,
+                        &format!("'{}' is not a number from {} to {}", s, min, max),
+                    )
+                })
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (number(&range[..i])?, number(&range[i + 1..])?)
+        } else {
+            let n = number(range)?;
+            // `5/10` means every 10 starting at 5.
+            (n, if step > 1 { max } else { n })
+        };
+        if start > end {
+            return Err(invalid(expr, &format!("'{}' is backwards", part)));
+        }
+        let mut n = start;
+        while n <= end {
+            matches[n as usize] = true;
+            n += step;
+        }
+    }
+    Ok(matches)
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Cron> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid(expr, "expected 5 fields"));
+        }
+        let mut weekdays = field(expr, fields[4], 0, 7)?;
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Cron {
+            minutes: field(expr, fields[0], 0, 59)?,
+            hours: field(expr, fields[1], 0, 23)?,
+            days: field(expr, fields[2], 1, 31)?,
+            months: field(expr, fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first minute after `t` that matches, if there is one in the next
+    /// few years.
+    pub fn next_after(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t = t.date().and_hms(t.hour(), t.minute(), 0) + Duration::minutes(1);
+        let limit = t + Duration::days(MAX_LOOKAHEAD_DAYS);
+        while t < limit {
+            if !self.months[t.month() as usize] {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = NaiveDate::from_ymd(year, month, 1).and_hms(0, 0, 0);
+            } else if !self.day_matches(t.date()) {
+                t = t.date().succ().and_hms(0, 0, 0);
+            } else if !self.hours[t.hour() as usize] {
+                t = t.date().and_hms(t.hour(), 0, 0) + Duration::hours(1);
+            } else if !self.minutes[t.minute() as usize] {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
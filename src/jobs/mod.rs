@@ -0,0 +1,321 @@
+//! Runs maintenance jobs on cron schedules, from a thread inside the server.
+//!
+//! Jobs are kept in the database with when they next run, so a restart
+//! doesn't lose or repeat a run. Before running a job a server locks it in
+//! the database, so when several servers share a database only one of them
+//! runs it. A lock expires after `LOCK_SECS`, in case a server dies mid-run.
+//! Every run is recorded with what it did, or why it failed, and a job that
+//! panics is recorded as failed without taking the scheduler down with it.
+pub mod cron;
+
+use self::cron::Cron;
+use crate::{
+    alerts, digests, logging,
+    models::{
+        job::{self, Run},
+        onetime_login, rate_limit, rollup,
+    },
+    ratelimit,
+    result::{Error, Result},
+    retention::{self, Policy},
+    schema::readings,
+    util::{
+        email::{Emailer, Notifier},
+        token::rand_str,
+    },
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{connection::TransactionManager, prelude::*};
+use rocket_contrib::templates::tera::Tera;
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    thread::{self, JoinHandle},
+    time,
+};
+
+/// How often the scheduler looks for jobs that are due. Schedules are in
+/// minutes, so this only needs to be well under one.
+pub const POLL_SECS: u64 = 15;
+
+/// How long a runner holds a job before another may take it over.
+const LOCK_SECS: i64 = 60 * 60;
+
+/// Days of rollups that `refresh_rollups` recomputes.
+const ROLLUP_REFRESH_DAYS: i64 = 2;
+
+/// Days of job runs that `purge_job_runs` keeps.
+pub const JOB_RUN_DAYS: i64 = 30;
+
+/// What jobs need to run.
+pub struct Context<'a> {
+    pub conn: &'a SqliteConnection,
+    pub now: NaiveDateTime,
+    pub notifier: &'a dyn Notifier,
+    pub tera: &'a Tera,
+    pub retention: Option<Policy>,
+    pub digest_schedule: digests::Schedule,
+}
+
+pub struct Job {
+    pub name: &'static str,
+    /// Default schedule, as a cron expression in UTC.
+    pub schedule: &'static str,
+    /// Does the job's work, returning a summary of what it did.
+    pub run: fn(&Context) -> Result<String>,
+}
+
+pub const JOBS: &[Job] = &[
+    Job {
+        name: "alerts",
+        schedule: "* * * * *",
+        run: run_alerts,
+    },
+    Job {
+        name: "digests",
+        schedule: "*/10 * * * *",
+        run: run_digests,
+    },
+    Job {
+        name: "purge_job_runs",
+        schedule: "50 3 * * *",
+        run: purge_job_runs,
+    },
+    Job {
+        name: "purge_onetime_logins",
+        schedule: "0 * * * *",
+        run: purge_onetime_logins,
+    },
+    Job {
+        name: "purge_rate_limits",
+        schedule: "45 * * * *",
+        run: purge_rate_limits,
+    },
+    Job {
+        name: "refresh_rollups",
+        schedule: "30 3 * * *",
+        run: refresh_rollups,
+    },
+    Job {
+        name: "retention",
+        schedule: "15 * * * *",
+        run: apply_retention,
+    },
+];
+
+fn summary<T: serde::Serialize>(report: &T) -> String {
+    serde_json::to_string(report).expect("failed to serialize report")
+}
+
+fn run_alerts(ctx: &Context) -> Result<String> {
+    alerts::run(ctx.now, ctx.conn, ctx.notifier).map(|r| summary(&r))
+}
+
+fn run_digests(ctx: &Context) -> Result<String> {
+    digests::run(
+        &ctx.digest_schedule,
+        ctx.now,
+        ctx.conn,
+        ctx.tera,
+        ctx.notifier,
+    )
+    .map(|r| summary(&r))
+}
+
+fn purge_job_runs(ctx: &Context) -> Result<String> {
+    let before = ctx.now - Duration::days(JOB_RUN_DAYS);
+    let n = job::purge_runs(before, ctx.conn)?;
+    Ok(format!("deleted {} job runs", n))
+}
+
+fn purge_onetime_logins(ctx: &Context) -> Result<String> {
+    let n = onetime_login::purge_expired(ctx.now, ctx.conn)?;
+    Ok(format!("deleted {} expired one-time logins", n))
+}
+
+/// Deletes rate limit buckets nobody has used lately, which are full again.
+fn purge_rate_limits(ctx: &Context) -> Result<String> {
+    let before = ctx.now - Duration::hours(ratelimit::IDLE_HOURS);
+    let n = rate_limit::purge_buckets(before, ctx.conn)?;
+    Ok(format!("deleted {} idle rate limit buckets", n))
+}
+
+/// Recomputes the latest rollups of sensors that sent readings lately, which
+/// repairs any a failed insert left behind.
+fn refresh_rollups(ctx: &Context) -> Result<String> {
+    let since = ctx.now - Duration::days(ROLLUP_REFRESH_DAYS);
+    let sensor_ids: Vec<i32> = readings::table
+        .select(readings::sensor_id)
+        .filter(readings::timestamp.ge(since))
+        .distinct()
+        .load(ctx.conn)?;
+    for &id in &sensor_ids {
+        rollup::refresh(id, since, ctx.now, ctx.conn)?;
+    }
+    Ok(format!("refreshed rollups of {} sensors", sensor_ids.len()))
+}
+
+fn apply_retention(ctx: &Context) -> Result<String> {
+    match ctx.retention {
+        Some(policy) => retention::apply(&policy, ctx.now, false, ctx.conn).map(|r| summary(&r)),
+        None => Ok("no retention policy is set".to_string()),
+    }
+}
+
+pub fn find(name: &str) -> Result<&'static Job> {
+    JOBS.iter()
+        .find(|job| job.name == name)
+        .ok_or_else(|| Error::UnknownJob(name.to_string()))
+}
+
+/// The first time after `now` that a schedule is due.
+pub fn next_run(schedule: &str, now: NaiveDateTime) -> Result<NaiveDateTime> {
+    Cron::parse(schedule)?
+        .next_after(now)
+        .ok_or_else(|| Error::InvalidSchedule(format!("'{}' never runs", schedule)))
+}
+
+/// An id for this process's runs, so its locks can be told apart from other
+/// servers'.
+pub fn runner_id() -> String {
+    format!("{}-{}", std::process::id(), &rand_str()[..8])
+}
+
+/// Adds jobs that aren't in the database yet, first due at their next
+/// scheduled time.
+pub fn sync(now: NaiveDateTime, conn: &SqliteConnection) -> Result<()> {
+    for job in JOBS {
+        job::insert_missing(job.name, job.schedule, next_run(job.schedule, now)?, conn)?;
+    }
+    Ok(())
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown panic", |msg| msg.as_str()),
+    }
+}
+
+/// Rolls back any transaction a panicking job left open on the connection, so
+/// later writes aren't stuck inside it.
+fn roll_back_open_transactions(conn: &SqliteConnection) -> Result<()> {
+    let manager = conn.transaction_manager();
+    while TransactionManager::<SqliteConnection>::get_transaction_depth(manager) > 0 {
+        manager.rollback_transaction(conn)?;
+    }
+    Ok(())
+}
+
+/// Runs a job that `runner` has locked, records the run and releases the
+/// lock. A job that panics fails like one that returns an error.
+fn run_locked(job: &Job, ctx: &Context, runner: &str) -> Result<Run> {
+    let run_id = job::start_run(job.name, runner, ctx.now, ctx.conn)?;
+    let (status, output) = match panic::catch_unwind(AssertUnwindSafe(|| (job.run)(ctx))) {
+        Ok(Ok(output)) => (job::SUCCEEDED, output),
+        Ok(Err(e)) => (job::FAILED, e.to_string()),
+        Err(payload) => {
+            roll_back_open_transactions(ctx.conn)?;
+            (
+                job::FAILED,
+                format!("panicked: {}", panic_message(payload.as_ref())),
+            )
+        }
+    };
+    let finished = Utc::now().naive_utc().max(ctx.now);
+    job::finish_run(run_id, status, &output, finished, ctx.conn)?;
+    let schedule = job::find(job.name, ctx.conn)?.map_or(job.schedule.to_string(), |j| j.schedule);
+    // A schedule that can't be run from is retried daily, and its runs say
+    // why.
+    let next = next_run(&schedule, ctx.now).unwrap_or_else(|_| ctx.now + Duration::days(1));
+    job::unlock(job.name, runner, ctx.now, next, ctx.conn)?;
+    job::find_run(run_id, ctx.conn)
+}
+
+fn lock_until(now: NaiveDateTime) -> NaiveDateTime {
+    now + Duration::seconds(LOCK_SECS)
+}
+
+/// Runs every enabled job that's due at `ctx.now` and not locked by another
+/// runner, returning their runs.
+pub fn run_due(ctx: &Context, runner: &str) -> Result<Vec<Run>> {
+    let mut runs = Vec::new();
+    for job in JOBS {
+        if job::lock(
+            job.name,
+            runner,
+            ctx.now,
+            lock_until(ctx.now),
+            true,
+            ctx.conn,
+        )? {
+            runs.push(run_locked(job, ctx, runner)?);
+        }
+    }
+    Ok(runs)
+}
+
+/// Runs a job straight away, whether or not it's due or enabled, unless
+/// another runner has it locked.
+pub fn run_now(name: &str, ctx: &Context, runner: &str) -> Result<Run> {
+    run_job(find(name)?, ctx, runner)
+}
+
+/// Like `run_now`, for a job that's given rather than looked up by name.
+pub fn run_job(job: &Job, ctx: &Context, runner: &str) -> Result<Run> {
+    if !job::lock(
+        job.name,
+        runner,
+        ctx.now,
+        lock_until(ctx.now),
+        false,
+        ctx.conn,
+    )? {
+        return Err(Error::JobLocked(job.name.to_string()));
+    }
+    run_locked(job, ctx, runner)
+}
+
+/// Runs due jobs every `POLL_SECS` on its own thread.
+pub fn spawn(
+    db_uri: &str,
+    retention: Option<Policy>,
+    digest_schedule: digests::Schedule,
+) -> JoinHandle<()> {
+    let db_uri = db_uri.to_string();
+    thread::spawn(move || {
+        let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+        let tera = digests::templates().expect("failed to load email templates");
+        let emailer = Emailer::new();
+        let runner = runner_id();
+        sync(Utc::now().naive_utc(), &conn).expect("failed to sync jobs");
+        loop {
+            let ctx = Context {
+                conn: &conn,
+                now: Utc::now().naive_utc(),
+                notifier: &emailer,
+                tera: &tera,
+                retention,
+                digest_schedule,
+            };
+            match run_due(&ctx, &runner) {
+                Ok(runs) => {
+                    for run in runs.iter().filter(|r| r.status == job::FAILED) {
+                        logging::error(
+                            "jobs",
+                            format!(
+                                "{} failed: {}",
+                                run.job_name,
+                                run.output.as_ref().map_or("", |o| o.as_str())
+                            ),
+                        );
+                    }
+                }
+                Err(e) => logging::error("jobs", format!("failed to run due jobs: {}", e)),
+            }
+            thread::sleep(time::Duration::from_secs(POLL_SECS));
+        }
+    })
+}
@@ -5,7 +5,14 @@
 extern crate argon2rs;
 extern crate base64;
 extern crate chrono;
+extern crate chrono_tz;
+extern crate csv;
+extern crate futures;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate native_tls;
 extern crate rand;
+extern crate reqwest;
 #[macro_use]
 extern crate rocket;
 #[macro_use]
@@ -17,22 +24,50 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 extern crate git_version;
+extern crate hex;
+extern crate hmac;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+#[cfg(feature = "parquet")]
+extern crate parquet;
+#[cfg(feature = "mqtt")]
+extern crate rumqtt;
 extern crate rusoto_core;
 extern crate rusoto_ses;
 extern crate serde_json;
+extern crate sha2;
+extern crate tokio;
 
+mod alerts;
 mod api;
 mod auth;
+mod csrf;
 mod db;
+mod digests;
+mod export;
+mod import;
+mod jobs;
+mod live;
+mod logging;
+mod lorawan;
+mod metrics;
 mod models;
+mod mqtt;
+mod probes;
+mod ratelimit;
 mod result;
+mod retention;
 mod schema;
+mod security;
 #[cfg(test)]
 mod tests;
 mod util;
 mod web;
+mod webhooks;
 
 use crate::db::SolDbConn;
+use diesel::{Connection, SqliteConnection};
 use rocket::{
     config::{Config, Environment, LoggingLevel},
     http::Status,
@@ -83,15 +118,26 @@ fn not_authorized(req: &Request) -> AnyResponder {
 }
 
 fn rocket(db_uri: &str, quiet: bool) -> Rocket {
+    rocket_with_limits(db_uri, quiet, ratelimit::RateLimiter::from_env())
+}
+
+fn rocket_with_limits(db_uri: &str, quiet: bool, limiter: ratelimit::RateLimiter) -> Rocket {
     let mut databases = HashMap::new();
     let mut sol = HashMap::new();
     sol.insert("url", db_uri);
     databases.insert("sqlite_sol", sol);
+    // Live streams each hold a worker, so they get their own on top of the
+    // default.
+    let workers = Config::new(Environment::Staging).workers + live::MAX_STREAMS as u16;
+    let log_format = logging::Format::from_env();
+    // With JSON logs, Rocket only logs what's gone badly wrong, so its lines
+    // don't get mixed in with ours.
     let config = Config::build(Environment::Staging)
-        .log_level(if quiet {
-            LoggingLevel::Off
-        } else {
-            LoggingLevel::Normal
+        .workers(workers)
+        .log_level(match (quiet, log_format) {
+            (true, _) => LoggingLevel::Off,
+            (false, logging::Format::Json) => LoggingLevel::Critical,
+            (false, logging::Format::Text) => LoggingLevel::Normal,
         })
         .extra("databases", databases)
         .finalize()
@@ -108,6 +154,8 @@ fn rocket(db_uri: &str, quiet: bool) -> Rocket {
                 web::user,
                 web::user_edit,
                 web::user_edit_post,
+                web::digest_unsubscribe,
+                web::digest_unsubscribe_post,
                 web::register,
                 web::register_post,
                 web::login,
@@ -123,7 +171,21 @@ fn rocket(db_uri: &str, quiet: bool) -> Rocket {
                 web::sensor_edit_post,
                 web::sensor_deactivate,
                 web::sensor_deactivate_post,
+                web::sensor_alerts,
+                web::sensor_alerts_post,
+                web::alert_rule_mute_post,
+                web::alert_acknowledge_post,
+                web::admin_jobs,
+                web::admin_job_run_post,
+                web::admin_job_toggle_post,
+                web::admin_audit,
+                web::sensor_import,
+                web::sensor_import_post,
                 set_flash,
+                metrics::metrics,
+                probes::healthz,
+                probes::readyz,
+                ratelimit::rate_limited,
             ],
         )
         .mount(
@@ -132,12 +194,45 @@ fn rocket(db_uri: &str, quiet: bool) -> Rocket {
                 api::add_user,
                 api::get_users,
                 api::get_token,
+                api::get_audit_log,
+                api::get_rate_limits,
+                api::set_sensor_rate_limit,
+                api::set_user_rate_limit,
                 api::get_sensor_token,
                 api::add_sensor,
                 api::add_reading,
                 api::add_readings,
+                api::sensor_stream,
+                api::user_stream,
+                api::import_readings,
+                api::add_integration,
+                api::lorawan_uplink,
                 api::get_readings,
+                api::get_aggregates,
+                api::export_sensor_readings,
+                api::export_readings,
+                api::get_sensor_channels,
+                api::get_model_channels,
+                api::set_model_channels,
                 api::get_energy_stats,
+                api::get_energy_summary,
+                api::get_energy_settings,
+                api::set_energy_settings,
+                api::get_performance,
+                api::get_sensor_health,
+                api::get_alerts,
+                api::add_alert_rule,
+                api::mute_alert_rule,
+                api::acknowledge_alert,
+                api::get_digest,
+                api::set_digest,
+                api::transfer_sensor,
+                api::add_webhook,
+                api::get_webhooks,
+                api::delete_webhook,
+                api::get_webhook_deliveries,
+                api::get_clear_sky,
+                api::set_location,
                 api::get_version,
             ],
         )
@@ -145,11 +240,86 @@ fn rocket(db_uri: &str, quiet: bool) -> Rocket {
         .register(catchers![not_authorized])
         .attach(Template::fairing())
         .attach(SolDbConn::fairing())
+        .attach(metrics::RequestMetrics)
+        .attach(logging::RequestLog {
+            format: log_format,
+            enabled: !quiet,
+        })
+        .attach(limiter)
+        .attach(security::SecurityHeaders::from_env())
+        .manage(live::Hub::default())
+        .manage(probes::Probes::new(db_uri))
 }
 
 const DB_URI: &'static str = "./sol.sqlite";
 
 pub fn run_server() {
+    util::signing::check_key();
     db::run_migrations(DB_URI);
+    #[cfg(feature = "mqtt")]
+    {
+        if let Ok(broker) = std::env::var("SOL_MQTT_BROKER") {
+            mqtt::spawn(DB_URI, &broker);
+        }
+    }
+    jobs::spawn(
+        DB_URI,
+        retention::Policy::from_env(),
+        digests::Schedule::from_env(),
+    );
+    webhooks::spawn(DB_URI);
     rocket(DB_URI, false).launch();
 }
+
+/// Applies the retention policy from the environment once and prints what was
+/// deleted, or what would be with `dry_run`.
+pub fn apply_retention(dry_run: bool) {
+    let policy = match retention::Policy::from_env() {
+        Some(policy) => policy,
+        None => {
+            eprintln!("SOL_RAW_RETENTION_DAYS is not set, nothing to do");
+            return;
+        }
+    };
+    db::run_migrations(DB_URI);
+    let conn = SqliteConnection::establish(DB_URI).expect("error connecting to db");
+    let report = retention::apply(&policy, chrono::Utc::now().naive_utc(), dry_run, &conn)
+        .expect("failed to apply retention policy");
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).expect("failed to serialize report")
+    );
+}
+
+/// Recomputes the rollups of one sensor, or of every sensor, from their raw
+/// readings. Used after backfilling readings outside of the API.
+pub fn rebuild_rollups(sensor_id: Option<i32>) {
+    db::run_migrations(DB_URI);
+    let conn = SqliteConnection::establish(DB_URI).expect("error connecting to db");
+    match sensor_id {
+        Some(id) => {
+            models::rollup::rebuild(id, &conn).expect("failed to rebuild rollups");
+            println!("rebuilt rollups for sensor {}", id);
+        }
+        None => {
+            let n = models::rollup::rebuild_all(&conn).expect("failed to rebuild rollups");
+            println!("rebuilt rollups for {} sensors", n);
+        }
+    }
+}
+
+/// Prints every rollup that doesn't match the raw readings of a sensor, and
+/// exits with an error if there are any.
+pub fn check_rollups(sensor_id: i32) {
+    let conn = SqliteConnection::establish(DB_URI).expect("error connecting to db");
+    let mismatches = models::rollup::check(sensor_id, &conn).expect("failed to check rollups");
+    for m in &mismatches {
+        println!(
+            "{} {} {}: expected {:?} readings, found {:?}",
+            m.period, m.bucket, m.channel, m.expected_count, m.actual_count
+        );
+    }
+    if !mismatches.is_empty() {
+        std::process::exit(1);
+    }
+}
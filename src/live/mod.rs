@@ -0,0 +1,222 @@
+//! Pushes readings to clients as the API accepts them, over Server-Sent Events.
+//! The hub lives in Rocket's managed state, so it only sees readings accepted
+//! by this process.
+//!
+//! Every stream holds one of Rocket's worker threads for as long as the client
+//! stays connected, so the number of streams is capped below the number of
+//! workers, and each client can only hold a few of them. Rocket notices a
+//! client has gone when a keepalive fails to send, and drops its stream, which
+//! takes it out of the hub.
+use crate::{
+    models::{channel::ChannelValues, ReadingInsert},
+    result::{Error, Result},
+};
+use chrono::{naive::serde::ts_seconds, NaiveDateTime};
+use rocket::{
+    http::ContentType,
+    request::Request,
+    response::{self, Responder, Response},
+};
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Streams open at once. Rocket is given this many workers on top of its
+/// default, so streams can't starve other requests.
+pub const MAX_STREAMS: usize = 32;
+
+/// Streams open at once for one client, a user or an ip, so one client can't
+/// take them all.
+pub const MAX_STREAMS_PER_CLIENT: usize = 4;
+
+/// Events buffered for a stream. A client that falls this far behind misses
+/// events rather than holding up everyone else.
+const BUFFER: usize = 256;
+
+/// How often a comment is sent on an idle stream, which keeps proxies from
+/// closing it and finds clients that have gone.
+const KEEPALIVE_SECS: u64 = 15;
+
+/// Which readings a stream gets.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Topic {
+    Sensor(i32),
+    /// Every sensor of a user.
+    Owner(i32),
+}
+
+/// A reading as it's sent to streams.
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct LiveReading {
+    pub sensor_id: i32,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: NaiveDateTime,
+    pub peak_power_mW: f32,
+    pub peak_current_mA: f32,
+    pub peak_voltage_V: f32,
+    pub temp_celsius: f32,
+    pub batt_V: f32,
+    pub channels: ChannelValues,
+}
+
+impl LiveReading {
+    pub fn new(reading: &ReadingInsert, channels: ChannelValues) -> LiveReading {
+        LiveReading {
+            sensor_id: reading.sensor_id,
+            timestamp: reading.timestamp,
+            peak_power_mW: reading.peak_power_mW,
+            peak_current_mA: reading.peak_current_mA,
+            peak_voltage_V: reading.peak_voltage_V,
+            temp_celsius: reading.temp_celsius,
+            batt_V: reading.batt_V,
+            channels,
+        }
+    }
+}
+
+struct Subscriber {
+    topic: Topic,
+    /// Who opened the stream, like `user:7` or `ip:203.0.113.9`.
+    client: String,
+    sender: SyncSender<String>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    by_id: HashMap<u64, Subscriber>,
+    next_id: u64,
+}
+
+#[derive(Default)]
+pub struct Hub {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl Hub {
+    /// Opens a stream of `topic` for `client`, until it's dropped.
+    pub fn subscribe(&self, topic: Topic, client: &str) -> Result<EventStream> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let open = subscribers
+            .by_id
+            .values()
+            .filter(|s| s.client == client)
+            .count();
+        if subscribers.by_id.len() >= MAX_STREAMS || open >= MAX_STREAMS_PER_CLIENT {
+            return Err(Error::TooManyStreams);
+        }
+        let (sender, receiver) = sync_channel(BUFFER);
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.by_id.insert(
+            id,
+            Subscriber {
+                topic,
+                client: client.to_string(),
+                sender,
+            },
+        );
+        Ok(EventStream {
+            id,
+            hub: self.subscribers.clone(),
+            receiver,
+            buf: Vec::new(),
+            pos: 0,
+            flush: false,
+        })
+    }
+
+    /// Sends readings of a sensor owned by `owner_id` to the streams that want
+    /// them.
+    pub fn publish(&self, owner_id: i32, readings: &[LiveReading]) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for reading in readings {
+            let event = format!(
+                "event: reading\ndata: {}\n\n",
+                serde_json::to_string(reading).expect("failed to serialize reading")
+            );
+            subscribers.by_id.retain(|_, s| {
+                let wanted = match s.topic {
+                    Topic::Sensor(id) => id == reading.sensor_id,
+                    Topic::Owner(id) => id == owner_id,
+                };
+                if !wanted {
+                    return true;
+                }
+                match s.sender.try_send(event.clone()) {
+                    Ok(()) | Err(TrySendError::Full(_)) => true,
+                    Err(TrySendError::Disconnected(_)) => false,
+                }
+            });
+        }
+    }
+
+    pub fn streams(&self) -> usize {
+        self.subscribers.lock().unwrap().by_id.len()
+    }
+}
+
+/// The body of an event stream response. Reading it blocks until there's an
+/// event or a keepalive to send.
+pub struct EventStream {
+    id: u64,
+    hub: Arc<Mutex<Subscribers>>,
+    receiver: Receiver<String>,
+    buf: Vec<u8>,
+    pos: usize,
+    /// Whether the bytes read so far should be sent before blocking again.
+    flush: bool,
+}
+
+impl Read for EventStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            if self.flush {
+                // Rocket's `sse` feature sends what it has read so far when a
+                // body returns `WouldBlock`, rather than waiting for a whole
+                // chunk.
+                self.flush = false;
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "flush"));
+            }
+            let text = match self
+                .receiver
+                .recv_timeout(Duration::from_secs(KEEPALIVE_SECS))
+            {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.buf = text.into_bytes();
+            self.pos = 0;
+            self.flush = true;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.hub.lock().unwrap().by_id.remove(&self.id);
+    }
+}
+
+impl<'r> Responder<'r> for EventStream {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            // Stops nginx buffering the stream.
+            .raw_header("X-Accel-Buffering", "no")
+            .chunked_body(self, 4096)
+            .ok()
+    }
+}
@@ -0,0 +1,228 @@
+//! Logs every request as a line of JSON, with an id that's also sent back in
+//! `X-Request-Id` and recorded with audit log entries, so one request can be
+//! followed from a client through the logs.
+//!
+//! A request keeps the id a trusted proxy gave it in `X-Request-Id`, if it
+//! looks like one. Anyone else's is replaced, so clients can't put what they
+//! like in the audit log. Auth guards record who made a request with
+//! `identify`, since the fairing only sees the request after the route has
+//! run.
+//!
+//! Anything else worth logging, like a background job failing, goes through
+//! `warn` or `error`, so every line of the server's output has the same
+//! format.
+use crate::util::{net, token::rand_str};
+use chrono::Utc;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    request::{FromRequest, Outcome},
+    Data, Request, Response,
+};
+use std::{fmt::Display, sync::Mutex, time::Instant};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The longest request id taken from a request.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Whether request logs are written as JSON, or left to Rocket.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json,
+    Text,
+}
+
+impl Format {
+    /// Reads `SOL_LOG_FORMAT`, which is `json` unless it's `text`.
+    pub fn from_env() -> Format {
+        match std::env::var("SOL_LOG_FORMAT") {
+            Ok(ref f) if f == "text" => Format::Text,
+            Ok(ref f) if f != "json" => panic!("SOL_LOG_FORMAT must be json or text"),
+            _ => Format::Json,
+        }
+    }
+}
+
+lazy_static! {
+    static ref FORMAT: Format = Format::from_env();
+}
+
+/// Something that happened outside of a request.
+#[derive(Serialize, Debug)]
+pub struct EventEntry<'a> {
+    pub time: String,
+    pub level: &'a str,
+    /// The part of the server it happened in, like `jobs`.
+    pub source: &'a str,
+    pub message: String,
+}
+
+fn write_json<T: serde::Serialize>(entry: &T) {
+    println!(
+        "{}",
+        serde_json::to_string(entry).expect("failed to serialize log entry")
+    );
+}
+
+fn event(level: &str, source: &str, message: &dyn Display) {
+    match *FORMAT {
+        Format::Json => write_json(&EventEntry {
+            time: Utc::now().to_rfc3339(),
+            level,
+            source,
+            message: message.to_string(),
+        }),
+        Format::Text => println!("{}: {}: {}", level, source, message),
+    }
+}
+
+/// Logs something that might need looking into.
+pub fn warn<M: Display>(source: &str, message: M) {
+    event("warn", source, &message);
+}
+
+/// Logs something that failed.
+pub fn error<M: Display>(source: &str, message: M) {
+    event("error", source, &message);
+}
+
+/// Who made a request.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Identity {
+    User(i32),
+    Sensor(i32),
+}
+
+/// What's known about a request, in its local cache.
+struct Context {
+    id: String,
+    started: Instant,
+    identity: Mutex<Option<Identity>>,
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn context<'r>(req: &'r Request) -> &'r Context {
+    req.local_cache(|| Context {
+        id: req
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| net::from_trusted_proxy(req) && valid_request_id(id))
+            .map_or_else(|| rand_str()[..16].to_string(), |id| id.to_string()),
+        started: Instant::now(),
+        identity: Mutex::new(None),
+    })
+}
+
+pub fn request_id(req: &Request) -> String {
+    context(req).id.clone()
+}
+
+/// Records who made a request, for its log line.
+pub fn identify(req: &Request, identity: Identity) {
+    *context(req).identity.lock().unwrap() = Some(identity);
+}
+
+#[derive(Serialize, Debug)]
+pub struct RequestEntry {
+    pub time: String,
+    pub request_id: String,
+    pub method: String,
+    /// The route's path, like `/api/sensor/<id>`, which keeps tokens in paths
+    /// out of the logs. Requests that matched no route log their path.
+    pub route: Option<String>,
+    pub path: Option<String>,
+    pub status: u16,
+    pub latency_ms: f64,
+    pub ip: Option<String>,
+    pub user_id: Option<i32>,
+    pub sensor_id: Option<i32>,
+}
+
+impl RequestEntry {
+    pub fn new(req: &Request, res: &Response) -> RequestEntry {
+        let ctx = context(req);
+        let elapsed = ctx.started.elapsed();
+        let identity = *ctx.identity.lock().unwrap();
+        let route = req.route().map(|r| r.uri.path().to_string());
+        RequestEntry {
+            time: Utc::now().to_rfc3339(),
+            request_id: ctx.id.clone(),
+            method: req.method().to_string(),
+            path: if route.is_none() {
+                Some(req.uri().path().to_string())
+            } else {
+                None
+            },
+            route,
+            status: res.status().code,
+            latency_ms: elapsed.as_secs() as f64 * 1e3 + f64::from(elapsed.subsec_micros()) / 1e3,
+            ip: net::client_ip(req).map(|ip| ip.to_string()),
+            user_id: match identity {
+                Some(Identity::User(id)) => Some(id),
+                _ => None,
+            },
+            sensor_id: match identity {
+                Some(Identity::Sensor(id)) => Some(id),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Gives every request an id and, with `Format::Json`, logs it once it has a
+/// response.
+pub struct RequestLog {
+    pub format: Format,
+    /// Whether to log at all.
+    pub enabled: bool,
+}
+
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, req: &mut Request, _: &Data) {
+        context(req);
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        res.set_header(Header::new(REQUEST_ID_HEADER, request_id(req)));
+        if self.enabled && self.format == Format::Json {
+            write_json(&RequestEntry::new(req, res));
+        }
+    }
+}
+
+/// Where a request came from, for audit log entries.
+pub struct RequestInfo {
+    pub request_id: String,
+    pub ip: Option<String>,
+}
+
+impl RequestInfo {
+    pub fn of(req: &Request) -> RequestInfo {
+        RequestInfo {
+            request_id: request_id(req),
+            ip: net::client_ip(req).map(|ip| ip.to_string()),
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestInfo {
+    type Error = ();
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestInfo::of(req))
+    }
+}
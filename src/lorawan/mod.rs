@@ -0,0 +1,166 @@
+//! Ingestion of uplinks forwarded by a LoRaWAN network server.
+//!
+//! The Things Stack and ChirpStack both post a JSON document for every uplink.
+//! The device is identified by its DevEUI, which maps to `sensors.hardware_id`,
+//! and the application payload is base64-encoded in our packed format.
+//!
+//! A packed payload holds one or more readings of `PACKED_READING_LEN` bytes,
+//! all fields big-endian:
+//!
+//! | bytes  | field           | type | unit           |
+//! |--------|-----------------|------|----------------|
+//! | 0..4   | timestamp       | u32  | unix seconds   |
+//! | 4..6   | peak_power_mW   | u16  | 0.1 mW         |
+//! | 6..8   | peak_current_mA | u16  | 0.1 mA         |
+//! | 8..10  | peak_voltage_V  | u16  | mV             |
+//! | 10..12 | temp_celsius    | i16  | 0.01 degrees C |
+//! | 12..14 | batt_V          | u16  | mV             |
+use crate::{
+    models::{integration::Integration, Reading, ReadingInsert, Sensor},
+    result::{Error, Result},
+};
+use chrono::NaiveDateTime;
+use diesel::SqliteConnection;
+
+pub const PACKED_READING_LEN: usize = 14;
+
+#[derive(Deserialize)]
+struct TtsDeviceIds {
+    dev_eui: String,
+}
+
+#[derive(Deserialize)]
+struct TtsUplinkMessage {
+    frm_payload: String,
+}
+
+#[derive(Deserialize)]
+struct TtsUplink {
+    end_device_ids: TtsDeviceIds,
+    uplink_message: TtsUplinkMessage,
+}
+
+#[derive(Deserialize)]
+struct ChirpstackDeviceInfo {
+    #[serde(rename = "devEui")]
+    dev_eui: String,
+}
+
+#[derive(Deserialize)]
+struct ChirpstackV4Uplink {
+    #[serde(rename = "deviceInfo")]
+    device_info: ChirpstackDeviceInfo,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct ChirpstackV3Uplink {
+    #[serde(rename = "devEUI")]
+    dev_eui: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Uplink {
+    Tts(TtsUplink),
+    ChirpstackV4(ChirpstackV4Uplink),
+    ChirpstackV3(ChirpstackV3Uplink),
+}
+
+impl Uplink {
+    fn dev_eui(&self) -> &str {
+        match self {
+            Uplink::Tts(u) => &u.end_device_ids.dev_eui,
+            Uplink::ChirpstackV4(u) => &u.device_info.dev_eui,
+            Uplink::ChirpstackV3(u) => &u.dev_eui,
+        }
+    }
+
+    fn payload(&self) -> &str {
+        match self {
+            Uplink::Tts(u) => &u.uplink_message.frm_payload,
+            Uplink::ChirpstackV4(u) => &u.data,
+            Uplink::ChirpstackV3(u) => &u.data,
+        }
+    }
+}
+
+/// Converts a DevEUI into a hardware id. DevEUIs are usually hex, but the
+/// ChirpStack v3 JSON marshaler sends them base64-encoded.
+pub fn hardware_id_from_dev_eui(dev_eui: &str) -> Result<i64> {
+    let bytes = if dev_eui.len() == 16 {
+        u64::from_str_radix(dev_eui, 16)
+            .map(|id| id.to_be_bytes().to_vec())
+            .map_err(|_| Error::MalformedUplink(format!("invalid DevEUI '{}'", dev_eui)))?
+    } else {
+        base64::decode(dev_eui)
+            .map_err(|_| Error::MalformedUplink(format!("invalid DevEUI '{}'", dev_eui)))?
+    };
+    if bytes.len() != 8 {
+        return Err(Error::MalformedUplink(format!(
+            "invalid DevEUI '{}'",
+            dev_eui
+        )));
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&bytes);
+    Ok(i64::from_be_bytes(id))
+}
+
+fn be_u16(b: &[u8]) -> f32 {
+    f32::from(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn be_i16(b: &[u8]) -> f32 {
+    f32::from(i16::from_be_bytes([b[0], b[1]]))
+}
+
+pub fn decode_readings(bytes: &[u8], sensor_id: i32) -> Result<Vec<ReadingInsert>> {
+    if bytes.is_empty() || bytes.len() % PACKED_READING_LEN != 0 {
+        return Err(Error::MalformedUplink(format!(
+            "payload length {} is not a multiple of {}",
+            bytes.len(),
+            PACKED_READING_LEN
+        )));
+    }
+    let readings = bytes
+        .chunks(PACKED_READING_LEN)
+        .map(|c| {
+            let ts = u32::from_be_bytes([c[0], c[1], c[2], c[3]]);
+            ReadingInsert {
+                sensor_id,
+                timestamp: NaiveDateTime::from_timestamp(i64::from(ts), 0),
+                peak_power_mW: be_u16(&c[4..6]) / 10.0,
+                peak_current_mA: be_u16(&c[6..8]) / 10.0,
+                peak_voltage_V: be_u16(&c[8..10]) / 1000.0,
+                temp_celsius: be_i16(&c[10..12]) / 100.0,
+                batt_V: be_u16(&c[12..14]) / 1000.0,
+                created: None,
+            }
+        })
+        .collect();
+    Ok(readings)
+}
+
+/// Stores the readings in an uplink. The uplink's device must be an active
+/// sensor owned by the same user as the integration.
+pub fn handle_uplink(
+    integration: &Integration,
+    body: serde_json::Value,
+    conn: &SqliteConnection,
+) -> Result<usize> {
+    let uplink: Uplink =
+        serde_json::from_value(body).map_err(|e| Error::MalformedUplink(e.to_string()))?;
+    let hardware_id = hardware_id_from_dev_eui(uplink.dev_eui())?;
+    let sensor = Sensor::find_by_hardware_id(hardware_id, conn)
+        .map_err(|_| Error::UnknownDevEui(uplink.dev_eui().to_string()))?;
+    if sensor.owner_id != integration.owner_id {
+        return Err(Error::NotSensorOwner);
+    }
+
+    let bytes = base64::decode(uplink.payload())
+        .map_err(|_| Error::MalformedUplink("payload is not base64".into()))?;
+    let readings = decode_readings(&bytes, sensor.id)?;
+    Reading::insert_many(&readings, conn)
+}
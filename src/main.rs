@@ -1,3 +1,14 @@
 fn main() {
-    sol::run_server()
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let sensor_id =
+        |arg: Option<&String>| arg.map(|id| id.parse::<i32>().expect("sensor id must be a number"));
+    match args.first().map(|a| a.as_str()) {
+        Some("rebuild-rollups") => sol::rebuild_rollups(sensor_id(args.get(1))),
+        Some("check-rollups") => match sensor_id(args.get(1)) {
+            Some(id) => sol::check_rollups(id),
+            None => eprintln!("usage: sol check-rollups <sensor id>"),
+        },
+        Some("apply-retention") => sol::apply_retention(args.iter().any(|a| a == "--dry-run")),
+        _ => sol::run_server(),
+    }
 }
@@ -0,0 +1,373 @@
+//! Serves Prometheus metrics at `/metrics`.
+//!
+//! Requests are counted and timed per route by the `RequestMetrics` fairing.
+//! Ingestion and email code count what they do in `METRICS`, which lives for
+//! the whole process, so jobs and other threads outside of Rocket are counted
+//! too. Sensor and database pool numbers are read when the metrics are
+//! scraped.
+//!
+//! Metrics are only served to requests with `SOL_METRICS_TOKEN` as a bearer
+//! token, and to nobody when it isn't set.
+use crate::{
+    db::{self, SolDbConn, SolDbConnPool},
+    result::{Error, Result},
+    util::token::same,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{Nullable, Timestamp},
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome},
+    response::content::Content,
+    Data, Request, Response, State,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+/// Upper bounds of the request latency buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds of the sensor last seen age buckets, in seconds: a minute,
+/// five minutes, a quarter of an hour, an hour, six hours, a day and a week.
+const LAST_SEEN_BUCKETS: &[f64] = &[60.0, 300.0, 900.0, 3600.0, 21600.0, 86400.0, 604800.0];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations at or under each bound, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|&b| value <= b) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests by route and status.
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    latencies: Mutex<BTreeMap<String, Histogram>>,
+    readings_ingested: AtomicUsize,
+    /// Rejected readings by reason.
+    readings_rejected: Mutex<BTreeMap<&'static str, u64>>,
+    email_failures: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn request(&self, route: &str, status: u16, seconds: f64) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), status))
+            .or_insert(0) += 1;
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(seconds);
+    }
+
+    pub fn readings_ingested(&self, n: usize) {
+        self.readings_ingested.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Counts readings that weren't stored, for a reason like `invalid`.
+    pub fn readings_rejected(&self, reason: &'static str, n: usize) {
+        if n > 0 {
+            *self
+                .readings_rejected
+                .lock()
+                .unwrap()
+                .entry(reason)
+                .or_insert(0) += n as u64;
+        }
+    }
+
+    pub fn email_failed(&self) {
+        self.email_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Escapes a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[derive(QueryableByName)]
+struct LastSeen {
+    #[sql_type = "Nullable<Timestamp>"]
+    last_seen: Option<NaiveDateTime>,
+}
+
+/// When the server last received a reading from each active sensor, if it
+/// ever has, the same as sensor health's `last_seen`. Readings sent late
+/// still count as the sensor being seen, and imported ones are dated by
+/// their timestamps, so they don't count as it being seen now.
+fn last_seen(conn: &SqliteConnection) -> Result<Vec<Option<NaiveDateTime>>> {
+    let rows: Vec<LastSeen> = sql_query(
+        "select (select max(r.created) from readings r where r.sensor_id = s.id) as last_seen
+         from sensors s where s.active",
+    )
+    .load(conn)?;
+    Ok(rows.into_iter().map(|r| r.last_seen).collect())
+}
+
+/// Every metric, in the Prometheus text format.
+pub fn render(
+    metrics: &Metrics,
+    pool: &db::PoolUsage,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<String> {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "sol_http_requests_total",
+        "counter",
+        "HTTP requests by route and status.",
+    );
+    for ((route, status), n) in metrics.requests.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "sol_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+            label(route),
+            status,
+            n
+        );
+    }
+    header(
+        &mut out,
+        "sol_http_request_duration_seconds",
+        "histogram",
+        "HTTP request latency by route.",
+    );
+    for (route, histogram) in metrics.latencies.lock().unwrap().iter() {
+        histogram.write(
+            &mut out,
+            "sol_http_request_duration_seconds",
+            &format!("route=\"{}\"", label(route)),
+        );
+    }
+
+    header(
+        &mut out,
+        "sol_readings_ingested_total",
+        "counter",
+        "Readings stored, from every ingestion path.",
+    );
+    let _ = writeln!(
+        out,
+        "sol_readings_ingested_total {}",
+        metrics.readings_ingested.load(Ordering::Relaxed)
+    );
+    header(
+        &mut out,
+        "sol_readings_rejected_total",
+        "counter",
+        "Readings that weren't stored, by reason.",
+    );
+    for (reason, n) in metrics.readings_rejected.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "sol_readings_rejected_total{{reason=\"{}\"}} {}",
+            reason, n
+        );
+    }
+
+    let seen = last_seen(conn)?;
+    header(&mut out, "sol_sensors_active", "gauge", "Active sensors.");
+    let _ = writeln!(out, "sol_sensors_active {}", seen.len());
+    header(
+        &mut out,
+        "sol_sensor_last_seen_age_seconds",
+        "histogram",
+        "Time since the server last received a reading from each active sensor that has sent one.",
+    );
+    let mut ages = Histogram::new(LAST_SEEN_BUCKETS);
+    for seen in seen.into_iter().flatten() {
+        ages.observe((now - seen).num_seconds().max(0) as f64);
+    }
+    ages.write(&mut out, "sol_sensor_last_seen_age_seconds", "");
+
+    header(
+        &mut out,
+        "sol_db_pool_connections",
+        "gauge",
+        "Open connections of the request database pool, by state.",
+    );
+    let _ = writeln!(
+        out,
+        "sol_db_pool_connections{{state=\"idle\"}} {}",
+        pool.idle
+    );
+    let _ = writeln!(
+        out,
+        "sol_db_pool_connections{{state=\"in_use\"}} {}",
+        pool.connections - pool.idle
+    );
+    header(
+        &mut out,
+        "sol_db_pool_max_connections",
+        "gauge",
+        "Connections the request database pool may open.",
+    );
+    let _ = writeln!(out, "sol_db_pool_max_connections {}", pool.max);
+
+    header(
+        &mut out,
+        "sol_email_send_failures_total",
+        "counter",
+        "Emails that failed to send.",
+    );
+    let _ = writeln!(
+        out,
+        "sol_email_send_failures_total {}",
+        metrics.email_failures.load(Ordering::Relaxed)
+    );
+    Ok(out)
+}
+
+/// When a request started, in its local cache.
+struct Started(Instant);
+
+/// Counts and times every request by its route.
+pub struct RequestMetrics;
+
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, req: &mut Request, _: &Data) {
+        req.local_cache(|| Started(Instant::now()));
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        let started = req.local_cache(|| Started(Instant::now()));
+        // Routes rather than paths, so there's a series per route and not one
+        // per sensor.
+        let route = req.route().map_or("unmatched".to_string(), |r| {
+            format!("{} {}", r.method, r.uri.path())
+        });
+        let elapsed = started.0.elapsed();
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        METRICS.request(&route, res.status().code, seconds);
+    }
+}
+
+/// A request that may see the metrics.
+pub struct MetricsAccess;
+
+impl<'a, 'r> FromRequest<'a, 'r> for MetricsAccess {
+    type Error = Error;
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let token = std::env::var("SOL_METRICS_TOKEN").unwrap_or_default();
+        let given = req.headers().get_one("Authorization").and_then(|h| {
+            let mut words = h.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(scheme), Some(given), None) if scheme.eq_ignore_ascii_case("bearer") => {
+                    Some(given)
+                }
+                _ => None,
+            }
+        });
+        let allowed = !token.is_empty() && given.map_or(false, |given| same(given, &token));
+        if allowed {
+            Outcome::Success(MetricsAccess)
+        } else {
+            Outcome::Failure((Status::Forbidden, Error::MetricsNotAllowed))
+        }
+    }
+}
+
+#[get("/metrics")]
+pub fn metrics(
+    _access: MetricsAccess,
+    pool: State<SolDbConnPool>,
+    conn: SolDbConn,
+) -> Result<Content<String>> {
+    let body = render(
+        &METRICS,
+        &db::pool_usage(&pool),
+        Utc::now().naive_utc(),
+        &conn,
+    )?;
+    Ok(Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        body,
+    ))
+}
@@ -0,0 +1,418 @@
+//! Time-bucketed aggregates of a sensor's readings, for every channel.
+//!
+//! Aggregates are computed in SQL from the hourly or daily rollups when the
+//! buckets line up with them, and from the `reading_channel_values` view
+//! otherwise. Fixed-size intervals are bucketed by rounding unix timestamps. Days, weeks
+//! and months are bucketed on local calendar boundaries in a timezone, which
+//! are computed here and joined against in a temporary table.
+use crate::{
+    models::{retention, rollup},
+    result::{Error, Result},
+};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Float, Integer, Text, Timestamp},
+};
+use std::collections::BTreeMap;
+
+/// The most buckets a single aggregate query may cover.
+pub const MAX_BUCKETS: i64 = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interval {
+    Minutes(i64),
+    Hours(i64),
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    pub fn from_string(s: &str) -> Result<Interval> {
+        match s {
+            "1m" => Ok(Interval::Minutes(1)),
+            "5m" => Ok(Interval::Minutes(5)),
+            "15m" => Ok(Interval::Minutes(15)),
+            "30m" => Ok(Interval::Minutes(30)),
+            "1h" => Ok(Interval::Hours(1)),
+            "6h" => Ok(Interval::Hours(6)),
+            "12h" => Ok(Interval::Hours(12)),
+            "1d" => Ok(Interval::Day),
+            "1w" => Ok(Interval::Week),
+            "1mo" => Ok(Interval::Month),
+            _ => Err(Error::InvalidInterval(s.to_string())),
+        }
+    }
+
+    pub fn get_string(&self) -> String {
+        match self {
+            Interval::Minutes(m) => format!("{}m", m),
+            Interval::Hours(h) => format!("{}h", h),
+            Interval::Day => "1d".into(),
+            Interval::Week => "1w".into(),
+            Interval::Month => "1mo".into(),
+        }
+    }
+
+    fn seconds(&self) -> Option<i64> {
+        match self {
+            Interval::Minutes(m) => Some(m * 60),
+            Interval::Hours(h) => Some(h * 60 * 60),
+            _ => None,
+        }
+    }
+
+    /// The first local date of the calendar bucket that `date` falls in.
+    fn bucket_date(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Interval::Week => {
+                date - Duration::days(i64::from(date.weekday().num_days_from_monday()))
+            }
+            Interval::Month => NaiveDate::from_ymd(date.year(), date.month(), 1),
+            _ => date,
+        }
+    }
+
+    /// About how many calendar buckets cover `start..end`, so huge ranges are
+    /// turned away before their buckets are built.
+    fn estimate_buckets(&self, start: NaiveDateTime, end: NaiveDateTime) -> i64 {
+        let days = match self {
+            Interval::Week => 7,
+            Interval::Month => 30,
+            _ => 1,
+        };
+        (end - start).num_days() / days
+    }
+
+    fn next_date(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Interval::Week => date + Duration::days(7),
+            Interval::Month if date.month() == 12 => NaiveDate::from_ymd(date.year() + 1, 1, 1),
+            Interval::Month => NaiveDate::from_ymd(date.year(), date.month() + 1, 1),
+            _ => date + Duration::days(1),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct ChannelStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub last: f32,
+    pub count: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Bucket {
+    pub start: NaiveDateTime,
+    pub channels: BTreeMap<String, ChannelStats>,
+}
+
+#[derive(QueryableByName)]
+struct AggregateRow {
+    #[sql_type = "BigInt"]
+    bucket: i64,
+    #[sql_type = "Text"]
+    channel: String,
+    #[sql_type = "Float"]
+    min: f32,
+    #[sql_type = "Float"]
+    max: f32,
+    #[sql_type = "Float"]
+    mean: f32,
+    #[sql_type = "Float"]
+    last: f32,
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Where aggregates are computed from. Rollups are used when the range and
+/// every bucket boundary fall on their hours or days, and raw readings
+/// otherwise.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Source {
+    Readings,
+    HourlyRollups,
+    DailyRollups,
+}
+
+impl Source {
+    fn choose<I>(start: NaiveDateTime, end: NaiveDateTime, boundaries: I) -> Source
+    where
+        I: IntoIterator<Item = NaiveDateTime>,
+    {
+        let mut hourly = true;
+        let mut daily = true;
+        for ts in [start, end].iter().cloned().chain(boundaries) {
+            hourly = hourly && ts == rollup::hour_start(ts);
+            daily = daily && ts == rollup::day_start(ts);
+        }
+        if daily {
+            Source::DailyRollups
+        } else if hourly {
+            Source::HourlyRollups
+        } else {
+            Source::Readings
+        }
+    }
+
+    /// Raw readings before `raw_before` have been deleted, so ranges that start
+    /// before then are read from the hourly rollups even if they don't line
+    /// up with them.
+    fn retained(self, start: NaiveDateTime, raw_before: Option<NaiveDateTime>) -> Source {
+        match raw_before {
+            Some(raw_before) if self == Source::Readings && start < raw_before => {
+                Source::HourlyRollups
+            }
+            _ => self,
+        }
+    }
+
+    /// A query with `sensor_id`, `ts`, `channel`, `count`, `min`, `max`, `sum`
+    /// and `last` columns.
+    fn sql(&self) -> &'static str {
+        match self {
+            Source::Readings => "select sensor_id, timestamp as ts, channel, 1 as count, value as min, value as max, value as sum, value as last from reading_channel_values",
+            Source::HourlyRollups => "select sensor_id, bucket as ts, channel, count, min, max, sum, last from hourly_rollups",
+            Source::DailyRollups => "select sensor_id, bucket as ts, channel, count, min, max, sum, last from daily_rollups",
+        }
+    }
+}
+
+/// Aggregates `src`, which must have a `bucket` column as well as the columns
+/// of a `Source`.
+const AGGREGATE_SQL: &str = "
+ ranked as (select *, row_number() over (partition by bucket, channel order by ts desc) as rn from src)
+select
+ bucket,
+ channel,
+ min(min) as min,
+ max(max) as max,
+ sum(sum) / sum(count) as mean,
+ max(case when rn = 1 then last end) as last,
+ sum(count) as count
+from ranked
+group by bucket, channel
+order by bucket, channel;
+";
+
+/// The UTC instant of local midnight on `date`. If midnight is skipped by a
+/// DST change, the day starts at the first instant after the gap.
+pub fn local_midnight(tz: Tz, date: NaiveDate) -> NaiveDateTime {
+    let mut local = date.and_hms(0, 0, 0);
+    loop {
+        if let Some(dt) = tz.from_local_datetime(&local).earliest() {
+            return dt.naive_utc();
+        }
+        local += Duration::minutes(15);
+    }
+}
+
+/// Part of a local calendar day with a single UTC offset. A day has two
+/// segments when a DST transition falls inside it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DaySegment {
+    pub date: NaiveDate,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    /// Seconds to add to UTC to get local time.
+    pub utc_offset: i32,
+}
+
+fn utc_offset(tz: Tz, ts: NaiveDateTime) -> i32 {
+    tz.offset_from_utc_datetime(&ts).fix().local_minus_utc()
+}
+
+/// The local calendar days `start..=end` in UTC, split at DST transitions.
+pub fn local_days(tz: Tz, start: NaiveDate, end: NaiveDate) -> Vec<DaySegment> {
+    let mut res = Vec::new();
+    let mut date = start;
+    while date <= end {
+        let day_start = local_midnight(tz, date);
+        let day_end = local_midnight(tz, date.succ());
+        let offset = utc_offset(tz, day_start);
+        // Transitions happen on a quarter hour everywhere.
+        let mut ts = day_start;
+        while ts < day_end && utc_offset(tz, ts) == offset {
+            ts += Duration::minutes(15);
+        }
+        res.push(DaySegment {
+            date,
+            start: day_start,
+            end: ts.min(day_end),
+            utc_offset: offset,
+        });
+        if ts < day_end {
+            res.push(DaySegment {
+                date,
+                start: ts,
+                end: day_end,
+                utc_offset: utc_offset(tz, ts),
+            });
+        }
+        date = date.succ();
+    }
+    res
+}
+
+/// Local calendar buckets covering `start..end`, as `(start, end)` pairs in
+/// UTC.
+pub fn calendar_buckets(
+    interval: Interval,
+    tz: Tz,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let mut date = interval.bucket_date(tz.from_utc_datetime(&start).date().naive_local());
+    let mut buckets = Vec::new();
+    loop {
+        let bucket_start = local_midnight(tz, date);
+        if bucket_start >= end {
+            break;
+        }
+        date = interval.next_date(date);
+        buckets.push((bucket_start, local_midnight(tz, date)));
+    }
+    buckets
+}
+
+fn fixed_rows(
+    sensor_id: i32,
+    seconds: i64,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    raw_before: Option<NaiveDateTime>,
+    conn: &SqliteConnection,
+) -> Result<Vec<AggregateRow>> {
+    let source = match Source::choose(start, end, None) {
+        Source::HourlyRollups | Source::DailyRollups if seconds % (60 * 60) == 0 => {
+            Source::HourlyRollups
+        }
+        _ => Source::Readings,
+    }
+    .retained(start, raw_before);
+    let query = format!(
+        "
+with
+ src as (
+  select (cast(strftime('%s', s.ts) as integer) / ?1) * ?1 as bucket, s.*
+  from ({}) s
+  where s.sensor_id = ?2 and s.ts >= ?3 and s.ts < ?4
+ ),
+{}",
+        source.sql(),
+        AGGREGATE_SQL
+    );
+    let rows = sql_query(query)
+        .bind::<BigInt, _>(seconds)
+        .bind::<Integer, _>(sensor_id)
+        .bind::<Timestamp, _>(start)
+        .bind::<Timestamp, _>(end)
+        .load(conn)?;
+    Ok(rows)
+}
+
+fn calendar_rows(
+    sensor_id: i32,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    buckets: &[(NaiveDateTime, NaiveDateTime)],
+    raw_before: Option<NaiveDateTime>,
+    conn: &SqliteConnection,
+) -> Result<Vec<AggregateRow>> {
+    let source = Source::choose(start, end, buckets.iter().map(|&(b_start, _)| b_start))
+        .retained(start, raw_before);
+    conn.transaction(|| {
+        sql_query(
+            "create temp table if not exists agg_buckets (bucket integer primary key, b_start datetime not null, b_end datetime not null)",
+        )
+        .execute(conn)?;
+        sql_query("delete from temp.agg_buckets").execute(conn)?;
+        for &(b_start, b_end) in buckets {
+            sql_query("insert into temp.agg_buckets values (?, ?, ?)")
+                .bind::<BigInt, _>(b_start.timestamp())
+                .bind::<Timestamp, _>(b_start)
+                .bind::<Timestamp, _>(b_end)
+                .execute(conn)?;
+        }
+        let query = format!(
+            "
+with
+ src as (
+  select k.bucket, s.*
+  from temp.agg_buckets k
+  join ({}) s on s.ts >= k.b_start and s.ts < k.b_end
+  where s.sensor_id = ?1 and s.ts >= ?2 and s.ts < ?3
+ ),
+{}",
+            source.sql(),
+            AGGREGATE_SQL
+        );
+        let rows = sql_query(query)
+            .bind::<Integer, _>(sensor_id)
+            .bind::<Timestamp, _>(start)
+            .bind::<Timestamp, _>(end)
+            .load(conn)?;
+        Ok(rows)
+    })
+}
+
+/// Aggregates a sensor's readings in `start..end` into buckets. Buckets with
+/// no readings are left out, and the first and last buckets only include
+/// readings inside the range.
+pub fn aggregate(
+    sensor_id: i32,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    interval: Interval,
+    tz: Tz,
+    conn: &SqliteConnection,
+) -> Result<Vec<Bucket>> {
+    let raw_before = retention::raw_before(sensor_id, conn)?;
+    let rows = match interval.seconds() {
+        Some(seconds) => {
+            let n = (end - start).num_seconds() / seconds;
+            if n > MAX_BUCKETS {
+                return Err(Error::TooManyBuckets(n));
+            }
+            fixed_rows(sensor_id, seconds, start, end, raw_before, conn)?
+        }
+        None => {
+            let n = interval.estimate_buckets(start, end);
+            if n > MAX_BUCKETS {
+                return Err(Error::TooManyBuckets(n));
+            }
+            let buckets = calendar_buckets(interval, tz, start, end);
+            if buckets.len() as i64 > MAX_BUCKETS {
+                return Err(Error::TooManyBuckets(buckets.len() as i64));
+            }
+            calendar_rows(sensor_id, start, end, &buckets, raw_before, conn)?
+        }
+    };
+
+    let mut res: Vec<Bucket> = Vec::new();
+    for row in rows {
+        let stats = ChannelStats {
+            min: row.min,
+            max: row.max,
+            mean: row.mean,
+            last: row.last,
+            count: row.count,
+        };
+        let start = NaiveDateTime::from_timestamp(row.bucket, 0);
+        if res.last().map(|b| b.start) != Some(start) {
+            res.push(Bucket {
+                start,
+                channels: BTreeMap::new(),
+            });
+        }
+        if let Some(b) = res.last_mut() {
+            b.channels.insert(row.channel, stats);
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,353 @@
+//! Alert rules that owners set on their sensors, and the incidents that are
+//! opened while a rule's condition holds and resolved once it stops holding.
+
+use crate::{
+    models::{channel, Sensor, SensorQuery},
+    result::{Error, Result},
+    schema::{alert_incidents, alert_rules, hourly_rollups, readings},
+};
+use chrono::{Duration, NaiveDateTime, TimeZone};
+use diesel::{dsl::max, insert_into, prelude::*, update};
+
+/// Days of energy that the daily energy rule compares the previous day with.
+pub const AVERAGE_DAYS: i64 = 7;
+
+/// How many of a sensor's latest incidents are shown.
+pub const HISTORY_LIMIT: i64 = 100;
+
+/// Longest a rule can be muted for, a year.
+pub const MAX_MUTE_HOURS: f64 = 365.0 * 24.0;
+
+/// What a rule checks for. Thresholds are stored in one column, whose meaning
+/// depends on the kind of rule.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Condition {
+    /// Nothing received from the sensor for this many hours.
+    NoReadings { hours: f32 },
+    /// The latest value of a channel is below a threshold.
+    Below { channel: String, value: f32 },
+    /// The latest value of a channel is above a threshold.
+    Above { channel: String, value: f32 },
+    /// The previous local day's energy is below this fraction of the average
+    /// of the `AVERAGE_DAYS` before it.
+    DailyEnergyBelowAverage { fraction: f32 },
+}
+
+#[derive(Insertable, Deserialize, Debug)]
+#[table_name = "alert_rules"]
+pub struct AlertRuleInsert {
+    #[serde(skip)]
+    pub sensor_id: i32,
+    /// One of `no_readings`, `below`, `above` or `daily_energy_below_average`.
+    pub kind: String,
+    #[serde(default)]
+    pub channel: Option<String>,
+    pub threshold: f32,
+}
+
+#[derive(Queryable, Serialize, Clone, Debug)]
+pub struct AlertRule {
+    pub id: i32,
+    pub sensor_id: i32,
+    pub kind: String,
+    pub channel: Option<String>,
+    pub threshold: f32,
+    /// Incidents are still opened while a rule is muted, but not emailed.
+    pub muted_until: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+}
+
+fn condition(kind: &str, channel: Option<&String>, threshold: f32) -> Result<Condition> {
+    let value = threshold;
+    let channel = || {
+        channel
+            .cloned()
+            .ok_or_else(|| Error::InvalidAlertRule(format!("{} rules need a channel", kind)))
+    };
+    match kind {
+        "no_readings" => Ok(Condition::NoReadings { hours: value }),
+        "below" => Ok(Condition::Below {
+            channel: channel()?,
+            value,
+        }),
+        "above" => Ok(Condition::Above {
+            channel: channel()?,
+            value,
+        }),
+        "daily_energy_below_average" => Ok(Condition::DailyEnergyBelowAverage { fraction: value }),
+        _ => Err(Error::InvalidAlertRule(format!("unknown kind '{}'", kind))),
+    }
+}
+
+impl AlertRule {
+    pub fn condition(&self) -> Result<Condition> {
+        condition(&self.kind, self.channel.as_ref(), self.threshold)
+    }
+
+    pub fn is_muted(&self, now: NaiveDateTime) -> bool {
+        self.muted_until.map_or(false, |until| until > now)
+    }
+
+    pub fn described(self) -> DescribedRule {
+        DescribedRule {
+            description: self.describe(),
+            rule: self,
+        }
+    }
+
+    /// The rule in words, like "batt_V below 3.4".
+    pub fn describe(&self) -> String {
+        match self.condition() {
+            Ok(Condition::NoReadings { hours }) => format!("no readings for {} hours", hours),
+            Ok(Condition::Below { channel, value }) => format!("{} below {}", channel, value),
+            Ok(Condition::Above { channel, value }) => format!("{} above {}", channel, value),
+            Ok(Condition::DailyEnergyBelowAverage { fraction }) => format!(
+                "daily kWh below {}% of the {}-day average",
+                fraction * 100.0,
+                AVERAGE_DAYS
+            ),
+            Err(_) => self.kind.clone(),
+        }
+    }
+}
+
+/// A rule along with its description, for showing to owners.
+#[derive(Serialize, Debug)]
+pub struct DescribedRule {
+    #[serde(flatten)]
+    pub rule: AlertRule,
+    pub description: String,
+}
+
+#[derive(Queryable, Serialize, Clone, Debug)]
+pub struct Incident {
+    pub id: i32,
+    pub rule_id: i32,
+    pub opened: NaiveDateTime,
+    pub resolved: Option<NaiveDateTime>,
+    pub acknowledged: Option<NaiveDateTime>,
+    pub message: String,
+}
+
+/// Checks that a rule makes sense for a sensor before it's created.
+fn validate(rule: &AlertRuleInsert, sensor: &SensorQuery, conn: &SqliteConnection) -> Result<()> {
+    let positive = |name: &str| {
+        if rule.threshold > 0.0 && rule.threshold.is_finite() {
+            Ok(())
+        } else {
+            Err(Error::InvalidAlertRule(format!(
+                "{} must be positive",
+                name
+            )))
+        }
+    };
+    match condition(&rule.kind, rule.channel.as_ref(), rule.threshold)? {
+        Condition::NoReadings { .. } => positive("hours"),
+        Condition::DailyEnergyBelowAverage { .. } => positive("fraction"),
+        Condition::Below { channel, .. } | Condition::Above { channel, .. } => {
+            if !rule.threshold.is_finite() {
+                return Err(Error::InvalidAlertRule("threshold must be a number".into()));
+            }
+            let channels = channel::for_sensor(sensor, conn)?;
+            if channels.iter().any(|c| c.name == channel) {
+                Ok(())
+            } else {
+                Err(Error::UnknownChannel(channel))
+            }
+        }
+    }
+}
+
+pub fn create(rule: &AlertRuleInsert, conn: &SqliteConnection) -> Result<()> {
+    let sensor = Sensor::find(rule.sensor_id, conn)?;
+    validate(rule, &sensor, conn)?;
+    insert_into(alert_rules::table).values(rule).execute(conn)?;
+    Ok(())
+}
+
+pub fn all_rules(conn: &SqliteConnection) -> Result<Vec<AlertRule>> {
+    alert_rules::table
+        .order(alert_rules::id.asc())
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+pub fn rules_for_sensor(sensor_id: i32, conn: &SqliteConnection) -> Result<Vec<AlertRule>> {
+    alert_rules::table
+        .filter(alert_rules::sensor_id.eq(sensor_id))
+        .order(alert_rules::id.asc())
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+pub fn find_rule(id: i32, conn: &SqliteConnection) -> Result<AlertRule> {
+    alert_rules::table
+        .find(id)
+        .first(conn)
+        .map_err(|e| e.into())
+}
+
+/// The sensor a rule is on, for checking who owns it.
+pub fn rule_sensor(rule_id: i32, conn: &SqliteConnection) -> Result<SensorQuery> {
+    Sensor::find(find_rule(rule_id, conn)?.sensor_id, conn)
+}
+
+/// When a rule muted at `now` for `hours` is muted until.
+pub fn mute_until(now: NaiveDateTime, hours: f64) -> Result<NaiveDateTime> {
+    if !(hours.is_finite() && hours > 0.0 && hours <= MAX_MUTE_HOURS) {
+        return Err(Error::InvalidAlertRule(format!(
+            "mute hours must be more than 0 and at most {}",
+            MAX_MUTE_HOURS
+        )));
+    }
+    Ok(now + Duration::seconds((hours * 3600.0).round() as i64))
+}
+
+/// Stops emailing a rule's incidents until `until`, or starts again with
+/// `None`.
+pub fn mute(rule_id: i32, until: Option<NaiveDateTime>, conn: &SqliteConnection) -> Result<()> {
+    update(alert_rules::table.find(rule_id))
+        .set(alert_rules::muted_until.eq(until))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// A sensor's incidents, newest first.
+pub fn incidents_for_sensor(
+    sensor_id: i32,
+    limit: i64,
+    conn: &SqliteConnection,
+) -> Result<Vec<Incident>> {
+    alert_incidents::table
+        .inner_join(alert_rules::table)
+        .select(alert_incidents::all_columns)
+        .filter(alert_rules::sensor_id.eq(sensor_id))
+        .order(alert_incidents::id.desc())
+        .limit(limit)
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+pub fn find_incident(id: i32, conn: &SqliteConnection) -> Result<Incident> {
+    alert_incidents::table
+        .find(id)
+        .first(conn)
+        .map_err(|e| e.into())
+}
+
+pub fn acknowledge(incident_id: i32, now: NaiveDateTime, conn: &SqliteConnection) -> Result<()> {
+    update(alert_incidents::table.find(incident_id))
+        .filter(alert_incidents::acknowledged.is_null())
+        .set(alert_incidents::acknowledged.eq(now))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn open_incident(rule_id: i32, conn: &SqliteConnection) -> Result<Option<Incident>> {
+    alert_incidents::table
+        .filter(alert_incidents::rule_id.eq(rule_id))
+        .filter(alert_incidents::resolved.is_null())
+        .first(conn)
+        .optional()
+        .map_err(|e| e.into())
+}
+
+pub fn open(
+    rule_id: i32,
+    message: &str,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    insert_into(alert_incidents::table)
+        .values((
+            alert_incidents::rule_id.eq(rule_id),
+            alert_incidents::opened.eq(now),
+            alert_incidents::message.eq(message),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn resolve(incident_id: i32, now: NaiveDateTime, conn: &SqliteConnection) -> Result<()> {
+    update(alert_incidents::table.find(incident_id))
+        .set(alert_incidents::resolved.eq(now))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The latest hourly value of a sensor's channel.
+fn latest_value(sensor_id: i32, channel: &str, conn: &SqliteConnection) -> Result<Option<f32>> {
+    hourly_rollups::table
+        .select(hourly_rollups::last)
+        .filter(hourly_rollups::sensor_id.eq(sensor_id))
+        .filter(hourly_rollups::channel.eq(channel))
+        .order(hourly_rollups::bucket.desc())
+        .first(conn)
+        .optional()
+        .map_err(|e| e.into())
+}
+
+/// Why a rule's condition holds at `now`, or `None` if it doesn't.
+pub fn evaluate(
+    rule: &AlertRule,
+    sensor: &SensorQuery,
+    now: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<Option<String>> {
+    let res = match rule.condition()? {
+        Condition::NoReadings { hours } => {
+            let last_seen: Option<NaiveDateTime> = readings::table
+                .select(max(readings::created))
+                .filter(readings::sensor_id.eq(sensor.id))
+                .first(conn)?;
+            let since = last_seen.unwrap_or(rule.created);
+            if (now - since).num_seconds() as f64 > f64::from(hours) * 3600.0 {
+                Some(match last_seen {
+                    Some(seen) => format!("no readings since {}", seen),
+                    None => "no readings yet".to_string(),
+                })
+            } else {
+                None
+            }
+        }
+        Condition::Below { channel, value } => latest_value(sensor.id, &channel, conn)?
+            .filter(|&v| v < value)
+            .map(|v| format!("{} is {}, below {}", channel, v, value)),
+        Condition::Above { channel, value } => latest_value(sensor.id, &channel, conn)?
+            .filter(|&v| v > value)
+            .map(|v| format!("{} is {}, above {}", channel, v, value)),
+        Condition::DailyEnergyBelowAverage { fraction } => {
+            let today = sensor.tz().from_utc_datetime(&now).date().naive_local();
+            let day = today.pred();
+            let stats =
+                Sensor::energy_stats(sensor, day - Duration::days(AVERAGE_DAYS), day, conn)?;
+            let before: Vec<f64> = stats
+                .iter()
+                .filter(|s| s.date < day)
+                .map(|s| f64::from(s.equiv_kWh))
+                .collect();
+            let kwh = stats
+                .iter()
+                .find(|s| s.date == day)
+                .map_or(0.0, |s| f64::from(s.equiv_kWh));
+            if before.is_empty() {
+                None
+            } else {
+                let average = before.iter().sum::<f64>() / before.len() as f64;
+                if kwh < f64::from(fraction) * average {
+                    Some(format!(
+                        "{} made {:.2} kWh, below {}% of the {}-day average of {:.2} kWh",
+                        day,
+                        kwh,
+                        fraction * 100.0,
+                        AVERAGE_DAYS,
+                        average
+                    ))
+                } else {
+                    None
+                }
+            }
+        }
+    };
+    Ok(res)
+}
@@ -0,0 +1,89 @@
+//! The audit log of security-relevant actions: logins, tokens, passwords,
+//! changes to sensors and what superusers do.
+
+use crate::{
+    logging::RequestInfo,
+    result::Result,
+    schema::{audit_log, users},
+};
+use chrono::NaiveDateTime;
+use diesel::{insert_into, prelude::*};
+
+pub const LOGIN: &str = "login";
+pub const LOGIN_FAILED: &str = "login.failed";
+pub const TOKEN_CREATED: &str = "token.created";
+pub const PASSWORD_CHANGED: &str = "password.changed";
+pub const PASSWORD_RESET_REQUESTED: &str = "password.reset_requested";
+pub const SENSOR_EDITED: &str = "sensor.edited";
+pub const SENSOR_DEACTIVATED: &str = "sensor.deactivated";
+pub const SENSOR_TRANSFERRED: &str = "sensor.transferred";
+/// Superusers acting on something that isn't theirs.
+pub const ADMIN_USER_EDITED: &str = "admin.user_edited";
+pub const ADMIN_MODEL_CHANNELS_SET: &str = "admin.model_channels_set";
+pub const ADMIN_JOB_RUN: &str = "admin.job_run";
+pub const ADMIN_JOB_TOGGLED: &str = "admin.job_toggled";
+pub const ADMIN_RATE_LIMIT_SET: &str = "admin.rate_limit_set";
+
+/// Entries shown unless a smaller limit is asked for.
+pub const DEFAULT_LIMIT: i64 = 100;
+pub const MAX_LIMIT: i64 = 1000;
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct Entry {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    /// The user's current email.
+    pub email: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub detail: Option<String>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    pub created: NaiveDateTime,
+}
+
+pub fn record(
+    user_id: Option<i32>,
+    action: &str,
+    target: Option<&str>,
+    detail: Option<&str>,
+    info: &RequestInfo,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    insert_into(audit_log::table)
+        .values((
+            audit_log::user_id.eq(user_id),
+            audit_log::action.eq(action),
+            audit_log::target.eq(target),
+            audit_log::detail.eq(detail),
+            audit_log::ip.eq(info.ip.as_ref()),
+            audit_log::request_id.eq(&info.request_id),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The latest entries, of one user's if `user_id` is given, newest first.
+/// `limit` is kept within `1..=MAX_LIMIT`.
+pub fn latest(user_id: Option<i32>, limit: i64, conn: &SqliteConnection) -> Result<Vec<Entry>> {
+    let mut query = audit_log::table
+        .left_join(users::table)
+        .select((
+            audit_log::id,
+            audit_log::user_id,
+            users::email.nullable(),
+            audit_log::action,
+            audit_log::target,
+            audit_log::detail,
+            audit_log::ip,
+            audit_log::request_id,
+            audit_log::created,
+        ))
+        .order(audit_log::id.desc())
+        .limit(limit.max(1).min(MAX_LIMIT))
+        .into_boxed();
+    if let Some(user_id) = user_id {
+        query = query.filter(audit_log::user_id.eq(user_id));
+    }
+    query.load(conn).map_err(|e| e.into())
+}
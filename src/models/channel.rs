@@ -0,0 +1,268 @@
+//! Reading channels beyond the five built-in reading columns.
+//!
+//! A sensor model declares its extra channels in `model_channels`, and their
+//! values are stored one row per reading and channel in `reading_values`, so
+//! new channels don't need a migration.
+use crate::{
+    models::{rollup, SensorQuery},
+    result::{Error, Result},
+    schema::{model_channels, reading_values},
+};
+use chrono::NaiveDateTime;
+use diesel::{insert_or_ignore_into, prelude::*, replace_into, Insertable, Queryable};
+use std::collections::BTreeMap;
+
+pub type ChannelValues = BTreeMap<String, f32>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelType {
+    Float,
+    Integer,
+    Boolean,
+}
+
+impl ChannelType {
+    pub fn from_string(s: &str) -> Result<ChannelType> {
+        match s {
+            "float" => Ok(ChannelType::Float),
+            "integer" => Ok(ChannelType::Integer),
+            "boolean" => Ok(ChannelType::Boolean),
+            _ => Err(Error::InvalidChannel(format!(
+                "unknown channel type '{}'",
+                s
+            ))),
+        }
+    }
+
+    pub fn get_string(&self) -> String {
+        let s = match self {
+            ChannelType::Float => "float",
+            ChannelType::Integer => "integer",
+            ChannelType::Boolean => "boolean",
+        };
+        s.to_string()
+    }
+
+    fn accepts(&self, value: f32) -> bool {
+        match self {
+            ChannelType::Float => value.is_finite(),
+            ChannelType::Integer => value.is_finite() && value.fract() == 0.0,
+            ChannelType::Boolean => value == 0.0 || value == 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Channel {
+    pub name: String,
+    pub unit: String,
+    #[serde(rename = "type")]
+    pub type_: ChannelType,
+    #[serde(default)]
+    pub builtin: bool,
+}
+
+/// The columns of the `readings` table, which every sensor reports.
+pub fn builtin() -> Vec<Channel> {
+    [
+        ("peak_power_mW", "mW"),
+        ("peak_current_mA", "mA"),
+        ("peak_voltage_V", "V"),
+        ("temp_celsius", "C"),
+        ("batt_V", "V"),
+    ]
+    .iter()
+    .map(|&(name, unit)| Channel {
+        name: name.to_string(),
+        unit: unit.to_string(),
+        type_: ChannelType::Float,
+        builtin: true,
+    })
+    .collect()
+}
+
+#[derive(Insertable, Queryable)]
+#[table_name = "model_channels"]
+struct ModelChannel {
+    model: String,
+    name: String,
+    unit: String,
+    type_: String,
+}
+
+impl ModelChannel {
+    fn channel(self) -> Result<Channel> {
+        Ok(Channel {
+            type_: ChannelType::from_string(&self.type_)?,
+            name: self.name,
+            unit: self.unit,
+            builtin: false,
+        })
+    }
+}
+
+/// The channels declared by a sensor model, not including the built-in ones.
+pub fn for_model(model: &str, conn: &SqliteConnection) -> Result<Vec<Channel>> {
+    use crate::schema::model_channels::dsl::{model as channel_model, model_channels, name};
+    let rows: Vec<ModelChannel> = model_channels
+        .filter(channel_model.eq(model))
+        .order(name.asc())
+        .load(conn)?;
+    rows.into_iter().map(ModelChannel::channel).collect()
+}
+
+/// Every channel a sensor reports, built-in channels first.
+pub fn for_sensor(sensor: &SensorQuery, conn: &SqliteConnection) -> Result<Vec<Channel>> {
+    let mut channels = builtin();
+    if let Some(ref model) = sensor.model {
+        channels.extend(for_model(model, conn)?);
+    }
+    Ok(channels)
+}
+
+/// Names of reading columns that extra channels would clash with, in exports
+/// and in readings with their channels flattened in.
+pub const RESERVED_NAMES: &[&str] = &["id", "sensor_id", "timestamp", "created"];
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Declares channels for a sensor model. Existing channels with the same
+/// names are replaced.
+pub fn declare(model: &str, channels: &[Channel], conn: &SqliteConnection) -> Result<()> {
+    let builtin = builtin();
+    let mut rows = Vec::new();
+    for c in channels {
+        if !valid_name(&c.name) || builtin.iter().any(|b| b.name == c.name) {
+            return Err(Error::InvalidChannel(format!(
+                "invalid channel name '{}'",
+                c.name
+            )));
+        }
+        if RESERVED_NAMES.contains(&c.name.as_str()) {
+            return Err(Error::InvalidChannel(format!(
+                "channel name '{}' is reserved",
+                c.name
+            )));
+        }
+        rows.push(ModelChannel {
+            model: model.to_string(),
+            name: c.name.clone(),
+            unit: c.unit.clone(),
+            type_: c.type_.get_string(),
+        });
+    }
+    replace_into(model_channels::table)
+        .values(&rows)
+        .execute(conn)?;
+    Ok(())
+}
+
+#[derive(Insertable)]
+#[table_name = "reading_values"]
+struct ReadingValueInsert {
+    reading_id: i32,
+    channel: String,
+    value: f32,
+}
+
+/// Checks that every value is for a channel the sensor's model declares, and
+/// has the right type.
+pub fn validate(channels: &[Channel], values: &ChannelValues) -> Result<()> {
+    for (name, &value) in values {
+        match channels.iter().find(|c| !c.builtin && &c.name == name) {
+            Some(c) if c.type_.accepts(value) => (),
+            Some(c) => {
+                return Err(Error::InvalidReading(format!(
+                    "{} is not a valid {} for channel {}",
+                    value,
+                    c.type_.get_string(),
+                    name
+                )))
+            }
+            None => return Err(Error::UnknownChannel(name.clone())),
+        }
+    }
+    Ok(())
+}
+
+/// Stores extra channel values for readings that have already been inserted.
+/// Readings are found by timestamp, and if the sensor sent more than one
+/// reading with the same timestamp the newest gets the values. Values that
+/// were already stored are left alone.
+pub fn insert_values(
+    sensor: &SensorQuery,
+    values: &[(NaiveDateTime, ChannelValues)],
+    conn: &SqliteConnection,
+) -> Result<usize> {
+    use crate::schema::readings::dsl::{id, readings, sensor_id, timestamp};
+
+    if values.iter().all(|(_, v)| v.is_empty()) {
+        return Ok(0);
+    }
+    let channels = for_sensor(sensor, conn)?;
+    for (_, v) in values {
+        validate(&channels, v)?;
+    }
+
+    conn.transaction(|| {
+        let mut count = 0;
+        for (ts, v) in values.iter().filter(|(_, v)| !v.is_empty()) {
+            let reading_id: i32 = readings
+                .select(id)
+                .filter(sensor_id.eq(sensor.id))
+                .filter(timestamp.eq(ts))
+                .order(id.desc())
+                .first(conn)?;
+            let rows: Vec<ReadingValueInsert> = v
+                .iter()
+                .map(|(channel, &value)| ReadingValueInsert {
+                    reading_id,
+                    channel: channel.clone(),
+                    value,
+                })
+                .collect();
+            count += insert_or_ignore_into(reading_values::table)
+                .values(&rows)
+                .execute(conn)?;
+        }
+        if count > 0 {
+            let timestamps = values.iter().map(|(ts, _)| *ts);
+            let first = timestamps.clone().min();
+            let last = timestamps.max();
+            if let (Some(first), Some(last)) = (first, last) {
+                rollup::refresh(sensor.id, first, last, conn)?;
+            }
+        }
+        Ok(count)
+    })
+}
+
+#[derive(Queryable)]
+struct ReadingValue {
+    reading_id: i32,
+    channel: String,
+    value: f32,
+}
+
+/// Loads the extra channel values for a set of readings, keyed by reading id.
+pub fn values_for_readings(
+    reading_ids: &[i32],
+    conn: &SqliteConnection,
+) -> Result<BTreeMap<i32, ChannelValues>> {
+    use crate::schema::reading_values::dsl::{reading_id, reading_values};
+
+    let mut res: BTreeMap<i32, ChannelValues> = BTreeMap::new();
+    // Stay under SQLite's limit on bound parameters.
+    for ids in reading_ids.chunks(500) {
+        let rows: Vec<ReadingValue> = reading_values.filter(reading_id.eq_any(ids)).load(conn)?;
+        for row in rows {
+            res.entry(row.reading_id)
+                .or_insert_with(BTreeMap::new)
+                .insert(row.channel, row.value);
+        }
+    }
+    Ok(res)
+}
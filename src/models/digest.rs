@@ -0,0 +1,165 @@
+//! Users who asked for digest emails, and the tokens in those emails that
+//! unsubscribe them.
+
+use crate::{
+    result::{Error, Result},
+    schema::{digest_subscriptions, unsubscribe_tokens},
+    util::{signing, token::rand_str},
+};
+use chrono::NaiveDateTime;
+use diesel::{insert_into, prelude::*, update};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Frequency {
+    Daily,
+    Weekly,
+}
+
+impl Frequency {
+    pub fn from_string(s: &str) -> Result<Frequency> {
+        match s {
+            "daily" => Ok(Frequency::Daily),
+            "weekly" => Ok(Frequency::Weekly),
+            _ => Err(Error::InvalidDigestFrequency(s.to_string())),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Frequency::Daily => "daily",
+            Frequency::Weekly => "weekly",
+        }
+    }
+
+    /// Days a digest covers.
+    pub fn days(self) -> i64 {
+        match self {
+            Frequency::Daily => 1,
+            Frequency::Weekly => 7,
+        }
+    }
+}
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct Subscription {
+    pub user_id: i32,
+    pub frequency: String,
+    pub last_sent: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+}
+
+impl Subscription {
+    pub fn frequency(&self) -> Result<Frequency> {
+        Frequency::from_string(&self.frequency)
+    }
+}
+
+pub fn find(user_id: i32, conn: &SqliteConnection) -> Result<Option<Subscription>> {
+    digest_subscriptions::table
+        .find(user_id)
+        .first(conn)
+        .optional()
+        .map_err(|e| e.into())
+}
+
+pub fn all(conn: &SqliteConnection) -> Result<Vec<Subscription>> {
+    digest_subscriptions::table
+        .order(digest_subscriptions::user_id.asc())
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+/// Subscribes a user to digests, changes how often they get them, or with
+/// `None` unsubscribes them.
+pub fn subscribe(
+    user_id: i32,
+    frequency: Option<Frequency>,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    let existing = digest_subscriptions::table.find(user_id);
+    match frequency {
+        Some(frequency) => {
+            let updated = update(existing)
+                .set(digest_subscriptions::frequency.eq(frequency.as_str()))
+                .execute(conn)?;
+            if updated == 0 {
+                insert_into(digest_subscriptions::table)
+                    .values((
+                        digest_subscriptions::user_id.eq(user_id),
+                        digest_subscriptions::frequency.eq(frequency.as_str()),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+        None => {
+            diesel::delete(existing).execute(conn)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn mark_sent(user_id: i32, now: NaiveDateTime, conn: &SqliteConnection) -> Result<()> {
+    update(digest_subscriptions::table.find(user_id))
+        .set(digest_subscriptions::last_sent.eq(now))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn unsubscribe_message(nonce: &str) -> String {
+    format!("unsubscribe:{}", nonce)
+}
+
+/// A new token for a digest's unsubscribe link, as `<nonce>.<signature>`,
+/// and its nonce. The token doesn't work until it's saved with
+/// `save_unsubscribe_token`.
+pub fn new_unsubscribe_token() -> (String, String) {
+    let nonce = rand_str();
+    let signature = signing::sign(&unsubscribe_message(&nonce));
+    (format!("{}.{}", nonce, signature), nonce)
+}
+
+/// Makes the token with `nonce` work for `user_id`, once the email it was
+/// sent in is on its way.
+pub fn save_unsubscribe_token(nonce: &str, user_id: i32, conn: &SqliteConnection) -> Result<()> {
+    insert_into(unsubscribe_tokens::table)
+        .values((
+            unsubscribe_tokens::nonce.eq(nonce),
+            unsubscribe_tokens::user_id.eq(user_id),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The user an unused unsubscribe token is for, and the token's nonce.
+pub fn check_unsubscribe_token(token: &str, conn: &SqliteConnection) -> Result<(i32, String)> {
+    let mut parts = token.splitn(2, '.');
+    let (nonce, signature) = match (parts.next(), parts.next()) {
+        (Some(nonce), Some(signature)) => (nonce, signature),
+        _ => return Err(Error::InvalidUnsubscribeToken),
+    };
+    if !signing::verify(&unsubscribe_message(nonce), signature) {
+        return Err(Error::InvalidUnsubscribeToken);
+    }
+    unsubscribe_tokens::table
+        .select(unsubscribe_tokens::user_id)
+        .filter(unsubscribe_tokens::nonce.eq(nonce))
+        .filter(unsubscribe_tokens::used.is_null())
+        .first(conn)
+        .optional()?
+        .map(|user_id| (user_id, nonce.to_string()))
+        .ok_or(Error::InvalidUnsubscribeToken)
+}
+
+/// Unsubscribes the user a token is for, and uses up the token. Returns the
+/// user's id.
+pub fn unsubscribe(token: &str, now: NaiveDateTime, conn: &SqliteConnection) -> Result<i32> {
+    conn.transaction(|| {
+        let (user_id, nonce) = check_unsubscribe_token(token, conn)?;
+        update(unsubscribe_tokens::table.find(&nonce))
+            .set(unsubscribe_tokens::used.eq(now))
+            .execute(conn)?;
+        subscribe(user_id, None, conn)?;
+        Ok(user_id)
+    })
+}
@@ -0,0 +1,327 @@
+//! Per-sensor settings for scaling the energy a sensor measures up to a full
+//! size system, and for what that energy is worth in money and emissions, and
+//! summaries of that energy by week, month and year.
+use crate::{
+    models::{Sensor, SensorQuery},
+    result::{Error, Result},
+    schema::{energy_settings, tariff_rates},
+};
+use chrono::{Datelike, Duration, NaiveDate};
+use diesel::{prelude::*, replace_into, Insertable, Queryable};
+
+/// Sized so that, with the default panel, the scale factor is the 7895 that
+/// stats have always used.
+pub const DEFAULT_SYSTEM_SIZE_KW: f32 = 4.5;
+pub const DEFAULT_PANEL_RATED_MW: f32 = 570.0;
+pub const DEFAULT_TARIFF_PER_KWH: f32 = 0.12;
+pub const DEFAULT_EMISSIONS_PER_KWH: f32 = 1.6;
+
+/// Settings left as `None` use the defaults.
+#[allow(non_snake_case)]
+#[derive(Insertable, Queryable, Serialize, Deserialize, Clone, Debug, Default)]
+#[table_name = "energy_settings"]
+pub struct EnergySettings {
+    #[serde(skip)]
+    pub sensor_id: i32,
+    /// Size of the system the sensor's panel stands in for.
+    pub system_size_kW: Option<f32>,
+    /// Rated peak power of the sensor's own panel.
+    pub panel_rated_mW: Option<f32>,
+    /// Flat electricity tariff, used for hours without a time-of-use rate.
+    pub tariff_per_kWh: Option<f32>,
+    /// Grid emissions avoided per kWh generated.
+    pub emissions_per_kWh: Option<f32>,
+}
+
+#[allow(non_snake_case)]
+impl EnergySettings {
+    pub fn system_size_kW(&self) -> f32 {
+        self.system_size_kW.unwrap_or(DEFAULT_SYSTEM_SIZE_KW)
+    }
+
+    pub fn panel_rated_mW(&self) -> f32 {
+        self.panel_rated_mW.unwrap_or(DEFAULT_PANEL_RATED_MW)
+    }
+
+    pub fn tariff_per_kWh(&self) -> f32 {
+        self.tariff_per_kWh.unwrap_or(DEFAULT_TARIFF_PER_KWH)
+    }
+
+    pub fn emissions_per_kWh(&self) -> f32 {
+        self.emissions_per_kWh.unwrap_or(DEFAULT_EMISSIONS_PER_KWH)
+    }
+
+    /// How many times more energy the full size system makes than the panel.
+    pub fn scale(&self) -> f64 {
+        f64::from(self.system_size_kW()) * 1000.0 * 1000.0 / f64::from(self.panel_rated_mW())
+    }
+}
+
+/// A time-of-use rate for the hours `start_hour..end_hour` of each day, in the
+/// sensor's local time.
+#[allow(non_snake_case)]
+#[derive(Insertable, Queryable, Serialize, Deserialize, Clone, Copy, Debug)]
+#[table_name = "tariff_rates"]
+pub struct TariffRate {
+    #[serde(skip)]
+    pub sensor_id: i32,
+    pub start_hour: i32,
+    pub end_hour: i32,
+    pub rate_per_kWh: f32,
+}
+
+pub fn settings(sensor_id: i32, conn: &SqliteConnection) -> Result<EnergySettings> {
+    use crate::schema::energy_settings::dsl::{energy_settings, sensor_id as s_sensor_id};
+
+    let res = energy_settings
+        .filter(s_sensor_id.eq(sensor_id))
+        .first(conn)
+        .optional()?;
+    Ok(res.unwrap_or(EnergySettings {
+        sensor_id,
+        ..Default::default()
+    }))
+}
+
+pub fn tariff_rates(sensor_id: i32, conn: &SqliteConnection) -> Result<Vec<TariffRate>> {
+    use crate::schema::tariff_rates::dsl::{sensor_id as r_sensor_id, start_hour, tariff_rates};
+
+    let res = tariff_rates
+        .filter(r_sensor_id.eq(sensor_id))
+        .order(start_hour.asc())
+        .load(conn)?;
+    Ok(res)
+}
+
+fn validate(settings: &EnergySettings, rates: &[TariffRate]) -> Result<()> {
+    let values = [
+        ("system_size_kW", settings.system_size_kW),
+        ("panel_rated_mW", settings.panel_rated_mW),
+        ("tariff_per_kWh", settings.tariff_per_kWh),
+        ("emissions_per_kWh", settings.emissions_per_kWh),
+    ];
+    for (name, value) in values.iter() {
+        if let Some(v) = value {
+            if !v.is_finite() || *v < 0.0 {
+                return Err(Error::InvalidEnergySettings(format!(
+                    "{} must not be negative",
+                    name
+                )));
+            }
+        }
+    }
+    if settings.panel_rated_mW == Some(0.0) {
+        return Err(Error::InvalidEnergySettings(
+            "panel_rated_mW must be a positive number".into(),
+        ));
+    }
+
+    let mut rates: Vec<&TariffRate> = rates.iter().collect();
+    rates.sort_by_key(|r| r.start_hour);
+    for r in &rates {
+        if r.start_hour < 0 || r.end_hour > 24 || r.start_hour >= r.end_hour {
+            return Err(Error::InvalidEnergySettings(format!(
+                "rate hours {}..{} must be within 0..24",
+                r.start_hour, r.end_hour
+            )));
+        }
+        if !r.rate_per_kWh.is_finite() || r.rate_per_kWh < 0.0 {
+            return Err(Error::InvalidEnergySettings(
+                "rate_per_kWh must not be negative".into(),
+            ));
+        }
+    }
+    for pair in rates.windows(2) {
+        if pair[1].start_hour < pair[0].end_hour {
+            return Err(Error::InvalidEnergySettings(format!(
+                "rates starting at hours {} and {} overlap",
+                pair[0].start_hour, pair[1].start_hour
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces a sensor's settings and time-of-use rates.
+pub fn save(
+    sensor_id: i32,
+    settings: EnergySettings,
+    rates: Vec<TariffRate>,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    use crate::schema::tariff_rates::dsl::{sensor_id as r_sensor_id, tariff_rates};
+
+    validate(&settings, &rates)?;
+    let settings = EnergySettings {
+        sensor_id,
+        ..settings
+    };
+    let rates: Vec<TariffRate> = rates
+        .into_iter()
+        .map(|r| TariffRate { sensor_id, ..r })
+        .collect();
+    conn.transaction(|| {
+        replace_into(energy_settings::table)
+            .values(&settings)
+            .execute(conn)?;
+        diesel::delete(tariff_rates.filter(r_sensor_id.eq(sensor_id))).execute(conn)?;
+        diesel::insert_into(tariff_rates)
+            .values(&rates)
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Week,
+    Month,
+    Year,
+}
+
+fn add_months(date: NaiveDate, months: i32) -> NaiveDate {
+    let total = date.year() * 12 + date.month0() as i32 + months;
+    NaiveDate::from_ymd(total.div_euclid(12), total.rem_euclid(12) as u32 + 1, 1)
+}
+
+impl Period {
+    pub fn from_string(s: &str) -> Result<Period> {
+        match s {
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            "year" => Ok(Period::Year),
+            _ => Err(Error::InvalidPeriod(s.to_string())),
+        }
+    }
+
+    /// The first day of the period `date` is in. Weeks start on Monday.
+    pub fn start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Week => date - Duration::days(i64::from(date.weekday().num_days_from_monday())),
+            Period::Month => NaiveDate::from_ymd(date.year(), date.month(), 1),
+            Period::Year => NaiveDate::from_ymd(date.year(), 1, 1),
+        }
+    }
+
+    /// The first day of the period `offset` periods after the one starting on
+    /// `start`.
+    fn shift(&self, start: NaiveDate, offset: i32) -> NaiveDate {
+        match self {
+            Period::Week => start + Duration::weeks(i64::from(offset)),
+            Period::Month => add_months(start, offset),
+            Period::Year => add_months(start, offset * 12),
+        }
+    }
+
+    /// The first day of the same period a year before the one starting on
+    /// `start`, or `None` for years, where that's just the previous period.
+    /// A week a year ago is 52 weeks ago, so it starts on the same weekday.
+    fn year_ago(&self, start: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Period::Week => Some(self.shift(start, -52)),
+            Period::Month => Some(self.shift(start, -12)),
+            Period::Year => None,
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct EnergyTotals {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    /// Days in the range that have readings.
+    pub days: usize,
+    pub equiv_kWh: f32,
+    pub dollars_saved: f32,
+    pub co2_saved: f32,
+}
+
+/// How much the current period differs from an earlier one.
+#[allow(non_snake_case)]
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct EnergyDelta {
+    pub equiv_kWh: f32,
+    pub dollars_saved: f32,
+    pub co2_saved: f32,
+    /// Change in energy as a percentage, or `None` if there was none before.
+    pub percent: Option<f32>,
+}
+
+impl EnergyDelta {
+    fn between(current: &EnergyTotals, base: &EnergyTotals) -> EnergyDelta {
+        let percent = if base.equiv_kWh > 0.0 {
+            Some((current.equiv_kWh - base.equiv_kWh) / base.equiv_kWh * 100.0)
+        } else {
+            None
+        };
+        EnergyDelta {
+            equiv_kWh: current.equiv_kWh - base.equiv_kWh,
+            dollars_saved: current.dollars_saved - base.dollars_saved,
+            co2_saved: current.co2_saved - base.co2_saved,
+            percent,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EnergySummary {
+    pub period: Period,
+    pub current: EnergyTotals,
+    pub previous: EnergyTotals,
+    pub vs_previous: EnergyDelta,
+    pub year_ago: Option<EnergyTotals>,
+    pub vs_year_ago: Option<EnergyDelta>,
+}
+
+fn totals(
+    sensor: &SensorQuery,
+    start: NaiveDate,
+    end: NaiveDate,
+    conn: &SqliteConnection,
+) -> Result<EnergyTotals> {
+    let stats = Sensor::energy_stats(sensor, start, end, conn)?;
+    Ok(EnergyTotals {
+        start,
+        end,
+        days: stats.len(),
+        equiv_kWh: stats.iter().map(|s| s.equiv_kWh).sum(),
+        dollars_saved: stats.iter().map(|s| s.dollars_saved).sum(),
+        co2_saved: stats.iter().map(|s| s.co2_saved).sum(),
+    })
+}
+
+/// Energy totals for the period containing `date`, compared with the period
+/// before it and the same period a year earlier. A period still in progress
+/// on `today` is compared with the same number of days of earlier periods.
+pub fn summary(
+    sensor: &SensorQuery,
+    period: Period,
+    date: NaiveDate,
+    today: NaiveDate,
+    conn: &SqliteConnection,
+) -> Result<EnergySummary> {
+    let start = period.start(date);
+    let end = period.shift(start, 1).pred().min(today.max(start));
+    let len = end - start;
+    let earlier = |start: NaiveDate| {
+        let end = (start + len).min(period.shift(start, 1).pred());
+        totals(sensor, start, end, conn)
+    };
+
+    let current = totals(sensor, start, end, conn)?;
+    let previous = earlier(period.shift(start, -1))?;
+    let year_ago = match period.year_ago(start) {
+        Some(start) => Some(earlier(start)?),
+        None => None,
+    };
+    Ok(EnergySummary {
+        period,
+        vs_previous: EnergyDelta::between(&current, &previous),
+        vs_year_ago: year_ago.as_ref().map(|y| EnergyDelta::between(&current, y)),
+        current,
+        previous,
+        year_ago,
+    })
+}
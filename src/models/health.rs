@@ -0,0 +1,244 @@
+//! Whether a sensor is still reporting, and whether its battery and
+//! temperature look like it will keep doing so.
+
+use crate::{
+    models::SensorQuery,
+    result::Result,
+    schema::{hourly_rollups, readings},
+};
+use chrono::{Duration, NaiveDateTime};
+use diesel::{dsl::max, prelude::*};
+
+/// Readings used to work out how often a sensor reports.
+const INTERVAL_SAMPLE: i64 = 25;
+
+/// Reporting interval assumed for sensors with fewer than two readings.
+pub const DEFAULT_INTERVAL_SECS: i64 = 300;
+
+/// A sensor is offline once it has missed this many reports in a row.
+pub const OFFLINE_INTERVALS: i64 = 3;
+
+/// Shortest time without readings before a sensor is offline, so sensors
+/// that report every few seconds don't flap.
+pub const MIN_OFFLINE_SECS: i64 = 15 * 60;
+
+/// Days of hourly rollups, ending at the latest reading, that battery and
+/// temperature trends are taken from.
+pub const TREND_DAYS: i64 = 7;
+
+/// Hourly means needed before a battery trend is projected.
+const MIN_TREND_HOURS: usize = 6;
+
+/// Battery voltage at which a sensor stops working.
+pub const BATTERY_EMPTY_V: f64 = 3.3;
+
+/// Battery voltage below which a sensor is degraded.
+pub const BATTERY_LOW_V: f64 = 3.5;
+
+/// A sensor is degraded when its battery is projected to run out sooner.
+pub const BATTERY_WARNING_DAYS: f64 = 7.0;
+
+/// Coldest temperature the hardware is rated for.
+pub const TEMP_MIN_CELSIUS: f64 = -20.0;
+
+/// Hottest temperature the hardware is rated for.
+pub const TEMP_MAX_CELSIUS: f64 = 70.0;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Healthy,
+    Degraded,
+    Offline,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct Battery {
+    /// Mean voltage of the latest hour.
+    pub latest_V: f64,
+    /// Least squares fit of the hourly means, in volts per day.
+    pub V_per_day: Option<f64>,
+    /// Days until the fitted trend reaches `BATTERY_EMPTY_V`, if it's falling.
+    pub days_to_empty: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Temperature {
+    pub min_celsius: f64,
+    pub max_celsius: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub sensor_id: i32,
+    pub status: Status,
+    /// Why the sensor isn't healthy, if it isn't.
+    pub reasons: Vec<String>,
+    /// When the server last received a reading from the sensor. Imported
+    /// readings count as received at their timestamps.
+    pub last_seen: Option<NaiveDateTime>,
+    /// Median time between the sensor's latest readings.
+    pub interval_secs: i64,
+    pub battery: Option<Battery>,
+    pub temperature: Option<Temperature>,
+}
+
+fn median_interval(timestamps: &[NaiveDateTime]) -> Option<i64> {
+    let mut gaps: Vec<i64> = timestamps
+        .windows(2)
+        .map(|w| (w[0] - w[1]).num_seconds().abs())
+        .filter(|&gap| gap > 0)
+        .collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort();
+    Some(gaps[gaps.len() / 2])
+}
+
+/// Hourly rollups of a channel from `start`, as (hour, mean, min, max).
+fn hourly(
+    sensor_id: i32,
+    channel: &str,
+    start: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<Vec<(NaiveDateTime, f64, f64, f64)>> {
+    let rows: Vec<(NaiveDateTime, i32, f32, f32, f32)> = hourly_rollups::table
+        .select((
+            hourly_rollups::bucket,
+            hourly_rollups::count,
+            hourly_rollups::sum,
+            hourly_rollups::min,
+            hourly_rollups::max,
+        ))
+        .filter(hourly_rollups::sensor_id.eq(sensor_id))
+        .filter(hourly_rollups::channel.eq(channel))
+        .filter(hourly_rollups::bucket.ge(start))
+        .order(hourly_rollups::bucket.asc())
+        .load(conn)?;
+    Ok(rows
+        .into_iter()
+        .filter(|r| r.1 > 0)
+        .map(|(bucket, count, sum, min, max)| {
+            (
+                bucket,
+                f64::from(sum) / f64::from(count),
+                f64::from(min),
+                f64::from(max),
+            )
+        })
+        .collect())
+}
+
+#[allow(non_snake_case)]
+fn battery(hours: &[(NaiveDateTime, f64, f64, f64)]) -> Option<Battery> {
+    let latest_V = hours.last()?.1;
+    let mut V_per_day = None;
+    let mut days_to_empty = None;
+    if hours.len() >= MIN_TREND_HOURS {
+        let t0 = hours[0].0;
+        let points: Vec<(f64, f64)> = hours
+            .iter()
+            .map(|h| ((h.0 - t0).num_seconds() as f64 / 86400.0, h.1))
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+        if sxx > 0.0 {
+            let slope = sxy / sxx;
+            V_per_day = Some(slope);
+            if slope < 0.0 {
+                // Project from the fitted value at the latest hour rather than
+                // the noisy latest mean.
+                let fitted = mean_y + slope * (points[points.len() - 1].0 - mean_x);
+                days_to_empty = Some(((fitted - BATTERY_EMPTY_V) / -slope).max(0.0));
+            }
+        }
+    }
+    Some(Battery {
+        latest_V,
+        V_per_day,
+        days_to_empty,
+    })
+}
+
+/// The health of a sensor at `now`.
+pub fn health(sensor: &SensorQuery, now: NaiveDateTime, conn: &SqliteConnection) -> Result<Health> {
+    let last_seen: Option<NaiveDateTime> = readings::table
+        .select(max(readings::created))
+        .filter(readings::sensor_id.eq(sensor.id))
+        .first(conn)?;
+    let latest: Vec<NaiveDateTime> = readings::table
+        .select(readings::timestamp)
+        .filter(readings::sensor_id.eq(sensor.id))
+        .order(readings::timestamp.desc())
+        .limit(INTERVAL_SAMPLE)
+        .load(conn)?;
+    let interval_secs = median_interval(&latest).unwrap_or(DEFAULT_INTERVAL_SECS);
+
+    let mut battery_info = None;
+    let mut temperature = None;
+    if let Some(&latest_ts) = latest.first() {
+        let start = latest_ts - Duration::days(TREND_DAYS);
+        battery_info = battery(&hourly(sensor.id, "batt_V", start, conn)?);
+        let temps = hourly(sensor.id, "temp_celsius", start, conn)?;
+        if !temps.is_empty() {
+            temperature = Some(Temperature {
+                min_celsius: temps.iter().map(|t| t.2).fold(f64::INFINITY, f64::min),
+                max_celsius: temps.iter().map(|t| t.3).fold(f64::NEG_INFINITY, f64::max),
+            });
+        }
+    }
+
+    let mut reasons = Vec::new();
+    let offline_after = (interval_secs * OFFLINE_INTERVALS).max(MIN_OFFLINE_SECS);
+    let offline = match last_seen {
+        None => {
+            reasons.push("no readings yet".to_string());
+            true
+        }
+        Some(seen) if (now - seen).num_seconds() > offline_after => {
+            reasons.push(format!("not seen since {}", seen));
+            true
+        }
+        Some(_) => false,
+    };
+    if let Some(ref b) = battery_info {
+        if b.latest_V < BATTERY_LOW_V {
+            reasons.push(format!("battery is low at {:.2} V", b.latest_V));
+        }
+        if let Some(days) = b.days_to_empty {
+            if days < BATTERY_WARNING_DAYS {
+                reasons.push(format!("battery will run out in {:.1} days", days));
+            }
+        }
+    }
+    if let Some(ref t) = temperature {
+        if t.min_celsius < TEMP_MIN_CELSIUS || t.max_celsius > TEMP_MAX_CELSIUS {
+            reasons.push(format!(
+                "temperature ranged from {:.1} to {:.1} °C",
+                t.min_celsius, t.max_celsius
+            ));
+        }
+    }
+    let status = if offline {
+        Status::Offline
+    } else if !reasons.is_empty() {
+        Status::Degraded
+    } else {
+        Status::Healthy
+    };
+
+    Ok(Health {
+        sensor_id: sensor.id,
+        status,
+        reasons,
+        last_seen,
+        interval_secs,
+        battery: battery_info,
+        temperature,
+    })
+}
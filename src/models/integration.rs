@@ -0,0 +1,43 @@
+use crate::{result::Result, schema::integrations, util};
+use chrono::NaiveDateTime;
+use diesel::{insert_into, prelude::*, Insertable, Queryable};
+
+#[derive(Insertable, Serialize)]
+#[table_name = "integrations"]
+pub struct IntegrationInsert {
+    pub owner_id: i32,
+    pub name: String,
+    pub secret: String,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct Integration {
+    pub id: i32,
+    pub owner_id: i32,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub created: NaiveDateTime,
+}
+
+pub fn create(owner_id: i32, name: String, conn: &SqliteConnection) -> Result<String> {
+    let integration = IntegrationInsert {
+        owner_id,
+        name,
+        secret: format!("webhook-{}", util::token::rand_str()),
+    };
+
+    insert_into(integrations::table)
+        .values(&integration)
+        .execute(conn)?;
+
+    Ok(integration.secret)
+}
+
+pub fn by_secret(secret: &String, conn: &SqliteConnection) -> Result<Integration> {
+    use crate::schema::integrations::dsl::{integrations, secret as integration_secret};
+    let integration = integrations
+        .filter(integration_secret.eq(secret))
+        .first(conn)?;
+    Ok(integration)
+}
@@ -0,0 +1,205 @@
+//! Scheduled jobs, the locks that stop two servers running one at once, and
+//! their run history.
+
+use crate::{
+    result::{Error, Result},
+    schema::{job_runs, jobs},
+};
+use chrono::NaiveDateTime;
+use diesel::{delete, insert_into, prelude::*, update};
+
+pub const SUCCEEDED: &str = "succeeded";
+pub const FAILED: &str = "failed";
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct Job {
+    pub name: String,
+    pub schedule: String,
+    pub enabled: bool,
+    pub next_run: NaiveDateTime,
+    pub locked_by: Option<String>,
+    pub locked_until: Option<NaiveDateTime>,
+    pub last_run: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct Run {
+    pub id: i32,
+    pub job_name: String,
+    pub runner: String,
+    pub started: NaiveDateTime,
+    pub finished: Option<NaiveDateTime>,
+    pub status: String,
+    pub output: Option<String>,
+}
+
+pub fn find(name: &str, conn: &SqliteConnection) -> Result<Option<Job>> {
+    jobs::table
+        .find(name)
+        .first(conn)
+        .optional()
+        .map_err(|e| e.into())
+}
+
+pub fn all(conn: &SqliteConnection) -> Result<Vec<Job>> {
+    jobs::table
+        .order(jobs::name.asc())
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+/// Adds a job that isn't in the database yet. Jobs already there keep their
+/// next run, so a restart doesn't skip or repeat one.
+pub fn insert_missing(
+    name: &str,
+    schedule: &str,
+    next_run: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<bool> {
+    if find(name, conn)?.is_some() {
+        return Ok(false);
+    }
+    insert_into(jobs::table)
+        .values((
+            jobs::name.eq(name),
+            jobs::schedule.eq(schedule),
+            jobs::next_run.eq(next_run),
+        ))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// Locks a job for `runner` until `until`, unless another runner holds an
+/// unexpired lock. With `due_only`, the job must also be enabled and due at
+/// `now`. Returns whether the lock was taken.
+pub fn lock(
+    name: &str,
+    runner: &str,
+    now: NaiveDateTime,
+    until: NaiveDateTime,
+    due_only: bool,
+    conn: &SqliteConnection,
+) -> Result<bool> {
+    // One statement, so two servers can't both see the job as unlocked.
+    let unlocked = jobs::table
+        .filter(jobs::name.eq(name))
+        .filter(jobs::locked_until.is_null().or(jobs::locked_until.lt(now)));
+    let lock = (jobs::locked_by.eq(runner), jobs::locked_until.eq(until));
+    let locked = if due_only {
+        update(
+            unlocked
+                .filter(jobs::enabled.eq(true))
+                .filter(jobs::next_run.le(now)),
+        )
+        .set(lock)
+        .execute(conn)?
+    } else {
+        update(unlocked).set(lock).execute(conn)?
+    };
+    Ok(locked == 1)
+}
+
+/// Releases a runner's lock on a job, recording when it ran and when it runs
+/// next.
+pub fn unlock(
+    name: &str,
+    runner: &str,
+    ran: NaiveDateTime,
+    next_run: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    update(
+        jobs::table
+            .filter(jobs::name.eq(name))
+            .filter(jobs::locked_by.eq(runner)),
+    )
+    .set((
+        jobs::locked_by.eq(None::<String>),
+        jobs::locked_until.eq(None::<NaiveDateTime>),
+        jobs::last_run.eq(ran),
+        jobs::next_run.eq(next_run),
+    ))
+    .execute(conn)?;
+    Ok(())
+}
+
+pub fn set_enabled(name: &str, enabled: bool, conn: &SqliteConnection) -> Result<()> {
+    let updated = update(jobs::table.find(name))
+        .set(jobs::enabled.eq(enabled))
+        .execute(conn)?;
+    if updated == 0 {
+        return Err(Error::UnknownJob(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Makes a job due at `now`, so the scheduler runs it on its next pass.
+pub fn schedule_now(name: &str, now: NaiveDateTime, conn: &SqliteConnection) -> Result<()> {
+    let updated = update(jobs::table.find(name))
+        .set(jobs::next_run.eq(now))
+        .execute(conn)?;
+    if updated == 0 {
+        return Err(Error::UnknownJob(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Records the start of a run, returning its id.
+pub fn start_run(
+    name: &str,
+    runner: &str,
+    started: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<i32> {
+    conn.transaction(|| {
+        insert_into(job_runs::table)
+            .values((
+                job_runs::job_name.eq(name),
+                job_runs::runner.eq(runner),
+                job_runs::started.eq(started),
+            ))
+            .execute(conn)?;
+        job_runs::table
+            .select(job_runs::id)
+            .order(job_runs::id.desc())
+            .first(conn)
+            .map_err(|e| e.into())
+    })
+}
+
+pub fn finish_run(
+    id: i32,
+    status: &str,
+    output: &str,
+    finished: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    update(job_runs::table.find(id))
+        .set((
+            job_runs::status.eq(status),
+            job_runs::output.eq(output),
+            job_runs::finished.eq(finished),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn find_run(id: i32, conn: &SqliteConnection) -> Result<Run> {
+    job_runs::table.find(id).first(conn).map_err(|e| e.into())
+}
+
+/// Deletes runs that started before `before`, returning how many.
+pub fn purge_runs(before: NaiveDateTime, conn: &SqliteConnection) -> Result<usize> {
+    delete(job_runs::table.filter(job_runs::started.lt(before)))
+        .execute(conn)
+        .map_err(|e| e.into())
+}
+
+/// The latest runs of every job, newest first.
+pub fn recent_runs(limit: i64, conn: &SqliteConnection) -> Result<Vec<Run>> {
+    job_runs::table
+        .order(job_runs::id.desc())
+        .limit(limit)
+        .load(conn)
+        .map_err(|e| e.into())
+}
@@ -1,21 +1,40 @@
+pub mod aggregate;
+pub mod alert;
+pub mod audit;
+pub mod channel;
+pub mod digest;
+pub mod energy;
+pub mod health;
+pub mod integration;
+pub mod job;
 pub mod onetime_login;
-
+pub mod performance;
+pub mod rate_limit;
+pub mod retention;
+pub mod rollup;
+pub mod solar;
+pub mod webhook;
+
+use self::channel::ChannelValues;
 use crate::{
+    metrics::METRICS,
     result::{Error, Result},
     schema::{readings, sensors, tokens, users},
     util,
 };
-use chrono::{naive::serde::ts_seconds, NaiveDate, NaiveDateTime};
+use chrono::{naive::serde::ts_seconds, Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
 use diesel::{
     insert_into,
     prelude::*,
     sql_query,
-    sql_types::{Date, Float},
+    sql_types::{Date, Double, Float, Integer, Text, Timestamp},
     update, Insertable, Queryable,
 };
+use std::{collections::BTreeMap, fmt};
 
 #[allow(non_snake_case)]
-#[derive(Insertable, Serialize, Deserialize)]
+#[derive(Insertable, Serialize, Deserialize, Clone, Debug)]
 #[table_name = "readings"]
 pub struct ReadingInsert {
     pub sensor_id: i32,
@@ -25,6 +44,11 @@ pub struct ReadingInsert {
     pub peak_voltage_V: f32,
     pub temp_celsius: f32,
     pub batt_V: f32,
+    /// When the reading was received, which the database fills in unless
+    /// it's given. Imported readings are dated by their timestamps, so an
+    /// old log doesn't make its sensor look like it's reporting.
+    #[serde(skip)]
+    pub created: Option<NaiveDateTime>,
 }
 
 #[allow(non_snake_case)]
@@ -41,6 +65,20 @@ pub struct ReadingQuery {
     pub created: NaiveDateTime,
 }
 
+impl ReadingQuery {
+    /// The value of a built-in channel.
+    pub fn value(&self, channel: &str) -> Option<f32> {
+        match channel {
+            "peak_power_mW" => Some(self.peak_power_mW),
+            "peak_current_mA" => Some(self.peak_current_mA),
+            "peak_voltage_V" => Some(self.peak_voltage_V),
+            "temp_celsius" => Some(self.temp_celsius),
+            "batt_V" => Some(self.batt_V),
+            _ => None,
+        }
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Serialize)]
 pub struct ReadingQueryUnix {
@@ -73,60 +111,329 @@ impl From<ReadingQuery> for ReadingQueryUnix {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Where a page of readings left off: the last reading's timestamp, and its
+/// id to tell apart readings with the same timestamp.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cursor {
+    pub timestamp: NaiveDateTime,
+    pub id: i32,
+}
+
+impl Cursor {
+    pub fn after(reading: &ReadingQuery) -> Cursor {
+        Cursor {
+            timestamp: reading.timestamp,
+            id: reading.id,
+        }
+    }
+
+    /// Parses a cursor in the form `<unix seconds>_<id>`, which is how it's
+    /// passed in URLs.
+    pub fn parse(s: &str) -> Option<Cursor> {
+        let mut parts = s.splitn(2, '_');
+        let timestamp = parts.next()?.parse().ok()?;
+        let id = parts.next()?.parse().ok()?;
+        Some(Cursor {
+            timestamp: NaiveDateTime::from_timestamp_opt(timestamp, 0)?,
+            id,
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}_{}", self.timestamp.timestamp(), self.id)
+    }
+}
+
+/// A reading along with the values of its sensor model's extra channels.
+/// Both are flattened into one object when serialized.
+#[derive(Serialize)]
+pub struct ReadingWithChannels<R> {
+    #[serde(flatten)]
+    pub reading: R,
+    #[serde(flatten)]
+    pub channels: ChannelValues,
+}
+
+impl ReadingWithChannels<ReadingQuery> {
+    pub fn value(&self, channel: &str) -> Option<f32> {
+        self.reading
+            .value(channel)
+            .or_else(|| self.channels.get(channel).cloned())
+    }
+}
+
+impl<R> ReadingWithChannels<R> {
+    pub fn map<S, F: FnOnce(R) -> S>(self, f: F) -> ReadingWithChannels<S> {
+        ReadingWithChannels {
+            reading: f(self.reading),
+            channels: self.channels,
+        }
+    }
+}
+
 pub struct Reading;
 
 impl Reading {
     pub fn count(conn: &SqliteConnection) -> Result<i64> {
-        use super::schema::readings::dsl::readings as all_readings;
-        use diesel::dsl::count_star;
+        rollup::reading_count(conn)
+    }
 
-        let count: i64 = all_readings.select(count_star()).first(conn)?;
-        Ok(count)
+    /// Checks a reading before it is stored. Every ingestion path goes
+    /// through `insert` or `insert_many`, so they all share these rules.
+    pub fn validate(reading: &ReadingInsert) -> Result<()> {
+        let values = [
+            reading.peak_power_mW,
+            reading.peak_current_mA,
+            reading.peak_voltage_V,
+            reading.temp_celsius,
+            reading.batt_V,
+        ];
+        if values.iter().any(|v| !v.is_finite()) {
+            return Err(Error::InvalidReading(
+                "values must be finite numbers".into(),
+            ));
+        }
+        if reading.timestamp > Utc::now().naive_utc() + Duration::days(1) {
+            return Err(Error::InvalidReading(format!(
+                "timestamp {} is in the future",
+                reading.timestamp
+            )));
+        }
+        Ok(())
     }
 
+    /// Inserts a reading.
     pub fn insert(reading: &ReadingInsert, conn: &SqliteConnection) -> Result<usize> {
         use super::schema::readings::table as readings_table;
-        insert_into(readings_table)
-            .values(reading)
-            .execute(conn)
-            .map_err(|e| e.into())
+        Self::validate(reading).map_err(|e| Self::rejected("invalid", 1, e))?;
+        Self::check_retained(reading.sensor_id, reading.timestamp, conn)
+            .map_err(|e| Self::rejected("before_retention", 1, e))?;
+        let count = conn.transaction(|| {
+            let count = insert_into(readings_table).values(reading).execute(conn)?;
+            rollup::refresh(
+                reading.sensor_id,
+                reading.timestamp,
+                reading.timestamp,
+                conn,
+            )?;
+            Self::emit_created(
+                reading.sensor_id,
+                count,
+                reading.timestamp,
+                reading.timestamp,
+                conn,
+            )?;
+            Ok(count)
+        })?;
+        METRICS.readings_ingested(count);
+        Ok(count)
     }
 
+    /// Inserts a batch of readings. The batch is rejected as a whole if any
+    /// reading is invalid.
     pub fn insert_many(readings: &Vec<ReadingInsert>, conn: &SqliteConnection) -> Result<usize> {
         use super::schema::readings::table as readings_table;
-        insert_into(readings_table)
-            .values(readings)
-            .execute(conn)
+        for (i, reading) in readings.iter().enumerate() {
+            Self::validate(reading).map_err(|e| match e {
+                Error::InvalidReading(msg) => Self::rejected(
+                    "invalid",
+                    readings.len(),
+                    Error::InvalidReading(format!("reading {}: {}", i, msg)),
+                ),
+                e => e,
+            })?;
+        }
+        for (sensor_id, (first, _)) in Self::time_ranges(readings) {
+            Self::check_retained(sensor_id, first, conn)
+                .map_err(|e| Self::rejected("before_retention", readings.len(), e))?;
+        }
+        let total = conn.transaction(|| {
+            // Each sensor's readings are inserted separately, so that its
+            // webhooks are told how many of them it got.
+            let mut total = 0;
+            for (sensor_id, (first, last)) in Self::time_ranges(readings) {
+                let batch: Vec<&ReadingInsert> = readings
+                    .iter()
+                    .filter(|r| r.sensor_id == sensor_id)
+                    .collect();
+                let count = insert_into(readings_table).values(batch).execute(conn)?;
+                rollup::refresh(sensor_id, first, last, conn)?;
+                Self::emit_created(sensor_id, count, first, last, conn)?;
+                total += count;
+            }
+            Ok(total)
+        })?;
+        METRICS.readings_ingested(total);
+        Ok(total)
+    }
+
+    /// Counts readings that were turned away, passing on why.
+    fn rejected(reason: &'static str, n: usize, e: Error) -> Error {
+        METRICS.readings_rejected(reason, n);
+        e
+    }
+
+    /// Queues a `readings.created` event for the owner of a sensor.
+    fn emit_created(
+        sensor_id: i32,
+        count: usize,
+        first: NaiveDateTime,
+        last: NaiveDateTime,
+        conn: &SqliteConnection,
+    ) -> Result<()> {
+        let sensor = Sensor::find(sensor_id, conn)?;
+        webhook::emit(
+            sensor.owner_id,
+            webhook::READINGS_CREATED,
+            json!({
+                "sensor_id": sensor.id,
+                "hardware_id": sensor.hardware_id,
+                "count": count,
+                "first_timestamp": first.timestamp(),
+                "last_timestamp": last.timestamp(),
+            }),
+            conn,
+        )
+    }
+
+    /// Rejects readings from before a sensor's raw readings were deleted, since
+    /// the rollups of those hours can no longer be recomputed.
+    fn check_retained(
+        sensor_id: i32,
+        timestamp: NaiveDateTime,
+        conn: &SqliteConnection,
+    ) -> Result<()> {
+        match retention::raw_before(sensor_id, conn)? {
+            Some(raw_before) if timestamp < raw_before => Err(Error::InvalidReading(format!(
+                "timestamp {} is before {}, when older readings were deleted",
+                timestamp, raw_before
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The earliest and latest timestamp of the readings for each sensor.
+    fn time_ranges(readings: &[ReadingInsert]) -> BTreeMap<i32, (NaiveDateTime, NaiveDateTime)> {
+        let mut ranges: BTreeMap<i32, (NaiveDateTime, NaiveDateTime)> = BTreeMap::new();
+        for r in readings {
+            let range = ranges
+                .entry(r.sensor_id)
+                .or_insert((r.timestamp, r.timestamp));
+            range.0 = range.0.min(r.timestamp);
+            range.1 = range.1.max(r.timestamp);
+        }
+        ranges
+    }
+
+    /// Attaches extra channel values to readings.
+    pub fn with_channels(
+        readings: Vec<ReadingQuery>,
+        conn: &SqliteConnection,
+    ) -> Result<Vec<ReadingWithChannels<ReadingQuery>>> {
+        let ids: Vec<i32> = readings.iter().map(|r| r.id).collect();
+        let mut values = channel::values_for_readings(&ids, conn)?;
+        let res = readings
+            .into_iter()
+            .map(|reading| ReadingWithChannels {
+                channels: values.remove(&reading.id).unwrap_or_default(),
+                reading,
+            })
+            .collect();
+        Ok(res)
+    }
+
+    /// Returns which of the given timestamps the sensor already has readings
+    /// for.
+    pub fn existing_timestamps(
+        sensor_id: i32,
+        timestamps: &[NaiveDateTime],
+        conn: &SqliteConnection,
+    ) -> Result<Vec<NaiveDateTime>> {
+        use super::schema::readings::dsl::{
+            readings as all_readings, sensor_id as reading_sensor_id, timestamp,
+        };
+        all_readings
+            .select(timestamp)
+            .filter(reading_sensor_id.eq(sensor_id))
+            .filter(timestamp.eq_any(timestamps))
+            .load(conn)
             .map_err(|e| e.into())
     }
 
-    pub fn find_for_sensor(sensor_id: i32, conn: &SqliteConnection) -> Result<Vec<ReadingQuery>> {
+    /// The most recent readings for a sensor.
+    pub fn find_for_sensor(
+        sensor_id: i32,
+        limit: i64,
+        conn: &SqliteConnection,
+    ) -> Result<Vec<ReadingQuery>> {
         use super::schema::readings::dsl::{
             readings as all_readings, sensor_id as reading_sensor_id, timestamp,
         };
         all_readings
             .filter(reading_sensor_id.eq(sensor_id))
             .order(timestamp.desc())
+            .limit(limit)
             .load(conn)
             .map_err(|e| e.into())
     }
 
+    /// One page of a sensor's readings in a time range, ordered by timestamp
+    /// and then id. `after` is the cursor of the last reading of the previous
+    /// page. A sensor can have several readings at the same timestamp, so the
+    /// cursor's id is what keeps a page from skipping or repeating them.
     pub fn find_for_sensor_in_time_range(
         sensor_id: i32,
         start: NaiveDateTime,
         end: NaiveDateTime,
+        after: Option<Cursor>,
+        order: Order,
+        limit: i64,
         conn: &SqliteConnection,
     ) -> Result<Vec<ReadingQuery>> {
         use super::schema::readings::dsl::{
-            readings as all_readings, sensor_id as reading_sensor_id,
+            id as reading_id, readings as all_readings, sensor_id as reading_sensor_id,
             timestamp as reading_timestamp,
         };
-        all_readings
+        let mut query = all_readings
             .filter(reading_sensor_id.eq(sensor_id))
             .filter(reading_timestamp.gt(start))
             .filter(reading_timestamp.lt(end))
-            .load(conn)
-            .map_err(|e| e.into())
+            .into_boxed();
+        query = match order {
+            Order::Asc => {
+                if let Some(after) = after {
+                    query = query.filter(
+                        reading_timestamp.gt(after.timestamp).or(reading_timestamp
+                            .eq(after.timestamp)
+                            .and(reading_id.gt(after.id))),
+                    );
+                }
+                query
+                    .order(reading_timestamp.asc())
+                    .then_order_by(reading_id.asc())
+            }
+            Order::Desc => {
+                if let Some(after) = after {
+                    query = query.filter(
+                        reading_timestamp.lt(after.timestamp).or(reading_timestamp
+                            .eq(after.timestamp)
+                            .and(reading_id.lt(after.id))),
+                    );
+                }
+                query
+                    .order(reading_timestamp.desc())
+                    .then_order_by(reading_id.desc())
+            }
+        };
+        query.limit(limit).load(conn).map_err(|e| e.into())
     }
 }
 
@@ -324,11 +631,61 @@ impl Token {
     }
 }
 
+/// Where a sensor's panel is and which way it faces. Tilt is in degrees from
+/// horizontal and azimuth in degrees clockwise from north.
+#[derive(Insertable, AsChangeset, Serialize, Deserialize, Clone, Debug, Default)]
+#[table_name = "sensors"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct SensorLocation {
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+    pub elevation_m: Option<f32>,
+    pub tilt_deg: Option<f32>,
+    pub azimuth_deg: Option<f32>,
+}
+
+impl SensorLocation {
+    fn validate(&self) -> Result<()> {
+        let check = |name: &str, value: Option<f32>, min: f32, max: f32| match value {
+            Some(v) if !(v >= min && v <= max) => Err(Error::InvalidLocation(format!(
+                "{} must be between {} and {}",
+                name, min, max
+            ))),
+            _ => Ok(()),
+        };
+        check("latitude", self.latitude, -90.0, 90.0)?;
+        check("longitude", self.longitude, -180.0, 180.0)?;
+        check("elevation_m", self.elevation_m, -500.0, 9000.0)?;
+        check("tilt_deg", self.tilt_deg, 0.0, 90.0)?;
+        check("azimuth_deg", self.azimuth_deg, 0.0, 360.0)?;
+        if self.latitude.is_some() != self.longitude.is_some() {
+            return Err(Error::InvalidLocation(
+                "latitude and longitude must be set together".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Local days of a sensor with a location have to follow its real
+    /// timezone, which can't be worked out from the coordinates alone.
+    fn check_timezone(&self, timezone: Option<&String>) -> Result<()> {
+        if self.longitude.is_some() && timezone.is_none() {
+            return Err(Error::MissingTimezone);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Insertable, Serialize, Deserialize)]
 #[table_name = "sensors"]
 pub struct SensorInsert {
     pub owner_id: i32,
     pub hardware_id: i64,
+    pub model: Option<String>,
+    pub timezone: Option<String>,
+    #[diesel(embed)]
+    #[serde(flatten)]
+    pub location: SensorLocation,
 }
 
 #[derive(Serialize, Deserialize, Queryable, Debug)]
@@ -339,6 +696,65 @@ pub struct SensorQuery {
     pub active: bool,
     pub name: Option<String>,
     pub description: Option<String>,
+    pub model: Option<String>,
+    /// IANA name of the sensor's timezone, for bucketing by local day.
+    pub timezone: Option<String>,
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+    pub elevation_m: Option<f32>,
+    pub tilt_deg: Option<f32>,
+    pub azimuth_deg: Option<f32>,
+}
+
+impl SensorQuery {
+    /// The sensor's timezone, or UTC if it doesn't have one.
+    pub fn tz(&self) -> Tz {
+        self.timezone
+            .as_ref()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
+
+    pub fn location(&self) -> SensorLocation {
+        SensorLocation {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            elevation_m: self.elevation_m,
+            tilt_deg: self.tilt_deg,
+            azimuth_deg: self.azimuth_deg,
+        }
+    }
+
+    /// The sensor's panel for the clear-sky model, if it has a location. A
+    /// panel without a tilt or azimuth is assumed to be tilted at the latitude
+    /// and to face the equator.
+    pub fn site(&self) -> Option<solar::Site> {
+        let latitude = f64::from(self.latitude?);
+        Some(solar::Site {
+            latitude,
+            longitude: f64::from(self.longitude?),
+            elevation_m: self.elevation_m.map(f64::from).unwrap_or(0.0),
+            tilt_deg: self
+                .tilt_deg
+                .map(f64::from)
+                .unwrap_or_else(|| solar::default_tilt(latitude)),
+            azimuth_deg: self
+                .azimuth_deg
+                .map(f64::from)
+                .unwrap_or_else(|| solar::default_azimuth(latitude)),
+        })
+    }
+
+    /// The current date in the sensor's timezone.
+    pub fn today(&self) -> NaiveDate {
+        Utc::now().with_timezone(&self.tz()).date().naive_local()
+    }
+}
+
+/// Parses an IANA timezone name.
+pub fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse()
+        .map_err(|_| Error::InvalidTimezone(name.to_string()))
 }
 
 pub struct Sensor;
@@ -364,15 +780,43 @@ impl Sensor {
         id: i32,
         name: String,
         description: String,
+        model: Option<String>,
+        timezone: Option<String>,
         conn: &SqliteConnection,
     ) -> Result<()> {
+        if let Some(ref tz) = timezone {
+            parse_timezone(tz)?;
+        }
         update(sensors::table.find(id))
-            .set((sensors::name.eq(name), sensors::description.eq(description)))
+            .set((
+                sensors::name.eq(name),
+                sensors::description.eq(description),
+                sensors::model.eq(model),
+                sensors::timezone.eq(timezone),
+            ))
             .execute(conn)
             .map(|_| ())?;
         Ok(())
     }
 
+    /// Replaces where a sensor's panel is and which way it faces. A sensor
+    /// needs a timezone before it can have a location.
+    pub fn set_location(id: i32, location: &SensorLocation, conn: &SqliteConnection) -> Result<()> {
+        location.validate()?;
+        conn.transaction(|| {
+            let timezone: Option<String> = sensors::table
+                .find(id)
+                .select(sensors::timezone)
+                .first(conn)?;
+            location.check_timezone(timezone.as_ref())?;
+            update(sensors::table.find(id))
+                .set(location)
+                .execute(conn)
+                .map(|_| ())?;
+            Ok(())
+        })
+    }
+
     pub fn find_by_hardware_id(hardware_id: i64, conn: &SqliteConnection) -> Result<SensorQuery> {
         use super::schema::sensors::dsl::{
             active as sensor_active, hardware_id as sensor_hardware_id, sensors as all_sensors,
@@ -404,6 +848,11 @@ impl Sensor {
         };
         use diesel::dsl::count_star;
 
+        if let Some(ref tz) = sensor.timezone {
+            parse_timezone(tz)?;
+        }
+        sensor.location.validate()?;
+        sensor.location.check_timezone(sensor.timezone.as_ref())?;
         let count: i64 = all_sensors
             .select(count_star())
             .filter(sensor_hardware_id.eq(sensor.hardware_id))
@@ -414,39 +863,136 @@ impl Sensor {
             return Err(Error::DuplicateHardwareId(sensor.hardware_id));
         }
 
-        insert_into(sensors_table)
-            .values(sensor)
-            .execute(conn)
-            .map_err(|e| e.into())
+        conn.transaction(|| {
+            let count = insert_into(sensors_table).values(sensor).execute(conn)?;
+            let added = Self::find_by_hardware_id(sensor.hardware_id, conn)?;
+            webhook::emit(
+                added.owner_id,
+                webhook::SENSOR_ADDED,
+                json!({ "sensor": added }),
+                conn,
+            )?;
+            Ok(count)
+        })
     }
 
     pub fn deactivate(conn: &SqliteConnection, id: i32) -> Result<()> {
-        update(sensors::table.find(id))
-            .set(sensors::active.eq(false))
-            .execute(conn)
-            .map(|_| ())?;
-        Ok(())
+        conn.transaction(|| {
+            update(sensors::table.find(id))
+                .set(sensors::active.eq(false))
+                .execute(conn)?;
+            let sensor = Self::find(id, conn)?;
+            webhook::emit(
+                sensor.owner_id,
+                webhook::SENSOR_DEACTIVATED,
+                json!({ "sensor": sensor }),
+                conn,
+            )
+        })
     }
 
-    pub fn energy_stats(sensor_id: i32, conn: &SqliteConnection) -> Result<Vec<Energy>> {
-        let query = format!(
-"
+    /// Gives a sensor to another user. Both owners' webhooks are told.
+    pub fn transfer(id: i32, new_owner_id: i32, conn: &SqliteConnection) -> Result<()> {
+        conn.transaction(|| {
+            let previous_owner_id = Self::find(id, conn)?.owner_id;
+            update(sensors::table.find(id))
+                .set(sensors::owner_id.eq(new_owner_id))
+                .execute(conn)?;
+            let sensor = Self::find(id, conn)?;
+            let data = json!({
+                "sensor": sensor,
+                "previous_owner_id": previous_owner_id,
+                "new_owner_id": new_owner_id,
+            });
+            webhook::emit(
+                previous_owner_id,
+                webhook::SENSOR_TRANSFERRED,
+                data.clone(),
+                conn,
+            )?;
+            if new_owner_id != previous_owner_id {
+                webhook::emit(new_owner_id, webhook::SENSOR_TRANSFERRED, data, conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Daily energy stats for the local days `start..=end` in the sensor's
+    /// timezone, from the integral of peak power in the hourly rollups. Days
+    /// whose hourly rollups were deleted fall back to the daily rollups, which
+    /// are UTC days priced at the flat tariff.
+    pub fn energy_stats(
+        sensor: &SensorQuery,
+        start: NaiveDate,
+        end: NaiveDate,
+        conn: &SqliteConnection,
+    ) -> Result<Vec<Energy>> {
+        let query = "
 with
- src as (select * from readings where sensor_id = {sensor_id} and timestamp > date('now', '-{lookback_days} days')),
- wins as (select peak_power_mW as pa, timestamp as ta, lead(peak_power_mW) over win as pb, lead(timestamp) over win as tb from src window win as (order by timestamp asc)),
- filtered as (select * from wins where tb is not null),
- calcs as (select ta as ts, min(pa, pb) as p0, abs(pa-pb) as dp, (julianday(tb)-julianday(ta))*(24) as dt from filtered),
- areas as (select ts, p0*dt + dp*dt*0.5 as mWh from calcs),
- by_day as (select date(ts) as date, sum(mWh) as sol_mWh from areas group by date),
- stats1 as (select *, sol_mWh*7895 as equiv_mWh, sol_mWh/(570*24) as cap_factor from by_day),
- stats2 as (select *, equiv_mWh/(1000*1000) as equiv_kWh from stats1),
- stats3 as (select *, equiv_kWh*0.12 as dollars_saved, equiv_kWh*1.6 as co2_saved from stats2)
-select * from stats3;
-",
-            sensor_id = sensor_id,
-            lookback_days = 10
-        );
-        let res: Vec<Energy> = sql_query(query).load(conn)?;
-        Ok(res)
+ hours as (
+  select d.date, h.integral as mWh,
+   coalesce((
+    select t.rate_per_kWh from tariff_rates t
+    where t.sensor_id = ?1
+     and t.start_hour <= cast(strftime('%H', h.bucket, d.utc_offset || ' seconds') as integer)
+     and cast(strftime('%H', h.bucket, d.utc_offset || ' seconds') as integer) < t.end_hour
+   ), ?3) as rate
+  from temp.local_days d
+  join hourly_rollups h on h.bucket >= d.seg_start and h.bucket < d.seg_end
+  where h.sensor_id = ?1 and h.channel = 'peak_power_mW' and h.bucket >= ?2
+ ),
+ old_days as (
+  select date(bucket) as date, integral as mWh, ?3 as rate from daily_rollups
+  where sensor_id = ?1 and channel = 'peak_power_mW' and bucket < ?2
+   and date(bucket) between (select min(date) from temp.local_days) and (select max(date) from temp.local_days)
+ ),
+ day_hours as (
+  select date, sum((julianday(seg_end) - julianday(seg_start)) * 24) as hours
+  from temp.local_days
+  group by date
+ ),
+ by_day as (
+  select date, sum(mWh) as sol_mWh, sum(mWh * rate) as priced_mWh
+  from (select * from hours union all select * from old_days)
+  group by date
+ )
+select
+ b.date,
+ b.sol_mWh * ?4 / (1000 * 1000) as equiv_kWh,
+ b.sol_mWh / (?5 * coalesce(d.hours, 24)) as cap_factor,
+ b.priced_mWh * ?4 / (1000 * 1000) as dollars_saved,
+ b.sol_mWh * ?4 / (1000 * 1000) * ?6 as co2_saved
+from by_day b
+left join day_hours d on d.date = b.date
+order by b.date;
+";
+        let settings = energy::settings(sensor.id, conn)?;
+        let hourly_before = retention::cutoffs(sensor.id, conn)?
+            .and_then(|c| c.hourly_before)
+            .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+        conn.transaction(|| {
+            sql_query(
+                "create temp table if not exists local_days (date text not null, seg_start datetime not null, seg_end datetime not null, utc_offset integer not null)",
+            )
+            .execute(conn)?;
+            sql_query("delete from temp.local_days").execute(conn)?;
+            for day in aggregate::local_days(sensor.tz(), start, end) {
+                sql_query("insert into temp.local_days values (?, ?, ?, ?)")
+                    .bind::<Text, _>(day.date.to_string())
+                    .bind::<Timestamp, _>(day.start)
+                    .bind::<Timestamp, _>(day.end)
+                    .bind::<Integer, _>(day.utc_offset)
+                    .execute(conn)?;
+            }
+            let res: Vec<Energy> = sql_query(query)
+                .bind::<Integer, _>(sensor.id)
+                .bind::<Timestamp, _>(hourly_before)
+                .bind::<Float, _>(settings.tariff_per_kWh())
+                .bind::<Double, _>(settings.scale())
+                .bind::<Float, _>(settings.panel_rated_mW())
+                .bind::<Float, _>(settings.emissions_per_kWh())
+                .load(conn)?;
+            Ok(res)
+        })
     }
 }
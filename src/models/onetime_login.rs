@@ -39,3 +39,10 @@ pub fn find(tok: &String, conn: &SqliteConnection) -> Result<OnetimeLogin> {
     let cred = onetime_logins.filter(token.eq(tok)).first(conn)?;
     Ok(cred)
 }
+
+/// Deletes logins that expired before `now`, returning how many there were.
+pub fn purge_expired(now: NaiveDateTime, conn: &SqliteConnection) -> Result<usize> {
+    use crate::schema::onetime_logins::dsl::{expires, onetime_logins};
+    let n = diesel::delete(onetime_logins.filter(expires.lt(now))).execute(conn)?;
+    Ok(n)
+}
@@ -0,0 +1,162 @@
+//! How much of its clear-sky output a sensor's panel makes, to spot panels
+//! that are dirty, shaded or failing.
+
+use crate::{
+    models::{aggregate, energy, solar, SensorQuery},
+    result::{Error, Result},
+    schema::hourly_rollups,
+};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use std::collections::BTreeMap;
+
+/// A panel is flagged when even its best day makes less than this fraction of
+/// its clear-sky energy. Clouds only ever lower the ratio, so a single clear
+/// day in the range is enough to show a panel is fine.
+pub const UNDERPERFORMING_RATIO: f64 = 0.6;
+
+/// Days with readings needed before a panel can be flagged.
+pub const MIN_DAYS: usize = 3;
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct DailyPerformance {
+    pub date: NaiveDate,
+    pub measured_mWh: f64,
+    pub expected_mWh: f64,
+    /// Measured over clear-sky energy, or `None` if the sun never rose.
+    pub performance_ratio: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Performance {
+    pub days: Vec<DailyPerformance>,
+    pub best_ratio: Option<f64>,
+    pub underperforming: bool,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct ClearSkyPoint {
+    pub timestamp: NaiveDateTime,
+    pub zenith_deg: f64,
+    pub ghi: f64,
+    pub poa: f64,
+    pub expected_mW: f64,
+}
+
+fn site(sensor: &SensorQuery) -> Result<solar::Site> {
+    sensor.site().ok_or(Error::MissingLocation)
+}
+
+#[allow(non_snake_case)]
+fn rated_mW(sensor: &SensorQuery, conn: &SqliteConnection) -> Result<f64> {
+    Ok(f64::from(
+        energy::settings(sensor.id, conn)?.panel_rated_mW(),
+    ))
+}
+
+/// Clear-sky irradiance and expected power of a sensor's panel every
+/// `solar::STEP_SECS` from `start` until `end`.
+pub fn clear_sky(
+    sensor: &SensorQuery,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<Vec<ClearSkyPoint>> {
+    let site = site(sensor)?;
+    let rated = rated_mW(sensor, conn)?;
+    let n = (end - start).num_seconds() / solar::STEP_SECS;
+    if n > aggregate::MAX_BUCKETS {
+        return Err(Error::TooManyBuckets(n));
+    }
+    let mut res = Vec::new();
+    let mut t = start;
+    while t < end {
+        let sun = solar::position(&site, t);
+        let irradiance = solar::clear_sky(&site, t);
+        res.push(ClearSkyPoint {
+            timestamp: t,
+            zenith_deg: sun.zenith_deg,
+            ghi: irradiance.ghi,
+            poa: irradiance.poa,
+            expected_mW: solar::expected_power_mW(&site, rated, t),
+        });
+        t += Duration::seconds(solar::STEP_SECS);
+    }
+    Ok(res)
+}
+
+/// Measured and clear-sky energy of a sensor's panel for each local day in
+/// `start..=end` that has readings. Measured energy comes from the hourly
+/// rollups, so days whose hourly rollups were deleted are left out.
+pub fn performance(
+    sensor: &SensorQuery,
+    start: NaiveDate,
+    end: NaiveDate,
+    conn: &SqliteConnection,
+) -> Result<Performance> {
+    let site = site(sensor)?;
+    let rated = rated_mW(sensor, conn)?;
+    let segments = aggregate::local_days(sensor.tz(), start, end);
+    let mut measured: BTreeMap<NaiveDate, f64> = BTreeMap::new();
+    if let (Some(first), Some(last)) = (segments.first(), segments.last()) {
+        let hours: Vec<(NaiveDateTime, f32)> = hourly_rollups::table
+            .select((hourly_rollups::bucket, hourly_rollups::integral))
+            .filter(hourly_rollups::sensor_id.eq(sensor.id))
+            .filter(hourly_rollups::channel.eq("peak_power_mW"))
+            .filter(hourly_rollups::bucket.ge(first.start))
+            .filter(hourly_rollups::bucket.lt(last.end))
+            .order(hourly_rollups::bucket)
+            .load(conn)?;
+        // Both are sorted, so each hour's day is found by walking the
+        // segments alongside the hours.
+        let mut seg = segments.iter().peekable();
+        for (bucket, integral) in hours {
+            while seg.peek().map_or(false, |s| s.end <= bucket) {
+                seg.next();
+            }
+            if let Some(s) = seg.peek().filter(|s| s.start <= bucket) {
+                *measured.entry(s.date).or_insert(0.0) += f64::from(integral);
+            }
+        }
+    }
+    let mut by_date: BTreeMap<NaiveDate, Vec<&aggregate::DaySegment>> = BTreeMap::new();
+    for s in &segments {
+        by_date.entry(s.date).or_insert_with(Vec::new).push(s);
+    }
+
+    let days: Vec<DailyPerformance> = measured
+        .into_iter()
+        .map(|(date, measured_mWh)| {
+            let expected_mWh: f64 = by_date[&date]
+                .iter()
+                .map(|s| solar::expected_energy_mWh(&site, rated, s.start, s.end))
+                .sum();
+            let performance_ratio = if expected_mWh > 0.0 {
+                Some(measured_mWh / expected_mWh)
+            } else {
+                None
+            };
+            DailyPerformance {
+                date,
+                measured_mWh,
+                expected_mWh,
+                performance_ratio,
+            }
+        })
+        .collect();
+    let best_ratio = days
+        .iter()
+        .filter_map(|d| d.performance_ratio)
+        .fold(None, |best: Option<f64>, r| {
+            Some(best.map_or(r, |b| b.max(r)))
+        });
+    let underperforming =
+        days.len() >= MIN_DAYS && best_ratio.map_or(false, |r| r < UNDERPERFORMING_RATIO);
+    Ok(Performance {
+        days,
+        best_ratio,
+        underperforming,
+    })
+}
@@ -0,0 +1,81 @@
+//! Raised rate limits, and token buckets kept in the database.
+
+use crate::{
+    result::Result,
+    schema::{rate_limit_buckets, rate_limit_overrides},
+};
+use chrono::NaiveDateTime;
+use diesel::{delete, prelude::*, replace_into};
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct Override {
+    pub principal: String,
+    pub multiplier: i32,
+    pub created: NaiveDateTime,
+}
+
+pub fn overrides(conn: &SqliteConnection) -> Result<Vec<Override>> {
+    rate_limit_overrides::table
+        .order(rate_limit_overrides::principal.asc())
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+/// How many times the usual limits `principal` gets, if an admin raised them.
+pub fn multiplier(principal: &str, conn: &SqliteConnection) -> Result<Option<i32>> {
+    rate_limit_overrides::table
+        .find(principal)
+        .select(rate_limit_overrides::multiplier)
+        .first(conn)
+        .optional()
+        .map_err(|e| e.into())
+}
+
+/// Gives `principal` `multiplier` times the usual limits, or the usual limits
+/// again at 1.
+pub fn set_multiplier(principal: &str, multiplier: i32, conn: &SqliteConnection) -> Result<()> {
+    if multiplier == 1 {
+        delete(rate_limit_overrides::table.find(principal)).execute(conn)?;
+    } else {
+        replace_into(rate_limit_overrides::table)
+            .values((
+                rate_limit_overrides::principal.eq(principal),
+                rate_limit_overrides::multiplier.eq(multiplier),
+            ))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// The tokens taken from a bucket, and when it last changed.
+pub fn bucket(key: &str, conn: &SqliteConnection) -> Result<Option<(f64, NaiveDateTime)>> {
+    rate_limit_buckets::table
+        .find(key)
+        .select((rate_limit_buckets::used, rate_limit_buckets::updated))
+        .first(conn)
+        .optional()
+        .map_err(|e| e.into())
+}
+
+pub fn save_bucket(
+    key: &str,
+    used: f64,
+    updated: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    replace_into(rate_limit_buckets::table)
+        .values((
+            rate_limit_buckets::key.eq(key),
+            rate_limit_buckets::used.eq(used),
+            rate_limit_buckets::updated.eq(updated),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Deletes buckets unused since `before`. Returns how many were deleted.
+pub fn purge_buckets(before: NaiveDateTime, conn: &SqliteConnection) -> Result<usize> {
+    delete(rate_limit_buckets::table.filter(rate_limit_buckets::updated.lt(before)))
+        .execute(conn)
+        .map_err(|e| e.into())
+}
@@ -0,0 +1,213 @@
+//! How far back a sensor's raw readings and hourly rollups still go, and
+//! reading lookups that fall back to rollups before that.
+use crate::{
+    models::{Cursor, Order, Reading, ReadingQuery, ReadingWithChannels},
+    result::Result,
+    schema::retention_cutoffs,
+};
+use chrono::{Duration, NaiveDateTime};
+use diesel::{
+    prelude::*,
+    replace_into, sql_query,
+    sql_types::{BigInt, Float, Integer, Text, Timestamp},
+    Insertable, Queryable,
+};
+use std::collections::BTreeMap;
+
+/// Raw readings before `raw_before` and hourly rollups before
+/// `hourly_before` have been deleted. Both are at midnight UTC.
+#[derive(Insertable, Queryable, Serialize, Clone, Copy, Debug)]
+#[table_name = "retention_cutoffs"]
+pub struct Cutoffs {
+    pub sensor_id: i32,
+    pub raw_before: NaiveDateTime,
+    pub hourly_before: Option<NaiveDateTime>,
+}
+
+/// A sensor's cutoffs, or `None` if nothing has been deleted yet.
+pub fn cutoffs(sensor_id: i32, conn: &SqliteConnection) -> Result<Option<Cutoffs>> {
+    use crate::schema::retention_cutoffs::dsl::{retention_cutoffs, sensor_id as c_sensor_id};
+
+    let res = retention_cutoffs
+        .filter(c_sensor_id.eq(sensor_id))
+        .first(conn)
+        .optional()?;
+    Ok(res)
+}
+
+/// The time before which a sensor has no raw readings left.
+pub fn raw_before(sensor_id: i32, conn: &SqliteConnection) -> Result<Option<NaiveDateTime>> {
+    Ok(cutoffs(sensor_id, conn)?.map(|c| c.raw_before))
+}
+
+/// Records new cutoffs for a sensor. Cutoffs never move back in time.
+pub fn advance(cutoffs: Cutoffs, conn: &SqliteConnection) -> Result<()> {
+    let new = match self::cutoffs(cutoffs.sensor_id, conn)? {
+        Some(old) => Cutoffs {
+            sensor_id: cutoffs.sensor_id,
+            raw_before: old.raw_before.max(cutoffs.raw_before),
+            hourly_before: old.hourly_before.max(cutoffs.hourly_before),
+        },
+        None => cutoffs,
+    };
+    replace_into(retention_cutoffs::table)
+        .values(&new)
+        .execute(conn)?;
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct RollupMean {
+    #[sql_type = "Timestamp"]
+    bucket: NaiveDateTime,
+    #[sql_type = "Text"]
+    channel: String,
+    #[sql_type = "Float"]
+    mean: f32,
+}
+
+/// Readings made from the hourly or daily means in a rollup table, one per
+/// bucket, timestamped at the start of the bucket and with an id of 0.
+fn rollup_readings(
+    table: &str,
+    sensor_id: i32,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    after: Option<NaiveDateTime>,
+    order: Order,
+    limit: i64,
+    conn: &SqliteConnection,
+) -> Result<Vec<ReadingWithChannels<ReadingQuery>>> {
+    let (op, dir, after) = match order {
+        Order::Asc => (">", "asc", after.unwrap_or(start - Duration::seconds(1))),
+        Order::Desc => ("<", "desc", after.unwrap_or(end)),
+    };
+    let query = format!(
+        "
+with buckets as (
+ select distinct bucket from {table}
+ where sensor_id = ?1 and bucket >= ?2 and bucket < ?3 and bucket {op} ?4
+ order by bucket {dir}
+ limit ?5
+)
+select r.bucket, r.channel, r.sum / r.count as mean
+from {table} r join buckets b on r.bucket = b.bucket
+where r.sensor_id = ?1
+order by r.bucket {dir}, r.channel;
+",
+        table = table,
+        op = op,
+        dir = dir
+    );
+    let rows: Vec<RollupMean> = sql_query(query)
+        .bind::<Integer, _>(sensor_id)
+        .bind::<Timestamp, _>(start)
+        .bind::<Timestamp, _>(end)
+        .bind::<Timestamp, _>(after)
+        .bind::<BigInt, _>(limit)
+        .load(conn)?;
+
+    let mut buckets: Vec<(NaiveDateTime, BTreeMap<String, f32>)> = Vec::new();
+    for row in rows {
+        if buckets.last().map(|b| b.0) != Some(row.bucket) {
+            buckets.push((row.bucket, BTreeMap::new()));
+        }
+        if let Some(b) = buckets.last_mut() {
+            b.1.insert(row.channel, row.mean);
+        }
+    }
+    let res = buckets
+        .into_iter()
+        .map(|(bucket, mut means)| {
+            let mut take = |name: &str| means.remove(name).unwrap_or(0.0);
+            let reading = ReadingQuery {
+                id: 0,
+                sensor_id,
+                timestamp: bucket,
+                peak_power_mW: take("peak_power_mW"),
+                peak_current_mA: take("peak_current_mA"),
+                peak_voltage_V: take("peak_voltage_V"),
+                temp_celsius: take("temp_celsius"),
+                batt_V: take("batt_V"),
+                created: bucket,
+            };
+            ReadingWithChannels {
+                reading,
+                channels: means,
+            }
+        })
+        .collect();
+    Ok(res)
+}
+
+/// Like `Reading::find_for_sensor_in_time_range`, but where raw readings have
+/// been deleted, hourly means stand in for them, and daily means where hourly
+/// rollups have been deleted too.
+pub fn find_readings(
+    sensor_id: i32,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    after: Option<Cursor>,
+    order: Order,
+    limit: i64,
+    conn: &SqliteConnection,
+) -> Result<Vec<ReadingWithChannels<ReadingQuery>>> {
+    let cutoffs = match cutoffs(sensor_id, conn)? {
+        Some(c) if start < c.raw_before => c,
+        _ => {
+            let readings = Reading::find_for_sensor_in_time_range(
+                sensor_id, start, end, after, order, limit, conn,
+            )?;
+            return Reading::with_channels(readings, conn);
+        }
+    };
+
+    // Oldest first. Raw readings before the cutoff are already gone, so the
+    // raw tier can use the whole range.
+    let hourly_start = cutoffs.hourly_before.unwrap_or(start).max(start);
+    let mut tiers = vec![
+        (
+            Some("hourly_rollups"),
+            hourly_start,
+            end.min(cutoffs.raw_before),
+        ),
+        (None, start, end),
+    ];
+    if let Some(hourly_before) = cutoffs.hourly_before {
+        tiers.insert(0, (Some("daily_rollups"), start, end.min(hourly_before)));
+    }
+    if order == Order::Desc {
+        tiers.reverse();
+    }
+
+    let mut res = Vec::new();
+    for (table, tier_start, tier_end) in tiers {
+        let remaining = limit - res.len() as i64;
+        if remaining <= 0 {
+            break;
+        }
+        if tier_start >= tier_end {
+            continue;
+        }
+        match table {
+            // Buckets are unique, so their timestamp is enough of a cursor.
+            Some(table) => res.extend(rollup_readings(
+                table,
+                sensor_id,
+                tier_start,
+                tier_end,
+                after.map(|c| c.timestamp),
+                order,
+                remaining,
+                conn,
+            )?),
+            None => {
+                let readings = Reading::find_for_sensor_in_time_range(
+                    sensor_id, tier_start, tier_end, after, order, remaining, conn,
+                )?;
+                res.extend(Reading::with_channels(readings, conn)?);
+            }
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,312 @@
+//! Hourly and daily rollups of every channel of every sensor.
+//!
+//! Rollups are recomputed for the hours touched whenever readings are
+//! inserted, so stats and charts never have to scan raw readings. `rebuild`
+//! recomputes a sensor's rollups from scratch and `check` compares them with
+//! its raw readings.
+use crate::{models::retention, result::Result};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Timelike};
+use diesel::{
+    dsl::{max, min},
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text, Timestamp},
+};
+
+/// Fills `{table}` with hourly rollups of sensor `?1` for readings in
+/// `?2..?3`. The first reading after the range is included so the integral of
+/// the last segment is counted.
+const HOURLY_SQL: &str = "
+with
+ next as (select coalesce((select min(timestamp) from readings where sensor_id = ?1 and timestamp >= ?3), ?3) as ts),
+ src as (
+  select timestamp, channel, value from reading_channel_values
+  where sensor_id = ?1 and timestamp >= ?2 and timestamp <= (select ts from next)
+ ),
+ wins as (
+  select timestamp, channel, value,
+   lead(value) over win as next_value,
+   lead(timestamp) over win as next_timestamp,
+   row_number() over (partition by strftime('%Y-%m-%d %H:00:00', timestamp), channel order by timestamp desc) as rn
+  from src
+  window win as (partition by channel order by timestamp asc)
+ )
+insert into {table} (sensor_id, bucket, channel, count, min, max, sum, last, last_timestamp, integral)
+select
+ ?1,
+ strftime('%Y-%m-%d %H:00:00', timestamp) as hour,
+ channel,
+ count(*),
+ min(value),
+ max(value),
+ sum(value),
+ max(case when rn = 1 then value end),
+ max(timestamp),
+ coalesce(sum((min(value, next_value) + abs(value - next_value) * 0.5) * (julianday(next_timestamp) - julianday(timestamp)) * 24), 0)
+from wins
+where timestamp < ?3
+group by hour, channel;
+";
+
+/// Fills `{table}` with daily rollups of sensor `?1` in `?2..?3` from its
+/// hourly rollups.
+const DAILY_SQL: &str = "
+with
+ ranked as (
+  select *, row_number() over (partition by date(bucket), channel order by bucket desc) as rn
+  from hourly_rollups
+  where sensor_id = ?1 and bucket >= ?2 and bucket < ?3
+ )
+insert into {table} (sensor_id, bucket, channel, count, min, max, sum, last, last_timestamp, integral)
+select
+ ?1,
+ date(bucket) || ' 00:00:00' as day,
+ channel,
+ sum(count),
+ min(min),
+ max(max),
+ sum(sum),
+ max(case when rn = 1 then last end),
+ max(last_timestamp),
+ sum(integral)
+from ranked
+group by day, channel;
+";
+
+pub fn hour_start(ts: NaiveDateTime) -> NaiveDateTime {
+    ts.date().and_hms(ts.hour(), 0, 0)
+}
+
+pub fn day_start(ts: NaiveDateTime) -> NaiveDateTime {
+    ts.date().and_hms(0, 0, 0)
+}
+
+fn delete_range(
+    table: &str,
+    sensor_id: i32,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    sql_query(format!(
+        "delete from {} where sensor_id = ? and bucket >= ? and bucket < ?",
+        table
+    ))
+    .bind::<Integer, _>(sensor_id)
+    .bind::<Timestamp, _>(start)
+    .bind::<Timestamp, _>(end)
+    .execute(conn)?;
+    Ok(())
+}
+
+fn fill(
+    sql: &str,
+    table: &str,
+    sensor_id: i32,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    sql_query(sql.replace("{table}", table))
+        .bind::<Integer, _>(sensor_id)
+        .bind::<Timestamp, _>(start)
+        .bind::<Timestamp, _>(end)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Recomputes a sensor's rollups for the hours between `first` and `last`,
+/// the earliest and latest timestamps of readings that were just inserted.
+/// The hour of the reading before `first` is recomputed too, since its
+/// integral now ends at a different reading.
+pub fn refresh(
+    sensor_id: i32,
+    first: NaiveDateTime,
+    last: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    use crate::schema::readings::dsl::{readings, sensor_id as r_sensor_id, timestamp};
+
+    let previous: Option<NaiveDateTime> = readings
+        .select(max(timestamp))
+        .filter(r_sensor_id.eq(sensor_id))
+        .filter(timestamp.lt(first))
+        .first(conn)?;
+    let start = hour_start(previous.unwrap_or(first));
+    let end = hour_start(last) + Duration::hours(1);
+    conn.transaction(|| {
+        delete_range("hourly_rollups", sensor_id, start, end, conn)?;
+        fill(HOURLY_SQL, "hourly_rollups", sensor_id, start, end, conn)?;
+        let (start, end) = (
+            day_start(start),
+            day_start(end - Duration::seconds(1)) + Duration::days(1),
+        );
+        delete_range("daily_rollups", sensor_id, start, end, conn)?;
+        fill(DAILY_SQL, "daily_rollups", sensor_id, start, end, conn)
+    })
+}
+
+/// The range of a sensor's rollups that can be recomputed from raw readings,
+/// which is all of them until old raw readings have been deleted.
+fn retained_range(
+    sensor_id: i32,
+    conn: &SqliteConnection,
+) -> Result<(NaiveDateTime, NaiveDateTime)> {
+    let start = retention::raw_before(sensor_id, conn)?
+        .unwrap_or_else(|| NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0));
+    Ok((start, NaiveDate::from_ymd(9999, 1, 1).and_hms(0, 0, 0)))
+}
+
+/// Recomputes a sensor's rollups for every reading it has. Rollups from before
+/// its raw readings were deleted are kept.
+pub fn rebuild(sensor_id: i32, conn: &SqliteConnection) -> Result<()> {
+    use crate::schema::readings::dsl::{readings, sensor_id as r_sensor_id, timestamp};
+
+    let (first, last): (Option<NaiveDateTime>, Option<NaiveDateTime>) = readings
+        .select((min(timestamp), max(timestamp)))
+        .filter(r_sensor_id.eq(sensor_id))
+        .first(conn)?;
+    let (start, end) = retained_range(sensor_id, conn)?;
+    conn.transaction(|| {
+        delete_range("hourly_rollups", sensor_id, start, end, conn)?;
+        delete_range("daily_rollups", sensor_id, start, end, conn)?;
+        match (first, last) {
+            (Some(first), Some(last)) => refresh(sensor_id, first, last, conn),
+            _ => Ok(()),
+        }
+    })
+}
+
+/// Rebuilds the rollups of every sensor, returning how many were rebuilt.
+pub fn rebuild_all(conn: &SqliteConnection) -> Result<usize> {
+    use crate::schema::sensors::dsl::{id, sensors};
+
+    let ids: Vec<i32> = sensors.select(id).load(conn)?;
+    for &sensor_id in &ids {
+        rebuild(sensor_id, conn)?;
+    }
+    Ok(ids.len())
+}
+
+/// A rollup row that doesn't match what its readings say it should be.
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct Mismatch {
+    #[sql_type = "Text"]
+    pub period: String,
+    #[sql_type = "Timestamp"]
+    pub bucket: NaiveDateTime,
+    #[sql_type = "Text"]
+    pub channel: String,
+    #[sql_type = "Nullable<BigInt>"]
+    pub expected_count: Option<i64>,
+    #[sql_type = "Nullable<BigInt>"]
+    pub actual_count: Option<i64>,
+}
+
+/// Rows of `actual` from `?2` on that are missing from, extra to, or differ
+/// from `expected`, for sensor `?1`. Float sums are compared with a tolerance.
+const MISMATCH_SQL: &str = "
+select '{period}' as period, e.bucket, e.channel, e.count as expected_count, a.count as actual_count
+from {expected} e
+left join {actual} a on a.sensor_id = e.sensor_id and a.bucket = e.bucket and a.channel = e.channel
+where e.sensor_id = ?1 and (
+ a.bucket is null or a.count != e.count or a.min != e.min or a.max != e.max or a.last != e.last
+ or a.last_timestamp != e.last_timestamp
+ or abs(a.sum - e.sum) > 0.001 * max(1, abs(e.sum))
+ or abs(a.integral - e.integral) > 0.001 * max(1, abs(e.integral))
+)
+union all
+select '{period}' as period, a.bucket, a.channel, null as expected_count, a.count as actual_count
+from {actual} a
+left join {expected} e on e.sensor_id = a.sensor_id and e.bucket = a.bucket and e.channel = a.channel
+where a.sensor_id = ?1 and a.bucket >= ?2 and e.bucket is null
+order by bucket, channel;
+";
+
+fn mismatches(
+    period: &str,
+    expected: &str,
+    actual: &str,
+    sensor_id: i32,
+    start: NaiveDateTime,
+    conn: &SqliteConnection,
+) -> Result<Vec<Mismatch>> {
+    let query = MISMATCH_SQL
+        .replace("{period}", period)
+        .replace("{expected}", expected)
+        .replace("{actual}", actual);
+    let res = sql_query(query)
+        .bind::<Integer, _>(sensor_id)
+        .bind::<Timestamp, _>(start)
+        .load(conn)?;
+    Ok(res)
+}
+
+/// Recomputes a sensor's rollups from its raw readings into temporary tables
+/// and returns every stored rollup that differs. The stored rollups are left
+/// untouched, and those from before raw readings were deleted aren't checked.
+pub fn check(sensor_id: i32, conn: &SqliteConnection) -> Result<Vec<Mismatch>> {
+    let (start, end) = retained_range(sensor_id, conn)?;
+    conn.transaction(|| {
+        for table in &["hourly_rollups", "daily_rollups"] {
+            sql_query(format!(
+                "create temp table if not exists expected_{0} as select * from main.{0} where 0",
+                table
+            ))
+            .execute(conn)?;
+            sql_query(format!("delete from temp.expected_{}", table)).execute(conn)?;
+        }
+        fill(
+            HOURLY_SQL,
+            "temp.expected_hourly_rollups",
+            sensor_id,
+            start,
+            end,
+            conn,
+        )?;
+        // Expected daily rollups are built from the expected hourly ones, so a
+        // bad hour is only reported once.
+        let daily = DAILY_SQL.replace("from hourly_rollups", "from temp.expected_hourly_rollups");
+        fill(
+            &daily,
+            "temp.expected_daily_rollups",
+            sensor_id,
+            start,
+            end,
+            conn,
+        )?;
+
+        let mut res = mismatches(
+            "hour",
+            "temp.expected_hourly_rollups",
+            "main.hourly_rollups",
+            sensor_id,
+            start,
+            conn,
+        )?;
+        res.extend(mismatches(
+            "day",
+            "temp.expected_daily_rollups",
+            "main.daily_rollups",
+            sensor_id,
+            start,
+            conn,
+        )?);
+        Ok(res)
+    })
+}
+
+/// The number of readings of every sensor, counted from the daily rollups.
+pub fn reading_count(conn: &SqliteConnection) -> Result<i64> {
+    #[derive(QueryableByName)]
+    struct Count {
+        #[sql_type = "BigInt"]
+        count: i64,
+    }
+
+    let res: Count = sql_query(
+        "select coalesce(sum(count), 0) as count from daily_rollups where channel = 'peak_power_mW'",
+    )
+    .get_result(conn)?;
+    Ok(res.count)
+}
@@ -0,0 +1,179 @@
+//! Solar position and clear-sky irradiance, for estimating what a sensor's
+//! panel would produce under a cloudless sky. The sun's position follows
+//! NOAA's solar calculator, and the clear-sky model is Meinel's direct beam
+//! with Laue's altitude correction, so nothing needs an external service.
+
+use chrono::{Duration, NaiveDateTime, Timelike};
+
+/// Irradiance above the atmosphere, in W/m².
+const SOLAR_CONSTANT: f64 = 1353.0;
+
+/// Irradiance that panels are rated at, in W/m².
+const RATED_IRRADIANCE: f64 = 1000.0;
+
+/// Fraction of irradiance the ground reflects onto a tilted panel.
+const ALBEDO: f64 = 0.2;
+
+/// How often expected power is sampled when integrating it into energy.
+pub const STEP_SECS: i64 = 300;
+
+/// Where a panel is and which way it faces. Azimuth is in degrees clockwise
+/// from north, and tilt in degrees from horizontal.
+#[derive(Clone, Copy, Debug)]
+pub struct Site {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub elevation_m: f64,
+    pub tilt_deg: f64,
+    pub azimuth_deg: f64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Position {
+    pub zenith_deg: f64,
+    pub azimuth_deg: f64,
+}
+
+/// Clear-sky irradiance in W/m²: global horizontal, direct normal, diffuse
+/// horizontal, and the total on the plane of the panel.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Irradiance {
+    pub ghi: f64,
+    pub dni: f64,
+    pub dhi: f64,
+    pub poa: f64,
+}
+
+fn sin_deg(d: f64) -> f64 {
+    d.to_radians().sin()
+}
+
+fn cos_deg(d: f64) -> f64 {
+    d.to_radians().cos()
+}
+
+/// The sun's position seen from `site` at `t`, in UTC.
+pub fn position(site: &Site, t: NaiveDateTime) -> Position {
+    let jd = t.timestamp() as f64 / 86400.0 + 2_440_587.5;
+    let jc = (jd - 2_451_545.0) / 36525.0;
+
+    let mean_long = (280.46646 + jc * (36000.76983 + jc * 0.0003032)) % 360.0;
+    let mean_anom = 357.52911 + jc * (35999.05029 - 0.0001537 * jc);
+    let ecc = 0.016708634 - jc * (0.000042037 + 0.0000001267 * jc);
+    let center = sin_deg(mean_anom) * (1.914602 - jc * (0.004817 + 0.000014 * jc))
+        + sin_deg(2.0 * mean_anom) * (0.019993 - 0.000101 * jc)
+        + sin_deg(3.0 * mean_anom) * 0.000289;
+    let omega = 125.04 - 1934.136 * jc;
+    let app_long = mean_long + center - 0.00569 - 0.00478 * sin_deg(omega);
+    let mean_obliq =
+        23.0 + (26.0 + (21.448 - jc * (46.815 + jc * (0.00059 - jc * 0.001813))) / 60.0) / 60.0;
+    let obliq = mean_obliq + 0.00256 * cos_deg(omega);
+    let decl = (sin_deg(obliq) * sin_deg(app_long)).asin().to_degrees();
+
+    let y = (obliq / 2.0).to_radians().tan().powi(2);
+    let eq_time = 4.0
+        * (y * sin_deg(2.0 * mean_long) - 2.0 * ecc * sin_deg(mean_anom)
+            + 4.0 * ecc * y * sin_deg(mean_anom) * cos_deg(2.0 * mean_long)
+            - 0.5 * y * y * sin_deg(4.0 * mean_long)
+            - 1.25 * ecc * ecc * sin_deg(2.0 * mean_anom))
+        .to_degrees();
+
+    let minutes = f64::from(t.num_seconds_from_midnight()) / 60.0;
+    let solar_time = (minutes + eq_time + 4.0 * site.longitude).rem_euclid(1440.0);
+    let hour_angle = solar_time / 4.0 - 180.0;
+
+    let cos_zenith = (sin_deg(site.latitude) * sin_deg(decl)
+        + cos_deg(site.latitude) * cos_deg(decl) * cos_deg(hour_angle))
+    .max(-1.0)
+    .min(1.0);
+    let zenith = cos_zenith.acos();
+    let denom = cos_deg(site.latitude) * zenith.sin();
+    let azimuth = if denom.abs() < 1e-9 {
+        180.0
+    } else {
+        let cos_az = ((sin_deg(site.latitude) * cos_zenith - sin_deg(decl)) / denom)
+            .max(-1.0)
+            .min(1.0);
+        let az = cos_az.acos().to_degrees();
+        if hour_angle > 0.0 {
+            (az + 180.0) % 360.0
+        } else {
+            (540.0 - az) % 360.0
+        }
+    };
+    Position {
+        zenith_deg: zenith.to_degrees(),
+        azimuth_deg: azimuth,
+    }
+}
+
+/// Clear-sky irradiance at `site` at `t`, zero while the sun is down.
+pub fn clear_sky(site: &Site, t: NaiveDateTime) -> Irradiance {
+    let sun = position(site, t);
+    if sun.zenith_deg >= 90.0 {
+        return Irradiance {
+            ghi: 0.0,
+            dni: 0.0,
+            dhi: 0.0,
+            poa: 0.0,
+        };
+    }
+    let cos_zenith = cos_deg(sun.zenith_deg);
+    // Kasten and Young's air mass, which stays finite at the horizon.
+    let air_mass = 1.0 / (cos_zenith + 0.50572 * (96.07995 - sun.zenith_deg).powf(-1.6364));
+    let h = (site.elevation_m / 1000.0).max(0.0);
+    let dni = SOLAR_CONSTANT * ((1.0 - 0.14 * h) * 0.7f64.powf(air_mass.powf(0.678)) + 0.14 * h);
+    let dhi = 0.1 * dni;
+    let ghi = dni * cos_zenith + dhi;
+
+    let cos_tilt = cos_deg(site.tilt_deg);
+    let cos_incidence = cos_zenith * cos_tilt
+        + sin_deg(sun.zenith_deg)
+            * sin_deg(site.tilt_deg)
+            * cos_deg(sun.azimuth_deg - site.azimuth_deg);
+    let poa = dni * cos_incidence.max(0.0)
+        + dhi * (1.0 + cos_tilt) / 2.0
+        + ghi * ALBEDO * (1.0 - cos_tilt) / 2.0;
+    Irradiance { ghi, dni, dhi, poa }
+}
+
+/// Power a panel rated at `rated_mW` would produce at `t` under a clear sky.
+#[allow(non_snake_case)]
+pub fn expected_power_mW(site: &Site, rated_mW: f64, t: NaiveDateTime) -> f64 {
+    rated_mW * clear_sky(site, t).poa / RATED_IRRADIANCE
+}
+
+/// Energy, in mWh, a panel rated at `rated_mW` would produce under a clear
+/// sky from `start` to `end`, sampled at the middle of every `STEP_SECS`.
+#[allow(non_snake_case)]
+pub fn expected_energy_mWh(
+    site: &Site,
+    rated_mW: f64,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> f64 {
+    let mut mWh = 0.0;
+    let mut t = start;
+    while t < end {
+        let next = (t + Duration::seconds(STEP_SECS)).min(end);
+        let hours = (next - t).num_seconds() as f64 / 3600.0;
+        mWh += expected_power_mW(site, rated_mW, t + (next - t) / 2) * hours;
+        t = next;
+    }
+    mWh
+}
+
+/// A panel's default tilt, equal to the latitude, which gets the most energy
+/// over a year.
+pub fn default_tilt(latitude: f64) -> f64 {
+    latitude.abs()
+}
+
+/// A panel's default azimuth, facing the equator.
+pub fn default_azimuth(latitude: f64) -> f64 {
+    if latitude < 0.0 {
+        0.0
+    } else {
+        180.0
+    }
+}
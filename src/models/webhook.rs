@@ -0,0 +1,217 @@
+//! Webhook endpoints that users register to be told about events on their
+//! sensors, and the deliveries queued for them.
+
+use crate::{
+    result::{Error, Result},
+    schema::{webhook_deliveries, webhooks},
+    util, webhooks,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, update};
+use serde_json::Value as JsonValue;
+
+pub const READINGS_CREATED: &str = "readings.created";
+pub const SENSOR_ADDED: &str = "sensor.added";
+pub const SENSOR_DEACTIVATED: &str = "sensor.deactivated";
+pub const SENSOR_TRANSFERRED: &str = "sensor.transferred";
+pub const ALERT_OPENED: &str = "alert.opened";
+pub const ALERT_RESOLVED: &str = "alert.resolved";
+
+pub const EVENTS: [&str; 6] = [
+    READINGS_CREATED,
+    SENSOR_ADDED,
+    SENSOR_DEACTIVATED,
+    SENSOR_TRANSFERRED,
+    ALERT_OPENED,
+    ALERT_RESOLVED,
+];
+
+/// `events` of a webhook that gets every event.
+pub const ALL_EVENTS: &str = "*";
+
+pub const PENDING: &str = "pending";
+pub const DELIVERED: &str = "delivered";
+pub const FAILED: &str = "failed";
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct Webhook {
+    pub id: i32,
+    pub owner_id: i32,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    /// Comma separated events the webhook gets, or `*` for all of them.
+    pub events: String,
+    pub active: bool,
+    pub created: NaiveDateTime,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &str) -> bool {
+        self.events == ALL_EVENTS || self.events.split(',').any(|e| e == event)
+    }
+}
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct Delivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: String,
+    /// `pending` until it's delivered, or `failed` once it runs out of
+    /// attempts.
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt: NaiveDateTime,
+    pub last_attempt: Option<NaiveDateTime>,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created: NaiveDateTime,
+}
+
+/// Registers a webhook, returning its id and secret. A secret is generated
+/// if one isn't given, and `events` defaults to every event.
+pub fn create(
+    owner_id: i32,
+    url: &str,
+    secret: Option<String>,
+    events: Option<Vec<String>>,
+    conn: &SqliteConnection,
+) -> Result<(i32, String)> {
+    webhooks::check_url(url).map_err(Error::InvalidWebhook)?;
+    let events = match events {
+        Some(events) => {
+            if let Some(e) = events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+                return Err(Error::InvalidWebhook(format!("unknown event '{}'", e)));
+            }
+            events.join(",")
+        }
+        None => ALL_EVENTS.to_string(),
+    };
+    let secret = secret
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(util::token::rand_str);
+    conn.transaction(|| {
+        insert_into(webhooks::table)
+            .values((
+                webhooks::owner_id.eq(owner_id),
+                webhooks::url.eq(url),
+                webhooks::secret.eq(&secret),
+                webhooks::events.eq(events),
+            ))
+            .execute(conn)?;
+        let id: i32 = webhooks::table
+            .select(webhooks::id)
+            .order(webhooks::id.desc())
+            .first(conn)?;
+        Ok((id, secret.clone()))
+    })
+}
+
+pub fn find(id: i32, conn: &SqliteConnection) -> Result<Webhook> {
+    webhooks::table.find(id).first(conn).map_err(|e| e.into())
+}
+
+pub fn for_user(owner_id: i32, conn: &SqliteConnection) -> Result<Vec<Webhook>> {
+    webhooks::table
+        .filter(webhooks::owner_id.eq(owner_id))
+        .filter(webhooks::active.eq(true))
+        .order(webhooks::id.asc())
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+/// Stops a webhook getting new events and drops its pending deliveries.
+pub fn deactivate(id: i32, conn: &SqliteConnection) -> Result<()> {
+    conn.transaction(|| {
+        update(webhooks::table.find(id))
+            .set(webhooks::active.eq(false))
+            .execute(conn)?;
+        update(
+            webhook_deliveries::table
+                .filter(webhook_deliveries::webhook_id.eq(id))
+                .filter(webhook_deliveries::status.eq(PENDING)),
+        )
+        .set((
+            webhook_deliveries::status.eq(FAILED),
+            webhook_deliveries::error.eq("webhook was deleted"),
+        ))
+        .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Queues `event` for every active webhook of `owner_id` that wants it. The
+/// payload is built here, so every attempt sends the same body.
+pub fn emit(owner_id: i32, event: &str, data: JsonValue, conn: &SqliteConnection) -> Result<()> {
+    let hooks: Vec<Webhook> = for_user(owner_id, conn)?
+        .into_iter()
+        .filter(|w| w.wants(event))
+        .collect();
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let now = Utc::now().naive_utc();
+    let payload = json!({
+        "event": event,
+        "created": now.timestamp(),
+        "data": data,
+    })
+    .to_string();
+    for hook in hooks {
+        insert_into(webhook_deliveries::table)
+            .values((
+                webhook_deliveries::webhook_id.eq(hook.id),
+                webhook_deliveries::event.eq(event),
+                webhook_deliveries::payload.eq(&payload),
+                webhook_deliveries::next_attempt.eq(now),
+            ))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Up to `limit` pending deliveries that are due at `now`, oldest first.
+pub fn due(now: NaiveDateTime, limit: i64, conn: &SqliteConnection) -> Result<Vec<Delivery>> {
+    webhook_deliveries::table
+        .filter(webhook_deliveries::status.eq(PENDING))
+        .filter(webhook_deliveries::next_attempt.le(now))
+        .order(webhook_deliveries::next_attempt.asc())
+        .limit(limit)
+        .load(conn)
+        .map_err(|e| e.into())
+}
+
+/// Records an attempt to send a delivery. `next_attempt` is when to retry it,
+/// if it's still pending.
+pub fn record_attempt(
+    delivery: &Delivery,
+    status: &str,
+    now: NaiveDateTime,
+    next_attempt: NaiveDateTime,
+    response_status: Option<i32>,
+    error: Option<String>,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    update(webhook_deliveries::table.find(delivery.id))
+        .set((
+            webhook_deliveries::status.eq(status),
+            webhook_deliveries::attempts.eq(delivery.attempts + 1),
+            webhook_deliveries::next_attempt.eq(next_attempt),
+            webhook_deliveries::last_attempt.eq(now),
+            webhook_deliveries::response_status.eq(response_status),
+            webhook_deliveries::error.eq(error),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// A webhook's deliveries, newest first.
+pub fn deliveries(webhook_id: i32, limit: i64, conn: &SqliteConnection) -> Result<Vec<Delivery>> {
+    webhook_deliveries::table
+        .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+        .order(webhook_deliveries::id.desc())
+        .limit(limit)
+        .load(conn)
+        .map_err(|e| e.into())
+}
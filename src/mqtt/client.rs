@@ -0,0 +1,67 @@
+use super::{Broker, Message};
+use crate::{
+    logging,
+    result::{Error, Result},
+};
+use diesel::{Connection, SqliteConnection};
+use rumqtt::{MqttClient, MqttOptions, Notification, QoS, Receiver};
+use std::thread::{self, JoinHandle};
+
+pub struct RumqttBroker {
+    client: MqttClient,
+    notifications: Receiver<Notification>,
+}
+
+impl RumqttBroker {
+    pub fn connect(host: &str, port: u16) -> Result<RumqttBroker> {
+        let opts = MqttOptions::new("sol-ingest", host, port);
+        let (client, notifications) =
+            MqttClient::start(opts).map_err(|e| Error::Mqtt(format!("{:?}", e)))?;
+        Ok(RumqttBroker {
+            client,
+            notifications,
+        })
+    }
+}
+
+impl Broker for RumqttBroker {
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.client
+            .subscribe(topic, QoS::AtLeastOnce)
+            .map_err(|e| Error::Mqtt(format!("{:?}", e)))
+    }
+
+    fn publish(&mut self, topic: &str, payload: Vec<u8>) -> Result<()> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .map_err(|e| Error::Mqtt(format!("{:?}", e)))
+    }
+
+    fn next_message(&mut self) -> Option<Message> {
+        for notification in self.notifications.iter() {
+            if let Notification::Publish(publish) = notification {
+                return Some(Message {
+                    topic: publish.topic_name.clone(),
+                    payload: publish.payload.to_vec(),
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Runs the bridge on its own thread with its own database connection.
+/// `broker` is given as `host:port`.
+pub fn spawn(db_uri: &str, broker: &str) -> JoinHandle<()> {
+    let db_uri = db_uri.to_string();
+    let mut parts = broker.splitn(2, ':');
+    let host = parts.next().unwrap_or("localhost").to_string();
+    let port = parts.next().and_then(|p| p.parse().ok()).unwrap_or(1883);
+    thread::spawn(move || {
+        let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+        let mut broker = RumqttBroker::connect(&host, port).expect("failed to connect to broker");
+        if let Err(e) = super::run(&mut broker, &conn) {
+            logging::error("mqtt", format!("bridge stopped: {}", e));
+        }
+    })
+}
@@ -0,0 +1,127 @@
+//! Ingestion of sensor readings published over MQTT.
+//!
+//! Gateways publish a batch of readings to `sol/<hardware_id>/readings`. The
+//! payload carries the sensor token, so each message is authenticated the same
+//! way as `api::add_readings`, and readings are stored through the same
+//! `api::store_readings` path. Every message gets an ack or a rejection
+//! published back on `sol/<hardware_id>/readings/reply`.
+use crate::{
+    api::{self, CreateReading},
+    auth,
+    result::{Error, Result},
+};
+use diesel::SqliteConnection;
+
+#[cfg(feature = "mqtt")]
+mod client;
+#[cfg(feature = "mqtt")]
+pub use self::client::spawn;
+
+pub const READINGS_TOPIC: &str = "sol/+/readings";
+
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// The parts of an MQTT client that the bridge needs. This lets the bridge
+/// run against a real broker or an in-process mock.
+pub trait Broker {
+    fn subscribe(&mut self, topic: &str) -> Result<()>;
+    fn publish(&mut self, topic: &str, payload: Vec<u8>) -> Result<()>;
+    /// Blocks until the next message arrives, or returns `None` once the
+    /// connection is gone.
+    fn next_message(&mut self) -> Option<Message>;
+}
+
+#[derive(Deserialize)]
+pub struct ReadingsMessage {
+    #[serde(default)]
+    id: Option<String>,
+    token: String,
+    readings: Vec<CreateReading>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Reply {
+    pub status: String,
+    pub id: Option<String>,
+    pub inserted: usize,
+    pub error: Option<String>,
+}
+
+impl Reply {
+    fn ack(id: Option<String>, inserted: usize) -> Reply {
+        Reply {
+            status: "ok".into(),
+            id,
+            inserted,
+            error: None,
+        }
+    }
+
+    fn reject(id: Option<String>, err: Error) -> Reply {
+        Reply {
+            status: "rejected".into(),
+            id,
+            inserted: 0,
+            error: Some(err.to_string()),
+        }
+    }
+}
+
+pub fn hardware_id_from_topic(topic: &str) -> Option<i64> {
+    let parts: Vec<&str> = topic.split('/').collect();
+    match parts.as_slice() {
+        ["sol", hw_id, "readings"] => hw_id.parse().ok(),
+        _ => None,
+    }
+}
+
+pub fn reply_topic(topic: &str) -> String {
+    format!("{}/reply", topic)
+}
+
+fn insert_readings(
+    hardware_id: i64,
+    msg: &ReadingsMessage,
+    conn: &SqliteConnection,
+) -> Result<usize> {
+    let sensor = auth::sensor_for_token(&msg.token, conn)?;
+    if sensor.hardware_id != hardware_id || !sensor.active {
+        return Err(Error::WrongSensor(hardware_id));
+    }
+    let readings = msg
+        .readings
+        .iter()
+        .map(|r| r.to_insert(sensor.id))
+        .collect();
+    Reading::insert_many(&readings, conn)
+}
+
+pub fn handle(msg: &Message, conn: &SqliteConnection) -> Reply {
+    let hardware_id = match hardware_id_from_topic(&msg.topic) {
+        Some(id) => id,
+        None => return Reply::reject(None, Error::MalformedTopic(msg.topic.clone())),
+    };
+    let body: ReadingsMessage = match serde_json::from_slice(&msg.payload) {
+        Ok(body) => body,
+        Err(e) => return Reply::reject(None, Error::MalformedPayload(e.to_string())),
+    };
+    match insert_readings(hardware_id, &body, conn) {
+        Ok(count) => Reply::ack(body.id, count),
+        Err(e) => Reply::reject(body.id, e),
+    }
+}
+
+/// Subscribes to the readings topic and handles messages until the broker
+/// disconnects.
+pub fn run<B: Broker>(broker: &mut B, conn: &SqliteConnection) -> Result<()> {
+    broker.subscribe(READINGS_TOPIC)?;
+    while let Some(msg) = broker.next_message() {
+        let reply = handle(&msg, conn);
+        let payload = serde_json::to_vec(&reply).expect("failed to serialize reply");
+        broker.publish(&reply_topic(&msg.topic), payload)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+//! Liveness and readiness probes for whatever supervises the server.
+//!
+//! `/healthz` answers as long as Rocket can handle a request, so a failure
+//! means the process should be restarted. `/readyz` also checks what the
+//! server needs to do its work, and answers 503 while any check fails, so the
+//! details say what to fix rather than suggesting a restart. Mail is reported
+//! but doesn't hold readiness up, since credentials from an instance profile
+//! can't be seen without asking AWS.
+//!
+//! `/readyz` tells of paths and free space, so it only answers requests from
+//! this host that didn't come through the proxy.
+use crate::{
+    db,
+    util::{email, net},
+};
+use diesel::{sql_query, RunQueryDsl, SqliteConnection};
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::status::Custom,
+    Request, State,
+};
+use rocket_contrib::json::JsonValue;
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Checks that are reported without holding readiness up.
+const ADVISORY: &[&str] = &["mail"];
+
+/// Free space the database's filesystem needs to be ready, unless
+/// `SOL_MIN_FREE_DISK_MB` says otherwise.
+const DEFAULT_MIN_FREE_MB: u64 = 100;
+
+/// What readiness is checked against.
+pub struct Probes {
+    /// Directory of the database file.
+    pub data_dir: PathBuf,
+    pub min_free_bytes: u64,
+}
+
+impl Probes {
+    pub fn new(db_uri: &str) -> Probes {
+        let data_dir = match Path::new(db_uri).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let min_free_mb =
+            std::env::var("SOL_MIN_FREE_DISK_MB")
+                .ok()
+                .map_or(DEFAULT_MIN_FREE_MB, |mb| {
+                    mb.parse()
+                        .unwrap_or_else(|_| panic!("SOL_MIN_FREE_DISK_MB must be a number"))
+                });
+        Probes {
+            data_dir,
+            min_free_bytes: min_free_mb * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn from_result(res: Result<String, String>) -> Check {
+        match res {
+            Ok(detail) => Check { ok: true, detail },
+            Err(detail) => Check { ok: false, detail },
+        }
+    }
+}
+
+/// Bytes available to this process on the filesystem holding `dir`.
+fn free_bytes(dir: &Path) -> Result<u64, String> {
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "failed to stat {}: {}",
+            dir.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+fn check_database(conn: Option<&SqliteConnection>) -> Result<String, String> {
+    let conn = conn.ok_or("the pool has no connection to give")?;
+    sql_query("select 1")
+        .execute(conn)
+        .map(|_| "queried".to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn check_migrations(conn: Option<&SqliteConnection>) -> Result<String, String> {
+    let conn = conn.ok_or("no connection to check with")?;
+    match db::pending_migrations(conn) {
+        Ok(ref pending) if pending.is_empty() => Ok("all applied".to_string()),
+        Ok(pending) => Err(format!("pending: {}", pending.join(", "))),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn check_disk(probes: &Probes) -> Result<String, String> {
+    let free = free_bytes(&probes.data_dir)?;
+    let detail = format!(
+        "{} MB free in {}",
+        free / 1024 / 1024,
+        probes.data_dir.display()
+    );
+    if free >= probes.min_free_bytes {
+        Ok(detail)
+    } else {
+        Err(format!(
+            "{}, needs {} MB",
+            detail,
+            probes.min_free_bytes / 1024 / 1024
+        ))
+    }
+}
+
+/// Every readiness check by name.
+pub fn checks(probes: &Probes, conn: Option<&SqliteConnection>) -> BTreeMap<&'static str, Check> {
+    let mut checks = BTreeMap::new();
+    checks.insert("database", Check::from_result(check_database(conn)));
+    checks.insert("migrations", Check::from_result(check_migrations(conn)));
+    checks.insert("disk", Check::from_result(check_disk(probes)));
+    checks.insert(
+        "mail",
+        Check::from_result(
+            email::credentials_source().map(|source| format!("AWS credentials from {}", source)),
+        ),
+    );
+    checks
+}
+
+#[get("/healthz")]
+pub fn healthz() -> JsonValue {
+    json!({ "status": "ok" })
+}
+
+/// A request from this host, not passed on by a proxy.
+pub struct Local;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Local {
+    type Error = ();
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        match net::client_ip(req) {
+            Some(ip) if ip.is_loopback() => Outcome::Success(Local),
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+#[get("/readyz")]
+pub fn readyz(
+    _local: Local,
+    probes: State<Probes>,
+    conn: Option<db::SolDbConn>,
+) -> Custom<JsonValue> {
+    let checks = checks(&probes, conn.as_ref().map(|c| &**c));
+    let ready = checks
+        .iter()
+        .all(|(name, check)| check.ok || ADVISORY.contains(name));
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    Custom(
+        status,
+        json!({
+            "status": if ready { "ready" } else { "unavailable" },
+            "checks": checks,
+        }),
+    )
+}
@@ -0,0 +1,546 @@
+//! Limits how fast sensors, users and anonymous clients can make requests.
+//!
+//! Every request is charged to a token bucket for who made it and the class
+//! of route it's for, like `sensor:3:ingest`. Sensors and users are known by
+//! their tokens, or a user by their session cookie, and anyone else by their
+//! ip. Limits are set per kind of client and class of route, and an admin can
+//! raise them for one sensor or user.
+//!
+//! A request over its limit is rerouted to `rate_limited`, since fairings
+//! can't answer requests themselves, so its route never runs. Every limited
+//! response says how much of the limit is left in `X-RateLimit-*` headers.
+//!
+//! Buckets are kept in memory unless `SOL_RATE_LIMIT_STORE=database`, which
+//! shares them between servers on one database and keeps them over restarts.
+use crate::{
+    db::SolDbConn,
+    logging,
+    models::{rate_limit, Token, TokenType, User},
+    result::Result,
+    util::net,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{Connection, SqliteConnection};
+use rocket::{
+    fairing::{Fairing, Info, Kind as FairingKind},
+    http::{uri::Origin, Method, Status},
+    request::{FromRequest, Outcome},
+    response::status::Custom,
+    Data, Request, Response,
+};
+use rocket_contrib::json::JsonValue;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+/// Where requests over their limit are sent.
+pub const LIMITED_PATH: &str = "/rate_limited";
+
+/// Buckets kept in memory before the least recently used are dropped.
+pub const MAX_MEMORY_BUCKETS: usize = 100_000;
+
+/// How long a bucket in the database can go unused before it's dropped.
+/// Dropping a bucket refills it, so this is longer than any bucket takes to
+/// refill.
+pub const IDLE_HOURS: i64 = 24;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Class {
+    /// Readings coming in.
+    Ingest,
+    /// Logging in and signing up, where passwords get guessed.
+    Auth,
+    Api,
+    Web,
+}
+
+impl Class {
+    pub const ALL: &'static [Class] = &[Class::Ingest, Class::Auth, Class::Api, Class::Web];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Ingest => "ingest",
+            Class::Auth => "auth",
+            Class::Api => "api",
+            Class::Web => "web",
+        }
+    }
+
+    /// The class of a request, or none for static files, probes and metrics,
+    /// which aren't limited.
+    pub fn of(method: Method, path: &str) -> Option<Class> {
+        let auth_post = [
+            "/api/token",
+            "/api/users/new",
+            "/login",
+            "/register",
+            "/forgot_password",
+        ];
+        if path.starts_with("/static/")
+            || ["/healthz", "/readyz", "/metrics", LIMITED_PATH].contains(&path)
+        {
+            None
+        } else if [
+            "/api/add_reading",
+            "/api/add_readings",
+            "/api/lorawan/uplink",
+        ]
+        .contains(&path)
+            || (method == Method::Post && path.ends_with("/import"))
+        {
+            Some(Class::Ingest)
+        } else if (method == Method::Post && auth_post.contains(&path))
+            || path.starts_with("/login/onetime/")
+        {
+            Some(Class::Auth)
+        } else if path.starts_with("/api/") {
+            Some(Class::Api)
+        } else {
+            Some(Class::Web)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Kind {
+    Sensor,
+    User,
+    Anonymous,
+}
+
+impl Kind {
+    pub const ALL: &'static [Kind] = &[Kind::Sensor, Kind::User, Kind::Anonymous];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Sensor => "sensor",
+            Kind::User => "user",
+            Kind::Anonymous => "anonymous",
+        }
+    }
+}
+
+/// Who a request is charged to.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Principal {
+    Sensor(i32),
+    User(i32),
+    /// By ip.
+    Anonymous(String),
+}
+
+impl Principal {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Principal::Sensor(_) => Kind::Sensor,
+            Principal::User(_) => Kind::User,
+            Principal::Anonymous(_) => Kind::Anonymous,
+        }
+    }
+
+    /// Like `sensor:3`, `user:7` or `ip:203.0.113.9`.
+    pub fn key(&self) -> String {
+        match self {
+            Principal::Sensor(id) => format!("sensor:{}", id),
+            Principal::User(id) => format!("user:{}", id),
+            Principal::Anonymous(ip) => format!("ip:{}", ip),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Limit {
+    /// How fast a bucket refills.
+    pub per_minute: u32,
+    /// How many requests a full bucket allows at once.
+    pub burst: u32,
+}
+
+impl Limit {
+    pub fn new(per_minute: u32, burst: u32) -> Limit {
+        Limit { per_minute, burst }
+    }
+
+    /// Parses `per_minute` or `per_minute/burst`. A burst defaults to a
+    /// minute's worth.
+    pub fn parse(s: &str) -> Option<Limit> {
+        let mut parts = s.splitn(2, '/');
+        let per_minute = parts.next()?.trim().parse().ok()?;
+        let burst = match parts.next() {
+            Some(burst) => burst.trim().parse().ok()?,
+            None => per_minute,
+        };
+        Some(Limit::new(per_minute, burst)).filter(|l| l.per_minute > 0 && l.burst > 0)
+    }
+
+    pub fn times(self, multiplier: u32) -> Limit {
+        Limit::new(
+            self.per_minute.saturating_mul(multiplier),
+            self.burst.saturating_mul(multiplier),
+        )
+    }
+
+    fn per_second(self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+/// The limit for each kind of client and class of route.
+pub struct Limits(HashMap<(Kind, Class), Limit>);
+
+impl Limits {
+    pub fn get(&self, kind: Kind, class: Class) -> Limit {
+        self.0[&(kind, class)]
+    }
+
+    pub fn set(&mut self, kind: Kind, class: Class, limit: Limit) {
+        self.0.insert((kind, class), limit);
+    }
+
+    /// The defaults, with any set like `SOL_RATE_LIMIT_SENSOR_INGEST=60/120`
+    /// replaced.
+    pub fn from_env() -> Limits {
+        let mut limits = Limits::default();
+        for &kind in Kind::ALL {
+            for &class in Class::ALL {
+                let name = format!(
+                    "SOL_RATE_LIMIT_{}_{}",
+                    kind.name().to_uppercase(),
+                    class.name().to_uppercase()
+                );
+                if let Ok(value) = std::env::var(&name) {
+                    let limit = Limit::parse(&value)
+                        .unwrap_or_else(|| panic!("{} must be like 60 or 60/120, and not 0", name));
+                    limits.set(kind, class, limit);
+                }
+            }
+        }
+        limits
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        use self::Class::*;
+        let mut limits = Limits(HashMap::new());
+        for &(kind, class, per_minute, burst) in &[
+            // Sensors send readings every few minutes, so even a backlog
+            // after being offline is a few batches.
+            (Kind::Sensor, Ingest, 30, 60),
+            (Kind::Sensor, Auth, 10, 20),
+            (Kind::Sensor, Api, 60, 120),
+            (Kind::Sensor, Web, 60, 120),
+            (Kind::User, Ingest, 60, 120),
+            (Kind::User, Auth, 10, 30),
+            (Kind::User, Api, 300, 600),
+            (Kind::User, Web, 300, 600),
+            // Network servers post uplinks for every sensor they carry from
+            // one ip.
+            (Kind::Anonymous, Ingest, 600, 1200),
+            (Kind::Anonymous, Auth, 10, 30),
+            (Kind::Anonymous, Api, 120, 240),
+            (Kind::Anonymous, Web, 300, 600),
+        ] {
+            limits.set(kind, class, Limit::new(per_minute, burst));
+        }
+        limits
+    }
+}
+
+/// What taking a token from a bucket decided.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset_secs: u64,
+    /// Seconds until a request would be allowed, when this one wasn't.
+    pub retry_after_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+    /// Tokens taken and not yet refilled, which is kept rather than the
+    /// tokens left so that a raised limit takes effect at once.
+    pub used: f64,
+    pub updated: NaiveDateTime,
+}
+
+impl Bucket {
+    pub fn new(now: NaiveDateTime) -> Bucket {
+        Bucket {
+            used: 0.0,
+            updated: now,
+        }
+    }
+
+    /// Refills the bucket for the time since it last changed, then takes a
+    /// token if there's one left.
+    pub fn take(&mut self, limit: Limit, now: NaiveDateTime) -> Decision {
+        let rate = limit.per_second();
+        let elapsed = (now - self.updated).num_milliseconds().max(0) as f64 / 1000.0;
+        self.used = (self.used - elapsed * rate).max(0.0);
+        self.updated = now;
+        let burst = f64::from(limit.burst);
+        let allowed = self.used + 1.0 <= burst;
+        if allowed {
+            self.used += 1.0;
+        }
+        Decision {
+            allowed,
+            limit: limit.burst,
+            remaining: (burst - self.used).max(0.0).floor() as u32,
+            reset_secs: (self.used / rate).ceil() as u64,
+            retry_after_secs: if allowed {
+                0
+            } else {
+                ((self.used + 1.0 - burst) / rate).ceil().max(1.0) as u64
+            },
+        }
+    }
+}
+
+/// Buckets in memory, at most `capacity` of them. Taking from a bucket marks
+/// it used, and a new bucket in a full map drops the least recently used one,
+/// which refills it.
+pub struct MemoryBuckets {
+    capacity: usize,
+    /// Each bucket, and when it was last used.
+    buckets: HashMap<String, (Bucket, u64)>,
+    /// Keys by when they were last used, oldest first.
+    used: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl MemoryBuckets {
+    pub fn new(capacity: usize) -> MemoryBuckets {
+        MemoryBuckets {
+            capacity: capacity.max(1),
+            buckets: HashMap::new(),
+            used: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.buckets.contains_key(key)
+    }
+
+    pub fn take(&mut self, key: &str, limit: Limit, now: NaiveDateTime) -> Decision {
+        self.clock += 1;
+        let mut bucket = match self.buckets.remove(key) {
+            Some((bucket, last)) => {
+                self.used.remove(&last);
+                bucket
+            }
+            None => {
+                if self.buckets.len() >= self.capacity {
+                    let oldest = *self.used.keys().next().expect("full map has no keys");
+                    let evicted = self.used.remove(&oldest).expect("oldest key is gone");
+                    self.buckets.remove(&evicted);
+                }
+                Bucket::new(now)
+            }
+        };
+        let decision = bucket.take(limit, now);
+        self.buckets.insert(key.to_string(), (bucket, self.clock));
+        self.used.insert(self.clock, key.to_string());
+        decision
+    }
+}
+
+/// Where buckets are kept.
+pub enum Store {
+    Memory(Mutex<MemoryBuckets>),
+    /// In `rate_limit_buckets`. Two servers taking from one bucket at the
+    /// same moment can both read it before either writes, so limits shared
+    /// this way are only close.
+    Database,
+}
+
+impl Store {
+    /// Reads `SOL_RATE_LIMIT_STORE`, which is `memory` unless it's
+    /// `database`.
+    pub fn from_env() -> Store {
+        match std::env::var("SOL_RATE_LIMIT_STORE") {
+            Ok(ref s) if s == "database" => Store::Database,
+            Ok(ref s) if s != "memory" => {
+                panic!("SOL_RATE_LIMIT_STORE must be memory or database")
+            }
+            _ => Store::memory(MAX_MEMORY_BUCKETS),
+        }
+    }
+
+    pub fn memory(capacity: usize) -> Store {
+        Store::Memory(Mutex::new(MemoryBuckets::new(capacity)))
+    }
+
+    pub fn take(
+        &self,
+        key: &str,
+        limit: Limit,
+        now: NaiveDateTime,
+        conn: &SqliteConnection,
+    ) -> Result<Decision> {
+        match self {
+            Store::Memory(buckets) => Ok(buckets.lock().unwrap().take(key, limit, now)),
+            Store::Database => conn.transaction(|| {
+                let mut bucket = match rate_limit::bucket(key, conn)? {
+                    Some((used, updated)) => Bucket { used, updated },
+                    None => Bucket::new(now),
+                };
+                let decision = bucket.take(limit, now);
+                rate_limit::save_bucket(key, bucket.used, bucket.updated, conn)?;
+                Ok(decision)
+            }),
+        }
+    }
+}
+
+/// Who to charge a request to. Tokens and cookies that aren't valid are
+/// charged to the ip, like no token at all.
+pub fn principal(req: &Request, conn: &SqliteConnection) -> Principal {
+    let bearer = req
+        .headers()
+        .get_one("Authorization")
+        .and_then(|h| {
+            h.split_whitespace()
+                .nth(1)
+                .filter(|_| h.starts_with("bearer "))
+        })
+        .map(String::from);
+    if let Some(token) = bearer.and_then(|t| Token::find(&t, conn).ok()) {
+        let principal = match TokenType::from_string(token.type_) {
+            TokenType::Sensor => token.sensor_id.map(Principal::Sensor),
+            TokenType::User => token.user_id.map(Principal::User),
+        };
+        if let Some(principal) = principal {
+            return principal;
+        }
+    }
+    let session = req
+        .cookies()
+        .get_private("user_token")
+        .map(|c| c.value().to_string());
+    if let Some(user) = session.and_then(|t| User::by_token(&t, conn).ok()) {
+        return Principal::User(user.id);
+    }
+    Principal::Anonymous(
+        net::client_ip(req).map_or_else(|| "unknown".to_string(), |ip| ip.to_string()),
+    )
+}
+
+/// What was decided about a request, in its local cache.
+struct Decided(Option<Decision>);
+
+pub struct RateLimiter {
+    pub limits: Limits,
+    pub store: Store,
+    /// Whether to limit at all.
+    pub enabled: bool,
+}
+
+impl RateLimiter {
+    /// Limits from `Limits::from_env`, kept in `Store::from_env`, unless
+    /// `SOL_RATE_LIMITS=off`.
+    pub fn from_env() -> RateLimiter {
+        RateLimiter {
+            limits: Limits::from_env(),
+            store: Store::from_env(),
+            enabled: std::env::var("SOL_RATE_LIMITS").map_or(true, |s| s != "off"),
+        }
+    }
+
+    fn decide(&self, req: &Request) -> Option<Decision> {
+        if !self.enabled {
+            return None;
+        }
+        let class = Class::of(req.method(), req.uri().path())?;
+        let conn: SolDbConn = req.guard().succeeded()?;
+        let principal = principal(req, &conn);
+        let multiplier = match principal {
+            Principal::Anonymous(_) => None,
+            _ => rate_limit::multiplier(&principal.key(), &conn).unwrap_or(None),
+        };
+        let limit = self
+            .limits
+            .get(principal.kind(), class)
+            .times(multiplier.unwrap_or(1).max(1) as u32);
+        let key = format!("{}:{}", principal.key(), class.name());
+        match self.store.take(&key, limit, Utc::now().naive_utc(), &conn) {
+            Ok(decision) => Some(decision),
+            // Better to let requests through than to fail them all.
+            Err(e) => {
+                logging::error("ratelimit", format!("failed to rate limit {}: {}", key, e));
+                None
+            }
+        }
+    }
+}
+
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: FairingKind::Request | FairingKind::Response,
+        }
+    }
+
+    fn on_request(&self, req: &mut Request, _: &Data) {
+        let decision = self.decide(req);
+        req.local_cache(|| Decided(decision));
+        if decision.map_or(false, |d| !d.allowed) {
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(LIMITED_PATH).expect("bad limited path"));
+        }
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        let decision = match req.local_cache(|| Decided(None)).0 {
+            Some(decision) => decision,
+            None => return,
+        };
+        res.set_raw_header("X-RateLimit-Limit", decision.limit.to_string());
+        res.set_raw_header("X-RateLimit-Remaining", decision.remaining.to_string());
+        res.set_raw_header("X-RateLimit-Reset", decision.reset_secs.to_string());
+        if !decision.allowed {
+            res.set_raw_header("Retry-After", decision.retry_after_secs.to_string());
+        }
+    }
+}
+
+/// A request that was over its limit.
+pub struct Limited(Decision);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Limited {
+    type Error = ();
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        match req.local_cache(|| Decided(None)).0 {
+            Some(decision) if !decision.allowed => Outcome::Success(Limited(decision)),
+            _ => Outcome::Forward(()),
+        }
+    }
+}
+
+#[get("/rate_limited")]
+pub fn rate_limited(limited: Limited) -> Custom<JsonValue> {
+    Custom(
+        Status::TooManyRequests,
+        json!({
+            "error": format!(
+                "rate limit exceeded, retry in {} seconds",
+                limited.0.retry_after_secs
+            ),
+        }),
+    )
+}
@@ -23,9 +23,48 @@ pub enum Error {
     MalformedBasicAuthHeader,
     WrongTokenType,
     NotSensorOwner,
+    NotWebhookOwner,
     NoTokenInRequest,
     NotAdmin,
     DbConnectionFailed,
+    WrongSensor(i64),
+    MalformedTopic(String),
+    MalformedPayload(String),
+    Mqtt(String),
+    MissingWebhookSecret,
+    InvalidWebhookSecret,
+    UnknownDevEui(String),
+    MalformedUplink(String),
+    InvalidReading(String),
+    MalformedCsv(String),
+    ImportTooLarge(u64),
+    InvalidTimezone(String),
+    InvalidChannel(String),
+    UnknownChannel(String),
+    UnknownField(String),
+    UnsupportedFormat(String),
+    InvalidInterval(String),
+    TooManyBuckets(i64),
+    InvalidEnergySettings(String),
+    InvalidDateRange,
+    DateRangeTooLong(i64),
+    InvalidPeriod(String),
+    InvalidLocation(String),
+    MissingLocation,
+    MissingTimezone,
+    InvalidAlertRule(String),
+    InvalidWebhook(String),
+    TooManyStreams,
+    InvalidDigestFrequency(String),
+    InvalidUnsubscribeToken,
+    RenderTemplate(String),
+    InvalidSchedule(String),
+    UnknownJob(String),
+    JobLocked(String),
+    MetricsNotAllowed,
+    CsrfTokenMismatch,
+    InvalidRateLimitMultiplier(i32),
+    MigrationCheck(String),
     SendEmail(SendEmailError),
     UnknownError(String),
 }
@@ -45,9 +84,58 @@ impl fmt::Display for Error {
             Error::MalformedToken => "malformed token".into(),
             Error::WrongTokenType => "wrong token type".into(),
             Error::NotSensorOwner => "user is not the owner of this sensor".into(),
+            Error::NotWebhookOwner => "user is not the owner of this webhook".into(),
             Error::NoTokenInRequest => "failed to get auth token from request".into(),
             Error::NotAdmin => "user is not an admin".into(),
             Error::DbConnectionFailed => "failed to connect to the database".into(),
+            Error::WrongSensor(id) => format!("token is not for sensor with hardware id {}", id),
+            Error::MalformedTopic(t) => format!("malformed topic '{}'", t),
+            Error::MalformedPayload(e) => format!("malformed payload: {}", e),
+            Error::Mqtt(e) => format!("mqtt error: {}", e),
+            Error::MissingWebhookSecret => "missing webhook secret".into(),
+            Error::InvalidWebhookSecret => "invalid webhook secret".into(),
+            Error::UnknownDevEui(eui) => format!("no active sensor with DevEUI {}", eui),
+            Error::MalformedUplink(e) => format!("malformed uplink: {}", e),
+            Error::InvalidReading(e) => format!("invalid reading: {}", e),
+            Error::MalformedCsv(e) => format!("malformed csv: {}", e),
+            Error::ImportTooLarge(max) => {
+                format!("import is larger than {} MB", max / (1024 * 1024))
+            }
+            Error::InvalidTimezone(tz) => format!("invalid timezone '{}'", tz),
+            Error::InvalidChannel(e) => format!("invalid channel: {}", e),
+            Error::UnknownChannel(name) => format!("sensor has no channel named '{}'", name),
+            Error::UnknownField(name) => format!("unknown field '{}'", name),
+            Error::UnsupportedFormat(f) => format!("unsupported format '{}'", f),
+            Error::InvalidInterval(i) => format!("invalid interval '{}'", i),
+            Error::InvalidEnergySettings(e) => format!("invalid energy settings: {}", e),
+            Error::InvalidDateRange => "start date must not be after end date".into(),
+            Error::DateRangeTooLong(max) => format!("date range is longer than {} days", max),
+            Error::InvalidPeriod(p) => format!("invalid period '{}'", p),
+            Error::InvalidLocation(msg) => format!("invalid location: {}", msg),
+            Error::MissingLocation => "sensor has no location".into(),
+            Error::MissingTimezone => "set the sensor's timezone before its location".into(),
+            Error::InvalidAlertRule(msg) => format!("invalid alert rule: {}", msg),
+            Error::InvalidWebhook(msg) => format!("invalid webhook: {}", msg),
+            Error::TooManyStreams => "too many live streams are open, try again later".into(),
+            Error::InvalidDigestFrequency(f) => format!("invalid digest frequency '{}'", f),
+            Error::InvalidUnsubscribeToken => {
+                "unsubscribe link is invalid or was already used".into()
+            }
+            Error::RenderTemplate(e) => format!("failed to render template: {}", e),
+            Error::InvalidSchedule(e) => format!("invalid schedule {}", e),
+            Error::UnknownJob(name) => format!("no job named '{}'", name),
+            Error::JobLocked(name) => format!("job '{}' is already running", name),
+            Error::MetricsNotAllowed => "not allowed to see metrics".into(),
+            Error::CsrfTokenMismatch => {
+                "form was missing its token or came from another site, try again".into()
+            }
+            Error::InvalidRateLimitMultiplier(m) => {
+                format!("rate limit multiplier must be at least 1, not {}", m)
+            }
+            Error::MigrationCheck(e) => format!("failed to check migrations: {}", e),
+            Error::TooManyBuckets(n) => {
+                format!("range would have {} buckets, use a longer interval", n)
+            }
             Error::SendEmail(e) => format!("failed to send email: {}", e),
             Error::UnknownError(e) => format!("unknown error: {}", e),
         };
@@ -0,0 +1,154 @@
+//! Deletes old raw readings once they are covered by hourly rollups, and
+//! optionally old hourly rollups once they are covered by daily ones. Daily
+//! rollups are kept forever.
+use crate::{
+    models::{
+        retention::{self, Cutoffs},
+        rollup,
+    },
+    result::Result,
+};
+use chrono::{Duration, NaiveDateTime};
+use diesel::{
+    prelude::*,
+    sql_query,
+    sql_types::{BigInt, Integer, Timestamp},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    /// Days of raw readings to keep.
+    pub raw_days: i64,
+    /// Days of hourly rollups to keep, or forever if `None`.
+    pub hourly_days: Option<i64>,
+}
+
+impl Policy {
+    /// Reads the policy from `SOL_RAW_RETENTION_DAYS` and
+    /// `SOL_HOURLY_RETENTION_DAYS`. Without the first, everything is kept.
+    pub fn from_env() -> Option<Policy> {
+        let days = |name: &str| {
+            std::env::var(name).ok().map(|d| {
+                d.parse::<i64>()
+                    .unwrap_or_else(|_| panic!("{} must be a number", name))
+            })
+        };
+        let raw_days = days("SOL_RAW_RETENTION_DAYS")?;
+        let hourly_days = days("SOL_HOURLY_RETENTION_DAYS").map(|d| d.max(raw_days));
+        Some(Policy {
+            raw_days,
+            hourly_days,
+        })
+    }
+
+    /// The cutoffs for a run at `now`, rounded down to midnight.
+    pub fn cutoffs(&self, now: NaiveDateTime) -> (NaiveDateTime, Option<NaiveDateTime>) {
+        let before = |days: i64| rollup::day_start(now - Duration::days(days));
+        (before(self.raw_days), self.hourly_days.map(before))
+    }
+}
+
+/// What a run deleted, or would delete in a dry run.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub dry_run: bool,
+    pub raw_before: NaiveDateTime,
+    pub hourly_before: Option<NaiveDateTime>,
+    pub sensors: Vec<SensorReport>,
+}
+
+#[derive(Serialize, QueryableByName, Debug)]
+pub struct SensorReport {
+    #[sql_type = "Integer"]
+    pub sensor_id: i32,
+    #[sql_type = "BigInt"]
+    pub readings: i64,
+    #[sql_type = "BigInt"]
+    pub reading_values: i64,
+    #[sql_type = "BigInt"]
+    pub hourly_rollups: i64,
+}
+
+/// Counts what the cutoffs would delete, for sensors with anything to delete.
+fn candidates(
+    raw_before: NaiveDateTime,
+    hourly_before: Option<NaiveDateTime>,
+    conn: &SqliteConnection,
+) -> Result<Vec<SensorReport>> {
+    // Without an hourly cutoff, compare against a time no rollup is before.
+    let hourly_before = hourly_before.unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    let res = sql_query(
+        "
+select
+ s.id as sensor_id,
+ (select count(*) from readings r where r.sensor_id = s.id and r.timestamp < ?1) as readings,
+ (select count(*) from reading_values v join readings r on r.id = v.reading_id
+  where r.sensor_id = s.id and r.timestamp < ?1) as reading_values,
+ (select count(*) from hourly_rollups h where h.sensor_id = s.id and h.bucket < ?2) as hourly_rollups
+from sensors s
+where readings > 0 or hourly_rollups > 0
+order by s.id;
+",
+    )
+    .bind::<Timestamp, _>(raw_before)
+    .bind::<Timestamp, _>(hourly_before)
+    .load(conn)?;
+    Ok(res)
+}
+
+fn prune(
+    sensor_id: i32,
+    raw_before: NaiveDateTime,
+    hourly_before: Option<NaiveDateTime>,
+    conn: &SqliteConnection,
+) -> Result<()> {
+    conn.transaction(|| {
+        sql_query(
+            "delete from reading_values where reading_id in (select id from readings where sensor_id = ? and timestamp < ?)",
+        )
+        .bind::<Integer, _>(sensor_id)
+        .bind::<Timestamp, _>(raw_before)
+        .execute(conn)?;
+        sql_query("delete from readings where sensor_id = ? and timestamp < ?")
+            .bind::<Integer, _>(sensor_id)
+            .bind::<Timestamp, _>(raw_before)
+            .execute(conn)?;
+        if let Some(hourly_before) = hourly_before {
+            sql_query("delete from hourly_rollups where sensor_id = ? and bucket < ?")
+                .bind::<Integer, _>(sensor_id)
+                .bind::<Timestamp, _>(hourly_before)
+                .execute(conn)?;
+        }
+        retention::advance(
+            Cutoffs {
+                sensor_id,
+                raw_before,
+                hourly_before,
+            },
+            conn,
+        )
+    })
+}
+
+/// Applies the policy as of `now`. With `dry_run`, only reports what would be
+/// deleted.
+pub fn apply(
+    policy: &Policy,
+    now: NaiveDateTime,
+    dry_run: bool,
+    conn: &SqliteConnection,
+) -> Result<Report> {
+    let (raw_before, hourly_before) = policy.cutoffs(now);
+    let sensors = candidates(raw_before, hourly_before, conn)?;
+    if !dry_run {
+        for s in &sensors {
+            prune(s.sensor_id, raw_before, hourly_before, conn)?;
+        }
+    }
+    Ok(Report {
+        dry_run,
+        raw_before,
+        hourly_before,
+        sensors,
+    })
+}
@@ -1,3 +1,132 @@
+table! {
+    alert_incidents (id) {
+        id -> Integer,
+        rule_id -> Integer,
+        opened -> Timestamp,
+        resolved -> Nullable<Timestamp>,
+        acknowledged -> Nullable<Timestamp>,
+        message -> Text,
+    }
+}
+
+table! {
+    alert_rules (id) {
+        id -> Integer,
+        sensor_id -> Integer,
+        kind -> Text,
+        channel -> Nullable<Text>,
+        threshold -> Float,
+        muted_until -> Nullable<Timestamp>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Integer,
+        user_id -> Nullable<Integer>,
+        action -> Text,
+        target -> Nullable<Text>,
+        detail -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        request_id -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    daily_rollups (sensor_id, bucket, channel) {
+        sensor_id -> Integer,
+        bucket -> Timestamp,
+        channel -> Text,
+        count -> Integer,
+        min -> Float,
+        max -> Float,
+        sum -> Float,
+        last -> Float,
+        last_timestamp -> Timestamp,
+        integral -> Float,
+    }
+}
+
+table! {
+    digest_subscriptions (user_id) {
+        user_id -> Integer,
+        frequency -> Text,
+        last_sent -> Nullable<Timestamp>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    energy_settings (sensor_id) {
+        sensor_id -> Integer,
+        system_size_kW -> Nullable<Float>,
+        panel_rated_mW -> Nullable<Float>,
+        tariff_per_kWh -> Nullable<Float>,
+        emissions_per_kWh -> Nullable<Float>,
+    }
+}
+
+table! {
+    hourly_rollups (sensor_id, bucket, channel) {
+        sensor_id -> Integer,
+        bucket -> Timestamp,
+        channel -> Text,
+        count -> Integer,
+        min -> Float,
+        max -> Float,
+        sum -> Float,
+        last -> Float,
+        last_timestamp -> Timestamp,
+        integral -> Float,
+    }
+}
+
+table! {
+    integrations (id) {
+        id -> Integer,
+        owner_id -> Integer,
+        name -> Text,
+        secret -> Text,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    job_runs (id) {
+        id -> Integer,
+        job_name -> Text,
+        runner -> Text,
+        started -> Timestamp,
+        finished -> Nullable<Timestamp>,
+        status -> Text,
+        output -> Nullable<Text>,
+    }
+}
+
+table! {
+    jobs (name) {
+        name -> Text,
+        schedule -> Text,
+        enabled -> Bool,
+        next_run -> Timestamp,
+        locked_by -> Nullable<Text>,
+        locked_until -> Nullable<Timestamp>,
+        last_run -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    model_channels (model, name) {
+        model -> Text,
+        name -> Text,
+        unit -> Text,
+        #[sql_name = "type"]
+        type_ -> Text,
+    }
+}
+
 table! {
     onetime_logins (token) {
         token -> Text,
@@ -7,6 +136,30 @@ table! {
     }
 }
 
+table! {
+    rate_limit_buckets (key) {
+        key -> Text,
+        used -> Double,
+        updated -> Timestamp,
+    }
+}
+
+table! {
+    rate_limit_overrides (principal) {
+        principal -> Text,
+        multiplier -> Integer,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    reading_values (reading_id, channel) {
+        reading_id -> Integer,
+        channel -> Text,
+        value -> Float,
+    }
+}
+
 table! {
     readings (id) {
         id -> Integer,
@@ -21,6 +174,14 @@ table! {
     }
 }
 
+table! {
+    retention_cutoffs (sensor_id) {
+        sensor_id -> Integer,
+        raw_before -> Timestamp,
+        hourly_before -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     sensors (id) {
         id -> Integer,
@@ -29,6 +190,22 @@ table! {
         active -> Bool,
         name -> Nullable<Text>,
         description -> Nullable<Text>,
+        model -> Nullable<Text>,
+        timezone -> Nullable<Text>,
+        latitude -> Nullable<Float>,
+        longitude -> Nullable<Float>,
+        elevation_m -> Nullable<Float>,
+        tilt_deg -> Nullable<Float>,
+        azimuth_deg -> Nullable<Float>,
+    }
+}
+
+table! {
+    tariff_rates (sensor_id, start_hour) {
+        sensor_id -> Integer,
+        start_hour -> Integer,
+        end_hour -> Integer,
+        rate_per_kWh -> Float,
     }
 }
 
@@ -42,6 +219,15 @@ table! {
     }
 }
 
+table! {
+    unsubscribe_tokens (nonce) {
+        nonce -> Text,
+        user_id -> Integer,
+        created -> Timestamp,
+        used -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     users (id) {
         id -> Integer,
@@ -51,8 +237,76 @@ table! {
     }
 }
 
+table! {
+    webhook_deliveries (id) {
+        id -> Integer,
+        webhook_id -> Integer,
+        event -> Text,
+        payload -> Text,
+        status -> Text,
+        attempts -> Integer,
+        next_attempt -> Timestamp,
+        last_attempt -> Nullable<Timestamp>,
+        response_status -> Nullable<Integer>,
+        error -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Integer,
+        owner_id -> Integer,
+        url -> Text,
+        secret -> Text,
+        events -> Text,
+        active -> Bool,
+        created -> Timestamp,
+    }
+}
+
+joinable!(alert_incidents -> alert_rules (rule_id));
+joinable!(alert_rules -> sensors (sensor_id));
+joinable!(audit_log -> users (user_id));
+joinable!(daily_rollups -> sensors (sensor_id));
+joinable!(digest_subscriptions -> users (user_id));
+joinable!(energy_settings -> sensors (sensor_id));
+joinable!(hourly_rollups -> sensors (sensor_id));
+joinable!(integrations -> users (owner_id));
+joinable!(job_runs -> jobs (job_name));
 joinable!(onetime_logins -> users (user_id));
+joinable!(reading_values -> readings (reading_id));
+joinable!(retention_cutoffs -> sensors (sensor_id));
 joinable!(sensors -> users (owner_id));
+joinable!(tariff_rates -> sensors (sensor_id));
 joinable!(tokens -> users (user_id));
+joinable!(unsubscribe_tokens -> users (user_id));
+joinable!(webhook_deliveries -> webhooks (webhook_id));
+joinable!(webhooks -> users (owner_id));
 
-allow_tables_to_appear_in_same_query!(onetime_logins, readings, sensors, tokens, users,);
+allow_tables_to_appear_in_same_query!(
+    alert_incidents,
+    alert_rules,
+    audit_log,
+    daily_rollups,
+    digest_subscriptions,
+    energy_settings,
+    hourly_rollups,
+    integrations,
+    job_runs,
+    jobs,
+    model_channels,
+    onetime_logins,
+    rate_limit_buckets,
+    rate_limit_overrides,
+    reading_values,
+    readings,
+    retention_cutoffs,
+    sensors,
+    tariff_rates,
+    tokens,
+    unsubscribe_tokens,
+    users,
+    webhook_deliveries,
+    webhooks,
+);
@@ -0,0 +1,143 @@
+//! Security headers for the website, and CORS for the API.
+//!
+//! Web responses get a Content-Security-Policy, HSTS, X-Frame-Options and a
+//! Referrer-Policy. API responses get none of those, but browsers on the
+//! origins in `SOL_CORS_ORIGINS` may call the API, and their preflight
+//! requests are answered here since no route handles `OPTIONS`.
+//!
+//! The API takes tokens in headers rather than cookies, so CORS responses
+//! never allow credentials.
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Method, Status},
+    Request, Response,
+};
+
+/// What the site's own pages need: scripts and styles from the site, some of
+/// them inline, Vega's compiled chart expressions, and the icon and font
+/// stylesheets.
+pub const DEFAULT_CSP: &str = "default-src 'self'; \
+     script-src 'self' 'unsafe-inline' 'unsafe-eval'; \
+     style-src 'self' 'unsafe-inline' https://use.fontawesome.com https://fonts.googleapis.com; \
+     font-src 'self' https://use.fontawesome.com https://fonts.gstatic.com; \
+     img-src 'self' data:; \
+     frame-ancestors 'none'; \
+     form-action 'self'; \
+     base-uri 'self'";
+
+/// A year, in seconds.
+pub const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+
+pub const ALLOWED_METHODS: &str = "GET, POST, PUT, DELETE";
+pub const ALLOWED_HEADERS: &str = "Authorization, Content-Type, X-Request-Id";
+/// Response headers that scripts on other origins may read.
+pub const EXPOSED_HEADERS: &str =
+    "X-Request-Id, Retry-After, X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset";
+/// How long browsers may cache a preflight response, in seconds.
+pub const PREFLIGHT_MAX_AGE: u64 = 86_400;
+
+pub struct SecurityHeaders {
+    pub content_security_policy: String,
+    /// The HSTS max-age in seconds, or none to not send HSTS.
+    pub hsts_max_age: Option<u64>,
+    /// Origins like `https://dashboard.solsensor.com` that may call the API,
+    /// or `*` for any.
+    pub allowed_origins: Vec<String>,
+}
+
+impl SecurityHeaders {
+    /// Reads `SOL_CSP`, `SOL_HSTS_MAX_AGE`, which turns HSTS off at 0, and
+    /// `SOL_CORS_ORIGINS`, a comma separated list that's empty by default.
+    pub fn from_env() -> SecurityHeaders {
+        let hsts_max_age =
+            std::env::var("SOL_HSTS_MAX_AGE")
+                .ok()
+                .map_or(DEFAULT_HSTS_MAX_AGE, |age| {
+                    age.parse()
+                        .unwrap_or_else(|_| panic!("SOL_HSTS_MAX_AGE must be a number"))
+                });
+        SecurityHeaders {
+            content_security_policy: std::env::var("SOL_CSP")
+                .unwrap_or_else(|_| DEFAULT_CSP.to_string()),
+            hsts_max_age: Some(hsts_max_age).filter(|&age| age > 0),
+            allowed_origins: std::env::var("SOL_CORS_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|o| o.trim().trim_end_matches('/').to_string())
+                .filter(|o| !o.is_empty())
+                .collect(),
+        }
+    }
+
+    /// What to send in `Access-Control-Allow-Origin` to `origin`, if it's
+    /// allowed.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            Some("*".to_string())
+        } else if self.allowed_origins.iter().any(|o| o == origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn web(&self, res: &mut Response) {
+        res.set_raw_header(
+            "Content-Security-Policy",
+            self.content_security_policy.clone(),
+        );
+        if let Some(age) = self.hsts_max_age {
+            res.set_raw_header("Strict-Transport-Security", format!("max-age={}", age));
+        }
+        res.set_raw_header("X-Frame-Options", "DENY");
+        res.set_raw_header("Referrer-Policy", "strict-origin-when-cross-origin");
+        res.set_raw_header("X-Content-Type-Options", "nosniff");
+    }
+
+    fn api(&self, req: &Request, res: &mut Response) {
+        let origin = match req.headers().get_one("Origin") {
+            Some(origin) => origin,
+            None => return,
+        };
+        // Whether a response is allowed depends on who asked.
+        res.adjoin_header(Header::new("Vary", "Origin"));
+        let allowed = self.allow_origin(origin);
+        let preflight = req.method() == Method::Options
+            && req.headers().contains("Access-Control-Request-Method");
+        if preflight {
+            // Nothing routes OPTIONS, so this replaces a 404.
+            res.take_body();
+            res.remove_header("Content-Type");
+            if allowed.is_none() {
+                res.set_status(Status::Forbidden);
+                return;
+            }
+            res.set_status(Status::NoContent);
+            res.set_raw_header("Access-Control-Allow-Methods", ALLOWED_METHODS);
+            res.set_raw_header("Access-Control-Allow-Headers", ALLOWED_HEADERS);
+            res.set_raw_header("Access-Control-Max-Age", PREFLIGHT_MAX_AGE.to_string());
+        } else if allowed.is_some() {
+            res.set_raw_header("Access-Control-Expose-Headers", EXPOSED_HEADERS);
+        }
+        if let Some(allowed) = allowed {
+            res.set_raw_header("Access-Control-Allow-Origin", allowed);
+        }
+    }
+}
+
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers and CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        if req.uri().path().starts_with("/api/") {
+            self.api(req, res);
+        } else {
+            self.web(res);
+        }
+    }
+}
@@ -1,10 +1,46 @@
 use crate::{
-    json_string,
+    alerts, api,
+    digests::{self, Schedule},
+    import,
+    jobs::{self, cron::Cron},
+    json_string, live,
+    models::{job, rate_limit, retention::cutoffs, rollup},
+    ratelimit::{
+        Class, Kind, Limit, Limits, MemoryBuckets, RateLimiter, Store, MAX_MEMORY_BUCKETS,
+    },
+    retention::{self, Policy},
+    schema::{onetime_logins, unsubscribe_tokens},
+    security::SecurityHeaders,
     tests::util::{
-        add_sensor, get_token, register, response_json_value, test_client, token_auth_header,
+        add_readings, add_sensor, basic_auth_header, csrf_token, get_sensor_token, get_token,
+        make_superuser, register, response_json_value, test_client, test_client_with_db,
+        test_client_with_limits, token_auth_header, web_login,
+    },
+    util::{
+        email::{self, Notifier},
+        net,
     },
+    webhooks,
+};
+use chrono::{Duration, NaiveDate, Utc, Weekday};
+use diesel::{
+    sql_query,
+    sql_types::{BigInt, Float, Integer, Nullable, Text},
+    Connection, QueryDsl, RunQueryDsl, SqliteConnection,
+};
+use rocket::{
+    config::{Config, Environment},
+    http::{ContentType, Header, Status},
+    local::{Client, LocalResponse},
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
 };
-use rocket::http::{ContentType, Status};
 
 #[test]
 fn get_users_unauthorized() {
@@ -115,3 +151,2649 @@ fn add_sensor_duplicate_hardware_id() {
     );
     assert_eq!(res.status(), Status::BadRequest);
 }
+
+fn add_integration(client: &Client, token: &str) -> String {
+    let mut res = client
+        .post("/api/integrations/new")
+        .header(ContentType::JSON)
+        .header(token_auth_header(token))
+        .body(json_string!({"name": "the things stack"}))
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(res.status(), Status::Ok);
+    data["secret"]
+        .as_str()
+        .expect("must have a secret")
+        .to_string()
+}
+
+fn packed_reading(ts: u32) -> Vec<u8> {
+    let mut bytes = ts.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&1234u16.to_be_bytes());
+    bytes.extend_from_slice(&250u16.to_be_bytes());
+    bytes.extend_from_slice(&4950u16.to_be_bytes());
+    bytes.extend_from_slice(&(-525i16).to_be_bytes());
+    bytes.extend_from_slice(&3700u16.to_be_bytes());
+    bytes
+}
+
+#[test]
+fn lorawan_uplink_stores_readings() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 16);
+    let secret = add_integration(&client, &tok);
+
+    let mut payload = packed_reading(1000);
+    payload.extend(packed_reading(2000));
+    let mut res = client
+        .post("/api/lorawan/uplink")
+        .header(ContentType::JSON)
+        .header(Header::new("X-Webhook-Secret", secret))
+        .body(json_string!({
+            "end_device_ids": {"device_id": "sol-1", "dev_eui": "0000000000000010"},
+            "uplink_message": {"f_port": 1, "frm_payload": base64::encode(&payload)},
+        }))
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(data["inserted"], 2);
+
+    let mut res = client
+        .get("/api/sensor/1/readings?start=0&end=3000")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let readings = data["data"]["readings"]
+        .as_array()
+        .expect("readings must be an array");
+    assert_eq!(readings.len(), 2);
+    let power = readings[0]["peak_power_mW"]
+        .as_f64()
+        .expect("must be a number");
+    assert!((power - 123.4).abs() < 1e-3);
+    assert_eq!(readings[0]["temp_celsius"], -5.25);
+}
+
+#[test]
+fn lorawan_uplink_invalid_secret() {
+    let client = test_client();
+    let mut res = client
+        .post("/api/lorawan/uplink")
+        .header(ContentType::JSON)
+        .header(Header::new("X-Webhook-Secret", "webhook-nope"))
+        .body(json_string!({"devEUI": "0000000000000010", "data": ""}))
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["error"], "ApiError(invalid webhook secret)");
+    assert_eq!(res.status(), Status::BadRequest);
+}
+
+#[test]
+fn lorawan_uplink_unknown_dev_eui() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    let secret = add_integration(&client, &tok);
+
+    let mut res = client
+        .post("/api/lorawan/uplink?event=up")
+        .header(ContentType::JSON)
+        .header(Header::new("X-Webhook-Secret", secret))
+        .body(json_string!({
+            "deviceInfo": {"devEui": "00000000000000ff"},
+            "data": base64::encode(&packed_reading(1000)),
+        }))
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(no active sensor with DevEUI 00000000000000ff)"
+    );
+}
+
+fn import_csv(client: &Client, token: &str, query: &str, csv: &str) -> serde_json::Value {
+    let mut res = client
+        .post(format!("/api/sensor/1/import?{}", query))
+        .header(ContentType::CSV)
+        .header(token_auth_header(token))
+        .body(csv)
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(res.status(), Status::Ok);
+    data
+}
+
+#[test]
+fn import_csv_dry_run_then_import() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+
+    let csv = "time,power,peak_current_mA,peak_voltage_V,temp_celsius,batt_V\n\
+               2019-07-01 12:00:00,10.5,1,2,20,3.7\n\
+               2019-07-01 12:05:00,11.5,1,2,20,3.7\n\
+               2019-07-01 12:05:00,11.5,1,2,20,3.7\n\
+               not a time,11.5,1,2,20,3.7\n";
+    let query = "timestamp_column=time&peak_power_mW_column=power&timezone=America/Denver";
+
+    let report = import_csv(&client, &tok, &format!("{}&dry_run=true", query), csv);
+    assert_eq!(report["rows"], 4);
+    assert_eq!(report["valid"], 3);
+    assert_eq!(report["duplicates"], 1);
+    assert_eq!(report["inserted"], 0);
+    assert_eq!(report["errors"][0]["line"], 5);
+    assert_eq!(report["preview"][0]["timestamp"], "2019-07-01T18:00:00");
+
+    let report = import_csv(&client, &tok, query, csv);
+    assert_eq!(report["inserted"], 2);
+
+    let report = import_csv(&client, &tok, query, csv);
+    assert_eq!(report["inserted"], 0);
+    assert_eq!(report["duplicates"], 3);
+}
+
+#[test]
+fn import_csv_too_large() {
+    let input = io::repeat(b'0').take(import::MAX_IMPORT_BYTES + 1);
+    assert_eq!(
+        import::spool(input).err().map(|e| e.to_string()),
+        Some("import is larger than 64 MB".to_string())
+    );
+}
+
+#[test]
+fn import_csv_not_owner() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    register(&client, "other@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    let other = get_token(&client, "other@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+
+    let mut res = client
+        .post("/api/sensor/1/import")
+        .header(ContentType::CSV)
+        .header(token_auth_header(&other))
+        .body("timestamp\n")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(user is not the owner of this sensor)"
+    );
+}
+
+#[test]
+fn model_channels_are_stored_and_returned() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "admin@gmail.com", "mypassword");
+    make_superuser(&db_uri, "admin@gmail.com");
+    let tok = get_token(&client, "admin@gmail.com", "mypassword");
+
+    let res = client
+        .post("/api/models/solv2/channels")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(json_string!([
+            {"name": "irradiance_Wm2", "unit": "W/m2", "type": "float"},
+            {"name": "door_open", "unit": "", "type": "boolean"},
+        ]))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    let mut res = client
+        .post("/api/models/solv2/channels")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(json_string!([{"name": "timestamp", "unit": "s", "type": "integer"}]))
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(invalid channel: channel name 'timestamp' is reserved)"
+    );
+
+    let res = client
+        .post("/api/add_sensor")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(json_string!({"hardware_id": 12, "model": "solv2"}))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    let mut res = client.get("/api/sensor/1/channels").dispatch();
+    let data = response_json_value(&mut res);
+    let names: Vec<&str> = data["channels"]
+        .as_array()
+        .expect("channels must be an array")
+        .iter()
+        .map(|c| c["name"].as_str().expect("name must be a string"))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "peak_power_mW",
+            "peak_current_mA",
+            "peak_voltage_V",
+            "temp_celsius",
+            "batt_V",
+            "door_open",
+            "irradiance_Wm2",
+        ]
+    );
+
+    let reading = json!({
+        "timestamp": 1000,
+        "peak_power_mW": 1.0,
+        "peak_current_mA": 1.0,
+        "peak_voltage_V": 1.0,
+        "temp_celsius": 20.0,
+        "batt_V": 3.7,
+        "channels": {"irradiance_Wm2": 812.5, "door_open": 0},
+    });
+    assert_eq!(
+        add_readings(&client, &sensor_tok, json!([reading])),
+        Status::Ok
+    );
+
+    let mut res = client
+        .get("/api/sensor/1/readings?start=0&end=3000")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let reading = &data["data"]["readings"][0];
+    assert_eq!(reading["peak_power_mW"], 1.0);
+    assert_eq!(reading["irradiance_Wm2"], 812.5);
+    assert_eq!(reading["door_open"], 0.0);
+}
+
+#[test]
+fn undeclared_channel_is_rejected() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    let reading = json!({
+        "timestamp": 1000,
+        "peak_power_mW": 1.0,
+        "peak_current_mA": 1.0,
+        "peak_voltage_V": 1.0,
+        "temp_celsius": 20.0,
+        "batt_V": 3.7,
+        "channels": {"humidity_pct": 40},
+    });
+    assert_eq!(
+        add_readings(&client, &sensor_tok, json!([reading])),
+        Status::BadRequest
+    );
+
+    let mut res = client
+        .get("/api/sensor/1/readings?start=0&end=3000")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["data"]["readings"], json!([]));
+}
+
+/// Inserts `n` readings five minutes apart from `start`, straight into the
+/// database, and rebuilds the sensor's rollups. Peak power is `power`, or
+/// cycles from 0 to 99 without one.
+fn insert_readings_with_power(
+    db_uri: &str,
+    sensor_id: i32,
+    start: i64,
+    n: i64,
+    power: Option<f32>,
+) {
+    let conn = SqliteConnection::establish(db_uri).expect("error connecting to db");
+    sql_query(
+        "
+with recursive n(i) as (select 0 union all select i + 1 from n where i < ? - 1)
+insert into readings (sensor_id, timestamp, peak_power_mW, peak_current_mA, peak_voltage_V, temp_celsius, batt_V)
+select ?, datetime(? + i * 300, 'unixepoch'), coalesce(?, i % 100), 1, 1, 20, 3.7 from n;
+",
+    )
+    .bind::<BigInt, _>(n)
+    .bind::<Integer, _>(sensor_id)
+    .bind::<BigInt, _>(start)
+    .bind::<Nullable<Float>, _>(power)
+    .execute(&conn)
+    .expect("failed to insert readings");
+    rollup::rebuild(sensor_id, &conn).expect("failed to rebuild rollups");
+}
+
+fn insert_readings(db_uri: &str, sensor_id: i32, start: i64, n: i64) {
+    insert_readings_with_power(db_uri, sensor_id, start, n, None);
+}
+
+/// Fills in a year of readings, one every five minutes, starting at
+/// 2019-01-01 00:00:00 UTC.
+fn insert_year_of_readings(db_uri: &str, sensor_id: i32) {
+    insert_readings(db_uri, sensor_id, 1546300800, 365 * 288);
+}
+
+#[test]
+fn readings_pagination_over_a_year() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    insert_year_of_readings(&db_uri, 1);
+
+    let base = "/api/sensor/1/readings?start=0&end=1600000000&limit=10000&fields=timestamp";
+    let mut cursor: Option<String> = None;
+    let mut pages = 0;
+    let mut last: Option<String> = None;
+    let mut total = 0;
+    loop {
+        let url = match cursor {
+            Some(c) => format!("{}&cursor={}", base, c),
+            None => base.to_string(),
+        };
+        let mut res = client.get(url).dispatch();
+        let data = response_json_value(&mut res);
+        let readings = data["data"]["readings"]
+            .as_array()
+            .expect("readings must be an array");
+        for r in readings {
+            let ts = r["timestamp"].as_str().expect("must have a timestamp");
+            assert!(last.as_ref().map(|l| l.as_str() < ts).unwrap_or(true));
+            assert_eq!(r.as_object().expect("must be an object").len(), 1);
+            last = Some(ts.to_string());
+        }
+        total += readings.len();
+        pages += 1;
+        match data["data"]["next_cursor"].as_str() {
+            Some(c) => cursor = Some(c.to_string()),
+            None => break,
+        }
+    }
+    assert_eq!(total, 365 * 288);
+    assert_eq!(pages, 11);
+    assert_eq!(last, Some("2019-12-31T23:55:00".to_string()));
+
+    let mut res = client
+        .get("/api/sensor/1/readings?start=0&end=1600000000&limit=2&order=desc&unixtime=true")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let readings = data["data"]["readings"]
+        .as_array()
+        .expect("readings must be an array");
+    assert_eq!(readings.len(), 2);
+    assert_eq!(readings[0]["timestamp"], 1577836500);
+    assert_eq!(readings[1]["timestamp"], 1577836200);
+    assert_eq!(
+        data["data"]["next_cursor"],
+        format!("1577836200_{}", 365 * 288 - 1)
+    );
+}
+
+#[test]
+fn readings_pages_split_readings_with_the_same_timestamp() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+    let readings = json!([
+        reading_at(1000, 1.0),
+        reading_at(1000, 2.0),
+        reading_at(1300, 3.0)
+    ]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    let powers = |url: &str| {
+        let mut res = client.get(url).dispatch();
+        let data = response_json_value(&mut res)["data"].clone();
+        let powers: Vec<f64> = data["readings"]
+            .as_array()
+            .expect("readings must be an array")
+            .iter()
+            .map(|r| r["peak_power_mW"].as_f64().expect("must be a number"))
+            .collect();
+        (powers, data["next_cursor"].as_str().map(|c| c.to_string()))
+    };
+    let base = "/api/sensor/1/readings?start=0&end=2000";
+    let (page, cursor) = powers(&format!("{}&limit=1", base));
+    assert_eq!(page, vec![1.0]);
+    let (page, cursor) = powers(&format!("{}&limit=1&cursor={}", base, cursor.unwrap()));
+    assert_eq!(page, vec![2.0]);
+    // A cursor alone gets a page of the default size.
+    let (page, cursor) = powers(&format!("{}&cursor={}", base, cursor.unwrap()));
+    assert_eq!(page, vec![3.0]);
+    assert_eq!(cursor, None);
+
+    // Without a limit or cursor, every reading comes back at once.
+    let (page, cursor) = powers(base);
+    assert_eq!(page, vec![1.0, 2.0, 3.0]);
+    assert_eq!(cursor, None);
+}
+
+#[test]
+fn readings_unknown_field() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+
+    let mut res = client
+        .get("/api/sensor/1/readings?start=0&end=3000&fields=timestamp,humidity")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["error"], "ApiError(unknown field 'humidity')");
+}
+
+fn reading_at(ts: i64, power: f32) -> serde_json::Value {
+    json!({
+        "timestamp": ts,
+        "peak_power_mW": power,
+        "peak_current_mA": 1.0,
+        "peak_voltage_V": 2.0,
+        "temp_celsius": 20.0,
+        "batt_V": 3.5,
+    })
+}
+
+#[test]
+fn export_sensor_readings_csv_and_ndjson() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+    let readings = json!([reading_at(1000, 1.5), reading_at(1300, 2.5)]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    let mut res = client
+        .get("/api/sensor/1/export?start=0&end=3000&format=csv&unixtime=true")
+        .dispatch();
+    assert_eq!(res.content_type(), Some(ContentType::CSV));
+    assert_eq!(
+        res.headers().get_one("Content-Disposition"),
+        Some("attachment; filename=\"sensor-1.csv\"")
+    );
+    assert_eq!(
+        res.body_string().expect("must have a body"),
+        "sensor_id,timestamp,peak_power_mW,peak_current_mA,peak_voltage_V,temp_celsius,batt_V\n\
+         1,1000,1.5,1,2,20,3.5\n\
+         1,1300,2.5,1,2,20,3.5\n"
+    );
+
+    let mut res = client
+        .get("/api/sensor/1/export?start=0&end=3000&format=ndjson")
+        .dispatch();
+    let body = res.body_string().expect("must have a body");
+    let lines: Vec<serde_json::Value> = body
+        .lines()
+        .map(|l| serde_json::from_str(l).expect("each line must be json"))
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["timestamp"], "1970-01-01T00:21:40");
+    assert_eq!(lines[1]["peak_power_mW"], 2.5);
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn export_sensor_readings_parquet() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+    let readings = json!([reading_at(1000, 1.5), reading_at(1300, 2.5)]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    let mut res = client
+        .get("/api/sensor/1/export?start=0&end=3000&format=parquet")
+        .dispatch();
+    assert_eq!(res.content_type(), Some(ContentType::Binary));
+    let body = res.body_bytes().expect("must have a body");
+    assert!(body.starts_with(b"PAR1"));
+    assert!(body.ends_with(b"PAR1"));
+
+    let path = std::env::temp_dir().join(format!(
+        "sol-test-{}.parquet",
+        crate::util::token::rand_str()
+    ));
+    std::fs::write(&path, &body).unwrap();
+    let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap())
+        .expect("must be a parquet file");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+}
+
+#[test]
+fn export_all_readings_requires_user_token() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    add_sensor(&client, &tok, 13);
+    let tok12 = get_sensor_token(&client, &tok, 12);
+    let tok13 = get_sensor_token(&client, &tok, 13);
+    assert_eq!(
+        add_readings(&client, &tok12, json!([reading_at(1000, 1.0)])),
+        Status::Ok
+    );
+    assert_eq!(
+        add_readings(&client, &tok13, json!([reading_at(1000, 2.0)])),
+        Status::Ok
+    );
+
+    let res = client.get("/api/export?start=0&end=3000").dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+
+    let mut res = client
+        .get("/api/export?start=0&end=3000&unixtime=true")
+        .header(token_auth_header(&tok))
+        .dispatch();
+    let body = res.body_string().expect("must have a body");
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[1], "1,1000,1,1,2,20,3.5");
+    assert_eq!(lines[2], "2,1000,2,1,2,20,3.5");
+}
+
+#[test]
+fn aggregate_readings_by_interval() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    insert_year_of_readings(&db_uri, 1);
+
+    let mut res = client
+        .get("/api/sensor/1/aggregate?start=1546300800&end=1546387200&interval=1h")
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let data = response_json_value(&mut res);
+    let buckets = data["data"]["buckets"]
+        .as_array()
+        .expect("must be an array");
+    assert_eq!(buckets.len(), 24);
+    assert_eq!(buckets[1]["start"], "2019-01-01T01:00:00");
+    assert_eq!(buckets[1]["channels"]["peak_power_mW"]["count"], 12);
+
+    let mut res = client
+        .get("/api/sensor/1/aggregate?start=1546300800&end=1546387200&interval=1d")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["data"]["timezone"], "UTC");
+    let buckets = data["data"]["buckets"]
+        .as_array()
+        .expect("must be an array");
+    assert_eq!(buckets.len(), 1);
+    let power = &buckets[0]["channels"]["peak_power_mW"];
+    assert_eq!(power["count"], 288);
+    assert_eq!(power["min"], 0.0);
+    assert_eq!(power["max"], 99.0);
+    assert_eq!(power["last"], 87.0);
+    let mean = power["mean"].as_f64().expect("mean must be a number");
+    assert!((mean - 13728.0 / 288.0).abs() < 0.001);
+    assert_eq!(buckets[0]["channels"]["batt_V"]["count"], 288);
+}
+
+#[test]
+fn aggregate_daily_buckets_use_timezone() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    insert_year_of_readings(&db_uri, 1);
+
+    // Sydney is UTC+11 in January, so local days start at 13:00 UTC.
+    let mut res = client
+        .get("/api/sensor/1/aggregate?start=1546300800&end=1546473600&interval=1d&tz=Australia/Sydney")
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let data = response_json_value(&mut res);
+    let buckets = data["data"]["buckets"]
+        .as_array()
+        .expect("must be an array");
+    assert_eq!(buckets.len(), 3);
+    assert_eq!(buckets[0]["start"], "2018-12-31T13:00:00");
+    assert_eq!(buckets[0]["channels"]["peak_power_mW"]["count"], 13 * 12);
+    assert_eq!(buckets[1]["start"], "2019-01-01T13:00:00");
+    assert_eq!(buckets[1]["channels"]["peak_power_mW"]["count"], 288);
+}
+
+#[test]
+fn aggregate_too_many_buckets() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+
+    let mut res = client
+        .get("/api/sensor/1/aggregate?start=1546300800&end=1577836800&interval=1m")
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert!(data["error"]
+        .as_str()
+        .expect("must have an error")
+        .contains("buckets"));
+
+    // Calendar buckets are counted before they're built.
+    let mut res = client
+        .get("/api/sensor/1/aggregate?start=0&end=253402300799&interval=1d")
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(range would have 2932896 buckets, use a longer interval)"
+    );
+}
+
+#[test]
+fn rollups_follow_inserted_readings() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    // Noon two days ago, so every reading is on the same day.
+    let t0 = rollup::day_start(Utc::now().naive_utc() - Duration::days(2)).timestamp() + 12 * 3600;
+    let readings = json!([
+        reading_at(t0, 100.0),
+        reading_at(t0 + 1800, 100.0),
+        reading_at(t0 + 3600, 200.0),
+    ]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+    // An earlier reading changes the integral of the hour before.
+    let readings = json!([reading_at(t0 - 1800, 0.0)]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+    let mismatches = rollup::check(1, &conn).expect("failed to check rollups");
+    assert!(mismatches.is_empty(), "{:?}", mismatches);
+
+    let url = format!(
+        "/api/sensor/1/aggregate?start={}&end={}&interval=1h",
+        t0 - 3600,
+        t0 + 7200
+    );
+    let mut res = client.get(url).dispatch();
+    let data = response_json_value(&mut res);
+    let buckets = data["data"]["buckets"]
+        .as_array()
+        .expect("must be an array");
+    assert_eq!(buckets.len(), 3);
+    assert_eq!(buckets[0]["channels"]["peak_power_mW"]["count"], 1);
+    assert_eq!(buckets[1]["channels"]["peak_power_mW"]["count"], 2);
+    assert_eq!(buckets[1]["channels"]["peak_power_mW"]["last"], 100.0);
+    assert_eq!(buckets[2]["channels"]["peak_power_mW"]["mean"], 200.0);
+
+    // 25 + 50 + 75 mWh over the three half hours.
+    let mut res = client.get("/api/sensor/1/energy_stats").dispatch();
+    let data = response_json_value(&mut res);
+    let stats = data["stats"].as_array().expect("must be an array");
+    assert_eq!(stats.len(), 1);
+    let kwh = stats[0]["equiv_kWh"].as_f64().expect("must be a number");
+    assert!((kwh - 150.0 * 7895.0 / 1e6).abs() < 0.001);
+}
+
+#[test]
+fn rollup_check_finds_stale_rollups() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+
+    let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+    sql_query(
+        "insert into readings (sensor_id, timestamp, peak_power_mW, peak_current_mA, peak_voltage_V, temp_celsius, batt_V) values (1, '2019-01-01 00:00:00', 1, 1, 1, 20, 3.7)",
+    )
+    .execute(&conn)
+    .expect("failed to insert reading");
+
+    let mismatches = rollup::check(1, &conn).expect("failed to check rollups");
+    assert_eq!(mismatches.len(), 10);
+    assert_eq!(mismatches[0].expected_count, Some(1));
+    assert_eq!(mismatches[0].actual_count, None);
+
+    rollup::rebuild(1, &conn).expect("failed to rebuild rollups");
+    let mismatches = rollup::check(1, &conn).expect("failed to check rollups");
+    assert!(mismatches.is_empty(), "{:?}", mismatches);
+}
+
+#[test]
+fn retention_replaces_old_readings_with_rollups() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    let now = Utc::now().naive_utc();
+    let day0 = rollup::day_start(now - Duration::days(10));
+    insert_readings(&db_uri, 1, day0.timestamp(), 3 * 288);
+
+    let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+    let policy = Policy {
+        raw_days: 8,
+        hourly_days: None,
+    };
+    let report = retention::apply(&policy, now, true, &conn).expect("failed dry run");
+    assert_eq!(report.raw_before, day0 + Duration::days(2));
+    assert_eq!(report.sensors.len(), 1);
+    assert_eq!(report.sensors[0].readings, 2 * 288);
+    assert!(cutoffs(1, &conn).expect("failed to load cutoffs").is_none());
+
+    retention::apply(&policy, now, false, &conn).expect("failed to apply policy");
+    let report = retention::apply(&policy, now, true, &conn).expect("failed dry run");
+    assert!(report.sensors.is_empty());
+    let mismatches = rollup::check(1, &conn).expect("failed to check rollups");
+    assert!(mismatches.is_empty(), "{:?}", mismatches);
+
+    // Hourly means stand in for the deleted readings.
+    let url = format!(
+        "/api/sensor/1/readings?start={}&end={}&limit=10000",
+        day0.timestamp(),
+        (day0 + Duration::days(3)).timestamp()
+    );
+    let mut res = client.get(url).dispatch();
+    let data = response_json_value(&mut res);
+    let readings = data["data"]["readings"]
+        .as_array()
+        .expect("must be an array");
+    assert_eq!(readings.len(), 2 * 24 + 288);
+    assert_eq!(readings[0]["id"], 0);
+    assert_eq!(
+        readings[1]["timestamp"],
+        format!("{}T01:00:00", day0.date())
+    );
+    assert_eq!(readings[0]["peak_power_mW"], 5.5);
+    assert!(readings[48]["id"].as_i64().expect("must have an id") > 0);
+
+    let mut res = client.get("/api/sensor/1/energy_stats").dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["stats"].as_array().expect("must be an array").len(), 3);
+
+    let readings = json!([reading_at(day0.timestamp() + 600, 1.0)]);
+    assert_eq!(
+        add_readings(&client, &sensor_tok, readings),
+        Status::BadRequest
+    );
+}
+
+#[test]
+fn energy_stats_use_sensor_settings_and_time_of_use_rates() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    // 100 mWh in each of the hours starting at 11:00 and 12:00 on 2019-01-01.
+    let noon = 1546344000;
+    let readings = json!([
+        reading_at(noon - 3600, 100.0),
+        reading_at(noon, 100.0),
+        reading_at(noon + 3600, 100.0),
+    ]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    let settings = json!({
+        "system_size_kW": 5.0,
+        "panel_rated_mW": 500.0,
+        "tariff_per_kWh": 0.1,
+        "emissions_per_kWh": 0.5,
+        "tariff_rates": [{ "start_hour": 12, "end_hour": 13, "rate_per_kWh": 0.5 }],
+    });
+    let res = client
+        .put("/api/sensor/1/energy_settings")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(settings.to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    let mut res = client.get("/api/sensor/1/energy_settings").dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["system_size_kW"], 5.0);
+    assert_eq!(data["tariff_rates"][0]["start_hour"], 12);
+
+    let mut res = client
+        .get("/api/sensor/1/energy_stats?start=2019-01-01&end=2019-01-01")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let stats = data["stats"].as_array().expect("must be an array");
+    assert_eq!(stats.len(), 1);
+    let stat = |name: &str| stats[0][name].as_f64().expect("must be a number");
+    assert!((stat("equiv_kWh") - 2.0).abs() < 0.001);
+    assert!((stat("dollars_saved") - 0.6).abs() < 0.001);
+    assert!((stat("co2_saved") - 1.0).abs() < 0.001);
+    assert!((stat("cap_factor") - 200.0 / (500.0 * 24.0)).abs() < 0.001);
+
+    let mut res = client
+        .get("/api/sensor/1/energy_stats?start=2019-01-02&end=2019-01-01")
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(start date must not be after end date)"
+    );
+
+    let mut res = client
+        .get("/api/sensor/1/energy_stats?start=0001-01-01&end=9999-12-31")
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(date range is longer than 1098 days)"
+    );
+    let mut res = client
+        .get("/api/sensor/1/performance?start=2016-01-01&end=2019-12-31")
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(date range is longer than 1098 days)"
+    );
+}
+
+#[test]
+fn energy_settings_are_validated() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    register(&client, "other@gmail.com", "mypassword");
+    let other_tok = get_token(&client, "other@gmail.com", "mypassword");
+
+    let overlapping = json!({
+        "tariff_rates": [
+            { "start_hour": 8, "end_hour": 12, "rate_per_kWh": 0.2 },
+            { "start_hour": 11, "end_hour": 14, "rate_per_kWh": 0.3 },
+        ],
+    });
+    let mut res = client
+        .put("/api/sensor/1/energy_settings")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(overlapping.to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(invalid energy settings: rates starting at hours 8 and 11 overlap)"
+    );
+
+    let res = client
+        .put("/api/sensor/1/energy_settings")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&other_tok))
+        .body(json!({ "system_size_kW": 3.0 }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+}
+
+fn add_sensor_in_timezone(client: &Client, token: &str, hw_id: usize, tz: &str) {
+    let res = client
+        .post("/api/add_sensor")
+        .header(ContentType::JSON)
+        .header(token_auth_header(token))
+        .body(json!({ "hardware_id": hw_id, "timezone": tz }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
+
+/// Daily kWh and capacity factor, with settings that make a constant 100 mW
+/// come out as 0.1 kWh per hour and a capacity factor of 0.1.
+fn local_daily_energy(
+    client: &Client,
+    token: &str,
+    start: &str,
+    end: &str,
+) -> Vec<(String, f64, f64)> {
+    let res = client
+        .put("/api/sensor/1/energy_settings")
+        .header(ContentType::JSON)
+        .header(token_auth_header(token))
+        .body(json!({ "system_size_kW": 1.0, "panel_rated_mW": 1000.0 }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let url = format!("/api/sensor/1/energy_stats?start={}&end={}", start, end);
+    let mut res = client.get(url).dispatch();
+    let data = response_json_value(&mut res);
+    data["stats"]
+        .as_array()
+        .expect("must be an array")
+        .iter()
+        .map(|s| {
+            (
+                s["date"].as_str().expect("must have a date").to_string(),
+                s["equiv_kWh"].as_f64().expect("must be a number"),
+                s["cap_factor"].as_f64().expect("must be a number"),
+            )
+        })
+        .collect()
+}
+
+fn assert_daily_energy(stats: &[(String, f64, f64)], expected: &[(&str, f64)]) {
+    assert_eq!(stats.len(), expected.len());
+    for (stat, &(date, kwh)) in stats.iter().zip(expected) {
+        assert_eq!(stat.0, date);
+        assert!(
+            (stat.1 - kwh).abs() < 0.001,
+            "{} != {} on {}",
+            stat.1,
+            kwh,
+            date
+        );
+        assert!(
+            (stat.2 - 0.1).abs() < 0.001,
+            "capacity factor {} on {}",
+            stat.2,
+            date
+        );
+    }
+}
+
+#[test]
+fn time_of_use_rates_apply_to_local_hours() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor_in_timezone(&client, &tok, 12, "America/Denver");
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    // 100 mWh in each of the hours starting at 11:00 and 12:00 local time on
+    // 2019-01-01, which are 18:00 and 19:00 UTC.
+    let local_noon = 1546369200;
+    let readings = json!([
+        reading_at(local_noon - 3600, 100.0),
+        reading_at(local_noon, 100.0),
+        reading_at(local_noon + 3600, 100.0),
+    ]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    let settings = json!({
+        "system_size_kW": 5.0,
+        "panel_rated_mW": 500.0,
+        "tariff_per_kWh": 0.1,
+        "tariff_rates": [{ "start_hour": 12, "end_hour": 13, "rate_per_kWh": 0.5 }],
+    });
+    let res = client
+        .put("/api/sensor/1/energy_settings")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(settings.to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    let mut res = client
+        .get("/api/sensor/1/energy_stats?start=2019-01-01&end=2019-01-01")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let stats = data["stats"].as_array().expect("must be an array");
+    assert_eq!(stats.len(), 1);
+    let dollars = stats[0]["dollars_saved"]
+        .as_f64()
+        .expect("must be a number");
+    assert!((dollars - 0.6).abs() < 0.001, "{}", dollars);
+}
+
+#[test]
+fn energy_stats_use_local_days_across_spring_forward() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor_in_timezone(&client, &tok, 12, "America/New_York");
+
+    // Local midnight on 2019-03-09 until local midnight on 2019-03-12, with
+    // one more reading to close the last segment.
+    let start = 1552107600;
+    insert_readings_with_power(&db_uri, 1, start, 71 * 12 + 1, Some(100.0));
+
+    let stats = local_daily_energy(&client, &tok, "2019-03-09", "2019-03-11");
+    assert_daily_energy(
+        &stats,
+        &[
+            ("2019-03-09", 2.4),
+            ("2019-03-10", 2.3),
+            ("2019-03-11", 2.4),
+        ],
+    );
+
+    // Daily aggregates default to the sensor's timezone too.
+    let url = format!(
+        "/api/sensor/1/aggregate?start={}&end={}&interval=1d",
+        start,
+        start + 71 * 3600
+    );
+    let mut res = client.get(url).dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["data"]["timezone"], "America/New_York");
+    let buckets = data["data"]["buckets"]
+        .as_array()
+        .expect("must be an array");
+    let starts: Vec<&str> = buckets
+        .iter()
+        .map(|b| b["start"].as_str().expect("must have a start"))
+        .collect();
+    assert_eq!(
+        starts,
+        vec![
+            "2019-03-09T05:00:00",
+            "2019-03-10T05:00:00",
+            "2019-03-11T04:00:00"
+        ]
+    );
+    assert_eq!(buckets[1]["channels"]["peak_power_mW"]["count"], 23 * 12);
+}
+
+#[test]
+fn energy_stats_use_local_days_across_fall_back() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor_in_timezone(&client, &tok, 12, "America/New_York");
+
+    // Local midnight on 2019-11-02 until local midnight on 2019-11-05.
+    insert_readings_with_power(&db_uri, 1, 1572667200, 73 * 12 + 1, Some(100.0));
+
+    let stats = local_daily_energy(&client, &tok, "2019-11-02", "2019-11-04");
+    assert_daily_energy(
+        &stats,
+        &[
+            ("2019-11-02", 2.4),
+            ("2019-11-03", 2.5),
+            ("2019-11-04", 2.4),
+        ],
+    );
+}
+
+fn energy_summary(client: &Client, query: &str) -> serde_json::Value {
+    let url = format!("/api/sensor/1/energy_summary?{}", query);
+    let mut res = client.get(url).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    response_json_value(&mut res)
+}
+
+fn assert_kwh(value: &serde_json::Value, kwh: f64) {
+    let actual = value.as_f64().expect("must be a number");
+    assert!((actual - kwh).abs() < 0.01, "{} != {}", actual, kwh);
+}
+
+#[test]
+fn energy_summaries_compare_with_earlier_periods() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+
+    // 2.4 kWh a day from 2019-02-01 until 2019-03-11.
+    insert_readings_with_power(&db_uri, 1, 1548979200, 38 * 288 + 1, Some(100.0));
+    let res = client
+        .put("/api/sensor/1/energy_settings")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(json!({ "system_size_kW": 1.0, "panel_rated_mW": 1000.0 }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    let month = energy_summary(&client, "period=month&date=2019-03-15");
+    assert_eq!(month["period"], "month");
+    assert_eq!(month["current"]["start"], "2019-03-01");
+    assert_eq!(month["current"]["end"], "2019-03-31");
+    // The reading that closes 2019-03-10 starts an 11th day.
+    assert_eq!(month["current"]["days"], 11);
+    assert_kwh(&month["current"]["equiv_kWh"], 24.0);
+    assert_eq!(month["previous"]["start"], "2019-02-01");
+    assert_eq!(month["previous"]["end"], "2019-02-28");
+    assert_kwh(&month["previous"]["equiv_kWh"], 67.2);
+    assert_kwh(&month["vs_previous"]["equiv_kWh"], -43.2);
+    assert_kwh(&month["vs_previous"]["percent"], -64.286);
+    assert_eq!(month["year_ago"]["start"], "2018-03-01");
+    assert_kwh(&month["year_ago"]["equiv_kWh"], 0.0);
+    assert!(month["vs_year_ago"]["percent"].is_null());
+
+    // Weeks start on Monday, and a week a year ago starts on the same weekday.
+    let week = energy_summary(&client, "period=week&date=2019-03-06");
+    assert_eq!(week["current"]["start"], "2019-03-04");
+    assert_eq!(week["previous"]["start"], "2019-02-25");
+    assert_eq!(week["year_ago"]["start"], "2018-03-05");
+    assert_kwh(&week["current"]["equiv_kWh"], 16.8);
+    assert_kwh(&week["vs_previous"]["percent"], 0.0);
+
+    let year = energy_summary(&client, "period=year&date=2019-06-01");
+    assert_eq!(year["current"]["end"], "2019-12-31");
+    assert_kwh(&year["current"]["equiv_kWh"], 91.2);
+    assert!(year["year_ago"].is_null());
+    assert!(year["vs_year_ago"].is_null());
+
+    let mut res = client
+        .get("/api/sensor/1/energy_summary?period=fortnight")
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(data["error"], "ApiError(invalid period 'fortnight')");
+}
+
+#[test]
+fn sensor_timezone_is_validated() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    let mut res = client
+        .post("/api/add_sensor")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(json!({ "hardware_id": 12, "timezone": "Mars/Olympus_Mons" }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(invalid timezone 'Mars/Olympus_Mons')"
+    );
+}
+
+fn set_location(client: &Client, token: &str, location: serde_json::Value) -> Status {
+    client
+        .put("/api/sensor/1/location")
+        .header(ContentType::JSON)
+        .header(token_auth_header(token))
+        .body(location.to_string())
+        .dispatch()
+        .status()
+}
+
+#[test]
+fn clear_sky_follows_the_sun() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor_in_timezone(&client, &tok, 12, "America/Los_Angeles");
+    let location = json!({ "latitude": 37.77, "longitude": -122.42 });
+    assert_eq!(set_location(&client, &tok, location), Status::Ok);
+
+    // A day of points from 1am local time on the summer solstice.
+    let start = 1561104000;
+    let url = format!(
+        "/api/sensor/1/clear_sky?start={}&end={}",
+        start,
+        start + 86400
+    );
+    let mut res = client.get(url).dispatch();
+    let data = response_json_value(&mut res);
+    let points = data["data"]["points"].as_array().expect("must be an array");
+    assert_eq!(points.len(), 288);
+    assert_eq!(points[0]["expected_mW"], 0.0);
+
+    // Solar noon is at about 20:11 UTC, when the sun is 90 - 37.77 + 23.44
+    // degrees high.
+    let noon = &points[146];
+    assert_eq!(noon["timestamp"], "2019-06-21T20:10:00");
+    let zenith = noon["zenith_deg"].as_f64().expect("must be a number");
+    assert!((zenith - 14.33).abs() < 0.5, "zenith {}", zenith);
+    let poa = noon["poa"].as_f64().expect("must be a number");
+    assert!(poa > 900.0 && poa < 1100.0, "poa {}", poa);
+}
+
+#[test]
+fn performance_flags_underperforming_panels() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor_in_timezone(&client, &tok, 12, "America/Los_Angeles");
+
+    let mut res = client
+        .get("/api/sensor/1/performance?start=2019-06-20&end=2019-06-22")
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(data["error"], "ApiError(sensor has no location)");
+
+    let location = json!({ "latitude": 95.0, "longitude": -122.42 });
+    assert_eq!(set_location(&client, &tok, location), Status::BadRequest);
+    let location = json!({ "latitude": 37.77, "longitude": -122.42, "tilt_deg": 20.0 });
+    assert_eq!(set_location(&client, &tok, location.clone()), Status::Ok);
+
+    // Local days can't be worked out from the longitude, so a sensor needs a
+    // timezone before it can have a location.
+    add_sensor(&client, &tok, 13);
+    let mut res = client
+        .put("/api/sensor/2/location")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(location.to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let data = response_json_value(&mut res);
+    assert_eq!(
+        data["error"],
+        "ApiError(set the sensor's timezone before its location)"
+    );
+
+    // Local days start at 07:00 UTC in summer. A constant 10 mW is far below
+    // a 570 mW panel's clear-sky output on every day.
+    insert_readings_with_power(&db_uri, 1, 1561014000, 3 * 288 + 1, Some(10.0));
+    let mut res = client
+        .get("/api/sensor/1/performance?start=2019-06-20&end=2019-06-22")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let days = data["days"].as_array().expect("must be an array");
+    assert_eq!(days.len(), 3);
+    assert_eq!(days[0]["date"], "2019-06-20");
+    let measured = days[0]["measured_mWh"].as_f64().expect("must be a number");
+    assert!((measured - 240.0).abs() < 0.01, "measured {}", measured);
+    let expected = days[0]["expected_mWh"].as_f64().expect("must be a number");
+    assert!(
+        expected > 3000.0 && expected < 7000.0,
+        "expected {}",
+        expected
+    );
+    assert_eq!(data["underperforming"], true);
+}
+
+fn sensor_health(client: &Client) -> serde_json::Value {
+    let mut res = client.get("/api/sensor/1/health").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    response_json_value(&mut res)
+}
+
+#[test]
+fn sensor_health_tracks_battery_and_last_seen() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+
+    let health = sensor_health(&client);
+    assert_eq!(health["status"], "offline");
+    assert_eq!(health["reasons"][0], "no readings yet");
+
+    // Two days of readings up to now, with the battery losing 0.05 V a day
+    // down to 3.6 V, which leaves 6 days until it's empty at 3.3 V.
+    let now = Utc::now().timestamp();
+    let start = now - now % 300 - 2 * 86400;
+    let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+    sql_query(
+        "
+with recursive n(i) as (select 0 union all select i + 1 from n where i < 576)
+insert into readings (sensor_id, timestamp, peak_power_mW, peak_current_mA, peak_voltage_V, temp_celsius, batt_V)
+select 1, datetime(?1 + i * 300, 'unixepoch'), 100, 1, 1, 20 + i % 10, 3.7 - 0.05 * i * 300 / 86400.0 from n;
+",
+    )
+    .bind::<BigInt, _>(start)
+    .execute(&conn)
+    .expect("failed to insert readings");
+    rollup::rebuild(1, &conn).expect("failed to rebuild rollups");
+
+    let health = sensor_health(&client);
+    assert_eq!(health["status"], "degraded");
+    assert_eq!(health["interval_secs"], 300);
+    let v_per_day = health["battery"]["V_per_day"]
+        .as_f64()
+        .expect("must be a number");
+    assert!((v_per_day + 0.05).abs() < 0.005, "{} V/day", v_per_day);
+    let days = health["battery"]["days_to_empty"]
+        .as_f64()
+        .expect("must be a number");
+    assert!(days > 5.5 && days < 6.5, "{} days to empty", days);
+    assert_eq!(health["temperature"]["min_celsius"], 20.0);
+    assert_eq!(health["temperature"]["max_celsius"], 29.0);
+
+    // Nothing received for an hour is well over three missed readings.
+    sql_query("update readings set created = datetime('now', '-1 hour')")
+        .execute(&conn)
+        .expect("failed to update readings");
+    let health = sensor_health(&client);
+    assert_eq!(health["status"], "offline");
+    let last_seen = health["last_seen"].clone();
+
+    // Importing an old log doesn't make the sensor look like it's back.
+    let csv = format!(
+        "timestamp,peak_power_mW,peak_current_mA,peak_voltage_V,temp_celsius,batt_V\n\
+         {},100,1,1,20,3.8\n\
+         {},100,1,1,20,3.8\n",
+        start - 86400,
+        start - 86100
+    );
+    let report = import_csv(&client, &tok, "dry_run=false", &csv);
+    assert_eq!(report["inserted"], 2);
+    let health = sensor_health(&client);
+    assert_eq!(health["status"], "offline");
+    assert_eq!(health["last_seen"], last_seen);
+}
+
+/// Records emails instead of sending them.
+#[derive(Default)]
+struct RecordingNotifier {
+    emails: RefCell<Vec<(String, String)>>,
+    bodies: RefCell<Vec<(String, Option<String>)>>,
+}
+
+impl Notifier for RecordingNotifier {
+    fn notify(
+        &self,
+        to: &str,
+        subject: &str,
+        html: &str,
+        text: Option<&str>,
+    ) -> crate::result::Result<()> {
+        self.emails
+            .borrow_mut()
+            .push((to.to_string(), subject.to_string()));
+        self.bodies
+            .borrow_mut()
+            .push((html.to_string(), text.map(|t| t.to_string())));
+        Ok(())
+    }
+}
+
+struct FailingNotifier;
+
+impl Notifier for FailingNotifier {
+    fn notify(
+        &self,
+        _to: &str,
+        _subject: &str,
+        _html: &str,
+        _text: Option<&str>,
+    ) -> crate::result::Result<()> {
+        Err(crate::result::Error::UnknownError(
+            "mail is down".to_string(),
+        ))
+    }
+}
+
+fn add_alert_rule(client: &Client, token: &str, rule: serde_json::Value) -> serde_json::Value {
+    let mut res = client
+        .post("/api/sensor/1/alert_rules")
+        .header(ContentType::JSON)
+        .header(token_auth_header(token))
+        .body(rule.to_string())
+        .dispatch();
+    response_json_value(&mut res)
+}
+
+fn get_alerts(client: &Client, token: &str) -> serde_json::Value {
+    let mut res = client
+        .get("/api/sensor/1/alerts")
+        .header(token_auth_header(token))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    response_json_value(&mut res)
+}
+
+fn reading_with_battery(ts: i64, batt_v: f32) -> serde_json::Value {
+    let mut reading = reading_at(ts, 100.0);
+    reading["batt_V"] = json!(batt_v);
+    reading
+}
+
+#[test]
+fn alert_rules_open_and_resolve_incidents() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+    let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+    let notifier = RecordingNotifier::default();
+
+    let data = add_alert_rule(
+        &client,
+        &tok,
+        json!({ "kind": "below", "channel": "nope", "threshold": 1.0 }),
+    );
+    assert_eq!(
+        data["error"],
+        "ApiError(sensor has no channel named 'nope')"
+    );
+    let data = add_alert_rule(
+        &client,
+        &tok,
+        json!({ "kind": "sometimes", "threshold": 1.0 }),
+    );
+    assert_eq!(
+        data["error"],
+        "ApiError(invalid alert rule: unknown kind 'sometimes')"
+    );
+    add_alert_rule(
+        &client,
+        &tok,
+        json!({ "kind": "below", "channel": "batt_V", "threshold": 3.6 }),
+    );
+    add_alert_rule(
+        &client,
+        &tok,
+        json!({ "kind": "no_readings", "threshold": 2.0 }),
+    );
+
+    let now = Utc::now();
+    let readings = json!([reading_with_battery(now.timestamp() - 120, 3.5)]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    let report = alerts::run(now.naive_utc(), &conn, &notifier).expect("failed to run alerts");
+    assert_eq!((report.opened, report.resolved, report.notified), (1, 0, 1));
+    {
+        let emails = notifier.emails.borrow();
+        assert_eq!(emails[0].0, "newuser@gmail.com");
+        assert_eq!(emails[0].1, "Sensor 1: alert for batt_V below 3.6");
+    }
+
+    // An incident that stays open isn't reported again.
+    let report = alerts::run(now.naive_utc(), &conn, &notifier).expect("failed to run alerts");
+    assert_eq!((report.opened, report.resolved, report.notified), (0, 0, 0));
+
+    let data = get_alerts(&client, &tok);
+    assert_eq!(data["rules"][0]["description"], "batt_V below 3.6");
+    let incident = &data["incidents"][0];
+    assert_eq!(incident["message"], "batt_V is 3.5, below 3.6");
+    assert!(incident["acknowledged"].is_null());
+    let url = format!("/api/alert/{}/acknowledge", incident["id"]);
+    let res = client.post(url).header(token_auth_header(&tok)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let data = get_alerts(&client, &tok);
+    assert!(!data["incidents"][0]["acknowledged"].is_null());
+
+    for hours in &[json!(-1), json!(0), json!(1e300)] {
+        let mut res = client
+            .put("/api/alert_rule/1/mute")
+            .header(ContentType::JSON)
+            .header(token_auth_header(&tok))
+            .body(json!({ "hours": hours }).to_string())
+            .dispatch();
+        assert_eq!(
+            response_json_value(&mut res)["error"],
+            "ApiError(invalid alert rule: mute hours must be more than 0 and at most 8760)"
+        );
+    }
+
+    // Muted rules still resolve, but without an email.
+    let res = client
+        .put("/api/alert_rule/1/mute")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(json!({ "hours": 24 }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let readings = json!([reading_with_battery(now.timestamp() - 60, 3.7)]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+    let report = alerts::run(now.naive_utc(), &conn, &notifier).expect("failed to run alerts");
+    assert_eq!((report.opened, report.resolved, report.notified), (0, 1, 0));
+
+    let later = now.naive_utc() + Duration::hours(3);
+    let report = alerts::run(later, &conn, &notifier).expect("failed to run alerts");
+    assert_eq!((report.opened, report.resolved, report.notified), (1, 0, 1));
+    assert_eq!(
+        notifier.emails.borrow()[1].1,
+        "Sensor 1: alert for no readings for 2 hours"
+    );
+}
+
+/// A stand-in for a partner's webhook receiver, which records the requests it
+/// gets and responds with the given statuses in turn, then with 200.
+struct Receiver {
+    url: String,
+    requests: Arc<Mutex<Vec<(HashMap<String, String>, String)>>>,
+}
+
+impl Receiver {
+    fn start(statuses: Vec<u16>) -> Receiver {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind receiver");
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            let mut statuses = statuses.into_iter();
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = HashMap::new();
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(i) = line.find(':') {
+                        headers.insert(
+                            line[..i].trim().to_lowercase(),
+                            line[i + 1..].trim().to_string(),
+                        );
+                    }
+                }
+                let len: usize = headers
+                    .get("content-length")
+                    .map_or(0, |l| l.parse().unwrap());
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((headers, String::from_utf8(body).unwrap()));
+                let status = statuses.next().unwrap_or(200);
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        Receiver { url, requests }
+    }
+}
+
+#[test]
+fn webhooks_are_signed_and_retried() {
+    let (client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 123);
+    let sensor_tok = get_sensor_token(&client, &tok, 123);
+    // The receiver is on localhost. Only this test creates webhooks.
+    std::env::set_var("SOL_WEBHOOK_ALLOW_PRIVATE", "true");
+    let receiver = Receiver::start(vec![500]);
+    let http = webhooks::client();
+
+    let mut res = client
+        .post("/api/webhooks")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(json!({ "url": "ftp://example.com" }).to_string())
+        .dispatch();
+    assert_eq!(
+        response_json_value(&mut res)["error"],
+        "ApiError(invalid webhook: url must start with http:// or https://)"
+    );
+    let mut res = client
+        .post("/api/webhooks")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&tok))
+        .body(
+            json!({
+                "url": receiver.url,
+                "secret": "shh",
+                "events": ["readings.created", "sensor.deactivated"],
+            })
+            .to_string(),
+        )
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data["secret"], "shh");
+    let mut res = client
+        .get("/api/webhooks")
+        .header(token_auth_header(&tok))
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data[0]["url"], json!(receiver.url));
+    assert!(data[0].get("secret").is_none());
+
+    let ts = Utc::now().timestamp() - 600;
+    let readings = json!([reading_at(ts, 100.0), reading_at(ts + 300, 200.0)]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+
+    // The receiver fails the first attempt, so it's retried after a backoff.
+    let now = Utc::now().naive_utc() + Duration::seconds(1);
+    let report = webhooks::deliver_due(now, &conn, &http).expect("failed to deliver");
+    assert_eq!((report.delivered, report.retrying), (0, 1));
+    let report = webhooks::deliver_due(now, &conn, &http).expect("failed to deliver");
+    assert_eq!((report.delivered, report.retrying), (0, 0));
+    let later = now + webhooks::backoff(1);
+    let report = webhooks::deliver_due(later, &conn, &http).expect("failed to deliver");
+    assert_eq!((report.delivered, report.retrying), (1, 0));
+
+    {
+        let requests = receiver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (headers, body) = &requests[1];
+        assert_eq!(requests[0].1, *body);
+        assert_eq!(headers["x-sol-event"], "readings.created");
+        let timestamp: i64 = headers["x-sol-timestamp"].parse().unwrap();
+        assert_eq!(timestamp, later.timestamp());
+        assert_eq!(
+            headers["x-sol-signature"],
+            webhooks::signature("shh", timestamp, body)
+        );
+        assert_ne!(
+            headers["x-sol-signature"],
+            webhooks::signature("wrong", timestamp, body)
+        );
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["event"], "readings.created");
+        assert_eq!(payload["data"]["sensor_id"], 1);
+        assert_eq!(payload["data"]["count"], 2);
+        assert_eq!(payload["data"]["first_timestamp"], ts);
+        assert_eq!(payload["data"]["last_timestamp"], ts + 300);
+    }
+
+    let mut res = client
+        .get("/api/webhook/1/deliveries")
+        .header(token_auth_header(&tok))
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data[0]["status"], "delivered");
+    assert_eq!(data[0]["attempts"], 2);
+    assert_eq!(data[0]["response_status"], 200);
+
+    // Deactivating the sensor is sent too, and other users can't see the
+    // deliveries.
+    web_login(&client, "newuser@gmail.com", "mypassword");
+    let csrf = csrf_token(&client, "/sensor/1/deactivate");
+    let res = client
+        .post("/sensor/1/deactivate")
+        .header(ContentType::Form)
+        .body(format!("csrf_token={}", csrf))
+        .dispatch();
+    assert_eq!(res.headers().get_one("Location"), Some("/sensor/1"));
+    let mut res = client
+        .get("/api/webhook/1/deliveries")
+        .header(token_auth_header(&tok))
+        .dispatch();
+    let data = response_json_value(&mut res);
+    assert_eq!(data[0]["event"], "sensor.deactivated");
+    assert_eq!(data[0]["status"], "pending");
+    register(&client, "other@gmail.com", "otherpassword");
+    let other_tok = get_token(&client, "other@gmail.com", "otherpassword");
+    let mut res = client
+        .get("/api/webhook/1/deliveries")
+        .header(token_auth_header(&other_tok))
+        .dispatch();
+    assert_eq!(
+        response_json_value(&mut res)["error"],
+        "ApiError(user is not the owner of this webhook)"
+    );
+}
+
+#[test]
+fn transferring_a_sensor_tells_both_owners() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    register(&client, "other@gmail.com", "otherpassword");
+    let other_tok = get_token(&client, "other@gmail.com", "otherpassword");
+    add_sensor(&client, &tok, 123);
+    for t in &[&tok, &other_tok] {
+        let res = client
+            .post("/api/webhooks")
+            .header(ContentType::JSON)
+            .header(token_auth_header(t))
+            .body(
+                json!({
+                    "url": "https://93.184.216.34/hook",
+                    "events": ["sensor.transferred"],
+                })
+                .to_string(),
+            )
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    let transfer = |t: &str| {
+        client
+            .post("/api/sensor/1/transfer")
+            .header(ContentType::JSON)
+            .header(token_auth_header(t))
+            .body(json!({ "email": "other@gmail.com" }).to_string())
+            .dispatch()
+    };
+    let mut res = transfer(&other_tok);
+    assert_eq!(
+        response_json_value(&mut res)["error"],
+        "ApiError(user is not the owner of this sensor)"
+    );
+    assert_eq!(transfer(&tok).status(), Status::Ok);
+    let mut res = transfer(&tok);
+    assert_eq!(
+        response_json_value(&mut res)["error"],
+        "ApiError(user is not the owner of this sensor)"
+    );
+
+    for (id, t) in &[(1, &tok), (2, &other_tok)] {
+        let mut res = client
+            .get(format!("/api/webhook/{}/deliveries", id))
+            .header(token_auth_header(t))
+            .dispatch();
+        let data = response_json_value(&mut res);
+        assert_eq!(data[0]["event"], "sensor.transferred");
+        let payload: serde_json::Value =
+            serde_json::from_str(data[0]["payload"].as_str().unwrap()).unwrap();
+        assert_eq!(payload["data"]["new_owner_id"], 2);
+    }
+}
+
+#[test]
+fn webhooks_can_only_be_sent_to_public_addresses() {
+    let err = |url: &str| net::check_public_url(url).unwrap_err();
+    assert_eq!(
+        err("http://127.0.0.1:8000/"),
+        "127.0.0.1 is not a public address"
+    );
+    assert_eq!(
+        err("http://169.254.169.254/latest/meta-data"),
+        "169.254.169.254 is not a public address"
+    );
+    assert_eq!(
+        err("https://10.1.2.3/hook"),
+        "10.1.2.3 is not a public address"
+    );
+    assert_eq!(err("http://[::1]/"), "::1 is not a public address");
+    assert_eq!(
+        err("http://[::ffff:192.168.0.1]/"),
+        "::ffff:192.168.0.1 is not a public address"
+    );
+    assert_eq!(err("http://0.0.0.0/"), "0.0.0.0 is not a public address");
+    assert!(err("http://localhost/").ends_with("is not a public address"));
+    assert_eq!(
+        err("ftp://example.com/"),
+        "url must start with http:// or https://"
+    );
+    assert!(net::check_public_url("https://93.184.216.34/hook").is_ok());
+    assert!(net::check_public_url("http://[2606:2800:220:1::]/").is_ok());
+}
+
+#[test]
+fn webhooks_are_not_connected_to_names_with_private_addresses() {
+    // Sending skips the check the url had when it was added, as if the name
+    // had moved to a private address since.
+    let receiver = Receiver::start(vec![]);
+    let url = receiver.url.replace("127.0.0.1", "localhost");
+    let http = webhooks::build_client(false);
+    let err = webhooks::post(&http, &url, &[], "{}".to_string()).unwrap_err();
+    assert!(err.contains("is not a public address"), "{}", err);
+    assert!(receiver.requests.lock().unwrap().is_empty());
+
+    let http = webhooks::build_client(true);
+    let status = webhooks::post(&http, &url, &[], "{}".to_string()).expect("failed to post");
+    assert_eq!(status.as_u16(), 200);
+    assert_eq!(receiver.requests.lock().unwrap().len(), 1);
+}
+
+/// Reads the next event from a live stream, skipping the flushes between them.
+fn next_event(stream: &mut dyn Read) -> serde_json::Value {
+    let mut text = Vec::new();
+    let mut byte = [0; 1];
+    while !text.ends_with(b"\n\n") {
+        match stream.read(&mut byte) {
+            Ok(0) => panic!("stream ended"),
+            Ok(_) => text.push(byte[0]),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("failed to read stream: {}", e),
+        }
+    }
+    let text = String::from_utf8(text).unwrap();
+    assert!(
+        text.starts_with("event: reading\n"),
+        "unexpected event {}",
+        text
+    );
+    let data = text.lines().nth(1).unwrap().trim_start_matches("data: ");
+    serde_json::from_str(data).unwrap()
+}
+
+#[test]
+fn live_streams_get_readings_as_they_arrive() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 123);
+    add_sensor(&client, &tok, 456);
+    let sensor_tok = get_sensor_token(&client, &tok, 123);
+    let other_sensor_tok = get_sensor_token(&client, &tok, 456);
+
+    let mut res = client.get("/api/user/stream").dispatch();
+    assert_eq!(
+        response_json_value(&mut res)["error"],
+        "ApiError(missing token)"
+    );
+    let mut sensor_res = client.get("/api/sensor/1/stream").dispatch();
+    assert_eq!(sensor_res.status(), Status::Ok);
+    assert_eq!(
+        sensor_res.content_type(),
+        Some(ContentType::new("text", "event-stream"))
+    );
+    let mut user_res = client
+        .get("/api/user/stream")
+        .header(token_auth_header(&tok))
+        .dispatch();
+    assert_eq!(user_res.status(), Status::Ok);
+
+    let ts = Utc::now().timestamp() - 600;
+    let readings = json!([reading_at(ts, 100.0)]);
+    assert_eq!(
+        add_readings(&client, &other_sensor_tok, readings),
+        Status::Ok
+    );
+    let readings = json!([reading_at(ts, 200.0), reading_at(ts + 300, 300.0)]);
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+    let res = client
+        .post("/api/add_reading")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&sensor_tok))
+        .body(reading_at(ts + 600, 400.0).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    // The sensor's stream only gets its own readings.
+    let mut stream = sensor_res.body().expect("no body").into_inner();
+    let powers: Vec<serde_json::Value> = (0..3)
+        .map(|_| next_event(&mut stream)["peak_power_mW"].clone())
+        .collect();
+    assert_eq!(powers, vec![json!(200.0), json!(300.0), json!(400.0)]);
+
+    // The user's stream gets every sensor they own.
+    let mut stream = user_res.body().expect("no body").into_inner();
+    let first = next_event(&mut stream);
+    assert_eq!(first["sensor_id"], 2);
+    assert_eq!(first["timestamp"], ts);
+    assert_eq!(next_event(&mut stream)["sensor_id"], 1);
+}
+
+#[test]
+fn live_streams_are_capped_per_client_and_freed_when_dropped() {
+    let hub = live::Hub::default();
+    let mut streams: Vec<_> = (0..live::MAX_STREAMS_PER_CLIENT)
+        .map(|_| {
+            hub.subscribe(live::Topic::Sensor(1), "ip:10.0.0.1")
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(
+        hub.subscribe(live::Topic::Sensor(2), "ip:10.0.0.1")
+            .err()
+            .map(|e| e.to_string()),
+        Some("too many live streams are open, try again later".to_string())
+    );
+    let other = hub.subscribe(live::Topic::Owner(1), "user:1").unwrap();
+    assert_eq!(hub.streams(), live::MAX_STREAMS_PER_CLIENT + 1);
+
+    // Streams of quiet sensors are freed too, without anything published.
+    streams.pop();
+    assert_eq!(hub.streams(), live::MAX_STREAMS_PER_CLIENT);
+    assert!(hub.subscribe(live::Topic::Sensor(2), "ip:10.0.0.1").is_ok());
+    drop(streams);
+    drop(other);
+    assert_eq!(hub.streams(), 0);
+}
+
+#[test]
+fn digests_summarize_the_period_and_unsubscribe_once() {
+    let (client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 123);
+    let sensor_tok = get_sensor_token(&client, &tok, 123);
+    let notifier = RecordingNotifier::default();
+    let tera = digests::templates().expect("failed to load templates");
+    let schedule = Schedule {
+        hour: 0,
+        weekday: Weekday::Mon,
+    };
+
+    let put_digest = |frequency: serde_json::Value| {
+        let mut res = client
+            .put("/api/digest")
+            .header(ContentType::JSON)
+            .header(token_auth_header(&tok))
+            .body(json!({ "frequency": frequency }).to_string())
+            .dispatch();
+        response_json_value(&mut res)
+    };
+    assert_eq!(
+        put_digest(json!("hourly"))["error"],
+        "ApiError(invalid digest frequency 'hourly')"
+    );
+    assert_eq!(put_digest(json!("daily"))["frequency"], "daily");
+
+    // Two hours of readings yesterday.
+    let now = Utc::now().naive_utc();
+    let yesterday = now.date().pred().and_hms(10, 0, 0).timestamp();
+    let readings: Vec<serde_json::Value> = (0..=24)
+        .map(|i| reading_at(yesterday + i * 300, 1000.0))
+        .collect();
+    assert_eq!(
+        add_readings(&client, &sensor_tok, json!(readings)),
+        Status::Ok
+    );
+
+    // New subscribers wait for the next scheduled time.
+    let report = digests::run(&schedule, now, &conn, &tera, &notifier).expect("failed to run");
+    assert_eq!(report.sent, 0);
+    sql_query("update digest_subscriptions set created = datetime('now', '-2 days')")
+        .execute(&conn)
+        .unwrap();
+    // A digest that fails to send doesn't leave a working unsubscribe link.
+    let report =
+        digests::run(&schedule, now, &conn, &tera, &FailingNotifier).expect("failed to run");
+    assert_eq!((report.sent, report.failed), (0, 1));
+    let tokens: i64 = unsubscribe_tokens::table.count().get_result(&conn).unwrap();
+    assert_eq!(tokens, 0);
+    let report = digests::run(&schedule, now, &conn, &tera, &notifier).expect("failed to run");
+    assert_eq!((report.sent, report.failed), (1, 0));
+    let report = digests::run(&schedule, now, &conn, &tera, &notifier).expect("failed to run");
+    assert_eq!(report.sent, 0);
+
+    assert_eq!(
+        notifier.emails.borrow()[0],
+        (
+            "newuser@gmail.com".to_string(),
+            "Your daily Sol digest".to_string()
+        )
+    );
+    let (html, text) = notifier.bodies.borrow()[0].clone();
+    let text = text.expect("digest must have a text part");
+    // Readings in three of the day's 24 hours.
+    assert!(text.contains("12.5% uptime"), "{}", text);
+    assert!(html.contains("Sensor 1"));
+    let start = text
+        .find("/digest/unsubscribe/")
+        .expect("no unsubscribe link");
+    let path = text[start..].split_whitespace().next().unwrap().to_string();
+
+    let res = client.get(format!("{}x", path)).dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+    let form = format!("csrf_token={}", csrf_token(&client, &path));
+    let res = client
+        .post(path.clone())
+        .header(ContentType::Form)
+        .body(&form)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let mut res = client
+        .get("/api/digest")
+        .header(token_auth_header(&tok))
+        .dispatch();
+    assert!(response_json_value(&mut res)["frequency"].is_null());
+    // The link only works once.
+    let res = client
+        .post(path)
+        .header(ContentType::Form)
+        .body(&form)
+        .dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+}
+
+#[test]
+fn cron_schedules_find_the_next_matching_minute() {
+    let at = |d: u32, h: u32, m: u32| NaiveDate::from_ymd(2020, 12, d).and_hms(h, m, 0);
+    let next = |expr: &str, t| Cron::parse(expr).expect("bad expression").next_after(t);
+
+    // Saturday noon to Monday morning.
+    assert_eq!(next("*/15 9-17 * * 1-5", at(5, 12, 0)), Some(at(7, 9, 0)));
+    assert_eq!(next("*/15 9-17 * * 1-5", at(7, 9, 0)), Some(at(7, 9, 15)));
+    assert_eq!(next("0,30 * * * *", at(7, 9, 45)), Some(at(7, 10, 0)));
+    // Days of the month and of the week match either way.
+    assert_eq!(next("0 0 1 * 0", at(1, 0, 0)), Some(at(6, 0, 0)));
+    assert_eq!(next("0 0 31 2 *", at(1, 0, 0)), None);
+
+    let err = |expr: &str| Cron::parse(expr).unwrap_err().to_string();
+    assert_eq!(err("* * *"), "invalid schedule '* * *': expected 5 fields");
+    assert_eq!(
+        err("60 * * * *"),
+        "invalid schedule '60 * * * *': '60' is not a number from 0 to 59"
+    );
+    assert_eq!(
+        err("*/0 * * * *"),
+        "invalid schedule '*/0 * * * *': bad step in '*/0'"
+    );
+}
+
+#[test]
+fn jobs_run_when_due_and_only_one_runner_at_a_time() {
+    let (client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    register(&client, "newuser@gmail.com", "mypassword");
+    let notifier = RecordingNotifier::default();
+    let tera = digests::templates().expect("failed to load templates");
+    let now = Utc::now().naive_utc();
+    jobs::sync(now, &conn).expect("failed to sync jobs");
+    jobs::sync(now, &conn).expect("failed to sync jobs again");
+    let ctx = jobs::Context {
+        conn: &conn,
+        now,
+        notifier: &notifier,
+        tera: &tera,
+        retention: None,
+        digest_schedule: Schedule::default(),
+    };
+
+    sql_query(
+        "insert into onetime_logins (token, user_id, created, expires) values
+         ('old', 1, datetime('now', '-2 days'), datetime('now', '-1 day')),
+         ('new', 1, datetime('now'), datetime('now', '+1 day'))",
+    )
+    .execute(&conn)
+    .unwrap();
+    // Nothing is due until the next minute.
+    assert!(jobs::run_due(&ctx, "runner-a").unwrap().is_empty());
+    let run = jobs::run_now("purge_onetime_logins", &ctx, "runner-a").expect("failed to run");
+    assert_eq!(run.status, "succeeded");
+    assert_eq!(
+        run.output,
+        Some("deleted 1 expired one-time logins".to_string())
+    );
+    let tokens: Vec<String> = onetime_logins::table
+        .select(onetime_logins::token)
+        .load(&conn)
+        .unwrap();
+    assert_eq!(tokens, vec!["new".to_string()]);
+
+    sql_query(
+        "update jobs set locked_by = 'runner-b', locked_until = datetime('now', '+1 hour')
+         where name = 'alerts'",
+    )
+    .execute(&conn)
+    .unwrap();
+    assert_eq!(
+        jobs::run_now("alerts", &ctx, "runner-a")
+            .unwrap_err()
+            .to_string(),
+        "job 'alerts' is already running"
+    );
+    assert_eq!(
+        jobs::run_now("nope", &ctx, "runner-a")
+            .unwrap_err()
+            .to_string(),
+        "no job named 'nope'"
+    );
+
+    // A day later everything is due, and runner-b's lock has expired.
+    let later = jobs::Context {
+        now: now + Duration::days(1),
+        ..ctx
+    };
+    let runs = jobs::run_due(&later, "runner-a").expect("failed to run due jobs");
+    let names: Vec<&str> = runs.iter().map(|r| r.job_name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "alerts",
+            "digests",
+            "purge_job_runs",
+            "purge_onetime_logins",
+            "purge_rate_limits",
+            "refresh_rollups",
+            "retention"
+        ]
+    );
+    assert!(runs.iter().all(|r| r.status == "succeeded"));
+    assert_eq!(
+        runs[6].output,
+        Some("no retention policy is set".to_string())
+    );
+    assert!(jobs::run_due(&later, "runner-a").unwrap().is_empty());
+    let alerts = job::find("alerts", &conn).unwrap().unwrap();
+    assert_eq!(alerts.locked_by, None);
+    assert!(alerts.next_run > later.now);
+
+    // Only admins can see and manage jobs.
+    let res = client.get("/admin/jobs").dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+    web_login(&client, "newuser@gmail.com", "mypassword");
+    let res = client.get("/admin/jobs").dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+    make_superuser(&db_uri, "newuser@gmail.com");
+    let mut res = client.get("/admin/jobs").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert!(res
+        .body_string()
+        .unwrap()
+        .contains("deleted 1 expired one-time logins"));
+
+    let form = format!("csrf_token={}", csrf_token(&client, "/admin/jobs"));
+    let res = client
+        .post("/admin/job/digests/toggle")
+        .header(ContentType::Form)
+        .body(&form)
+        .dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+    assert!(!job::find("digests", &conn).unwrap().unwrap().enabled);
+    client
+        .post("/admin/job/alerts/run")
+        .header(ContentType::Form)
+        .body(&form)
+        .dispatch();
+    assert!(job::find("alerts", &conn).unwrap().unwrap().next_run <= Utc::now().naive_utc());
+    let two_days = jobs::Context {
+        now: now + Duration::days(2),
+        ..ctx
+    };
+    let runs = jobs::run_due(&two_days, "runner-a").expect("failed to run due jobs");
+    assert!(runs.iter().all(|r| r.job_name != "digests"));
+}
+
+#[test]
+fn panicking_jobs_fail_and_release_their_lock() {
+    let (_client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    let notifier = RecordingNotifier::default();
+    let tera = digests::templates().expect("failed to load templates");
+    let now = Utc::now().naive_utc();
+    jobs::sync(now, &conn).expect("failed to sync jobs");
+    let ctx = jobs::Context {
+        conn: &conn,
+        now,
+        notifier: &notifier,
+        tera: &tera,
+        retention: None,
+        digest_schedule: Schedule::default(),
+    };
+
+    let panicking = jobs::Job {
+        name: "alerts",
+        schedule: "* * * * *",
+        run: |ctx| {
+            ctx.conn
+                .transaction::<(), diesel::result::Error, _>(|| panic!("out of sunshine"))
+                .map(|_| String::new())
+                .map_err(|e| e.into())
+        },
+    };
+    let run = jobs::run_job(&panicking, &ctx, "runner-a").expect("failed to run");
+    assert_eq!(run.status, "failed");
+    assert_eq!(run.output, Some("panicked: out of sunshine".to_string()));
+    let alerts = job::find("alerts", &conn).unwrap().unwrap();
+    assert_eq!(alerts.locked_by, None);
+    // The transaction the job left open was rolled back, so the next run is
+    // stored.
+    let run = jobs::run_now("alerts", &ctx, "runner-a").expect("failed to run");
+    assert_eq!(run.status, "succeeded");
+
+    // Runs older than the history kept are purged.
+    let later = jobs::Context {
+        now: now + Duration::days(jobs::JOB_RUN_DAYS + 1),
+        ..ctx
+    };
+    let run = jobs::run_now("purge_job_runs", &later, "runner-a").expect("failed to run");
+    assert_eq!(run.output, Some("deleted 2 job runs".to_string()));
+    assert_eq!(job::recent_runs(10, &conn).unwrap().len(), 1);
+}
+
+#[test]
+fn metrics_are_served_to_scrapers_with_the_token() {
+    let client = test_client();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 123);
+    let sensor_tok = get_sensor_token(&client, &tok, 123);
+    let readings = json!([reading_at(1000, 1.0), reading_at(1300, 2.0)]);
+    assert_eq!(
+        add_readings(&client, &sensor_tok, readings.clone()),
+        Status::Ok
+    );
+    assert_eq!(add_readings(&client, &sensor_tok, readings), Status::Ok);
+    let future = Utc::now().timestamp() + 7 * 24 * 3600;
+    assert_eq!(
+        add_readings(&client, &sensor_tok, json!([reading_at(future, 1.0)])),
+        Status::BadRequest
+    );
+
+    // Only this test reads the token.
+    std::env::set_var("SOL_METRICS_TOKEN", "metrics-token");
+    let scrape = |auth: &str| {
+        client
+            .get("/metrics")
+            .remote("127.0.0.1:9000".parse().unwrap())
+            .header(Header::new("Authorization", auth.to_string()))
+            .dispatch()
+            .status()
+    };
+    // Local requests aren't trusted, since the proxy's requests are local too.
+    let res = client
+        .get("/metrics")
+        .remote("127.0.0.1:9000".parse().unwrap())
+        .dispatch();
+    assert_eq!(res.status(), Status::Forbidden);
+    assert_eq!(scrape("bearer wrong-token"), Status::Forbidden);
+    assert_eq!(scrape("bearer "), Status::Forbidden);
+    assert_eq!(scrape("Bearer metrics-token"), Status::Ok);
+    let mut res = client
+        .get("/metrics")
+        .header(Header::new("Authorization", "bearer metrics-token"))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body = res.body_string().expect("must have a body");
+    // Other tests count into the same metrics, so only what's there is
+    // checked, not how much.
+    for line in &[
+        "sol_http_requests_total{route=\"POST /api/add_readings\",status=\"200\"}",
+        "sol_http_request_duration_seconds_bucket{route=\"POST /api/add_readings\",le=\"+Inf\"}",
+        "sol_readings_ingested_total",
+        "sol_readings_rejected_total{reason=\"invalid\"}",
+        "sol_sensors_active 1\n",
+        "sol_sensor_last_seen_age_seconds_count 1\n",
+        // Last seen is when readings arrived, not their timestamps from 1970.
+        "sol_sensor_last_seen_age_seconds_bucket{le=\"60\"} 1\n",
+        "sol_db_pool_max_connections",
+        "sol_email_send_failures_total",
+    ] {
+        assert!(body.contains(line), "no {} in\n{}", line, body);
+    }
+}
+
+#[test]
+fn probes_report_liveness_and_what_readiness_waits_on() {
+    let (client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    let mut res = client.get("/healthz").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(response_json_value(&mut res), json!({"status": "ok"}));
+
+    let readyz = || {
+        client
+            .get("/readyz")
+            .remote("127.0.0.1:9000".parse().unwrap())
+    };
+    assert_eq!(client.get("/readyz").dispatch().status(), Status::Forbidden);
+    let proxied = readyz()
+        .header(Header::new("X-Real-IP", "203.0.113.1"))
+        .dispatch();
+    assert_eq!(proxied.status(), Status::Forbidden);
+
+    // Mail is reported, but whether it's configured depends on where the
+    // tests run, and doesn't hold readiness up either way.
+    let mut res = readyz().dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let data = response_json_value(&mut res);
+    assert_eq!(data["checks"]["database"]["ok"], true);
+    assert_eq!(
+        data["checks"]["migrations"],
+        json!({"ok": true, "detail": "all applied"})
+    );
+    assert_eq!(data["checks"]["disk"]["ok"], true);
+    assert_eq!(
+        data["checks"]["mail"]["ok"],
+        email::credentials_source().is_ok()
+    );
+
+    #[derive(QueryableByName)]
+    struct Version {
+        #[sql_type = "Text"]
+        version: String,
+    }
+    let latest = sql_query("select max(version) as version from __diesel_schema_migrations")
+        .get_result::<Version>(&conn)
+        .unwrap()
+        .version;
+    sql_query("delete from __diesel_schema_migrations where version = ?")
+        .bind::<Text, _>(&latest)
+        .execute(&conn)
+        .unwrap();
+    // Checking twice shows the check doesn't apply what it finds.
+    for _ in 0..2 {
+        let mut res = readyz().dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        let data = response_json_value(&mut res);
+        assert_eq!(data["status"], "unavailable");
+        assert_eq!(
+            data["checks"]["migrations"],
+            json!({"ok": false, "detail": format!("pending: {}", latest)})
+        );
+    }
+}
+
+#[test]
+fn requests_get_ids_and_security_actions_are_audited() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "user@example.com", "password");
+    register(&client, "admin@example.com", "password");
+    make_superuser(&db_uri, "admin@example.com");
+
+    let res = client
+        .get("/api/version")
+        .remote("127.0.0.1:9000".parse().unwrap())
+        .header(Header::new("X-Request-Id", "proxy-id-1"))
+        .dispatch();
+    assert_eq!(res.headers().get_one("X-Request-Id"), Some("proxy-id-1"));
+    let res = client
+        .get("/api/version")
+        .remote("127.0.0.1:9000".parse().unwrap())
+        .header(Header::new("X-Request-Id", "not an id"))
+        .dispatch();
+    let id = res.headers().get_one("X-Request-Id").unwrap();
+    assert_eq!(id.len(), 16);
+    assert_ne!(id, "not an id");
+    // Only a trusted proxy can name a request.
+    let res = client
+        .get("/api/version")
+        .remote("203.0.113.7:9000".parse().unwrap())
+        .header(Header::new("X-Request-Id", "client-id-1"))
+        .dispatch();
+    assert_ne!(res.headers().get_one("X-Request-Id"), Some("client-id-1"));
+
+    let res = client
+        .post("/api/token")
+        .remote("127.0.0.1:9000".parse().unwrap())
+        .header(basic_auth_header("user@example.com", "wrong"))
+        .header(Header::new("X-Request-Id", "failed-login"))
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    // Made up accounts don't fill the audit log.
+    let res = client
+        .post("/api/token")
+        .header(basic_auth_header("nobody@example.com", "wrong"))
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    let token = get_token(&client, "user@example.com", "password");
+    add_sensor(&client, &token, 1);
+    get_sensor_token(&client, &token, 1);
+    let res = client
+        .post("/api/sensor/1/transfer")
+        .header(ContentType::JSON)
+        .header(token_auth_header(&token))
+        .body(json!({ "email": "admin@example.com" }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    let res = client
+        .get("/api/audit")
+        .header(token_auth_header(&token))
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+
+    let admin_token = get_token(&client, "admin@example.com", "password");
+    let mut res = client
+        .get("/api/audit?email=user@example.com")
+        .header(token_auth_header(&admin_token))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let entries = response_json_value(&mut res)["entries"].clone();
+    let actions: Vec<_> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        actions,
+        vec![
+            "sensor.transferred",
+            "token.created",
+            "token.created",
+            "login.failed"
+        ]
+    );
+    assert_eq!(entries[0]["target"], "sensor 1");
+    assert_eq!(entries[0]["detail"], "to admin@example.com");
+    assert_eq!(entries[1]["target"], "sensor 1");
+    assert_eq!(entries[2]["target"], "user 1");
+    assert_eq!(entries[3]["email"], "user@example.com");
+    assert_eq!(entries[3]["detail"], "user@example.com");
+    assert_eq!(entries[3]["request_id"], "failed-login");
+
+    // Everyone's entries, including the admin's own token.
+    let mut res = client
+        .get("/api/audit?limit=1")
+        .header(token_auth_header(&admin_token))
+        .dispatch();
+    let entries = response_json_value(&mut res)["entries"].clone();
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    assert_eq!(entries[0]["email"], "admin@example.com");
+    let mut res = client
+        .get("/api/audit?limit=-5")
+        .header(token_auth_header(&admin_token))
+        .dispatch();
+    let entries = response_json_value(&mut res)["entries"].clone();
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    let mut res = client
+        .get("/api/audit")
+        .header(token_auth_header(&admin_token))
+        .dispatch();
+    let entries = response_json_value(&mut res)["entries"].clone();
+    assert!(entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|e| e["detail"] != "nobody@example.com"));
+}
+
+#[test]
+fn cors_preflights_are_answered_for_allowed_origins() {
+    let security = SecurityHeaders {
+        allowed_origins: vec!["https://dashboard.example.com".to_string()],
+        ..SecurityHeaders::from_env()
+    };
+    let rocket = rocket::custom(Config::new(Environment::Staging))
+        .mount("/api", routes![api::get_version])
+        .attach(security);
+    let client = Client::new(rocket).expect("created test client");
+    let preflight = |origin: &'static str| {
+        client
+            .options("/api/version")
+            .header(Header::new("Origin", origin))
+            .header(Header::new("Access-Control-Request-Method", "GET"))
+            .header(Header::new(
+                "Access-Control-Request-Headers",
+                "authorization",
+            ))
+            .dispatch()
+    };
+
+    let mut res = preflight("https://dashboard.example.com");
+    assert_eq!(res.status(), Status::NoContent);
+    assert!(res.body().is_none());
+    let header = |res: &LocalResponse, name| res.headers().get_one(name).map(String::from);
+    assert_eq!(
+        header(&res, "Access-Control-Allow-Origin").as_deref(),
+        Some("https://dashboard.example.com")
+    );
+    assert_eq!(header(&res, "Vary").as_deref(), Some("Origin"));
+    assert!(header(&res, "Access-Control-Allow-Headers")
+        .unwrap()
+        .contains("Authorization"));
+    assert!(header(&res, "Access-Control-Allow-Methods")
+        .unwrap()
+        .contains("POST"));
+
+    let res = preflight("https://evil.example.com");
+    assert_eq!(res.status(), Status::Forbidden);
+    assert_eq!(header(&res, "Access-Control-Allow-Origin"), None);
+
+    let res = client
+        .get("/api/version")
+        .header(Header::new("Origin", "https://dashboard.example.com"))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(
+        header(&res, "Access-Control-Allow-Origin").as_deref(),
+        Some("https://dashboard.example.com")
+    );
+    // API responses aren't pages, so they don't get the page headers.
+    assert_eq!(header(&res, "Content-Security-Policy"), None);
+    let res = client
+        .get("/api/version")
+        .header(Header::new("Origin", "https://evil.example.com"))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(header(&res, "Access-Control-Allow-Origin"), None);
+}
+
+#[test]
+fn requests_over_their_rate_limit_are_refused_until_an_admin_raises_it() {
+    let mut limits = Limits::default();
+    limits.set(Kind::Sensor, Class::Ingest, Limit::new(2, 2));
+    let (client, db_uri) = test_client_with_limits(RateLimiter {
+        limits,
+        store: Store::memory(MAX_MEMORY_BUCKETS),
+        enabled: true,
+    });
+    register(&client, "user@example.com", "password");
+    register(&client, "admin@example.com", "password");
+    make_superuser(&db_uri, "admin@example.com");
+    let token = get_token(&client, "user@example.com", "password");
+    let admin_token = get_token(&client, "admin@example.com", "password");
+    add_sensor(&client, &token, 1);
+    let sensor_token = get_sensor_token(&client, &token, 1);
+    let post = || {
+        client
+            .post("/api/add_readings")
+            .header(ContentType::JSON)
+            .header(token_auth_header(&sensor_token))
+            .body("[]")
+            .dispatch()
+    };
+    let header = |res: &LocalResponse, name| res.headers().get_one(name).map(String::from);
+
+    for remaining in &["1", "0"] {
+        let res = post();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(header(&res, "X-RateLimit-Limit").as_deref(), Some("2"));
+        assert_eq!(
+            header(&res, "X-RateLimit-Remaining").as_deref(),
+            Some(*remaining)
+        );
+    }
+    let mut res = post();
+    assert_eq!(res.status(), Status::TooManyRequests);
+    assert_eq!(header(&res, "Retry-After").as_deref(), Some("30"));
+    assert_eq!(
+        response_json_value(&mut res),
+        json!({"error": "rate limit exceeded, retry in 30 seconds"})
+    );
+    // The sensor's owner has buckets of their own.
+    let res = client
+        .get("/api/sensor/1/health")
+        .header(token_auth_header(&token))
+        .dispatch();
+    assert_ne!(res.status(), Status::TooManyRequests);
+    assert_eq!(header(&res, "X-RateLimit-Limit").as_deref(), Some("600"));
+    // Probes are never limited.
+    let res = client.get("/healthz").dispatch();
+    assert_eq!(header(&res, "X-RateLimit-Limit"), None);
+
+    let raise = |token: &str, multiplier: i32| {
+        client
+            .put("/api/rate_limits/sensor/1")
+            .header(ContentType::JSON)
+            .header(token_auth_header(token))
+            .body(json_string!({ "multiplier": multiplier }))
+            .dispatch()
+    };
+    assert_eq!(raise(&token, 10).status(), Status::BadRequest);
+    let mut res = raise(&admin_token, 0);
+    assert_eq!(res.status(), Status::BadRequest);
+    assert_eq!(
+        response_json_value(&mut res)["error"],
+        "ApiError(rate limit multiplier must be at least 1, not 0)"
+    );
+    let set_user = |token: &str, id: i32| {
+        let mut res = client
+            .put(format!("/api/rate_limits/user/{}", id))
+            .header(ContentType::JSON)
+            .header(token_auth_header(token))
+            .body(json_string!({"multiplier": 2}))
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        response_json_value(&mut res)["error"].clone()
+    };
+    assert!(set_user(&admin_token, 99).is_string());
+    // Others get the same error whether or not the user exists.
+    assert_eq!(set_user(&token, 99), set_user(&token, 1));
+
+    // A raised limit counts what was already used against the new one.
+    assert_eq!(raise(&admin_token, 10).status(), Status::Ok);
+    let res = post();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(header(&res, "X-RateLimit-Limit").as_deref(), Some("20"));
+    assert_eq!(header(&res, "X-RateLimit-Remaining").as_deref(), Some("17"));
+
+    let mut res = client
+        .get("/api/rate_limits")
+        .header(token_auth_header(&admin_token))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let overrides = response_json_value(&mut res)["overrides"].clone();
+    assert_eq!(overrides.as_array().unwrap().len(), 1);
+    assert_eq!(overrides[0]["principal"], "sensor:1");
+    assert_eq!(overrides[0]["multiplier"], 10);
+}
+
+#[test]
+fn rate_limit_buckets_in_the_database_are_shared() {
+    let (_client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    let limit = Limit::new(1, 1);
+    let now = Utc::now().naive_utc();
+    let take = |store: &Store, now| store.take("ip:10.0.0.1:auth", limit, now, &conn).unwrap();
+
+    assert!(take(&Store::Database, now).allowed);
+    // Another server on the same database sees the bucket as used.
+    let decision = take(&Store::Database, now);
+    assert!(!decision.allowed);
+    assert_eq!(decision.retry_after_secs, 60);
+    assert!(take(&Store::Database, now + Duration::minutes(1)).allowed);
+    // Memory buckets are each server's own.
+    assert!(take(&Store::memory(MAX_MEMORY_BUCKETS), now).allowed);
+
+    let purged = rate_limit::purge_buckets(now + Duration::hours(1), &conn).unwrap();
+    assert_eq!(purged, 1);
+}
+
+#[test]
+fn anonymous_clients_are_limited_by_the_ip_a_trusted_proxy_gives() {
+    let mut limits = Limits::default();
+    limits.set(Kind::Anonymous, Class::Auth, Limit::new(1, 1));
+    let (client, _) = test_client_with_limits(RateLimiter {
+        limits,
+        store: Store::memory(MAX_MEMORY_BUCKETS),
+        enabled: true,
+    });
+    let login = |remote: &str, real_ip: &str| {
+        client
+            .post("/api/token")
+            .remote(format!("{}:9000", remote).parse().unwrap())
+            .header(Header::new("X-Real-IP", real_ip.to_string()))
+            .header(basic_auth_header("nobody@example.com", "guess"))
+            .dispatch()
+            .status()
+    };
+    // The proxy on localhost passes on who each request is from.
+    assert_eq!(login("127.0.0.1", "203.0.113.1"), Status::BadRequest);
+    assert_eq!(login("127.0.0.1", "203.0.113.2"), Status::BadRequest);
+    assert_eq!(login("127.0.0.1", "203.0.113.1"), Status::TooManyRequests);
+    // Anyone else saying who they are is ignored.
+    assert_eq!(login("198.51.100.7", "203.0.113.3"), Status::BadRequest);
+    assert_eq!(
+        login("198.51.100.7", "203.0.113.4"),
+        Status::TooManyRequests
+    );
+}
+
+#[test]
+fn memory_rate_limit_buckets_drop_the_least_recently_used() {
+    let mut buckets = MemoryBuckets::new(2);
+    let limit = Limit::new(1, 1);
+    let now = Utc::now().naive_utc();
+    let store = Store::memory(2);
+    let (_client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    assert!(store.take("a", limit, now, &conn).unwrap().allowed);
+    assert!(store.take("b", limit, now, &conn).unwrap().allowed);
+    assert!(!store.take("a", limit, now, &conn).unwrap().allowed);
+    // "b" was used longest ago, so it's dropped for "c" and starts full.
+    assert!(store.take("c", limit, now, &conn).unwrap().allowed);
+    assert!(!store.take("a", limit, now, &conn).unwrap().allowed);
+    assert!(store.take("b", limit, now, &conn).unwrap().allowed);
+
+    for key in &["a", "b", "c", "d"] {
+        buckets.take(key, limit, now);
+    }
+    assert_eq!(buckets.len(), 2);
+    assert!(buckets.contains("c") && buckets.contains("d"));
+}
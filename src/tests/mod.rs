@@ -1,3 +1,4 @@
 mod api;
+mod mqtt;
 mod util;
 mod web;
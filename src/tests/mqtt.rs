@@ -0,0 +1,135 @@
+use crate::{
+    mqtt::{self, Broker, Message, Reply},
+    result::Result,
+    tests::util::{
+        add_sensor, get_sensor_token, get_token, register, response_json_value, test_client_with_db,
+    },
+};
+use diesel::{Connection, SqliteConnection};
+use std::collections::VecDeque;
+
+#[derive(Default)]
+struct MockBroker {
+    subscriptions: Vec<String>,
+    incoming: VecDeque<Message>,
+    published: Vec<Message>,
+}
+
+impl MockBroker {
+    fn push(&mut self, topic: &str, payload: serde_json::Value) {
+        self.incoming.push_back(Message {
+            topic: topic.to_string(),
+            payload: payload.to_string().into_bytes(),
+        });
+    }
+
+    fn replies(&self) -> Vec<(String, Reply)> {
+        self.published
+            .iter()
+            .map(|m| {
+                let reply = serde_json::from_slice(&m.payload).expect("reply must be json");
+                (m.topic.clone(), reply)
+            })
+            .collect()
+    }
+}
+
+impl Broker for MockBroker {
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.subscriptions.push(topic.to_string());
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, payload: Vec<u8>) -> Result<()> {
+        self.published.push(Message {
+            topic: topic.to_string(),
+            payload,
+        });
+        Ok(())
+    }
+
+    fn next_message(&mut self) -> Option<Message> {
+        self.incoming.pop_front()
+    }
+}
+
+fn reading(ts: i64) -> serde_json::Value {
+    json!({
+        "timestamp": ts,
+        "peak_power_mW": 1.0,
+        "peak_current_mA": 1.0,
+        "peak_voltage_V": 1.0,
+        "temp_celsius": 20.0,
+        "batt_V": 3.7,
+    })
+}
+
+#[test]
+fn mqtt_readings_are_acked_and_stored() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    let mut broker = MockBroker::default();
+    broker.push(
+        "sol/12/readings",
+        json!({"id": "a", "token": sensor_tok, "readings": [reading(1000), reading(2000)]}),
+    );
+    let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+    mqtt::run(&mut broker, &conn).expect("bridge failed");
+
+    assert_eq!(broker.subscriptions, vec![mqtt::READINGS_TOPIC.to_string()]);
+    let replies = broker.replies();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].0, "sol/12/readings/reply");
+    assert_eq!(replies[0].1.status, "ok");
+    assert_eq!(replies[0].1.id, Some("a".to_string()));
+    assert_eq!(replies[0].1.inserted, 2);
+
+    let mut res = client
+        .get("/api/sensor/1/readings?start=0&end=3000")
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let readings = data["data"]["readings"]
+        .as_array()
+        .expect("readings must be an array");
+    assert_eq!(readings.len(), 2);
+}
+
+#[test]
+fn mqtt_rejects_token_for_other_sensor() {
+    let (client, db_uri) = test_client_with_db();
+    register(&client, "newuser@gmail.com", "mypassword");
+    let tok = get_token(&client, "newuser@gmail.com", "mypassword");
+    add_sensor(&client, &tok, 12);
+    add_sensor(&client, &tok, 13);
+    let sensor_tok = get_sensor_token(&client, &tok, 12);
+
+    let mut broker = MockBroker::default();
+    broker.push(
+        "sol/13/readings",
+        json!({"token": sensor_tok, "readings": [reading(1000)]}),
+    );
+    broker.push("sol/13/readings", json!({"token": tok, "readings": []}));
+    broker.push("sol/13/readings", json!("not an object"));
+    let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+    mqtt::run(&mut broker, &conn).expect("bridge failed");
+
+    let replies = broker.replies();
+    assert_eq!(replies.len(), 3);
+    assert!(replies.iter().all(|(_, r)| r.status == "rejected"));
+    assert_eq!(
+        replies[0].1.error,
+        Some("token is not for sensor with hardware id 13".to_string())
+    );
+    assert_eq!(replies[1].1.error, Some("wrong token type".to_string()));
+}
+
+#[test]
+fn mqtt_topic_parsing() {
+    assert_eq!(mqtt::hardware_id_from_topic("sol/42/readings"), Some(42));
+    assert_eq!(mqtt::hardware_id_from_topic("sol/42/readings/reply"), None);
+    assert_eq!(mqtt::hardware_id_from_topic("sol/abc/readings"), None);
+}
@@ -1,4 +1,5 @@
-use crate::util::token::rand_str;
+use crate::{ratelimit::RateLimiter, util::token::rand_str};
+use diesel::{prelude::*, update};
 use rocket::{
     http::{ContentType, Header, Status},
     local::{Client, LocalResponse},
@@ -12,10 +13,22 @@ macro_rules! json_string {
 }
 
 pub fn test_client() -> Client {
+    test_client_with_db().0
+}
+
+/// Like `test_client`, but also returns the database uri so that tests can
+/// open their own connection to the same database.
+pub fn test_client_with_db() -> (Client, String) {
+    test_client_with_limits(RateLimiter::from_env())
+}
+
+/// Like `test_client_with_db`, but rate limited by `limiter`.
+pub fn test_client_with_limits(limiter: RateLimiter) -> (Client, String) {
     let db_uri = format!("./target/testdbs/{}.db", rand_str());
     crate::db::run_migrations(&db_uri);
-    let rocket = crate::rocket(&db_uri, true);
-    Client::new(rocket).expect("created test client")
+    let rocket = crate::rocket_with_limits(&db_uri, true, limiter);
+    let client = Client::new(rocket).expect("created test client");
+    (client, db_uri)
 }
 
 pub fn response_json_value(response: &mut LocalResponse) -> serde_json::Value {
@@ -59,6 +72,34 @@ pub fn get_token(client: &Client, email: &str, pass: &str) -> String {
     token.to_string()
 }
 
+/// The CSRF token in the form on the page at `path`, for the client's session.
+pub fn csrf_token(client: &Client, path: &str) -> String {
+    let mut res = client.get(path).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body = res.body_string().expect("no body");
+    let marker = "name=\"csrf_token\" value=\"";
+    let start = body.find(marker).expect("page has no csrf token") + marker.len();
+    let len = body[start..].find('"').expect("unterminated csrf token");
+    body[start..start + len].to_string()
+}
+
+/// Logs the client in on the web, through the login form.
+pub fn web_login(client: &Client, email: &str, pass: &str) {
+    let token = csrf_token(client, "/login");
+    let res = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "email={}&password={}&csrf_token={}",
+            email.replace('@', "%40"),
+            pass,
+            token
+        ))
+        .dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+    assert_ne!(res.headers().get_one("Location"), Some("/"));
+}
+
 pub fn add_sensor(client: &Client, token: &str, hw_id: usize) {
     let mut res = client
         .post("/api/add_sensor")
@@ -69,3 +110,39 @@ pub fn add_sensor(client: &Client, token: &str, hw_id: usize) {
     let _data = response_json_value(&mut res);
     assert_eq!(res.status(), Status::Ok);
 }
+
+pub fn get_sensor_token(client: &Client, token: &str, hw_id: usize) -> String {
+    let mut res = client
+        .post("/api/sensor_token")
+        .header(ContentType::JSON)
+        .header(token_auth_header(token))
+        .body(json!({ "hardware_id": hw_id }).to_string())
+        .dispatch();
+    let data = response_json_value(&mut res);
+    let token = data
+        .get("token")
+        .expect("must have a 'token' field")
+        .as_str()
+        .expect("value must be string");
+    assert_eq!(res.status(), Status::Ok);
+    token.to_string()
+}
+
+pub fn make_superuser(db_uri: &str, email: &str) {
+    use crate::schema::users;
+    let conn = SqliteConnection::establish(db_uri).expect("error connecting to db");
+    update(users::table.filter(users::email.eq(email)))
+        .set(users::superuser.eq(true))
+        .execute(&conn)
+        .expect("failed to make superuser");
+}
+
+pub fn add_readings(client: &Client, sensor_token: &str, readings: serde_json::Value) -> Status {
+    let res = client
+        .post("/api/add_readings")
+        .header(ContentType::JSON)
+        .header(token_auth_header(sensor_token))
+        .body(readings.to_string())
+        .dispatch();
+    res.status()
+}
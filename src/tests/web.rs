@@ -1,5 +1,12 @@
-use crate::tests::util::test_client;
-use rocket::http::Status;
+use crate::{
+    models::Sensor,
+    tests::util::{add_sensor, csrf_token, get_token, register, test_client, test_client_with_db},
+};
+use diesel::{Connection, SqliteConnection};
+use rocket::{
+    http::{ContentType, Status},
+    local::Client,
+};
 
 #[test]
 fn test_simple_ok() {
@@ -14,3 +21,105 @@ fn test_simple_not_ok() {
     let res = client.get("/invalid_endpoint").dispatch();
     assert_eq!(res.status(), Status::NotFound);
 }
+
+#[test]
+fn forged_form_posts_are_rejected() {
+    let (client, db_uri) = test_client_with_db();
+    let conn = SqliteConnection::establish(&db_uri).expect("failed to connect");
+    register(&client, "user@example.com", "password");
+    let token = get_token(&client, "user@example.com", "password");
+    add_sensor(&client, &token, 1);
+    let active = || Sensor::find(1, &conn).unwrap().active;
+
+    // Another site can make the browser post with its cookies, but can't
+    // read the token out of a page, and its own token is for another session.
+    let attacker = Client::new(crate::rocket(&db_uri, true)).expect("created test client");
+    let forged = format!("csrf_token={}", csrf_token(&attacker, "/login"));
+
+    let res = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "email=user%40example.com&password=password&{}",
+            forged
+        ))
+        .dispatch();
+    assert_eq!(res.headers().get_one("Location"), Some("/"));
+    let res = client.get("/user/user@example.com/edit").dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+
+    let token = csrf_token(&client, "/login");
+    let res = client
+        .post("/login")
+        .header(ContentType::Form)
+        .body(format!(
+            "email=user%40example.com&password=password&csrf_token={}",
+            token
+        ))
+        .dispatch();
+    assert_ne!(res.headers().get_one("Location"), Some("/"));
+    let session = res
+        .headers()
+        .get("Set-Cookie")
+        .find(|c| c.starts_with("user_token="))
+        .expect("no session cookie");
+    assert!(session.contains("SameSite=Lax"), "{}", session);
+
+    for body in &["csrf_token=", forged.as_str()] {
+        let res = client
+            .post("/sensor/1/deactivate")
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch();
+        assert_eq!(res.status(), Status::SeeOther);
+        assert_eq!(res.headers().get_one("Location"), Some("/"));
+        assert!(active());
+    }
+    let res = client
+        .post("/sensor/1/deactivate")
+        .header(ContentType::Form)
+        .dispatch();
+    assert_eq!(res.status(), Status::UnprocessableEntity);
+    assert!(active());
+    let res = client
+        .post("/sensor/1/import?dry_run=false")
+        .body("timestamp,peak_power_mW,peak_current_mA,peak_voltage_V,temp_celsius,batt_V\n")
+        .dispatch();
+    assert_eq!(res.headers().get_one("Location"), Some("/"));
+
+    let token = csrf_token(&client, "/sensor/1/deactivate");
+    let res = client
+        .post("/sensor/1/deactivate")
+        .header(ContentType::Form)
+        .body(format!("csrf_token={}", token))
+        .dispatch();
+    assert_eq!(res.headers().get_one("Location"), Some("/sensor/1"));
+    assert!(!active());
+
+    // Logging out gives the session a new token.
+    client.get("/logout").dispatch();
+    assert_ne!(csrf_token(&client, "/login"), token);
+}
+
+#[test]
+fn pages_are_sent_with_security_headers() {
+    let client = test_client();
+    let res = client.get("/login").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let headers = res.headers();
+    let csp = headers
+        .get_one("Content-Security-Policy")
+        .expect("no content security policy");
+    assert!(csp.contains("default-src 'self'"), "{}", csp);
+    assert!(csp.contains("frame-ancestors 'none'"), "{}", csp);
+    assert_eq!(
+        headers.get_one("Strict-Transport-Security"),
+        Some("max-age=31536000")
+    );
+    assert_eq!(headers.get_one("X-Frame-Options"), Some("DENY"));
+    assert_eq!(
+        headers.get_one("Referrer-Policy"),
+        Some("strict-origin-when-cross-origin")
+    );
+    assert_eq!(headers.get_one("X-Content-Type-Options"), Some("nosniff"));
+}
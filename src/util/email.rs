@@ -1,17 +1,53 @@
-use crate::result::{Error, Result};
+use crate::{
+    metrics::METRICS,
+    result::{Error, Result},
+};
 use rocket::{
     request::{FromRequest, Outcome},
     Request,
 };
 use rusoto_core::{region::Region, RusotoFuture};
 use rusoto_ses::{Body, Content, Destination, Message, SendEmailRequest, Ses, SesClient};
+use std::path::Path;
+
+pub const SITE_URL: &str = "https://dev.solsensor.com";
+
+/// Sends emails. Background jobs only need this much of `Emailer`, so tests can
+/// record emails instead of sending them.
+pub trait Notifier {
+    /// Sends an HTML email, with a plain text version if there is one.
+    fn notify(&self, to: &str, subject: &str, html: &str, text: Option<&str>) -> Result<()>;
+}
+
+impl Notifier for Emailer {
+    fn notify(&self, to: &str, subject: &str, html: &str, text: Option<&str>) -> Result<()> {
+        self.send_multipart(to, subject, html, text)
+    }
+}
 
 pub struct Emailer {
     client: SesClient,
 }
 
 impl Emailer {
+    pub fn new() -> Emailer {
+        Emailer {
+            client: SesClient::new(Region::UsEast1),
+        }
+    }
+
     pub fn send<S: Into<String>>(&self, to: S, subject: S, html: S) -> Result<()> {
+        self.send_multipart(to.into(), subject.into(), html.into(), None)
+    }
+
+    /// Sends an email with a plain text alternative to its HTML.
+    pub fn send_multipart<S: Into<String>>(
+        &self,
+        to: S,
+        subject: S,
+        html: S,
+        text: Option<&str>,
+    ) -> Result<()> {
         RusotoFuture::sync(self.client.send_email(SendEmailRequest {
             configuration_set_name: None,
             destination: Destination {
@@ -25,7 +61,10 @@ impl Emailer {
                     data: subject.into(),
                 },
                 body: Body {
-                    text: None,
+                    text: text.map(|text| Content {
+                        charset: None,
+                        data: text.to_string(),
+                    }),
                     html: Some(Content {
                         charset: None,
                         data: html.into(),
@@ -39,16 +78,47 @@ impl Emailer {
             source: "devteam@solsensor.com".to_string(),
             source_arn: None,
             tags: None,
-        }))?;
+        }))
+        .map_err(|e| {
+            METRICS.email_failed();
+            e
+        })?;
         Ok(())
     }
 }
 
+/// Where SES will get AWS credentials from, or an error if it won't find any.
+/// Credentials from an EC2 instance profile can only be found by asking AWS,
+/// so they aren't looked for.
+pub fn credentials_source() -> std::result::Result<String, String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    if var("AWS_ACCESS_KEY_ID").is_some() && var("AWS_SECRET_ACCESS_KEY").is_some() {
+        return Ok("environment".to_string());
+    }
+    if var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI").is_some() {
+        return Ok("container".to_string());
+    }
+    let file = var("AWS_SHARED_CREDENTIALS_FILE")
+        .or_else(|| var("HOME").map(|home| format!("{}/.aws/credentials", home)));
+    match file {
+        Some(file) if Path::new(&file).is_file() => Ok(format!("profile in {}", file)),
+        _ => Err(
+            "no AWS credentials in the environment or a credentials file, \
+             which is fine if an instance profile has them"
+                .to_string(),
+        ),
+    }
+}
+
+impl Default for Emailer {
+    fn default() -> Emailer {
+        Emailer::new()
+    }
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for Emailer {
     type Error = Error;
     fn from_request(_req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
-        Outcome::Success(Emailer {
-            client: SesClient::new(Region::UsEast1),
-        })
+        Outcome::Success(Emailer::new())
     }
 }
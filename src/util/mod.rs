@@ -1,2 +1,4 @@
 pub mod email;
+pub mod net;
+pub mod signing;
 pub mod token;
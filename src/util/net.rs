@@ -0,0 +1,105 @@
+//! Who requests came from, when they can come through a proxy, and whether
+//! addresses we send requests to are on the internet.
+use reqwest::Url;
+use rocket::Request;
+use std::net::{IpAddr, ToSocketAddrs};
+
+lazy_static! {
+    /// `SOL_TRUSTED_PROXIES`, the comma separated addresses of proxies whose
+    /// `X-Real-IP` is believed, which is localhost by default.
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = std::env::var("SOL_TRUSTED_PROXIES")
+        .unwrap_or_else(|_| "127.0.0.1,::1".to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| {
+            ip.parse()
+                .unwrap_or_else(|_| panic!("SOL_TRUSTED_PROXIES has a bad address {}", ip))
+        })
+        .collect();
+}
+
+/// Whether a request came straight from a trusted proxy, whose headers about
+/// the client it's passing on for can be believed.
+pub fn from_trusted_proxy(req: &Request) -> bool {
+    req.remote()
+        .map_or(false, |remote| TRUSTED_PROXIES.contains(&remote.ip()))
+}
+
+/// The ip a request came from. That's the connection's address, unless the
+/// connection is from a trusted proxy that says who it's passing on for.
+/// Anyone else could say they were anyone.
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    let remote = req.remote()?.ip();
+    if !from_trusted_proxy(req) {
+        return Some(remote);
+    }
+    req.real_ip().or(Some(remote))
+}
+
+/// Whether `ip` is on the internet, rather than this host, a private network,
+/// or link-local like the cloud metadata address `169.254.169.254`.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || octets[0] == 0
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            if ip.is_loopback() || ip.is_unspecified() {
+                false
+            } else if let Some(ip) = ip.to_ipv4() {
+                // Mapped and compatible addresses reach the IPv4 address.
+                is_public(IpAddr::V4(ip))
+            } else {
+                // Unique local fc00::/7 and link-local fe80::/10.
+                first & 0xfe00 != 0xfc00 && first & 0xffc0 != 0xfe80
+            }
+        }
+    }
+}
+
+/// Fails unless `url` is http or https and every address its host resolves
+/// to is public, so requests to it can't reach this host or its networks.
+pub fn check_public_url(url: &str) -> std::result::Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("bad url: {}", e))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err("url must start with http:// or https://".into());
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| "url has no host".to_string())?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    resolve_public(host).map(|_| ())
+}
+
+/// The addresses `host` resolves to, or the address it is.
+pub fn resolve(host: &str) -> std::result::Result<Vec<IpAddr>, String> {
+    let addrs: Vec<IpAddr> = (host, 0)
+        .to_socket_addrs()
+        .map_err(|e| format!("can't resolve {}: {}", host, e))?
+        .map(|addr| addr.ip())
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("can't resolve {}", host));
+    }
+    Ok(addrs)
+}
+
+/// The addresses `host` resolves to, failing unless every one is public.
+pub fn resolve_public(host: &str) -> std::result::Result<Vec<IpAddr>, String> {
+    let addrs = resolve(host)?;
+    match addrs.iter().find(|ip| !is_public(**ip)) {
+        Some(ip) => Err(format!("{} is not a public address", ip)),
+        None => Ok(addrs),
+    }
+}
@@ -0,0 +1,53 @@
+//! Signs tokens that are handed out in links, so made up tokens are rejected
+//! without looking them up.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+lazy_static! {
+    /// `SOL_SECRET_KEY`.
+    static ref KEY: Vec<u8> = key();
+}
+
+/// Reads `SOL_SECRET_KEY`, which must be set so signed links keep working
+/// when the server restarts.
+#[cfg(not(test))]
+fn key() -> Vec<u8> {
+    match std::env::var("SOL_SECRET_KEY") {
+        Ok(key) if !key.is_empty() => key.into_bytes(),
+        _ => panic!("SOL_SECRET_KEY must be set"),
+    }
+}
+
+/// Tests sign with a random key, unless they set one.
+#[cfg(test)]
+fn key() -> Vec<u8> {
+    match std::env::var("SOL_SECRET_KEY") {
+        Ok(key) if !key.is_empty() => key.into_bytes(),
+        _ => crate::util::token::rand_str().into_bytes(),
+    }
+}
+
+/// Reads the key now, so a server without one fails to start rather than on
+/// the first link it signs.
+pub fn check_key() {
+    lazy_static::initialize(&KEY);
+}
+
+fn mac(message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(&KEY).expect("hmac takes keys of any length");
+    mac.input(message.as_bytes());
+    mac
+}
+
+/// The hex HMAC-SHA256 of `message`.
+pub fn sign(message: &str) -> String {
+    hex::encode(mac(message).result().code())
+}
+
+/// Whether `signature` is `sign(message)`, compared in constant time.
+pub fn verify(message: &str, signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(bytes) => mac(message).verify(&bytes).is_ok(),
+        Err(_) => false,
+    }
+}
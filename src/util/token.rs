@@ -8,3 +8,13 @@ pub fn rand_str() -> String {
         .take(64)
         .collect()
 }
+
+/// Compares in the same time wherever the first difference is, so timing
+/// doesn't give away how much of a guess was right.
+pub fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
@@ -1,35 +1,75 @@
 use crate::{
+    api::DEFAULT_ENERGY_STATS_DAYS,
     auth,
+    csrf::{self, Csrf, TokenForm},
     db::SolDbConn,
-    models::{onetime_login, Reading, ReadingQuery, Sensor, SensorQuery, Token, User, UserQuery},
-    result::Result,
+    import::{self, ImportOptions, ImportReport},
+    logging::RequestInfo,
+    models::{
+        alert::{self, AlertRule, AlertRuleInsert, DescribedRule, Incident},
+        audit::{self, Entry},
+        channel::{self, Channel},
+        digest::{self, Frequency, Subscription},
+        energy::{self, EnergySummary, Period},
+        health::{self, Health},
+        job::{self, Job, Run},
+        onetime_login,
+        performance::{self, Performance},
+        Reading, Sensor, SensorLocation, SensorQuery, Token, User, UserQuery,
+    },
+    result::{Error, Result},
     util::email::Emailer,
 };
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::Connection;
 use rocket::{
     get,
     http::{Cookie, Cookies, Status},
     post,
     request::{FlashMessage, Form, FromRequest},
     response::{Flash, Redirect},
-    Outcome, Request,
+    Data, Outcome, Request,
 };
 use rocket_contrib::templates::Template;
 
 pub(crate) mod result;
 use self::result::Result as WebResult;
 
+/// A reading with its values in the same order as the sensor's channels, so
+/// templates can render a table for any set of channels.
+#[derive(Serialize)]
+pub struct ReadingRow {
+    timestamp: NaiveDateTime,
+    values: Vec<Option<f32>>,
+}
+
 #[derive(Serialize)]
 pub struct TemplateCtx {
     title: Option<String>,
     current_user: Option<UserQuery>,
     flash: Option<String>,
+    /// For every form to post back.
+    csrf_token: String,
     users: Option<Vec<UserQuery>>,
     user: Option<UserQuery>,
     sensors: Option<Vec<SensorQuery>>,
     sensor: Option<SensorQuery>,
-    readings: Option<Vec<ReadingQuery>>,
+    channels: Option<Vec<Channel>>,
+    readings: Option<Vec<ReadingRow>>,
     reading_count: Option<i64>,
     sensor_count: Option<i64>,
+    import_report: Option<ImportReport>,
+    energy_summaries: Option<Vec<EnergySummary>>,
+    performance: Option<Performance>,
+    health: Option<Health>,
+    sensor_healths: Option<Vec<Health>>,
+    alert_rules: Option<Vec<DescribedRule>>,
+    incidents: Option<Vec<Incident>>,
+    digest: Option<Subscription>,
+    unsubscribe_token: Option<String>,
+    jobs: Option<Vec<Job>>,
+    job_runs: Option<Vec<Run>>,
+    audit_entries: Option<Vec<Entry>>,
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for TemplateCtx {
@@ -45,17 +85,32 @@ impl<'a, 'r> FromRequest<'a, 'r> for TemplateCtx {
             .success_or("failed")
             .ok()
             .map(|f: FlashMessage| format!("{}: {}", f.name(), f.msg()));
+        let csrf: Csrf = req.guard().expect("csrf guard failed");
         let ctx = TemplateCtx {
             current_user,
             title: None,
             flash,
+            csrf_token: csrf.token().to_string(),
             user: None,
             users: None,
             sensors: None,
             sensor: None,
+            channels: None,
             readings: None,
             reading_count: None,
             sensor_count: None,
+            import_report: None,
+            energy_summaries: None,
+            performance: None,
+            health: None,
+            sensor_healths: None,
+            alert_rules: None,
+            incidents: None,
+            digest: None,
+            unsubscribe_token: None,
+            jobs: None,
+            job_runs: None,
+            audit_entries: None,
         };
         Outcome::Success(ctx)
     }
@@ -108,10 +163,17 @@ pub fn users(
 pub fn user(mut ctx: TemplateCtx, email: String, conn: SolDbConn) -> WebResult<Template> {
     let user = User::by_email(&email, &conn)?;
     let sensors = Sensor::find_for_user(user.id, &conn).ok();
+    let now = Utc::now().naive_utc();
+    let healths = sensors
+        .iter()
+        .flatten()
+        .map(|sensor| health::health(sensor, now, &conn))
+        .collect::<Result<Vec<_>>>()?;
 
     ctx.title = Some(email);
     ctx.user = Some(user);
     ctx.sensors = sensors;
+    ctx.sensor_healths = Some(healths);
     Ok(Template::render("user", &ctx))
 }
 
@@ -125,6 +187,7 @@ pub fn user_edit(
     auth?;
     let user = User::by_email(&email, &conn)?;
     ctx.title = Some(format!("{} | edit", email));
+    ctx.digest = digest::find(user.id, &conn)?;
     ctx.user = Some(user);
     Ok(Template::render("user_edit", &ctx))
 }
@@ -132,15 +195,21 @@ pub fn user_edit(
 #[derive(Deserialize, FromForm)]
 pub struct UserEdit {
     email: String,
+    /// `daily` or `weekly`, or empty for no digest emails.
+    digest: String,
+    csrf_token: String,
 }
 
 #[post("/user/<email>/edit", data = "<form>")]
 pub fn user_edit_post(
+    csrf: Csrf,
     auth: Result<auth::UserCookie>,
     form: Form<UserEdit>,
     email: String,
     conn: SolDbConn,
+    info: RequestInfo,
 ) -> WebResult<Flash<Redirect>> {
+    csrf.verify(&form.csrf_token)?;
     let auth = auth?;
     let user = User::by_email(&email, &conn)?;
     let editor = auth.user();
@@ -152,7 +221,25 @@ pub fn user_edit_post(
     }
 
     let form = form.0;
-    User::update(user.id, &form.email, &conn)?;
+    let frequency = match form.digest.as_str() {
+        "" => None,
+        f => Some(Frequency::from_string(f)?),
+    };
+    conn.transaction(|| {
+        User::update(user.id, &form.email, &conn)?;
+        digest::subscribe(user.id, frequency, &conn)?;
+        if user.id != editor.id {
+            audit::record(
+                Some(editor.id),
+                audit::ADMIN_USER_EDITED,
+                Some(&format!("user {}", user.id)),
+                Some(&format!("email {}, digest {}", form.email, form.digest)),
+                &info,
+                &conn,
+            )?;
+        }
+        Ok(())
+    })?;
 
     Ok(Flash::success(
         Redirect::to(uri!(user: &form.email)),
@@ -160,14 +247,63 @@ pub fn user_edit_post(
     ))
 }
 
+#[get("/digest/unsubscribe/<token>")]
+pub fn digest_unsubscribe(
+    mut ctx: TemplateCtx,
+    token: String,
+    conn: SolDbConn,
+) -> WebResult<Template> {
+    digest::check_unsubscribe_token(&token, &conn)?;
+    ctx.title = Some("unsubscribe".into());
+    ctx.unsubscribe_token = Some(token);
+    Ok(Template::render("digest_unsubscribe", &ctx))
+}
+
+/// Unsubscribing takes a POST from the confirmation page, so that link
+/// checkers that follow links in emails don't unsubscribe anyone.
+#[post("/digest/unsubscribe/<token>")]
+pub fn digest_unsubscribe_post(
+    mut ctx: TemplateCtx,
+    csrf: Csrf,
+    form: Form<TokenForm>,
+    token: String,
+    conn: SolDbConn,
+) -> WebResult<Template> {
+    csrf.verify(&form.csrf_token)?;
+    digest::unsubscribe(&token, Utc::now().naive_utc(), &conn)?;
+    ctx.title = Some("unsubscribe".into());
+    ctx.flash = Some("you have been unsubscribed from digest emails".into());
+    Ok(Template::render("digest_unsubscribe", &ctx))
+}
+
 #[get("/sensor/<id>")]
 pub fn sensor(mut ctx: TemplateCtx, id: i32, conn: SolDbConn) -> WebResult<Template> {
     let sensor = Sensor::find(id, &conn)?;
-    let readings = Reading::find_for_sensor(sensor.id, &conn)?;
-    let readings = Some(readings.into_iter().take(20).collect());
+    let channels = channel::for_sensor(&sensor, &conn)?;
+    let readings = Reading::find_for_sensor(sensor.id, 20, &conn)?;
+    let readings = Reading::with_channels(readings, &conn)?;
+    let rows = readings
+        .iter()
+        .map(|r| ReadingRow {
+            timestamp: r.reading.timestamp,
+            values: channels.iter().map(|c| r.value(&c.name)).collect(),
+        })
+        .collect();
+    let today = sensor.today();
+    let summaries = [Period::Week, Period::Month, Period::Year]
+        .iter()
+        .map(|&period| energy::summary(&sensor, period, today, today, &conn))
+        .collect::<Result<Vec<_>>>()?;
+    if sensor.site().is_some() {
+        let start = today - Duration::days(DEFAULT_ENERGY_STATS_DAYS - 1);
+        ctx.performance = Some(performance::performance(&sensor, start, today, &conn)?);
+    }
+    ctx.health = Some(health::health(&sensor, Utc::now().naive_utc(), &conn)?);
     ctx.title = Some(format!("sensor {}", id));
     ctx.sensor = Some(sensor);
-    ctx.readings = readings;
+    ctx.channels = Some(channels);
+    ctx.energy_summaries = Some(summaries);
+    ctx.readings = Some(rows);
     Ok(Template::render("sensor", &ctx))
 }
 
@@ -189,18 +325,42 @@ pub fn sensor_edit(
 pub struct SensorEdit {
     name: String,
     description: String,
+    model: String,
+    timezone: String,
+    latitude: String,
+    longitude: String,
+    elevation_m: String,
+    tilt_deg: String,
+    azimuth_deg: String,
+    csrf_token: String,
+}
+
+/// Parses an optional number from a form field, which is left empty to clear
+/// it.
+fn form_number(name: &str, value: &str) -> Result<Option<f32>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| Error::InvalidLocation(format!("{} '{}' is not a number", name, value)))
 }
 
 #[post("/sensor/<id>/edit", data = "<form>")]
 pub fn sensor_edit_post(
+    csrf: Csrf,
     auth: Result<auth::UserCookie>,
     form: Form<SensorEdit>,
     id: i32,
     conn: SolDbConn,
+    info: RequestInfo,
 ) -> WebResult<Flash<Redirect>> {
-    let auth = auth?;
+    csrf.verify(&form.csrf_token)?;
+    let user = auth?.user();
     let sensor = Sensor::find(id, &conn)?;
-    if sensor.owner_id != auth.user().id {
+    if sensor.owner_id != user.id {
         return Ok(Flash::error(
             Redirect::to(uri!(sensor: id)),
             "user does not own this sensor",
@@ -208,7 +368,27 @@ pub fn sensor_edit_post(
     }
 
     let form = form.0;
-    Sensor::update(id, form.name, form.description, &conn)?;
+    let model = Some(form.model).filter(|m| !m.is_empty());
+    let timezone = Some(form.timezone).filter(|tz| !tz.is_empty());
+    let location = SensorLocation {
+        latitude: form_number("latitude", &form.latitude)?,
+        longitude: form_number("longitude", &form.longitude)?,
+        elevation_m: form_number("elevation_m", &form.elevation_m)?,
+        tilt_deg: form_number("tilt_deg", &form.tilt_deg)?,
+        azimuth_deg: form_number("azimuth_deg", &form.azimuth_deg)?,
+    };
+    conn.transaction(|| {
+        Sensor::update(id, form.name, form.description, model, timezone, &conn)?;
+        Sensor::set_location(id, &location, &conn)?;
+        audit::record(
+            Some(user.id),
+            audit::SENSOR_EDITED,
+            Some(&format!("sensor {}", id)),
+            None,
+            &info,
+            &conn,
+        )
+    })?;
 
     Ok(Flash::success(
         Redirect::to(uri!(sensor: id)),
@@ -230,15 +410,19 @@ pub fn sensor_deactivate(
     Ok(Template::render("sensor_deactivate", &ctx))
 }
 
-#[post("/sensor/<id>/deactivate")]
+#[post("/sensor/<id>/deactivate", data = "<form>")]
 pub fn sensor_deactivate_post(
+    csrf: Csrf,
     auth: Result<auth::UserCookie>,
+    form: Form<TokenForm>,
     id: i32,
     conn: SolDbConn,
+    info: RequestInfo,
 ) -> WebResult<Flash<Redirect>> {
-    let auth = auth?;
+    csrf.verify(&form.csrf_token)?;
+    let user = auth?.user();
     let sensor = Sensor::find(id, &conn)?;
-    if sensor.owner_id != auth.user().id {
+    if sensor.owner_id != user.id {
         return Ok(Flash::error(
             Redirect::to(uri!(sensor: id)),
             "user does not own this sensor",
@@ -246,6 +430,14 @@ pub fn sensor_deactivate_post(
     }
 
     Sensor::deactivate(&conn, id)?;
+    audit::record(
+        Some(user.id),
+        audit::SENSOR_DEACTIVATED,
+        Some(&format!("sensor {}", id)),
+        None,
+        &info,
+        &conn,
+    )?;
 
     Ok(Flash::success(
         Redirect::to(uri!(sensor: id)),
@@ -253,6 +445,272 @@ pub fn sensor_deactivate_post(
     ))
 }
 
+#[get("/sensor/<id>/alerts")]
+pub fn sensor_alerts(
+    mut ctx: TemplateCtx,
+    id: i32,
+    conn: SolDbConn,
+    auth: Result<auth::UserCookie>,
+) -> WebResult<Template> {
+    let auth = auth?;
+    let sensor = Sensor::find(id, &conn)?;
+    if sensor.owner_id != auth.user().id {
+        return Err(Error::NotSensorOwner.into());
+    }
+    let rules = alert::rules_for_sensor(id, &conn)?
+        .into_iter()
+        .map(AlertRule::described)
+        .collect();
+    let channels = channel::for_sensor(&sensor, &conn)?;
+    ctx.title = Some(format!("sensor {} | alerts", id));
+    ctx.sensor = Some(sensor);
+    ctx.channels = Some(channels);
+    ctx.alert_rules = Some(rules);
+    ctx.incidents = Some(alert::incidents_for_sensor(
+        id,
+        alert::HISTORY_LIMIT,
+        &conn,
+    )?);
+    Ok(Template::render("sensor_alerts", &ctx))
+}
+
+#[derive(FromForm)]
+pub struct NewAlertRule {
+    kind: String,
+    channel: String,
+    threshold: f32,
+    csrf_token: String,
+}
+
+#[post("/sensor/<id>/alerts", data = "<form>")]
+pub fn sensor_alerts_post(
+    csrf: Csrf,
+    auth: Result<auth::UserCookie>,
+    form: Form<NewAlertRule>,
+    id: i32,
+    conn: SolDbConn,
+) -> WebResult<Flash<Redirect>> {
+    csrf.verify(&form.csrf_token)?;
+    let auth = auth?;
+    let sensor = Sensor::find(id, &conn)?;
+    if sensor.owner_id != auth.user().id {
+        return Ok(Flash::error(
+            Redirect::to(uri!(sensor: id)),
+            "user does not own this sensor",
+        ));
+    }
+
+    let form = form.into_inner();
+    let rule = AlertRuleInsert {
+        sensor_id: id,
+        kind: form.kind,
+        channel: Some(form.channel).filter(|c| !c.is_empty()),
+        threshold: form.threshold,
+    };
+    Ok(match alert::create(&rule, &conn) {
+        Ok(()) => Flash::success(Redirect::to(uri!(sensor_alerts: id)), "added alert rule"),
+        Err(e) => Flash::error(Redirect::to(uri!(sensor_alerts: id)), e.to_string()),
+    })
+}
+
+#[derive(FromForm)]
+pub struct MuteAlertRule {
+    hours: Option<f64>,
+    csrf_token: String,
+}
+
+#[post("/alert_rule/<id>/mute", data = "<form>")]
+pub fn alert_rule_mute_post(
+    csrf: Csrf,
+    auth: Result<auth::UserCookie>,
+    form: Form<MuteAlertRule>,
+    id: i32,
+    conn: SolDbConn,
+) -> WebResult<Flash<Redirect>> {
+    csrf.verify(&form.csrf_token)?;
+    let auth = auth?;
+    let sensor = alert::rule_sensor(id, &conn)?;
+    let redirect = Redirect::to(uri!(sensor_alerts: sensor.id));
+    if sensor.owner_id != auth.user().id {
+        return Ok(Flash::error(redirect, "user does not own this sensor"));
+    }
+
+    let until = match form.hours {
+        Some(hours) => Some(alert::mute_until(Utc::now().naive_utc(), hours)?),
+        None => None,
+    };
+    alert::mute(id, until, &conn)?;
+    let msg = if until.is_some() {
+        "muted alert rule"
+    } else {
+        "unmuted alert rule"
+    };
+    Ok(Flash::success(redirect, msg))
+}
+
+#[post("/alert/<id>/acknowledge", data = "<form>")]
+pub fn alert_acknowledge_post(
+    csrf: Csrf,
+    auth: Result<auth::UserCookie>,
+    form: Form<TokenForm>,
+    id: i32,
+    conn: SolDbConn,
+) -> WebResult<Flash<Redirect>> {
+    csrf.verify(&form.csrf_token)?;
+    let auth = auth?;
+    let incident = alert::find_incident(id, &conn)?;
+    let sensor = alert::rule_sensor(incident.rule_id, &conn)?;
+    let redirect = Redirect::to(uri!(sensor_alerts: sensor.id));
+    if sensor.owner_id != auth.user().id {
+        return Ok(Flash::error(redirect, "user does not own this sensor"));
+    }
+
+    alert::acknowledge(id, Utc::now().naive_utc(), &conn)?;
+    Ok(Flash::success(redirect, "acknowledged alert"))
+}
+
+/// Runs shown on the jobs page.
+const JOB_RUNS_SHOWN: i64 = 50;
+
+#[get("/admin/jobs")]
+pub fn admin_jobs(
+    mut ctx: TemplateCtx,
+    conn: SolDbConn,
+    auth: Result<auth::AdminCookie>,
+) -> WebResult<Template> {
+    auth?;
+    ctx.title = Some(String::from("Jobs"));
+    ctx.jobs = Some(job::all(&conn)?);
+    ctx.job_runs = Some(job::recent_runs(JOB_RUNS_SHOWN, &conn)?);
+    Ok(Template::render("admin_jobs", &ctx))
+}
+
+#[post("/admin/job/<name>/run", data = "<form>")]
+pub fn admin_job_run_post(
+    csrf: Csrf,
+    auth: Result<auth::AdminCookie>,
+    form: Form<TokenForm>,
+    name: String,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> WebResult<Flash<Redirect>> {
+    csrf.verify(&form.csrf_token)?;
+    let admin = auth?.user();
+    let redirect = Redirect::to(uri!(admin_jobs));
+    let job = job::find(&name, &conn)?.ok_or_else(|| Error::UnknownJob(name.clone()))?;
+    if !job.enabled {
+        return Ok(Flash::error(redirect, "job is disabled"));
+    }
+    // The scheduler picks it up on its next pass, rather than it running on
+    // this request.
+    job::schedule_now(&name, Utc::now().naive_utc(), &conn)?;
+    audit::record(
+        Some(admin.id),
+        audit::ADMIN_JOB_RUN,
+        Some(&name),
+        None,
+        &info,
+        &conn,
+    )?;
+    Ok(Flash::success(
+        redirect,
+        format!("{} will run shortly", name),
+    ))
+}
+
+#[post("/admin/job/<name>/toggle", data = "<form>")]
+pub fn admin_job_toggle_post(
+    csrf: Csrf,
+    auth: Result<auth::AdminCookie>,
+    form: Form<TokenForm>,
+    name: String,
+    conn: SolDbConn,
+    info: RequestInfo,
+) -> WebResult<Flash<Redirect>> {
+    csrf.verify(&form.csrf_token)?;
+    let admin = auth?.user();
+    let job = job::find(&name, &conn)?.ok_or_else(|| Error::UnknownJob(name.clone()))?;
+    job::set_enabled(&name, !job.enabled, &conn)?;
+    let msg = if job.enabled { "disabled" } else { "enabled" };
+    audit::record(
+        Some(admin.id),
+        audit::ADMIN_JOB_TOGGLED,
+        Some(&name),
+        Some(msg),
+        &info,
+        &conn,
+    )?;
+    Ok(Flash::success(
+        Redirect::to(uri!(admin_jobs)),
+        format!("{} {}", msg, name),
+    ))
+}
+
+#[get("/admin/audit?<email>")]
+pub fn admin_audit(
+    mut ctx: TemplateCtx,
+    email: Option<String>,
+    conn: SolDbConn,
+    auth: Result<auth::AdminCookie>,
+) -> WebResult<Template> {
+    auth?;
+    let user = match email.filter(|e| !e.is_empty()) {
+        Some(email) => Some(User::by_email(&email, &conn)?),
+        None => None,
+    };
+    let user_id = user.as_ref().map(|u| u.id);
+    ctx.title = Some(String::from("Audit log"));
+    ctx.audit_entries = Some(audit::latest(user_id, audit::DEFAULT_LIMIT, &conn)?);
+    ctx.user = user;
+    Ok(Template::render("admin_audit", &ctx))
+}
+
+#[get("/sensor/<id>/import")]
+pub fn sensor_import(
+    mut ctx: TemplateCtx,
+    id: i32,
+    conn: SolDbConn,
+    auth: Result<auth::UserCookie>,
+) -> WebResult<Template> {
+    let auth = auth?;
+    let sensor = Sensor::find(id, &conn)?;
+    if sensor.owner_id != auth.user().id {
+        return Err(Error::NotSensorOwner.into());
+    }
+    ctx.title = Some(format!("sensor {} | import", id));
+    ctx.sensor = Some(sensor);
+    Ok(Template::render("sensor_import", &ctx))
+}
+
+/// The import page posts the file itself as the body, and shows the rendered
+/// report in place, so errors are shown in the report instead of redirecting.
+#[post("/sensor/<id>/import?<opts..>", data = "<data>")]
+pub fn sensor_import_post(
+    mut ctx: TemplateCtx,
+    csrf: Result<csrf::HeaderToken>,
+    auth: Result<auth::UserCookie>,
+    opts: Form<ImportOptions>,
+    data: Data,
+    id: i32,
+    conn: SolDbConn,
+) -> WebResult<Template> {
+    csrf?;
+    let auth = auth?;
+    let sensor = Sensor::find(id, &conn)?;
+    if sensor.owner_id != auth.user().id {
+        return Err(Error::NotSensorOwner.into());
+    }
+
+    let report = import::spool(data.open())
+        .and_then(|input| import::import_csv(sensor.id, input, &opts, &conn));
+    match report {
+        Ok(report) => ctx.import_report = Some(report),
+        Err(e) => ctx.flash = Some(format!("error: {}", e)),
+    }
+    ctx.sensor = Some(sensor);
+    Ok(Template::render("sensor_import_result", &ctx))
+}
+
 #[get("/login")]
 pub fn login(mut ctx: TemplateCtx) -> Template {
     ctx.title = Some(String::from("Login"));
@@ -272,25 +730,39 @@ pub fn change_password(
 #[derive(Deserialize, FromForm)]
 pub struct Password {
     password: String,
+    csrf_token: String,
 }
 
 #[post("/change_password", data = "<form>")]
 pub fn change_password_post(
     form: Form<Password>,
     conn: SolDbConn,
+    csrf: Csrf,
     auth: Result<auth::UserCookie>,
     mut cookies: Cookies,
+    info: RequestInfo,
 ) -> WebResult<Redirect> {
+    csrf.verify(&form.csrf_token)?;
     let user = auth?.user();
     let pwd = form.into_inner().password;
     User::update_password(user.id, pwd, &conn)?;
+    audit::record(
+        Some(user.id),
+        audit::PASSWORD_CHANGED,
+        None,
+        None,
+        &info,
+        &conn,
+    )?;
     cookies.remove_private(Cookie::named("user_token"));
+    csrf::reset(&mut cookies);
     Ok(Redirect::to("/login"))
 }
 
 #[derive(Deserialize, FromForm)]
 pub struct Email {
     email: String,
+    csrf_token: String,
 }
 
 #[get("/forgot_password")]
@@ -301,13 +773,24 @@ pub fn forgot_password(mut ctx: TemplateCtx) -> Template {
 
 #[post("/forgot_password", data = "<form>")]
 pub fn forgot_password_post(
+    csrf: Csrf,
     form: Form<Email>,
     emailer: Result<Emailer>,
     conn: SolDbConn,
+    info: RequestInfo,
 ) -> WebResult<Flash<Redirect>> {
+    csrf.verify(&form.csrf_token)?;
     let email = &form.0.email;
     let user = User::by_email(email, &conn)?;
     let token = onetime_login::create(user.id, &conn)?;
+    audit::record(
+        Some(user.id),
+        audit::PASSWORD_RESET_REQUESTED,
+        None,
+        None,
+        &info,
+        &conn,
+    )?;
 
     emailer?.send("ryan@ryanchipman.com", "Password Reset", &format!("<html><body>Reset password at this link: <a href=\"https://dev.solsensor.com/login/onetime/{}\">Reset Password</a></body></html>", token))?;
 
@@ -320,6 +803,7 @@ pub fn forgot_password_post(
 #[get("/logout")]
 pub fn logout(mut cookies: Cookies) -> Redirect {
     cookies.remove_private(Cookie::named("user_token"));
+    csrf::reset(&mut cookies);
     Redirect::to("/")
 }
 
@@ -327,19 +811,31 @@ pub fn logout(mut cookies: Cookies) -> Redirect {
 pub struct EmailPassword {
     email: String,
     password: String,
+    csrf_token: String,
 }
 
 #[post("/login", data = "<creds>")]
 pub fn login_post(
     creds: Form<EmailPassword>,
     conn: SolDbConn,
+    csrf: Csrf,
     mut cookies: Cookies,
+    info: RequestInfo,
 ) -> WebResult<Redirect> {
     let creds = creds.into_inner();
-    let user = User::verify_password(&creds.email, &creds.password, &conn)?;
+    csrf.verify(&creds.csrf_token)?;
+    let user = match User::verify_password(&creds.email, &creds.password, &conn) {
+        Ok(user) => user,
+        Err(e) => {
+            auth::failed_login(&creds.email, &info, &conn);
+            return Err(e.into());
+        }
+    };
     let token = Token::new_user_token(&user);
     Token::insert(&token, &conn)?;
-    cookies.add_private(Cookie::build("user_token", token.token).path("/").finish());
+    audit::record(Some(user.id), audit::LOGIN, None, None, &info, &conn)?;
+    cookies.add_private(auth::session_cookie(token.token));
+    csrf::reset(&mut cookies);
     Ok(Redirect::to(uri!(user: user.email)))
 }
 
@@ -353,21 +849,37 @@ pub fn register(mut ctx: TemplateCtx) -> Template {
 pub struct Register {
     email: String,
     password: String,
+    csrf_token: String,
 }
 
 #[post("/register", data = "<form>")]
-pub fn register_post(form: Form<Register>, conn: SolDbConn) -> WebResult<Redirect> {
+pub fn register_post(csrf: Csrf, form: Form<Register>, conn: SolDbConn) -> WebResult<Redirect> {
     let form = form.into_inner();
+    csrf.verify(&form.csrf_token)?;
     User::insert(form.email.clone(), form.password.clone(), &conn)?;
     Ok(Redirect::to(uri!(user: form.email)))
 }
 
 #[get("/login/onetime/<token>")]
-pub fn login_onetime(token: String, conn: SolDbConn, mut cookies: Cookies) -> WebResult<Redirect> {
+pub fn login_onetime(
+    token: String,
+    conn: SolDbConn,
+    mut cookies: Cookies,
+    info: RequestInfo,
+) -> WebResult<Redirect> {
     let user = User::by_onetime(&token, &conn)?;
     onetime_login::delete(&token, &conn)?;
     let token = Token::new_user_token(&user);
     Token::insert(&token, &conn)?;
-    cookies.add_private(Cookie::build("user_token", token.token).path("/").finish());
+    audit::record(
+        Some(user.id),
+        audit::LOGIN,
+        None,
+        Some("onetime"),
+        &info,
+        &conn,
+    )?;
+    cookies.add_private(auth::session_cookie(token.token));
+    csrf::reset(&mut cookies);
     Ok(Redirect::to(uri!(change_password)))
 }
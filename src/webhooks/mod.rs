@@ -0,0 +1,254 @@
+//! Sends queued webhook deliveries. Events are queued in the database by
+//! `models::webhook::emit`, so deliveries survive restarts, and failed ones are
+//! retried with exponential backoff until they run out of attempts.
+//!
+//! Every request is signed with the webhook's secret. Receivers should
+//! recompute `X-Sol-Signature` from `X-Sol-Timestamp` and the raw body, and
+//! can reject old timestamps to stop replays.
+//!
+//! Webhooks can only be sent to public addresses, checked when they're
+//! created and again when they're connected to, so a name can't resolve to
+//! a public address for the check and a private one for the request.
+//! Redirects aren't followed, since they could lead anywhere.
+use crate::{
+    logging,
+    models::webhook::{self, Delivery},
+    result::Result,
+    util::net,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{Connection, SqliteConnection};
+use futures::future::{self, FutureResult};
+use hmac::{Hmac, Mac};
+use hyper::{
+    client::{
+        connect::dns::{Name, Resolve},
+        HttpConnector,
+    },
+    Body, Request, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    io,
+    net::IpAddr,
+    thread::{self, JoinHandle},
+    time,
+};
+use tokio::{runtime::current_thread::Runtime, timer::Timeout};
+
+/// How often the queue is checked for due deliveries.
+pub const INTERVAL_SECS: u64 = 5;
+
+/// Deliveries sent per check.
+const BATCH_SIZE: i64 = 50;
+
+/// How long a receiver has to respond.
+const TIMEOUT_SECS: u64 = 10;
+
+/// A delivery is failed after this many attempts.
+pub const MAX_ATTEMPTS: i32 = 8;
+
+/// Wait before the first retry, doubled after every failed attempt.
+pub const BACKOFF_SECS: i64 = 30;
+
+/// Longest wait between attempts.
+pub const MAX_BACKOFF_SECS: i64 = 6 * 3600;
+
+pub const SIGNATURE_HEADER: &str = "X-Sol-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Sol-Timestamp";
+pub const EVENT_HEADER: &str = "X-Sol-Event";
+pub const DELIVERY_HEADER: &str = "X-Sol-Delivery";
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub delivered: usize,
+    pub retrying: usize,
+    pub failed: usize,
+}
+
+/// The `X-Sol-Signature` header of a request: the hex HMAC-SHA256 of
+/// `"<timestamp>.<body>"` keyed with the webhook's secret.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.input(timestamp.to_string().as_bytes());
+    mac.input(b".");
+    mac.input(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.result().code()))
+}
+
+/// When to retry a delivery that has failed `attempts` times.
+pub fn backoff(attempts: i32) -> Duration {
+    let secs = (0..attempts - 1).fold(BACKOFF_SECS, |secs, _| (secs * 2).min(MAX_BACKOFF_SECS));
+    Duration::seconds(secs)
+}
+
+/// `SOL_WEBHOOK_ALLOW_PRIVATE=true` allows any address, for receivers on the
+/// same network in development.
+fn allow_private() -> bool {
+    std::env::var("SOL_WEBHOOK_ALLOW_PRIVATE").map_or(false, |v| v == "true")
+}
+
+/// Fails unless webhooks may be sent to `url`.
+pub fn check_url(url: &str) -> std::result::Result<(), String> {
+    if allow_private() {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Ok(());
+        }
+        return Err("url must start with http:// or https://".into());
+    }
+    net::check_public_url(url)
+}
+
+/// Resolves receivers' names as they're connected to. Unless private
+/// addresses are allowed, a name with any address that isn't public fails to
+/// resolve, and only the addresses checked here are connected to. Addresses
+/// in urls aren't resolved, so `check_url` is what checks those.
+#[derive(Clone)]
+pub struct Resolver {
+    allow_private: bool,
+}
+
+impl Resolve for Resolver {
+    type Addrs = std::vec::IntoIter<IpAddr>;
+    type Future = FutureResult<Self::Addrs, io::Error>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let addrs = if self.allow_private {
+            net::resolve(name.as_str())
+        } else {
+            net::resolve_public(name.as_str())
+        };
+        future::result(
+            addrs
+                .map(Vec::into_iter)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        )
+    }
+}
+
+pub type Client = hyper::Client<HttpsConnector<HttpConnector<Resolver>>>;
+
+pub fn client() -> Client {
+    build_client(allow_private())
+}
+
+/// A client that doesn't follow redirects, and only connects to public
+/// addresses unless `allow_private`.
+pub fn build_client(allow_private: bool) -> Client {
+    let mut http = HttpConnector::new_with_resolver(Resolver { allow_private });
+    http.enforce_http(false);
+    let tls = native_tls::TlsConnector::new().expect("error building tls connector");
+    hyper::Client::builder()
+        .keep_alive(false)
+        .build(HttpsConnector::from((http, tls)))
+}
+
+/// Posts `body` to `url`, returning the response status.
+pub fn post(
+    client: &Client,
+    url: &str,
+    headers: &[(&str, String)],
+    body: String,
+) -> std::result::Result<StatusCode, String> {
+    let mut req = Request::post(url);
+    req.header("Content-Type", "application/json");
+    for (name, value) in headers {
+        req.header(*name, value.as_str());
+    }
+    let req = req.body(Body::from(body)).map_err(|e| e.to_string())?;
+    let mut runtime = Runtime::new().map_err(|e| e.to_string())?;
+    let res = Timeout::new(client.request(req), time::Duration::from_secs(TIMEOUT_SECS));
+    runtime
+        .block_on(res)
+        .map(|resp| resp.status())
+        .map_err(|e| match e.into_inner() {
+            Some(e) => e.to_string(),
+            None => "timed out waiting for the receiver".to_string(),
+        })
+}
+
+/// Posts a delivery, returning the response status if there was a response.
+fn send(
+    client: &Client,
+    url: &str,
+    secret: &str,
+    delivery: &Delivery,
+    now: NaiveDateTime,
+) -> (Option<i32>, Option<String>) {
+    if let Err(e) = check_url(url) {
+        return (None, Some(e));
+    }
+    let timestamp = now.timestamp();
+    let headers = [
+        (
+            SIGNATURE_HEADER,
+            signature(secret, timestamp, &delivery.payload),
+        ),
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (EVENT_HEADER, delivery.event.clone()),
+        (DELIVERY_HEADER, delivery.id.to_string()),
+    ];
+    match post(client, url, &headers, delivery.payload.clone()) {
+        Ok(status) if status.is_success() => (Some(i32::from(status.as_u16())), None),
+        Ok(status) => (
+            Some(i32::from(status.as_u16())),
+            Some(format!("receiver responded with {}", status)),
+        ),
+        Err(e) => (None, Some(e)),
+    }
+}
+
+/// Sends the deliveries that are due at `now`.
+pub fn deliver_due(now: NaiveDateTime, conn: &SqliteConnection, client: &Client) -> Result<Report> {
+    let mut report = Report::default();
+    let mut hooks = BTreeMap::new();
+    for delivery in webhook::due(now, BATCH_SIZE, conn)? {
+        if !hooks.contains_key(&delivery.webhook_id) {
+            hooks.insert(
+                delivery.webhook_id,
+                webhook::find(delivery.webhook_id, conn)?,
+            );
+        }
+        let hook = &hooks[&delivery.webhook_id];
+        let (response_status, error) = send(client, &hook.url, &hook.secret, &delivery, now);
+        let attempts = delivery.attempts + 1;
+        let status = if error.is_none() {
+            report.delivered += 1;
+            webhook::DELIVERED
+        } else if attempts >= MAX_ATTEMPTS {
+            report.failed += 1;
+            webhook::FAILED
+        } else {
+            report.retrying += 1;
+            webhook::PENDING
+        };
+        webhook::record_attempt(
+            &delivery,
+            status,
+            now,
+            now + backoff(attempts),
+            response_status,
+            error,
+            conn,
+        )?;
+    }
+    Ok(report)
+}
+
+/// Sends due deliveries every `INTERVAL_SECS` on its own thread.
+pub fn spawn(db_uri: &str) -> JoinHandle<()> {
+    let db_uri = db_uri.to_string();
+    thread::spawn(move || {
+        let conn = SqliteConnection::establish(&db_uri).expect("error connecting to db");
+        let client = client();
+        loop {
+            if let Err(e) = deliver_due(Utc::now().naive_utc(), &conn, &client) {
+                logging::error("webhooks", format!("failed to deliver: {}", e));
+            }
+            thread::sleep(time::Duration::from_secs(INTERVAL_SECS));
+        }
+    })
+}
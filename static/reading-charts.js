@@ -1,124 +1,97 @@
-var startTime = moment().subtract(5, 'days').format('X');
-var endTime = moment().format('X');
+// Whole hours, so the aggregates come from the hourly rollups.
+var startTime = moment().startOf('hour').subtract(30, 'days').format('X');
+var endTime = moment().startOf('hour').add(1, 'hours').format('X');
+var tz = Intl.DateTimeFormat().resolvedOptions().timeZone || 'UTC';
 
 var a = document.createElement('a');
 a.href = window.location.href;
 
-var url = '/api' + a.pathname + '/readings?start=' + startTime + '&end=' + endTime;
+var url = '/api' + a.pathname + '/aggregate?start=' + startTime + '&end=' + endTime +
+	'&interval=1h&tz=' + encodeURIComponent(tz);
 
-var spec = {
-	"$schema": "https://vega.github.io/schema/vega-lite/v3.json",
-	"description": "Sensor Data",
-	"data": {
-		"url": url,
-		"format": { "property": "data.readings" }
-	},
-	"vconcat": [
-		{
-			"width": 1000,
-			"mark": {
-				"type": "line",
-				"point": true
-			},
-			"encoding": {
-				"x": {
-					"field": "timestamp",
-					"type": "temporal",
-					"timeUnit": "yearmonthdatehoursminutesseconds",
-					"axis": { "title": "" }
-				},
-				"y": {
-					"field": "peak_current_mA",
-					"type": "quantitative",
-					"axis": { "title": "peak current (mA)" },
-				}
-			}
-		},
-		{
-			"width": 1000,
-			"mark": {
-				"type": "line",
-				"point": true
-			},
-			"encoding": {
-				"x": {
-					"field": "timestamp",
-					"type": "temporal",
-					"timeUnit": "yearmonthdatehoursminutesseconds",
-					"axis": { "title": "" }
-				},
-				"y": {
-					"field": "peak_power_mW",
-					"type": "quantitative",
-					"axis": { "title": "peak power (mW)" },
-				}
-			}
-		},
-		{
-			"width": 1000,
-			"mark": {
-				"type": "line",
-				"point": true
-			},
-			"encoding": {
-				"x": {
-					"field": "timestamp",
-					"type": "temporal",
-					"timeUnit": "yearmonthdatehoursminutesseconds",
-					"axis": { "title": "" }
-				},
-				"y": {
-					"field": "peak_voltage_V",
-					"type": "quantitative",
-					"axis": { "title": "peak voltage (V)" },
-				}
-			}
-		},
-		{
-			"width": 1000,
-			"mark": {
-				"type": "line",
-				"point": true
-			},
-			"encoding": {
-				"x": {
-					"field": "timestamp",
-					"type": "temporal",
-					"timeUnit": "yearmonthdatehoursminutesseconds",
-					"axis": { "title": "" }
-				},
-				"y": {
-					"field": "temp_celsius",
-					"type": "quantitative",
-					"axis": { "title": "temp (C)" },
-				}
-			}
+var channelsUrl = '/api' + a.pathname + '/channels';
+
+var streamUrl = '/api' + a.pathname + '/stream';
+
+function channelChart(channel) {
+	return {
+		"width": 1000,
+		"mark": {
+			"type": "line",
+			"point": true
 		},
-		{
-			"width": 1000,
-			"mark": {
-				"type": "line",
-				"point": true
+		"encoding": {
+			"x": {
+				"field": "timestamp",
+				"type": "temporal",
+				"timeUnit": "yearmonthdatehoursminutesseconds",
+				"axis": { "title": "" }
 			},
-			"encoding": {
-				"x": {
-					"field": "timestamp",
-					"type": "temporal",
-					"timeUnit": "yearmonthdatehoursminutesseconds",
-					"axis": { "title": "" }
-				},
-				"y": {
-					"field": "batt_V",
-					"type": "quantitative",
-					"axis": { "title": "battery (V)" },
-				}
+			"y": {
+				"field": channel.name,
+				"type": "quantitative",
+				"axis": { "title": channel.name.replace(/_/g, ' ') + ' (' + channel.unit + ')' },
 			}
 		}
-	]
+	};
 }
 
 var embedOpts = {
 	actions: false,
 };
 
-vegaEmbed('#reading-charts', spec, embedOpts);
+// One row per bucket with the mean of each channel, which is what the charts
+// plot.
+function bucketRows(buckets) {
+	return buckets.map(function(b) {
+		var row = { "timestamp": b.start + 'Z' };
+		Object.keys(b.channels).forEach(function(name) {
+			row[name] = b.channels[name].mean;
+		});
+		return row;
+	});
+}
+
+Promise.all([
+	fetch(channelsUrl).then(function(res) { return res.json(); }),
+	fetch(url).then(function(res) { return res.json(); })
+]).then(function(bodies) {
+	var spec = {
+		"$schema": "https://vega.github.io/schema/vega-lite/v3.json",
+		"description": "Sensor Data",
+		"data": {
+			"name": "readings",
+			"values": bucketRows(bodies[1].data.buckets)
+		},
+		"vconcat": bodies[0].channels.map(channelChart)
+	};
+	return vegaEmbed('#reading-charts', spec, embedOpts);
+}).then(function(result) {
+	followReadings(result.view);
+});
+
+// A row like the bucket rows for a reading from the live stream.
+function liveRow(reading) {
+	var row = { "timestamp": new Date(reading.timestamp * 1000).toISOString() };
+	Object.keys(reading).forEach(function(name) {
+		if (name !== 'timestamp' && name !== 'sensor_id' && name !== 'channels') {
+			row[name] = reading[name];
+		}
+	});
+	Object.keys(reading.channels).forEach(function(name) {
+		row[name] = reading.channels[name];
+	});
+	return row;
+}
+
+// Appends readings to the charts as the sensor sends them.
+function followReadings(view) {
+	if (!window.EventSource) {
+		return;
+	}
+	var source = new EventSource(streamUrl);
+	source.addEventListener('reading', function(e) {
+		var row = liveRow(JSON.parse(e.data));
+		view.change('readings', vega.changeset().insert([row])).run();
+	});
+}